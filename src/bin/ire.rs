@@ -126,15 +126,13 @@ fn cli_client(args: &ArgMatches) -> i32 {
     info!("Connecting to {}", peer_ri.router_id.hash());
     match args.get_one::<String>("transport").map(|s| s.as_str()) {
         Some("NTCP") => {
-            let mut ntcp =
-                transport::ntcp::Manager::new("127.0.0.1:0".parse().unwrap(), distributor);
-            ntcp.set_context(mock_context());
+            let ntcp = transport::ntcp::Manager::new("127.0.0.1:0".parse().unwrap(), distributor);
             let conn = ntcp
-                .connect(rsk.rid, rsk.signing_private_key, peer_ri.clone())
+                .connect(rsk.rid, rsk.signing_private_key, peer_ri)
                 .unwrap()
-                .and_then(move |_| {
+                .and_then(|conn| {
                     info!("Connection established!");
-                    ntcp.sink().send((peer_ri, i2np::Message::dummy_data()))
+                    conn.send(i2np::Message::dummy_data())
                 })
                 .and_then(|_| {
                     info!("Dummy data sent!");
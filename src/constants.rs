@@ -11,6 +11,11 @@ lazy_static! {
         spec.padding = Some('=');
         spec.encoding().unwrap()
     };
+    pub static ref I2P_BASE32: Encoding = {
+        let mut spec = Specification::new();
+        spec.symbols.push_str("abcdefghijklmnopqrstuvwxyz234567");
+        spec.encoding().unwrap()
+    };
 }
 
 // Sig types
@@ -22,6 +27,7 @@ pub const RSA_SHA256_2048: u16 = 4;
 pub const RSA_SHA384_3072: u16 = 5;
 pub const RSA_SHA512_4096: u16 = 6;
 pub const ED25519: u16 = 7;
+pub const REDDSA_SHA512_ED25519: u16 = 11;
 
 // Enc types
 pub const ELGAMAL2048: u16 = 0;
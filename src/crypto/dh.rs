@@ -1,11 +1,20 @@
+use futures::{future, sync::oneshot, Future};
 use num_bigint::BigUint;
 use rand::{rngs::OsRng, Rng};
+use std::collections::VecDeque;
 use std::iter::repeat;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{
+    mpsc::{self, TrySendError},
+    Arc, Mutex,
+};
+use std::thread;
 
-use crate::constants::{ELGAMAL_G, ELGAMAL_P};
+use crate::constants::{ELGAMAL_G, ELGAMAL_P, ELGAMAL_PM2};
 use crate::crypto::math::rectify;
 use crate::crypto::SessionKey;
 
+#[derive(Clone)]
 pub struct DHSessionKeyBuilder {
     dh_priv: BigUint,
     dh_pub: BigUint,
@@ -13,7 +22,12 @@ pub struct DHSessionKeyBuilder {
 
 impl DHSessionKeyBuilder {
     pub fn new() -> Self {
-        let mut rng = OsRng;
+        DHSessionKeyBuilder::from_rng(&mut OsRng)
+    }
+
+    /// Generates a DH pair from the given RNG. With a deterministic RNG, this
+    /// makes the handshake reproducible.
+    pub fn from_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         let mut buf = vec![0; 256];
         rng.fill(&mut buf[..]);
         let dh_priv = BigUint::from_bytes_be(&buf);
@@ -21,40 +35,521 @@ impl DHSessionKeyBuilder {
         DHSessionKeyBuilder { dh_priv, dh_pub }
     }
 
+    /// Returns our DH public value as exactly 256 big-endian bytes.
+    ///
+    /// This is the form sent on the wire in the NTCP SessionRequest (X) and
+    /// SessionCreated (Y) messages, and hashed into `HXxorHB` and the
+    /// SessionConfirmed signatures.
     pub fn get_pub(&self) -> Vec<u8> {
         rectify(&self.dh_pub, 256)
     }
 
+    /// Returns our DH public value as minimal-length big-endian bytes.
+    ///
+    /// Leading zero bytes are stripped, so whenever the top byte of the value
+    /// is zero (about one key in 256) this is shorter than 256 bytes, and it
+    /// differs from [`DHSessionKeyBuilder::get_pub`].
+    ///
+    /// This is the form used wherever I2P treats the value as a plain number
+    /// rather than a fixed-size field. Use [`DHSessionKeyBuilder::get_pub`]
+    /// for anything that is sent or hashed during the NTCP handshake.
+    pub fn get_pub_minimal(&self) -> Vec<u8> {
+        self.dh_pub.to_bytes_be()
+    }
+
     pub fn build_session_key(&self, peer_pub: &[u8; 256]) -> SessionKey {
         // Calculate the exchanged DH key
         let peer_pub = BigUint::from_bytes_be(peer_pub);
         let dh_key = peer_pub.modpow(&self.dh_priv, &ELGAMAL_P);
-        // Represent the exchanged key as a positive minimal-length two's-complement
-        // big-endian byte array. If most significant bit is 1, prepend a zero-byte
-        // (to match Java's BigInteger.toByteArray() representation).
-        let mut buf = dh_key.to_bytes_be();
-        if buf[0] & 0x80 != 0 {
-            buf.insert(0, 0x00);
+        session_key_from_dh(&dh_key)
+    }
+}
+
+/// Checks that a peer's DH public value is 256 bytes and in the range
+/// [2, p - 2]. Anything else would produce a degenerate shared secret.
+pub fn is_valid_pub(peer_pub: &[u8]) -> bool {
+    if peer_pub.len() != 256 {
+        return false;
+    }
+    let peer_pub = BigUint::from_bytes_be(peer_pub);
+    peer_pub >= BigUint::from(2u8) && peer_pub <= *ELGAMAL_PM2
+}
+
+struct PoolKeys {
+    ready: VecDeque<DHSessionKeyBuilder>,
+    /// The number of keypairs queued for generation on a [`DhWorkers`] pool.
+    queued: usize,
+}
+
+/// A pool of pre-generated DH keypairs, so that new connections don't have to
+/// wait for key generation.
+///
+/// Each keypair is removed from the pool when it is handed out, and the pool
+/// is refilled to its target size on the workers it was taken from.
+#[derive(Clone)]
+pub struct DhKeyPool {
+    target: usize,
+    keys: Arc<Mutex<PoolKeys>>,
+}
+
+impl DhKeyPool {
+    /// Creates an empty pool. It will start filling when first used.
+    pub fn new(target: usize) -> Self {
+        DhKeyPool {
+            target,
+            keys: Arc::new(Mutex::new(PoolKeys {
+                ready: VecDeque::with_capacity(target),
+                queued: 0,
+            })),
+        }
+    }
+
+    /// Takes a keypair out of the pool, generating one on the given workers if
+    /// the pool is empty. The pool is then refilled on the same workers.
+    pub fn get_on(&self, workers: &DhWorkers) -> DhFuture<DHSessionKeyBuilder> {
+        let (key, missing) = {
+            let mut keys = self.keys.lock().unwrap();
+            let key = keys.ready.pop_front();
+            let missing = self.target.saturating_sub(keys.ready.len() + keys.queued);
+            keys.queued += missing;
+            (key, missing)
+        };
+
+        // Queue the keypair we need now ahead of the refill
+        let key = match key {
+            Some(key) => Box::new(future::ok(key)),
+            None => workers.keypair(),
+        };
+        for _ in 0..missing {
+            self.refill_one_on(workers);
+        }
+        key
+    }
+
+    /// Returns the number of keypairs currently in the pool.
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap().ready.len()
+    }
+
+    /// Returns true if the pool currently has no keypairs.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Generates keypairs until the pool reaches its target size.
+    pub fn refill(&self) {
+        while self.len() < self.target {
+            // Generate outside the lock
+            let key = DHSessionKeyBuilder::new();
+            let mut keys = self.keys.lock().unwrap();
+            if keys.ready.len() < self.target {
+                keys.ready.push_back(key);
+            }
+        }
+    }
+
+    /// Generates one keypair for the pool on the given workers. The caller
+    /// must already have counted it as queued.
+    fn refill_one_on(&self, workers: &DhWorkers) {
+        let pool = self.clone();
+        let job = Box::new(move || {
+            let key = DHSessionKeyBuilder::new();
+            let mut keys = pool.keys.lock().unwrap();
+            keys.queued -= 1;
+            if keys.ready.len() < pool.target {
+                keys.ready.push_back(key);
+            }
+        });
+        if workers.submit(job).is_err() {
+            // The pool will try again when the next keypair is taken
+            self.keys.lock().unwrap().queued -= 1;
         }
-        // If that byte array is less than 32 bytes, append 0x00 bytes to extend to
-        // 32 bytes. This is vanishingly unlikely, but have to do it for compatibility.
-        let length = buf.len();
-        if length < 32 {
-            buf.extend(repeat(0).take(32 - length));
+    }
+}
+
+/// Why a computation on a [`DhWorkers`] pool did not produce a result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DhError {
+    /// The pool's queue was full, so the computation was not started.
+    Busy,
+    /// The computation panicked, or the pool's threads have gone away.
+    Failed,
+}
+
+/// The result of a computation on a [`DhWorkers`] pool.
+pub type DhFuture<T> = Box<dyn Future<Item = T, Error = DhError> + Send>;
+
+type DhJob = Box<dyn FnOnce() + Send>;
+
+/// The number of computations that may wait for each thread of a
+/// [`DhWorkers`] pool.
+const DH_QUEUE_PER_THREAD: usize = 16;
+
+/// A bounded pool of threads for DH computations.
+///
+/// Generating a keypair and deriving a session key are each a 2048-bit modpow,
+/// which can take long enough to stall the reactor if run on it. Computations
+/// are instead queued for the pool, and their results returned as futures.
+/// Once the queue is full, new computations fail with [`DhError::Busy`].
+#[derive(Clone)]
+pub struct DhWorkers {
+    jobs: Arc<Mutex<mpsc::SyncSender<DhJob>>>,
+}
+
+impl DhWorkers {
+    /// Starts a pool with the given number of threads, each with room for
+    /// `DH_QUEUE_PER_THREAD` waiting computations.
+    ///
+    /// Panics if `threads` is zero.
+    pub fn new(threads: usize) -> Self {
+        DhWorkers::with_queue_len(threads, threads * DH_QUEUE_PER_THREAD)
+    }
+
+    /// Starts a pool with the given number of threads, and room for
+    /// `queue_len` computations waiting for them. The threads exit once every
+    /// handle to the pool has been dropped.
+    ///
+    /// Panics if `threads` is zero.
+    pub fn with_queue_len(threads: usize, queue_len: usize) -> Self {
+        assert!(threads > 0, "DhWorkers needs at least one thread");
+        let (tx, rx) = mpsc::sync_channel::<DhJob>(queue_len);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx = rx.clone();
+            thread::spawn(move || loop {
+                // Only hold the lock while waiting for a job
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    Ok(job) => {
+                        // Keep the thread if a job panics; its result is dropped
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            error!("DH computation panicked");
+                        }
+                    }
+                    Err(_) => break,
+                }
+            });
+        }
+        DhWorkers {
+            jobs: Arc::new(Mutex::new(tx)),
+        }
+    }
+
+    /// Queues a job for the pool, unless the queue is full.
+    fn submit(&self, job: DhJob) -> Result<(), DhError> {
+        match self.jobs.lock().unwrap().try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(DhError::Busy),
+            Err(TrySendError::Disconnected(_)) => Err(DhError::Failed),
         }
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&buf[0..32]);
-        SessionKey(key)
+    }
+
+    fn run<F, T>(&self, f: F) -> DhFuture<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        // If the receiver was dropped, nobody wants the result
+        let job: DhJob = Box::new(move || {
+            let _ = tx.send(f());
+        });
+        match self.submit(job) {
+            // If the job panics, tx is dropped and rx is canceled
+            Ok(()) => Box::new(rx.map_err(|_| DhError::Failed)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Generates a new keypair.
+    pub fn keypair(&self) -> DhFuture<DHSessionKeyBuilder> {
+        self.run(DHSessionKeyBuilder::new)
+    }
+
+    /// Derives the session key for the given keypair and peer public value.
+    pub fn session_key(
+        &self,
+        dh_key_builder: DHSessionKeyBuilder,
+        peer_pub: [u8; 256],
+    ) -> DhFuture<SessionKey> {
+        self.run(move || dh_key_builder.build_session_key(&peer_pub))
     }
 }
 
+/// Derives a session key from an exchanged DH key, as the Java implementation does.
+///
+/// The key is the first 32 bytes of the value's `BigInteger.toByteArray()`
+/// representation, extended with trailing zero bytes if that is too short.
+fn session_key_from_dh(dh_key: &BigUint) -> SessionKey {
+    // Represent the exchanged key as a positive minimal-length two's-complement
+    // big-endian byte array. If most significant bit is 1, prepend a zero-byte
+    // (to match Java's BigInteger.toByteArray() representation).
+    let mut buf = dh_key.to_bytes_be();
+    if buf[0] & 0x80 != 0 {
+        buf.insert(0, 0x00);
+    }
+    // If that byte array is less than 32 bytes, append 0x00 bytes to extend to
+    // 32 bytes. This is vanishingly unlikely, but have to do it for compatibility.
+    let length = buf.len();
+    if length < 32 {
+        buf.extend(repeat(0).take(32 - length));
+    }
+    // The key is the most significant 32 bytes, including any sign byte.
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&buf[0..32]);
+    SessionKey(key)
+}
+
 #[cfg(test)]
 mod tests {
+    use futures::Future;
     use num_bigint::BigUint;
     use num_traits::Num;
+    use rand::rngs::mock::StepRng;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
 
-    use super::DHSessionKeyBuilder;
-    use crate::crypto::SessionKey;
+    use super::{
+        is_valid_pub, session_key_from_dh, DHSessionKeyBuilder, DhError, DhKeyPool, DhWorkers,
+    };
+    use crate::constants::{ELGAMAL_P, ELGAMAL_PM2};
+    use crate::crypto::{math::rectify, SessionKey};
+
+    #[test]
+    fn dh_key_pool() {
+        let workers = DhWorkers::new(1);
+        let pool = DhKeyPool::new(3);
+        assert_eq!(pool.len(), 0);
+        pool.refill();
+        assert_eq!(pool.len(), 3);
+
+        // Keypairs are handed out once, and are distinct
+        let mut pubs: Vec<_> = (0..5)
+            .map(|_| pool.get_on(&workers).wait().unwrap().get_pub())
+            .collect();
+        pubs.sort();
+        pubs.dedup();
+        assert_eq!(pubs.len(), 5);
+
+        // The pool refills in the background
+        let start = Instant::now();
+        while pool.len() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(60));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn dh_workers() {
+        let workers = DhWorkers::new(2);
+        let alice = workers.keypair().wait().unwrap();
+        let bob = workers.keypair().wait().unwrap();
+        assert_ne!(alice.get_pub(), bob.get_pub());
+
+        // Keys derived on the workers match those derived directly
+        let mut bob_pub = [0; 256];
+        bob_pub.copy_from_slice(&bob.get_pub());
+        let key = workers.session_key(alice.clone(), bob_pub).wait().unwrap();
+        assert_eq!(key.0, alice.build_session_key(&bob_pub).0);
+    }
+
+    #[test]
+    fn dh_workers_queue_full() {
+        let workers = DhWorkers::with_queue_len(1, 1);
+
+        // Occupy the thread, and fill the queue behind it
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let blocked = workers.run(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        started_rx.recv().unwrap();
+        let queued = workers.keypair();
+
+        // Further computations are refused straight away
+        assert_eq!(workers.keypair().wait().err(), Some(DhError::Busy));
+
+        // Once the thread is free, the queue drains
+        release_tx.send(()).unwrap();
+        blocked.wait().unwrap();
+        queued.wait().unwrap();
+        workers.keypair().wait().unwrap();
+    }
+
+    #[test]
+    fn dh_workers_survive_panic() {
+        let workers = DhWorkers::new(1);
+        assert_eq!(
+            workers.run(|| panic!("Test panic")).wait().err(),
+            Some(DhError::Failed)
+        );
+
+        // The thread is still there for the next computation
+        workers.keypair().wait().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "DhWorkers needs at least one thread")]
+    fn dh_workers_without_threads() {
+        DhWorkers::new(0);
+    }
+
+    #[test]
+    fn session_key_agreement() {
+        let pub_of = |dh: &DHSessionKeyBuilder| {
+            let mut buf = [0; 256];
+            buf.copy_from_slice(&dh.get_pub());
+            buf
+        };
+
+        let mut keys = Vec::new();
+        for i in 0..8u64 {
+            let alice = DHSessionKeyBuilder::from_rng(&mut StepRng::new(
+                0x0123_4567_89ab_cdef ^ i,
+                0x1111_1111_1111_1111,
+            ));
+            let bob = DHSessionKeyBuilder::from_rng(&mut StepRng::new(
+                0xfedc_ba98_7654_3210 ^ i,
+                0x0101_0101_0101_0101,
+            ));
+            let eve = DHSessionKeyBuilder::from_rng(&mut StepRng::new(i, 0x0f0f_0f0f_0f0f_0f0f));
+
+            // Both sides of the exchange derive the same key
+            let alice_key = alice.build_session_key(&pub_of(&bob));
+            let bob_key = bob.build_session_key(&pub_of(&alice));
+            assert_eq!(alice_key.0, bob_key.0);
+
+            // A substituted public value gives a different key
+            assert_ne!(alice.build_session_key(&pub_of(&eve)).0, alice_key.0);
+            assert_ne!(eve.build_session_key(&pub_of(&alice)).0, bob_key.0);
+
+            keys.push(alice_key.0);
+        }
+
+        // Every exchange has its own key
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 8);
+    }
+
+    #[test]
+    fn minimal_pub() {
+        for _ in 0..8 {
+            let dh = DHSessionKeyBuilder::new();
+            let minimal = dh.get_pub_minimal();
+            assert!(minimal.len() <= 256);
+            assert_ne!(minimal[0], 0);
+
+            let mut extended = vec![0; 256 - minimal.len()];
+            extended.extend_from_slice(&minimal);
+            assert_eq!(extended, dh.get_pub());
+        }
+
+        // A public value whose top byte is zero loses it
+        let mut pub_bytes = [0x5a; 256];
+        pub_bytes[0] = 0;
+        let dh = DHSessionKeyBuilder {
+            dh_priv: BigUint::from(1u8),
+            dh_pub: BigUint::from_bytes_be(&pub_bytes),
+        };
+        assert_eq!(dh.get_pub(), &pub_bytes[..]);
+        assert_eq!(dh.get_pub_minimal(), &pub_bytes[1..]);
+    }
+
+    #[test]
+    fn valid_pub() {
+        assert!(is_valid_pub(&DHSessionKeyBuilder::new().get_pub()));
+        assert!(is_valid_pub(&rectify(&BigUint::from(2u8), 256)));
+        assert!(is_valid_pub(&rectify(&ELGAMAL_PM2, 256)));
+
+        // Degenerate values
+        assert!(!is_valid_pub(&[0; 256]));
+        assert!(!is_valid_pub(&rectify(&BigUint::from(1u8), 256)));
+        assert!(!is_valid_pub(&rectify(&(&*ELGAMAL_PM2 + 1u8), 256)));
+        assert!(!is_valid_pub(&rectify(&ELGAMAL_P, 256)));
+        assert!(!is_valid_pub(&[0xff; 256]));
+
+        // Wrong length
+        assert!(!is_valid_pub(&DHSessionKeyBuilder::new().get_pub()[1..]));
+        assert!(!is_valid_pub(&[]));
+    }
+
+    #[test]
+    fn session_key_sign_byte() {
+        // MSB set: a zero byte is prepended, and the last byte of the first
+        // 32 is pushed out of the key.
+        let mut dh_key = [0u8; 256];
+        for (i, b) in dh_key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        dh_key[0] = 0x80;
+        let mut expected = [0u8; 32];
+        expected[1..].copy_from_slice(&dh_key[..31]);
+        assert_eq!(
+            session_key_from_dh(&BigUint::from_bytes_be(&dh_key)).0,
+            expected
+        );
+
+        // MSB clear: the key is the first 32 bytes as-is
+        dh_key[0] = 0x7f;
+        assert_eq!(
+            session_key_from_dh(&BigUint::from_bytes_be(&dh_key)).0,
+            dh_key[..32]
+        );
+
+        // A leading zero byte is dropped by BigUint, then re-added as a sign
+        // byte if the next byte has its MSB set
+        dh_key[0] = 0x00;
+        dh_key[1] = 0xff;
+        assert_eq!(
+            session_key_from_dh(&BigUint::from_bytes_be(&dh_key)).0,
+            dh_key[..32]
+        );
+    }
+
+    #[test]
+    fn session_key_short_buffer() {
+        // Short values are extended with trailing zeroes
+        assert_eq!(
+            session_key_from_dh(&BigUint::from_bytes_be(&[0x01, 0x02, 0x03])).0,
+            [
+                0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+
+        // The sign byte is added before extending
+        assert_eq!(
+            session_key_from_dh(&BigUint::from_bytes_be(&[0x80, 0x02])).0,
+            [
+                0x00, 0x80, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+
+        // 31 bytes with the MSB set fill the key exactly
+        let dh_key = [0xaa; 31];
+        let mut expected = [0xaa; 32];
+        expected[0] = 0x00;
+        assert_eq!(
+            session_key_from_dh(&BigUint::from_bytes_be(&dh_key)).0,
+            expected
+        );
+
+        // 31 bytes with the MSB clear get one trailing zero
+        let dh_key = [0x55; 31];
+        let mut expected = [0x55; 32];
+        expected[31] = 0x00;
+        assert_eq!(
+            session_key_from_dh(&BigUint::from_bytes_be(&dh_key)).0,
+            expected
+        );
+    }
 
     #[test]
     fn build_session_key() {
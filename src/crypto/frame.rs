@@ -25,6 +25,7 @@ pub fn sig_type(i: &[u8]) -> IResult<&[u8], SigType> {
         constants::RSA_SHA384_3072 => Some(SigType::Rsa3072Sha384),
         constants::RSA_SHA512_4096 => Some(SigType::Rsa4096Sha512),
         constants::ED25519 => Some(SigType::Ed25519),
+        constants::REDDSA_SHA512_ED25519 => Some(SigType::RedDsaSha512Ed25519),
         _ => None,
     })(i)
 }
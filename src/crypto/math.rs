@@ -1,20 +1,25 @@
 use num_bigint::BigUint;
+use num_traits::Zero;
 
 /// Converts the given number into an array of exactly len bytes, padding with
 /// zeroes if necessary.
 ///
+/// Panics if the number does not fit in len bytes.
+///
 /// The Java implementation handles the fact that Java BigInteger prepends a
 /// sign bit, which can create an extra leading zero-byte. BigUint does not do
 /// this, so we simplify the logic.
 pub fn rectify(bi: &BigUint, len: usize) -> Vec<u8> {
+    // BigUint serializes zero as a single zero byte
+    if bi.is_zero() {
+        warn!("Warning: dh_pub is zero!");
+        return vec![0u8; len];
+    }
+
     let mut b = bi.to_bytes_be();
     match b.len() {
         sz if sz == len => b,
         sz if sz > len => panic!("key too big ({}) max is {}", sz, len),
-        0 => {
-            warn!("Warning: dh_pub is zero!");
-            vec![0u8; len]
-        }
         _ => {
             // Smaller than needed
             let mut ret = vec![0u8; len];
@@ -55,4 +60,42 @@ mod tests {
             &[0x00, 0xff, 0xab, 0xcd, 0xef]
         );
     }
+
+    #[test]
+    fn rectify_left_pad() {
+        // Many leading zero bytes
+        let mut val = [0u8; 256];
+        val[250..].copy_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let res = rectify(&BigUint::from_bytes_be(&val), 256);
+        assert_eq!(res.len(), 256);
+        assert_eq!(&res[..], &val[..]);
+
+        // Only the first byte is zero
+        let mut val = [0xffu8; 256];
+        val[0] = 0;
+        let res = rectify(&BigUint::from_bytes_be(&val), 256);
+        assert_eq!(res.len(), 256);
+        assert_eq!(&res[..], &val[..]);
+    }
+
+    #[test]
+    fn rectify_exact() {
+        // High bit set, no sign byte is prepended
+        let mut val = [0x5au8; 256];
+        val[0] = 0x80;
+        let res = rectify(&BigUint::from_bytes_be(&val), 256);
+        assert_eq!(res.len(), 256);
+        assert_eq!(&res[..], &val[..]);
+
+        let val = [0xffu8; 256];
+        assert_eq!(&rectify(&BigUint::from_bytes_be(&val), 256)[..], &val[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "key too big (257) max is 256")]
+    fn rectify_too_big() {
+        let mut val = [0u8; 257];
+        val[0] = 0x01;
+        rectify(&BigUint::from_bytes_be(&val), 256);
+    }
 }
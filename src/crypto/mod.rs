@@ -33,6 +33,7 @@ pub(crate) mod dh;
 mod dsa;
 pub(crate) mod elgamal;
 pub(crate) mod math;
+mod reddsa;
 
 pub(crate) const AES_BLOCK_SIZE: usize = 16;
 
@@ -46,6 +47,7 @@ pub enum Error {
     NoSignature,
     SigningFailed,
     TypeMismatch,
+    UnsupportedSigType,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -59,6 +61,7 @@ impl fmt::Display for Error {
             Error::NoSignature => "No signature".fmt(f),
             Error::SigningFailed => "Failed to create a signature".fmt(f),
             Error::TypeMismatch => "Signature type doesn't match key type".fmt(f),
+            Error::UnsupportedSigType => "Signature type is not supported".fmt(f),
         }
     }
 }
@@ -74,6 +77,7 @@ pub enum SigType {
     Rsa3072Sha384,
     Rsa4096Sha512,
     Ed25519,
+    RedDsaSha512Ed25519,
 }
 
 impl SigType {
@@ -95,6 +99,22 @@ impl SigType {
             SigType::Rsa3072Sha384 => constants::RSA_SHA384_3072,
             SigType::Rsa4096Sha512 => constants::RSA_SHA512_4096,
             SigType::Ed25519 => constants::ED25519,
+            SigType::RedDsaSha512Ed25519 => constants::REDDSA_SHA512_ED25519,
+        }
+    }
+
+    /// Returns true if we can verify signatures of this type online.
+    pub fn is_supported(self) -> bool {
+        match self {
+            SigType::DsaSha1
+            | SigType::EcdsaSha256P256
+            | SigType::EcdsaSha384P384
+            | SigType::Ed25519
+            | SigType::RedDsaSha512Ed25519 => true,
+            SigType::EcdsaSha512P521
+            | SigType::Rsa2048Sha256
+            | SigType::Rsa3072Sha384
+            | SigType::Rsa4096Sha512 => false,
         }
     }
 
@@ -107,7 +127,9 @@ impl SigType {
             SigType::Rsa2048Sha256 => 256,
             SigType::Rsa3072Sha384 => 384,
             SigType::Rsa4096Sha512 => 512,
-            SigType::Ed25519 => ed25519_dalek::PUBLIC_KEY_LENGTH as u32,
+            SigType::Ed25519 | SigType::RedDsaSha512Ed25519 => {
+                ed25519_dalek::PUBLIC_KEY_LENGTH as u32
+            }
         }
     }
 
@@ -121,6 +143,7 @@ impl SigType {
             SigType::Rsa3072Sha384 => 768,
             SigType::Rsa4096Sha512 => 1024,
             SigType::Ed25519 => ed25519_dalek::SECRET_KEY_LENGTH as u32,
+            SigType::RedDsaSha512Ed25519 => reddsa::SECRET_KEY_LENGTH as u32,
         }
     }
 
@@ -133,7 +156,9 @@ impl SigType {
             SigType::Rsa2048Sha256 => 256,
             SigType::Rsa3072Sha384 => 384,
             SigType::Rsa4096Sha512 => 512,
-            SigType::Ed25519 => ed25519_dalek::SIGNATURE_LENGTH as u32,
+            SigType::Ed25519 | SigType::RedDsaSha512Ed25519 => {
+                ed25519_dalek::SIGNATURE_LENGTH as u32
+            }
         }
     }
 
@@ -261,6 +286,9 @@ pub enum SigningPublicKey {
     EcdsaSha384P384(p384::EncodedPoint, p384::ecdsa::VerifyingKey),
     EcdsaSha512P521,
     Ed25519(ed25519_dalek::PublicKey),
+    RedDsaSha512Ed25519(ed25519_dalek::PublicKey),
+    /// A key of a type that we can parse but not verify signatures with.
+    Unsupported(SigType, Vec<u8>),
 }
 
 impl SigningPublicKey {
@@ -271,6 +299,8 @@ impl SigningPublicKey {
             SigningPublicKey::EcdsaSha384P384(_, _) => SigType::EcdsaSha384P384,
             SigningPublicKey::EcdsaSha512P521 => SigType::EcdsaSha512P521,
             SigningPublicKey::Ed25519(_) => SigType::Ed25519,
+            SigningPublicKey::RedDsaSha512Ed25519(_) => SigType::RedDsaSha512Ed25519,
+            SigningPublicKey::Unsupported(sig_type, _) => sig_type,
         }
     }
 }
@@ -293,17 +323,33 @@ impl SigningPublicKey {
                     .map(|vk| SigningPublicKey::EcdsaSha384P384(encoded, vk))
                     .map_err(|_| Error::InvalidKey)
             }
-            SigType::EcdsaSha512P521 => unimplemented!(),
+            SigType::EcdsaSha512P521 => Err(Error::UnsupportedSigType),
             SigType::Rsa2048Sha256 | SigType::Rsa3072Sha384 | SigType::Rsa4096Sha512 => {
-                panic!("Online verifying not supported")
+                Err(Error::UnsupportedSigType)
             }
             SigType::Ed25519 => ed25519_dalek::PublicKey::from_bytes(data)
                 .map(SigningPublicKey::Ed25519)
                 .map_err(|_| Error::InvalidKey),
+            SigType::RedDsaSha512Ed25519 => ed25519_dalek::PublicKey::from_bytes(data)
+                .map(SigningPublicKey::RedDsaSha512Ed25519)
+                .map_err(|_| Error::InvalidKey),
             _ => Err(Error::InvalidKey),
         }
     }
 
+    /// Parses a key like [`SigningPublicKey::from_bytes`], but keeps keys of
+    /// types we do not support as [`SigningPublicKey::Unsupported`] instead
+    /// of failing.
+    pub fn from_bytes_unverified(sig_type: SigType, data: &[u8]) -> Result<Self, Error> {
+        if sig_type.is_supported() {
+            SigningPublicKey::from_bytes(sig_type, data)
+        } else if data.len() == sig_type.pubkey_len() as usize {
+            Ok(SigningPublicKey::Unsupported(sig_type, data.to_vec()))
+        } else {
+            Err(Error::InvalidKey)
+        }
+    }
+
     pub fn from_secret(priv_key: &SigningPrivateKey) -> Result<Self, Error> {
         match *priv_key {
             SigningPrivateKey::DsaSha1 => unimplemented!(),
@@ -311,6 +357,11 @@ impl SigningPublicKey {
             SigningPrivateKey::EcdsaSha384P384 => unimplemented!(),
             SigningPrivateKey::EcdsaSha512P521 => unimplemented!(),
             SigningPrivateKey::Ed25519(_, _, ref pk) => Ok(SigningPublicKey::Ed25519(*pk)),
+            SigningPrivateKey::RedDsaSha512Ed25519(ref sk) => {
+                ed25519_dalek::PublicKey::from_bytes(sk.public_bytes())
+                    .map(SigningPublicKey::RedDsaSha512Ed25519)
+                    .map_err(|_| Error::InvalidKey)
+            }
         }
     }
 
@@ -321,6 +372,8 @@ impl SigningPublicKey {
             SigningPublicKey::EcdsaSha384P384(ref pk, _) => &pk.as_bytes()[1..],
             SigningPublicKey::EcdsaSha512P521 => unimplemented!(),
             SigningPublicKey::Ed25519(ref pk) => pk.as_ref(),
+            SigningPublicKey::RedDsaSha512Ed25519(ref pk) => pk.as_ref(),
+            SigningPublicKey::Unsupported(_, ref pk) => &pk[..],
         }
     }
 
@@ -343,6 +396,11 @@ impl SigningPublicKey {
             (&SigningPublicKey::Ed25519(ref pk), &Signature::Ed25519(ref s)) => {
                 pk.verify(message, s).map_err(|_| Error::InvalidSignature)
             }
+            (
+                &SigningPublicKey::RedDsaSha512Ed25519(ref pk),
+                &Signature::RedDsaSha512Ed25519(ref s),
+            ) => pk.verify(message, s).map_err(|_| Error::InvalidSignature),
+            (&SigningPublicKey::Unsupported(..), _) => Err(Error::UnsupportedSigType),
             _ => Err(Error::TypeMismatch),
         }
     }
@@ -360,6 +418,7 @@ pub enum SigningPrivateKey {
         ed25519_dalek::ExpandedSecretKey,
         ed25519_dalek::PublicKey,
     ),
+    RedDsaSha512Ed25519(reddsa::SigningKey),
 }
 
 impl SigningPrivateKey {
@@ -382,6 +441,9 @@ impl SigningPrivateKey {
                 let pk = (&sk).into();
                 SigningPrivateKey::Ed25519(seed, sk, pk)
             }
+            SigType::RedDsaSha512Ed25519 => {
+                SigningPrivateKey::RedDsaSha512Ed25519(reddsa::SigningKey::generate())
+            }
         }
     }
 
@@ -401,6 +463,9 @@ impl SigningPrivateKey {
                     SigningPrivateKey::Ed25519(seed, sk, pk)
                 })
                 .map_err(|_| Error::InvalidKey),
+            SigType::RedDsaSha512Ed25519 => reddsa::SigningKey::from_bytes(data)
+                .map(SigningPrivateKey::RedDsaSha512Ed25519)
+                .ok_or(Error::InvalidKey),
         }
     }
 
@@ -411,6 +476,7 @@ impl SigningPrivateKey {
             SigningPrivateKey::EcdsaSha384P384 => unimplemented!(),
             SigningPrivateKey::EcdsaSha512P521 => unimplemented!(),
             SigningPrivateKey::Ed25519(ref seed, _, _) => seed.as_ref(),
+            SigningPrivateKey::RedDsaSha512Ed25519(ref sk) => sk.as_bytes(),
         }
     }
 
@@ -423,6 +489,11 @@ impl SigningPrivateKey {
             SigningPrivateKey::Ed25519(_, ref sk, ref pk) => {
                 Ok(Signature::Ed25519(sk.sign(msg, pk)))
             }
+            SigningPrivateKey::RedDsaSha512Ed25519(ref sk) => {
+                ed25519_dalek::Signature::from_bytes(&sk.sign(msg))
+                    .map(Signature::RedDsaSha512Ed25519)
+                    .map_err(|_| Error::SigningFailed)
+            }
         }
     }
 }
@@ -437,6 +508,7 @@ impl Clone for SigningPrivateKey {
                 SigningPrivateKey::EcdsaSha384P384 => SigType::EcdsaSha384P384,
                 SigningPrivateKey::EcdsaSha512P521 => SigType::EcdsaSha512P521,
                 SigningPrivateKey::Ed25519(..) => SigType::Ed25519,
+                SigningPrivateKey::RedDsaSha512Ed25519(_) => SigType::RedDsaSha512Ed25519,
             },
             self.as_bytes(),
         )
@@ -533,6 +605,7 @@ pub enum Signature {
     Rsa3072Sha384(Vec<u8>),
     Rsa4096Sha512(Vec<u8>),
     Ed25519(ed25519_dalek::Signature),
+    RedDsaSha512Ed25519(ed25519_dalek::Signature),
     Unsupported(Vec<u8>),
 }
 
@@ -549,6 +622,9 @@ impl Signature {
             SigType::Ed25519 => Ok(Signature::Ed25519(
                 ed25519_dalek::Signature::from_bytes(data).map_err(|_| Error::InvalidSignature)?,
             )),
+            SigType::RedDsaSha512Ed25519 => Ok(Signature::RedDsaSha512Ed25519(
+                ed25519_dalek::Signature::from_bytes(data).map_err(|_| Error::InvalidSignature)?,
+            )),
             SigType::EcdsaSha512P521
             | SigType::Rsa2048Sha256
             | SigType::Rsa3072Sha384
@@ -575,6 +651,7 @@ impl Signature {
             Signature::Rsa3072Sha384(ref s) => s.clone(),
             Signature::Rsa4096Sha512(ref s) => s.clone(),
             Signature::Ed25519(ref s) => s.to_bytes().to_vec(),
+            Signature::RedDsaSha512Ed25519(ref s) => s.to_bytes().to_vec(),
             Signature::Unsupported(ref s) => s.clone(),
         }
     }
@@ -698,6 +775,63 @@ mod tests {
         assert_eq!(SigType::Ed25519.extra_data_len(EncType::ElGamal2048), 0);
     }
 
+    #[test]
+    fn reddsa_verify() {
+        // RedDSA_SHA512_Ed25519 verification is identical to Ed25519, so we
+        // can check it against TEST 1 from RFC 8032 section 7.1.
+        let pk = SigningPublicKey::from_bytes(
+            SigType::RedDsaSha512Ed25519,
+            &[
+                0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64,
+                0x07, 0x3a, 0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68,
+                0xf7, 0x07, 0x51, 0x1a,
+            ],
+        )
+        .unwrap();
+        let mut sig_bytes = [
+            0xe5, 0x56, 0x43, 0x00, 0xc3, 0x60, 0xac, 0x72, 0x90, 0x86, 0xe2, 0xcc, 0x80, 0x6e,
+            0x82, 0x8a, 0x84, 0x87, 0x7f, 0x1e, 0xb8, 0xe5, 0xd9, 0x74, 0xd8, 0x73, 0xe0, 0x65,
+            0x22, 0x49, 0x01, 0x55, 0x5f, 0xb8, 0x82, 0x15, 0x90, 0xa3, 0x3b, 0xac, 0xc6, 0x1e,
+            0x39, 0x70, 0x1c, 0xf9, 0xb4, 0x6b, 0xd2, 0x5b, 0xf5, 0xf0, 0x59, 0x5b, 0xbe, 0x24,
+            0x65, 0x51, 0x41, 0x43, 0x8e, 0x7a, 0x10, 0x0b,
+        ];
+        let sig = Signature::from_bytes(SigType::RedDsaSha512Ed25519, &sig_bytes).unwrap();
+        assert_eq!(pk.verify(&[], &sig), Ok(()));
+        assert_eq!(pk.verify(&[0], &sig), Err(Error::InvalidSignature));
+
+        // Ed25519 signatures are not accepted for a RedDSA key
+        let ed_sig = Signature::from_bytes(SigType::Ed25519, &sig_bytes).unwrap();
+        assert_eq!(pk.verify(&[], &ed_sig), Err(Error::TypeMismatch));
+
+        // Tampered signature
+        sig_bytes[63] ^= 0x01;
+        let sig = Signature::from_bytes(SigType::RedDsaSha512Ed25519, &sig_bytes).unwrap();
+        assert_eq!(pk.verify(&[], &sig), Err(Error::InvalidSignature));
+    }
+
+    #[test]
+    fn reddsa_round_trip() {
+        let msg = b"LeaseSet2 contents";
+        let sk = SigningPrivateKey::with_type(SigType::RedDsaSha512Ed25519);
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+        assert_eq!(pk.sig_type(), SigType::RedDsaSha512Ed25519);
+
+        let sig = sk.sign(msg).unwrap();
+        assert_eq!(sig.to_bytes().len(), 64);
+        assert_eq!(pk.verify(msg, &sig), Ok(()));
+        assert_eq!(
+            pk.verify(b"other contents", &sig),
+            Err(Error::InvalidSignature)
+        );
+
+        // Keys survive a round trip through their encodings
+        let sk2 = sk.clone();
+        assert_eq!(sk2.as_bytes(), sk.as_bytes());
+        let pk2 =
+            SigningPublicKey::from_bytes(SigType::RedDsaSha512Ed25519, pk.as_bytes()).unwrap();
+        assert_eq!(pk2.verify(msg, &sk2.sign(msg).unwrap()), Ok(()));
+    }
+
     #[test]
     fn aes_256_cbc_test_vectors() {
         struct TestVector {
@@ -0,0 +1,111 @@
+//! Implementation of the RedDSA signature scheme over Ed25519, as used by I2P
+//! for LeaseSet2 and encrypted LeaseSets (signature type 11).
+//!
+//! I2P's RedDSA uses SHA-512 in place of BLAKE2b, so signatures are verified
+//! exactly like Ed25519 signatures. The difference is in the private key: it is
+//! a raw scalar rather than a seed, so that it can be blinded, and the signing
+//! nonce is randomized.
+
+use curve25519_dalek::{constants::ED25519_BASEPOINT_TABLE, scalar::Scalar};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha512};
+
+pub const SECRET_KEY_LENGTH: usize = 32;
+
+pub struct SigningKey {
+    bytes: [u8; SECRET_KEY_LENGTH],
+    scalar: Scalar,
+    public: [u8; 32],
+}
+
+/// Reduces a 512-bit hash of the given data to a scalar.
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        let mut wide = [0u8; 64];
+        OsRng.fill_bytes(&mut wide);
+        SigningKey::from_scalar(Scalar::from_bytes_mod_order_wide(&wide))
+    }
+
+    /// Parses a little-endian scalar. Returns `None` if the scalar is not
+    /// fully reduced.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != SECRET_KEY_LENGTH {
+            return None;
+        }
+        let mut bytes = [0u8; SECRET_KEY_LENGTH];
+        bytes.copy_from_slice(data);
+        Scalar::from_canonical_bytes(bytes).map(SigningKey::from_scalar)
+    }
+
+    fn from_scalar(scalar: Scalar) -> Self {
+        let public = (&scalar * &ED25519_BASEPOINT_TABLE).compress().to_bytes();
+        SigningKey {
+            bytes: scalar.to_bytes(),
+            scalar,
+            public,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn public_bytes(&self) -> &[u8; 32] {
+        &self.public
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> [u8; 64] {
+        let mut t = [0u8; 80];
+        OsRng.fill_bytes(&mut t);
+
+        let r = hash_to_scalar(&[&t[..], &self.public[..], msg]);
+        let big_r = (&r * &ED25519_BASEPOINT_TABLE).compress().to_bytes();
+        let k = hash_to_scalar(&[&big_r[..], &self.public[..], msg]);
+        let s = k * self.scalar + r;
+
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&big_r);
+        sig[32..].copy_from_slice(s.as_bytes());
+        sig
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SigningKey;
+
+    #[test]
+    fn public_key() {
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        let key = SigningKey::from_bytes(&one).unwrap();
+
+        // The scalar 1 maps to the Ed25519 basepoint
+        let mut basepoint = [0x66; 32];
+        basepoint[0] = 0x58;
+        assert_eq!(key.public_bytes(), &basepoint);
+        assert_eq!(key.as_bytes(), &one[..]);
+    }
+
+    #[test]
+    fn non_canonical_scalar() {
+        assert!(SigningKey::from_bytes(&[0xff; 32]).is_none());
+        assert!(SigningKey::from_bytes(&[0; 31]).is_none());
+    }
+
+    #[test]
+    fn randomized_nonce() {
+        let key = SigningKey::generate();
+        assert_ne!(&key.sign(b"hello")[..], &key.sign(b"hello")[..]);
+    }
+}
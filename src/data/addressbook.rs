@@ -0,0 +1,38 @@
+//! Helpers for integrating with I2P address books.
+//!
+//! [Naming specification](https://geti2p.net/en/docs/naming)
+
+use super::Hash;
+
+/// Returns the key under which an address book stores the given hostname.
+///
+/// Hostnames are case-insensitive, so they are lowercased before hashing with
+/// SHA-256.
+pub fn hostname_key(name: &str) -> Hash {
+    Hash::digest(name.to_ascii_lowercase().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hostname_key;
+    use crate::data::Hash;
+
+    #[test]
+    fn known_hostname() {
+        assert_eq!(
+            hostname_key("example.i2p"),
+            Hash([
+                0xd0, 0x78, 0x27, 0x62, 0x0d, 0xbf, 0x59, 0x48, 0xd7, 0x23, 0xd8, 0x3f, 0xb9, 0x3c,
+                0x28, 0x49, 0x5b, 0x2a, 0x36, 0x3e, 0x2d, 0x79, 0x72, 0x09, 0x32, 0xbc, 0x4f, 0x94,
+                0xe9, 0xd7, 0x86, 0x60,
+            ])
+        );
+    }
+
+    #[test]
+    fn case_is_normalized() {
+        assert_eq!(hostname_key("Example.I2P"), hostname_key("example.i2p"));
+        assert_eq!(hostname_key("EXAMPLE.I2P"), hostname_key("example.i2p"));
+        assert_ne!(hostname_key("example2.i2p"), hostname_key("example.i2p"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::SystemTime;
 
 use super::{cert_and_padding_from_keys, Certificate, Padding};
+use crate::constants;
 use crate::crypto::{
     self, elgamal, PrivateKey, PublicKey, Signature, SigningPrivateKey, SigningPublicKey,
 };
@@ -37,6 +38,17 @@ impl Destination {
     pub fn hash(&self) -> Hash {
         Hash::digest(&self.to_bytes()[..])
     }
+
+    /// Returns the number of bytes this Destination serializes to.
+    pub(crate) fn serialized_len(&self) -> usize {
+        let padding_len = self.padding.as_ref().map_or(0, |padding| padding.0.len());
+        let signing_key_len = self
+            .signing_key
+            .as_bytes()
+            .len()
+            .min(constants::KEYCERT_SIGKEY_BYTES);
+        256 + padding_len + signing_key_len + self.certificate.serialized_len()
+    }
 }
 
 /// Key material for a Destination.
@@ -136,13 +148,24 @@ impl LeaseSet {
         });
         expiry < I2PDate::from_system_time(SystemTime::now())
     }
+
+    /// Returns the number of bytes this LeaseSet serializes to, once signed.
+    pub(crate) fn serialized_len(&self) -> usize {
+        self.dest.serialized_len()
+            + 256
+            + self.sig_key.as_bytes().len()
+            + 1
+            + 44 * self.leases.len()
+            + self.dest.signing_key.sig_type().sig_len() as usize
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::SystemTime;
 
-    use super::{Destination, DestinationSecretKeys, Lease, LeaseSet};
+    use super::{frame, Destination, DestinationSecretKeys, Lease, LeaseSet};
+    use crate::util::serialize;
     use crate::{
         crypto::{
             self, elgamal::KeyPairGenerator, PublicKey, SigType, SigningPrivateKey,
@@ -188,5 +211,9 @@ mod tests {
         assert_eq!(ls.verify(), Err(crypto::Error::NoSignature));
         ls.sign(&dsk.signing_private_key).unwrap();
         assert_eq!(ls.verify(), Ok(()));
+
+        let buf = serialize(|input| frame::gen_lease_set(input, &ls));
+        assert_eq!(ls.serialized_len(), buf.len());
+        assert_eq!(ls.dest.serialized_len(), ls.dest.to_bytes().len());
     }
 }
@@ -4,7 +4,7 @@ use cookie_factory::*;
 use itertools::Itertools;
 use nom::{
     bytes::streaming::{tag, take},
-    combinator::{complete, map, map_res},
+    combinator::{all_consuming, complete, map, map_res, verify},
     error::{Error as NomError, ErrorKind},
     multi::{length_count, length_data, length_value, many0},
     number::streaming::{be_u16, be_u32, be_u64, be_u8},
@@ -67,14 +67,34 @@ pub fn gen_i2p_string<'a>(
     do_gen!(input, gen_be_u8!(buf.len() as u8) >> gen_slice!(buf))
 }
 
+/// A parser for the Mappings within a larger structure.
+type MappingParser = fn(&[u8]) -> IResult<&[u8], Mapping>;
+
+fn mapping_pairs(i: &[u8]) -> IResult<&[u8], Vec<(I2PString, I2PString)>> {
+    many0(complete(terminated(
+        separated_pair(i2p_string, tag("="), i2p_string),
+        tag(";"),
+    )))(i)
+}
+
+/// Parses a Mapping in any order. Duplicate keys keep the last value, and
+/// re-serializing the Mapping will sort it.
 pub fn mapping(i: &[u8]) -> IResult<&[u8], Mapping> {
+    map(length_value(be_u16, mapping_pairs), |pairs| {
+        Mapping(pairs.into_iter().collect())
+    })(i)
+}
+
+/// Parses a Mapping, rejecting it unless it is canonically encoded: keys
+/// strictly sorted with no duplicates, and nothing else within its length.
+pub fn strict_mapping(i: &[u8]) -> IResult<&[u8], Mapping> {
     map(
         length_value(
             be_u16,
-            many0(complete(terminated(
-                separated_pair(i2p_string, tag("="), i2p_string),
-                tag(";"),
-            ))),
+            verify(
+                all_consuming(mapping_pairs),
+                |pairs: &Vec<(I2PString, I2PString)>| pairs.windows(2).all(|w| w[0].0 < w[1].0),
+            ),
         ),
         |pairs| Mapping(pairs.into_iter().collect()),
     )(i)
@@ -126,22 +146,33 @@ pub fn gen_tunnel_id<'a>(
 
 // SigningPublicKey
 
+/// A decoder for the SigningPublicKey within a RouterIdentity.
+type SigningKeyDecoder = fn(SigType, &[u8]) -> Result<SigningPublicKey, crypto::Error>;
+
 pub(crate) fn split_signing_key(
     base_data: &[u8; constants::KEYCERT_SIGKEY_BYTES],
     cert: &Certificate,
+) -> Result<SigningPublicKey, crypto::Error> {
+    split_signing_key_with(base_data, cert, SigningPublicKey::from_bytes)
+}
+
+fn split_signing_key_with(
+    base_data: &[u8; constants::KEYCERT_SIGKEY_BYTES],
+    cert: &Certificate,
+    decode: SigningKeyDecoder,
 ) -> Result<SigningPublicKey, crypto::Error> {
     match cert {
         Certificate::Key(kc) => {
             if kc.sig_type.extra_data_len(kc.enc_type) > 0 {
                 let mut data = Vec::from(&base_data[..]);
                 data.extend(&kc.sig_data);
-                SigningPublicKey::from_bytes(kc.sig_type, &data)
+                decode(kc.sig_type, &data)
             } else {
                 let pad = kc.sig_type.pad_len(kc.enc_type);
-                SigningPublicKey::from_bytes(kc.sig_type, &base_data[pad..])
+                decode(kc.sig_type, &base_data[pad..])
             }
         }
-        _ => SigningPublicKey::from_bytes(SigType::DsaSha1, &base_data[..]),
+        _ => decode(SigType::DsaSha1, &base_data[..]),
     }
 }
 
@@ -266,6 +297,16 @@ pub fn gen_certificate<'a>(
 // RouterIdentity
 
 pub fn router_identity(i: &[u8]) -> IResult<&[u8], RouterIdentity> {
+    router_identity_with(i, SigningPublicKey::from_bytes)
+}
+
+/// Parses a RouterIdentity, keeping a signing key of a type we do not support
+/// as `SigningPublicKey::Unsupported`.
+fn unverified_router_identity(i: &[u8]) -> IResult<&[u8], RouterIdentity> {
+    router_identity_with(i, SigningPublicKey::from_bytes_unverified)
+}
+
+fn router_identity_with(i: &[u8], decode: SigningKeyDecoder) -> IResult<&[u8], RouterIdentity> {
     map_res(
         tuple((
             public_key,
@@ -277,9 +318,10 @@ pub fn router_identity(i: &[u8]) -> IResult<&[u8], RouterIdentity> {
                 array_ref![signing_data, 0, constants::KEYCERT_SIGKEY_BYTES],
                 &certificate,
             );
-            split_signing_key(
+            split_signing_key_with(
                 array_ref![signing_data, 0, constants::KEYCERT_SIGKEY_BYTES],
                 &certificate,
+                decode,
             )
             .map(|signing_key| RouterIdentity {
                 public_key,
@@ -337,9 +379,9 @@ pub fn gen_router_secret_keys<'a>(
 
 // RouterAddress
 
-fn router_address(i: &[u8]) -> IResult<&[u8], RouterAddress> {
+fn router_address(i: &[u8], options: MappingParser) -> IResult<&[u8], RouterAddress> {
     map(
-        tuple((be_u8, i2p_date, i2p_string, mapping)),
+        tuple((be_u8, i2p_date, i2p_string, options)),
         |(cost, expiration, transport_style, options)| RouterAddress {
             cost,
             expiration,
@@ -366,12 +408,31 @@ fn gen_router_address<'a>(
 // RouterInfo
 
 pub fn router_info(i: &[u8]) -> IResult<&[u8], RouterInfo> {
-    let (i, router_id) = router_identity(i)?;
+    router_info_with(i, router_identity, mapping)
+}
+
+/// Parses a RouterInfo, rejecting it if any of its Mappings are not
+/// canonically encoded.
+pub fn strict_router_info(i: &[u8]) -> IResult<&[u8], RouterInfo> {
+    router_info_with(i, router_identity, strict_mapping)
+}
+
+/// Parses a RouterInfo, even if it is signed with a type we do not support.
+pub fn unverified_router_info(i: &[u8]) -> IResult<&[u8], RouterInfo> {
+    router_info_with(i, unverified_router_identity, mapping)
+}
+
+fn router_info_with(
+    i: &[u8],
+    identity: fn(&[u8]) -> IResult<&[u8], RouterIdentity>,
+    options: MappingParser,
+) -> IResult<&[u8], RouterInfo> {
+    let (i, router_id) = identity(i)?;
     let (i, (published, addresses, peers, options, signature)) = tuple((
         i2p_date,
-        length_count(be_u8, router_address),
+        length_count(be_u8, |i| router_address(i, options)),
         length_count(be_u8, hash),
-        mapping,
+        options,
         signature(router_id.signing_key.sig_type()),
     ))(i)?;
     Ok((
@@ -421,6 +482,90 @@ mod tests {
 
     use nom::{Err, HexDisplay};
 
+    #[test]
+    fn rsa_router_identity() {
+        let (_, ri) = router_info(ROUTER_INFO).unwrap();
+        let rid = RouterIdentity {
+            public_key: ri.router_id.public_key,
+            padding: None,
+            signing_key: SigningPublicKey::Unsupported(SigType::Rsa2048Sha256, vec![7; 256]),
+            certificate: Certificate::Key(KeyCertificate {
+                sig_type: SigType::Rsa2048Sha256,
+                enc_type: EncType::ElGamal2048,
+                sig_data: vec![7; 128],
+                enc_data: vec![],
+            }),
+        };
+        let data = rid.to_bytes();
+
+        // The strict parser rejects the key type instead of panicking
+        assert!(router_identity(&data).is_err());
+
+        // The unverified parser keeps the key as it is
+        let (i, parsed) = unverified_router_identity(&data).unwrap();
+        assert!(i.is_empty());
+        assert_eq!(parsed, rid);
+    }
+
+    #[test]
+    fn test_strict_mapping() {
+        const CANONICAL: &[u8] = &[
+            0, 12, 1, b'a', b'=', 1, b'2', b';', 1, b'b', b'=', 1, b'1', b';',
+        ];
+        const UNSORTED: &[u8] = &[
+            0, 12, 1, b'b', b'=', 1, b'1', b';', 1, b'a', b'=', 1, b'2', b';',
+        ];
+        const DUPLICATE: &[u8] = &[
+            0, 12, 1, b'a', b'=', 1, b'1', b';', 1, b'a', b'=', 1, b'2', b';',
+        ];
+        const TRAILING: &[u8] = &[0, 7, 1, b'a', b'=', 1, b'2', b';', 0];
+
+        // A canonical mapping is accepted by both modes
+        let (_, m) = strict_mapping(CANONICAL).unwrap();
+        assert_eq!(m.0.len(), 2);
+        assert_eq!(mapping(CANONICAL).unwrap().1, m);
+
+        // Non-canonical mappings are rejected in strict mode
+        assert!(strict_mapping(UNSORTED).is_err());
+        assert!(strict_mapping(DUPLICATE).is_err());
+        assert!(strict_mapping(TRAILING).is_err());
+
+        // In lenient mode they are accepted, and canonicalized on output
+        let (_, unsorted) = mapping(UNSORTED).unwrap();
+        assert_eq!(unsorted, m);
+        let mut buf = vec![0; CANONICAL.len()];
+        match gen_mapping((&mut buf, 0), &unsorted) {
+            Ok((o, _)) => assert_eq!(o, CANONICAL),
+            Err(e) => panic!("error in gen_mapping: {:?}", e),
+        }
+        let (_, duplicate) = mapping(DUPLICATE).unwrap();
+        assert_eq!(duplicate.0.len(), 1);
+        let (_, trailing) = mapping(TRAILING).unwrap();
+        assert_eq!(trailing.0.len(), 1);
+    }
+
+    #[test]
+    fn test_strict_router_info() {
+        let (_, ri) = strict_router_info(ROUTER_INFO).unwrap();
+        assert_eq!(router_info(ROUTER_INFO).unwrap().1, ri);
+
+        // Put the first two options out of order in an otherwise valid RouterInfo
+        let sk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(sk.rid.clone());
+        let options = &mut ri.options.0;
+        options.insert(I2PString::new("a"), I2PString::new("2"));
+        options.insert(I2PString::new("b"), I2PString::new("1"));
+        ri.sign(&sk.signing_private_key);
+        let mut data = ri.to_bytes();
+        let sorted = b"\x01a=\x012;\x01b=\x011;";
+        let pos = data.windows(sorted.len()).position(|w| w == sorted);
+        let pos = pos.unwrap();
+        data[pos..pos + sorted.len()].copy_from_slice(b"\x01b=\x011;\x01a=\x012;");
+
+        assert!(strict_router_info(&data).is_err());
+        assert_eq!(router_info(&data).unwrap().1.options, ri.options);
+    }
+
     #[test]
     fn test_router_info() {
         let data = ROUTER_INFO;
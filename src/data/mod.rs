@@ -12,6 +12,7 @@ use std::fs::File;
 use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 
 use crate::constants;
 use crate::crypto::{
@@ -20,6 +21,7 @@ use crate::crypto::{
 };
 use crate::util::{fmt_colon_delimited_hex, serialize};
 
+pub mod addressbook;
 pub mod dest;
 
 #[allow(clippy::needless_pass_by_value)]
@@ -58,6 +60,25 @@ impl fmt::Display for ReadError {
     }
 }
 
+/// RouterInfo consistency errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouterInfoError {
+    /// The address at the given index expires before the RouterInfo was
+    /// published.
+    AddressExpired(usize),
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Display for RouterInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouterInfoError::AddressExpired(i) => {
+                write!(f, "Address {} expires before publication", i)
+            }
+        }
+    }
+}
+
 impl From<io::Error> for ReadError {
     fn from(e: io::Error) -> Self {
         ReadError::FileIo(format!("{}", e))
@@ -93,11 +114,23 @@ impl Hash {
         Hash::from_bytes(array_ref![hash.as_slice(), 0, 32])
     }
 
+    /// Returns the hash in the unpadded, lowercase base32 encoding used in
+    /// `.b32.i2p` addresses.
+    pub fn to_base32(&self) -> String {
+        constants::I2P_BASE32.encode(&self.0)
+    }
+
     pub fn xor(&mut self, other: &Hash) {
         for i in 0..32 {
             self.0[i] ^= other.0[i];
         }
     }
+
+    /// Compares two hashes in constant time. Use this instead of `==` when
+    /// either hash is derived from secret data.
+    pub fn ct_eq(&self, other: &Hash) -> bool {
+        self.0[..].ct_eq(&other.0[..]).into()
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -227,6 +260,17 @@ impl Certificate {
             Certificate::Key(_) => constants::KEY_CERT,
         }
     }
+
+    /// Returns the number of bytes this certificate serializes to.
+    pub(crate) fn serialized_len(&self) -> usize {
+        3 + match *self {
+            Certificate::Null | Certificate::Hidden => 0,
+            Certificate::HashCash(ref payload)
+            | Certificate::Signed(ref payload)
+            | Certificate::Multiple(ref payload) => payload.len(),
+            Certificate::Key(ref kc) => 4 + kc.sig_data.len() + kc.enc_data.len(),
+        }
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -268,6 +312,67 @@ fn cert_and_padding_from_keys(
     (certificate, padding)
 }
 
+/// The shared bandwidth that a router advertises in its caps, from slowest to
+/// fastest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BandwidthTier {
+    /// Under 12 KBps
+    K,
+    /// 12 - 48 KBps
+    L,
+    /// 48 - 64 KBps
+    M,
+    /// 64 - 128 KBps
+    N,
+    /// 128 - 256 KBps
+    O,
+    /// 256 - 2000 KBps
+    P,
+    /// Over 2000 KBps
+    X,
+}
+
+impl BandwidthTier {
+    fn from_cap(cap: char) -> Option<Self> {
+        match cap {
+            'K' => Some(BandwidthTier::K),
+            'L' => Some(BandwidthTier::L),
+            'M' => Some(BandwidthTier::M),
+            'N' => Some(BandwidthTier::N),
+            'O' => Some(BandwidthTier::O),
+            'P' => Some(BandwidthTier::P),
+            'X' => Some(BandwidthTier::X),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a router accepts inbound connections, as advertised in its caps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// Peers can connect to the router directly.
+    Reachable,
+    /// The router is firewalled, and only makes outbound connections.
+    Unreachable,
+}
+
+impl Reachability {
+    fn from_cap(cap: char) -> Option<Self> {
+        match cap {
+            'R' => Some(Reachability::Reachable),
+            'U' => Some(Reachability::Unreachable),
+            _ => None,
+        }
+    }
+
+    fn cap(self) -> char {
+        match self {
+            Reachability::Reachable => 'R',
+            Reachability::Unreachable => 'U',
+        }
+    }
+}
+
 /// Defines the way to uniquely identify a particular router.
 #[derive(Clone, Debug, PartialEq)]
 pub struct RouterIdentity {
@@ -384,6 +489,17 @@ impl RouterAddress {
         self.options.0.insert(key, value);
     }
 
+    /// Sets when the address expires. A zero date means it never does.
+    pub fn set_expiration(&mut self, expiration: I2PDate) {
+        self.expiration = expiration;
+    }
+
+    /// Returns true if the address has an expiration, and it is not after the
+    /// given time.
+    fn expired_at(&self, t: I2PDate) -> bool {
+        self.expiration.0 != 0 && self.expiration <= t
+    }
+
     pub fn addr(&self) -> Option<SocketAddr> {
         let host = self.options.0.get(&I2PString(String::from("host")));
         let port = self.options.0.get(&I2PString(String::from("port")));
@@ -397,6 +513,16 @@ impl RouterAddress {
     }
 }
 
+/// Whether we are able to check the signature on a RouterInfo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// The RouterInfo is signed with a type we support.
+    Supported,
+    /// The RouterInfo is signed with a type we can parse but not verify. It can
+    /// be relayed, but not trusted.
+    Unsupported,
+}
+
 /// Defines all of the data that a router wants to publish for the network to
 /// see.
 ///
@@ -438,6 +564,10 @@ impl RouterInfo {
         self.signature = None;
     }
 
+    pub fn addresses(&self) -> &[RouterAddress] {
+        &self.addresses
+    }
+
     pub fn address<F>(&self, style: &I2PString, filter: F) -> Option<RouterAddress>
     where
         F: Fn(&RouterAddress) -> bool,
@@ -445,6 +575,7 @@ impl RouterInfo {
         self.addresses
             .iter()
             .filter(|a| a.transport_style == *style)
+            .filter(|a| !a.expired_at(self.published))
             .filter(|a| match a.addr() {
                 Some(addr) => addr.is_ipv4(),
                 None => false,
@@ -453,6 +584,18 @@ impl RouterInfo {
             .map(|a| (*a).clone())
     }
 
+    /// Checks that the RouterInfo is internally consistent.
+    pub fn validate(&self) -> Result<(), RouterInfoError> {
+        match self
+            .addresses
+            .iter()
+            .position(|a| a.expired_at(self.published))
+        {
+            Some(i) => Err(RouterInfoError::AddressExpired(i)),
+            None => Ok(()),
+        }
+    }
+
     pub fn network_id(&self) -> Option<&I2PString> {
         self.options.0.get(&OPT_NET_ID)
     }
@@ -465,6 +608,39 @@ impl RouterInfo {
             .unwrap_or(false)
     }
 
+    /// Returns the highest bandwidth tier in the router's caps, if it
+    /// advertises one.
+    pub fn bandwidth_tier(&self) -> Option<BandwidthTier> {
+        self.options
+            .0
+            .get(&OPT_CAPS)
+            .and_then(|caps| caps.0.chars().filter_map(BandwidthTier::from_cap).max())
+    }
+
+    /// Returns whether the router's caps say it is reachable, if they say
+    /// either way.
+    pub fn reachability(&self) -> Option<Reachability> {
+        self.options
+            .0
+            .get(&OPT_CAPS)
+            .and_then(|caps| caps.0.chars().find_map(Reachability::from_cap))
+    }
+
+    /// Sets the reachability advertised in the router's caps, replacing any
+    /// existing one.
+    ///
+    /// Caller must re-sign the RouterInfo afterwards.
+    pub fn set_reachability(&mut self, reachability: Reachability) {
+        let caps = self
+            .options
+            .0
+            .entry(OPT_CAPS.clone())
+            .or_insert_with(|| I2PString::new(""));
+        caps.0.retain(|c| Reachability::from_cap(c).is_none());
+        caps.0.push(reachability.cap());
+        self.signature = None;
+    }
+
     pub fn from_file(path: &str) -> Result<Self, ReadError> {
         let mut ri = File::open(path)?;
         let mut data: Vec<u8> = Vec::new();
@@ -473,6 +649,8 @@ impl RouterInfo {
         Ok(res)
     }
 
+    /// Returns the full RouterInfo, including its signature if it has one, as
+    /// it is stored and sent over the network.
     pub fn to_bytes(&self) -> Vec<u8> {
         serialize(|input| frame::gen_router_info(input, self))
     }
@@ -482,24 +660,92 @@ impl RouterInfo {
         ri.write(&self.to_bytes()).map(|_| ())
     }
 
-    fn signature_bytes(&self) -> Vec<u8> {
+    /// Returns the bytes that are signed by the router: everything up to, but
+    /// not including, the signature.
+    pub fn signing_bytes(&self) -> Vec<u8> {
         serialize(|input| frame::gen_router_info_minus_sig(input, self))
     }
 
     pub fn sign(&mut self, spk: &SigningPrivateKey) {
-        let sig_msg = self.signature_bytes();
+        let sig_msg = self.signing_bytes();
         self.signature = Some(spk.sign(&sig_msg).unwrap());
     }
 
+    /// Parses a RouterInfo without requiring that we support its signature
+    /// type, so that it can be relayed to other routers.
+    ///
+    /// If [`RouterInfo::verification`] is `Unsupported`, the RouterInfo must
+    /// not be trusted: `verify()` will fail with
+    /// `crypto::Error::UnsupportedSigType`.
+    pub fn parse_unverified(data: &[u8]) -> Result<Self, ReadError> {
+        let (_, res) = frame::unverified_router_info(data)?;
+        Ok(res)
+    }
+
+    /// Returns whether we are able to verify this RouterInfo's signature.
+    pub fn verification(&self) -> Verification {
+        if self.router_id.signing_key.sig_type().is_supported() {
+            Verification::Supported
+        } else {
+            Verification::Unsupported
+        }
+    }
+
     pub fn verify(&self) -> Result<(), crypto::Error> {
         match self.signature.as_ref() {
             Some(s) => {
-                let sig_msg = self.signature_bytes();
+                let sig_msg = self.signing_bytes();
                 self.router_id.signing_key.verify(&sig_msg, s)
             }
             None => Err(crypto::Error::NoSignature),
         }
     }
+
+    /// Compares this RouterInfo with another (typically a newer version of the
+    /// same router's RouterInfo), and reports which fields differ.
+    pub fn diff(&self, other: &RouterInfo) -> RouterInfoDiff {
+        let same_addresses = self.addresses.len() == other.addresses.len()
+            && self.addresses.iter().all(|a| other.addresses.contains(a));
+
+        let without_caps = |options: &Mapping| {
+            let mut options = options.0.clone();
+            options.remove(&OPT_CAPS);
+            options
+        };
+
+        RouterInfoDiff {
+            identity: self.router_id != other.router_id,
+            published: self.published != other.published,
+            addresses: !same_addresses,
+            caps: self.options.0.get(&OPT_CAPS) != other.options.0.get(&OPT_CAPS),
+            options: without_caps(&self.options) != without_caps(&other.options),
+            peers: self.peers != other.peers,
+            signature: self.signature != other.signature,
+        }
+    }
+}
+
+/// The fields that differ between two RouterInfos.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RouterInfoDiff {
+    /// The RouterIdentity, including its keys.
+    pub identity: bool,
+    pub published: bool,
+    /// The set of addresses. Reordering them is not a change.
+    pub addresses: bool,
+    pub caps: bool,
+    /// The options, other than the caps.
+    pub options: bool,
+    pub peers: bool,
+    pub signature: bool,
+}
+
+impl RouterInfoDiff {
+    /// Returns true if anything changed other than the published time and the
+    /// signature, which change every time a router republishes.
+    pub fn is_meaningful(&self) -> bool {
+        self.identity || self.addresses || self.caps || self.options || self.peers
+    }
 }
 
 #[cfg(test)]
@@ -507,6 +753,51 @@ mod tests {
     use super::*;
     use crate::tests::{RI_SIGTYPE_1, RI_SIGTYPE_2, ROUTER_INFO};
 
+    #[test]
+    fn parse_unverified_router_info() {
+        let (_, ri) = frame::router_info(ROUTER_INFO).unwrap();
+        let parsed = RouterInfo::parse_unverified(ROUTER_INFO).unwrap();
+        assert_eq!(parsed, ri);
+        assert_eq!(parsed.verification(), Verification::Supported);
+        assert_eq!(parsed.verify(), Ok(()));
+
+        // Re-sign the RouterInfo's contents with a key type we can't verify
+        let kc = KeyCertificate {
+            sig_type: SigType::EcdsaSha512P521,
+            enc_type: EncType::ElGamal2048,
+            sig_data: vec![7; 4],
+            enc_data: vec![],
+        };
+        let mut unsupported = ri.clone();
+        unsupported.router_id = RouterIdentity {
+            public_key: ri.router_id.public_key.clone(),
+            padding: None,
+            signing_key: SigningPublicKey::Unsupported(SigType::EcdsaSha512P521, vec![7; 132]),
+            certificate: Certificate::Key(kc),
+        };
+        unsupported.signature = Some(Signature::Unsupported(vec![9; 132]));
+        let data = unsupported.to_bytes();
+
+        // The strict parser rejects it
+        assert!(frame::router_info(&data).is_err());
+
+        // Its structure and addresses are still accessible
+        let parsed = RouterInfo::parse_unverified(&data).unwrap();
+        assert_eq!(
+            parsed.router_id.signing_key.sig_type(),
+            SigType::EcdsaSha512P521
+        );
+        assert_eq!(parsed.router_id.signing_key.as_bytes(), &[7; 132][..]);
+        assert_eq!(parsed.published, ri.published);
+        assert_eq!(parsed.addresses(), ri.addresses());
+        assert_eq!(parsed.options, ri.options);
+
+        // It can be relayed but not trusted
+        assert_eq!(parsed.verification(), Verification::Unsupported);
+        assert_eq!(parsed.verify(), Err(crypto::Error::UnsupportedSigType));
+        assert_eq!(parsed.to_bytes(), data);
+    }
+
     #[test]
     fn hash_xor() {
         let mut h = Hash::from_bytes(&[0u8; 32]);
@@ -525,6 +816,26 @@ mod tests {
         assert_eq!(h, h0);
     }
 
+    #[test]
+    fn hash_ct_eq() {
+        let h = Hash::digest(b"hello");
+        assert!(h.ct_eq(&h.clone()));
+        assert!(!h.ct_eq(&Hash::digest(b"world")));
+
+        // A difference in any single byte is detected
+        for i in 0..32 {
+            let mut other = h.clone();
+            other.0[i] ^= 0x01;
+            assert!(!h.ct_eq(&other));
+        }
+    }
+
+    #[test]
+    fn hash_to_base32() {
+        assert_eq!(Hash([0; 32]).to_base32(), "a".repeat(52));
+        assert_eq!(Hash([0xff; 32]).to_base32(), format!("{}q", "7".repeat(51)));
+    }
+
     #[test]
     fn i2pstring_to_csv() {
         let s1 = I2PString(String::from("a-b,c/d,1,2"));
@@ -600,6 +911,138 @@ mod tests {
         assert_eq!(ra.addr().unwrap(), "127.0.0.1:34567".parse().unwrap());
     }
 
+    #[test]
+    fn router_info_expired_address() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.published = I2PDate(1_000_000);
+        let style = I2PString::new("test");
+
+        let mut expired = RouterAddress::new(&style, "127.0.0.1:12345".parse().unwrap());
+        expired.expiration = I2PDate(999_999);
+        let mut current = RouterAddress::new(&style, "127.0.0.1:23456".parse().unwrap());
+        current.expiration = I2PDate(1_000_001);
+        let never = RouterAddress::new(&style, "127.0.0.1:34567".parse().unwrap());
+
+        ri.set_addresses(vec![current.clone(), never.clone()]);
+        assert_eq!(ri.validate(), Ok(()));
+
+        // An address expiring before the RouterInfo was published is invalid,
+        // and never selected
+        ri.set_addresses(vec![never, expired.clone(), current]);
+        assert_eq!(ri.validate(), Err(RouterInfoError::AddressExpired(1)));
+        assert!(ri.address(&style, |ra| *ra == expired).is_none());
+
+        // An address expiring at the publication time is also expired
+        expired.expiration = ri.published;
+        ri.set_addresses(vec![expired]);
+        assert_eq!(ri.validate(), Err(RouterInfoError::AddressExpired(0)));
+        assert!(ri.address(&style, |_| true).is_none());
+    }
+
+    #[test]
+    fn router_info_diff() {
+        let rsk = RouterSecretKeys::new();
+        let style = I2PString::new("NTCP");
+        let mut ri = RouterInfo::new(rsk.rid.clone());
+        ri.set_addresses(vec![
+            RouterAddress::new(&style, "127.0.0.1:12345".parse().unwrap()),
+            RouterAddress::new(&style, "127.0.0.2:12345".parse().unwrap()),
+        ]);
+        ri.sign(&rsk.signing_private_key);
+        assert_eq!(ri.diff(&ri), RouterInfoDiff::default());
+
+        // Republishing changes only the published time and signature
+        let mut republished = ri.clone();
+        republished.published = I2PDate(ri.published.0 + 1000);
+        republished.sign(&rsk.signing_private_key);
+        let diff = ri.diff(&republished);
+        assert!(diff.published);
+        assert!(!diff.is_meaningful());
+
+        // Reordering addresses is not a change
+        let mut reordered = ri.clone();
+        reordered.set_addresses(vec![
+            RouterAddress::new(&style, "127.0.0.2:12345".parse().unwrap()),
+            RouterAddress::new(&style, "127.0.0.1:12345".parse().unwrap()),
+        ]);
+        assert!(!ri.diff(&reordered).addresses);
+
+        // Changing the NTCP port is an address change
+        let mut moved = republished.clone();
+        moved.set_addresses(vec![
+            RouterAddress::new(&style, "127.0.0.1:23456".parse().unwrap()),
+            RouterAddress::new(&style, "127.0.0.2:12345".parse().unwrap()),
+        ]);
+        moved.sign(&rsk.signing_private_key);
+        let diff = ri.diff(&moved);
+        assert_eq!(
+            diff,
+            RouterInfoDiff {
+                published: true,
+                addresses: true,
+                signature: true,
+                ..Default::default()
+            }
+        );
+        assert!(diff.is_meaningful());
+
+        // Caps are reported separately from other options
+        let mut floodfill = ri.clone();
+        floodfill
+            .options
+            .0
+            .insert(OPT_CAPS.clone(), I2PString::new("fR"));
+        let diff = ri.diff(&floodfill);
+        assert!(diff.caps);
+        assert!(!diff.options);
+        assert!(diff.is_meaningful());
+
+        // A different router has a different identity
+        let other = RouterInfo::new(RouterSecretKeys::new().rid);
+        assert!(ri.diff(&other).identity);
+    }
+
+    #[test]
+    fn router_info_bandwidth_tier() {
+        let mut ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        assert_eq!(ri.bandwidth_tier(), Some(BandwidthTier::K));
+
+        // Faster routers also advertise a slower tier for older peers
+        for (caps, tier) in &[
+            ("LR", Some(BandwidthTier::L)),
+            ("OfR", Some(BandwidthTier::O)),
+            ("PfOR", Some(BandwidthTier::P)),
+            ("XOfR", Some(BandwidthTier::X)),
+            ("fR", None),
+        ] {
+            ri.options.0.insert(OPT_CAPS.clone(), I2PString::new(caps));
+            assert_eq!(ri.bandwidth_tier(), *tier);
+        }
+
+        ri.options.0.remove(&OPT_CAPS);
+        assert_eq!(ri.bandwidth_tier(), None);
+    }
+
+    #[test]
+    fn router_info_reachability() {
+        let mut ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        assert_eq!(ri.reachability(), Some(Reachability::Unreachable));
+
+        ri.set_reachability(Reachability::Reachable);
+        assert_eq!(ri.reachability(), Some(Reachability::Reachable));
+        assert_eq!(ri.bandwidth_tier(), Some(BandwidthTier::K));
+        assert_eq!(ri.options.0[&OPT_CAPS], I2PString::new("KR"));
+
+        ri.set_reachability(Reachability::Unreachable);
+        assert_eq!(ri.options.0[&OPT_CAPS], I2PString::new("KU"));
+
+        ri.options.0.remove(&OPT_CAPS);
+        assert_eq!(ri.reachability(), None);
+        ri.set_reachability(Reachability::Unreachable);
+        assert_eq!(ri.options.0[&OPT_CAPS], I2PString::new("U"));
+    }
+
     #[test]
     fn router_info_sign() {
         let rsk = RouterSecretKeys::new();
@@ -610,6 +1053,33 @@ mod tests {
         assert!(ri.verify().is_ok());
     }
 
+    #[test]
+    fn router_info_signing_bytes() {
+        let (_, ri) = frame::router_info(ROUTER_INFO).unwrap();
+        let signing = ri.signing_bytes();
+        let full = ri.to_bytes();
+        let sig = ri.signature.as_ref().unwrap().to_bytes();
+        assert_eq!(&full[..], &ROUTER_INFO[..]);
+
+        // The signed bytes are everything except the trailing signature
+        assert_eq!(signing.len() + sig.len(), full.len());
+        assert_eq!(&full[..signing.len()], &signing[..]);
+        assert_eq!(&full[signing.len()..], &sig[..]);
+
+        let spk = &ri.router_id.signing_key;
+        let s = ri.signature.as_ref().unwrap();
+        assert!(spk.verify(&signing, s).is_ok());
+        assert!(spk.verify(&full, s).is_err());
+
+        // Signing does not change the signed bytes
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        let unsigned = ri.signing_bytes();
+        ri.sign(&rsk.signing_private_key);
+        assert_eq!(ri.signing_bytes(), unsigned);
+        assert!(ri.to_bytes().starts_with(&unsigned));
+    }
+
     fn router_info_verify(data: &[u8]) {
         match frame::router_info(data) {
             Ok((_, ri)) => {
@@ -81,7 +81,7 @@ mod tests {
     use std::num::NonZeroUsize;
 
     use super::{Error, Su3Content, Su3File};
-    use crate::crypto::SigType;
+    use crate::crypto::{self, SigType};
     use crate::data::ReadError;
     use crate::netdb::reseed::RESEED_SIGNERS;
     use crate::tests::I2PSEEDS_SU3;
@@ -166,4 +166,31 @@ mod tests {
             Err(e) => panic!("Error while parsing reseed file: {:?}", e),
         }
     }
+
+    #[test]
+    fn reseed_file_tampered() {
+        // Change the version, which is covered by the signature
+        let mut data = I2PSEEDS_SU3.to_vec();
+        let version = data.windows(10).position(|w| w == b"1539145006").unwrap();
+        data[version + 9] = b'7';
+        match Su3File::from_bytes(&data, &RESEED_SIGNERS) {
+            Ok(_) => panic!("Accepted a tampered reseed file"),
+            Err(e) => assert_eq!(e, Error::Crypto(crypto::Error::InvalidSignature)),
+        }
+
+        // Corrupt the signature itself
+        let mut data = I2PSEEDS_SU3.to_vec();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        match Su3File::from_bytes(&data, &RESEED_SIGNERS) {
+            Ok(_) => panic!("Accepted a tampered reseed file"),
+            Err(e) => assert_eq!(e, Error::Crypto(crypto::Error::InvalidSignature)),
+        }
+
+        // Signers we don't know are rejected
+        match Su3File::from_bytes(I2PSEEDS_SU3, &HashMap::new()) {
+            Ok(_) => panic!("Accepted a reseed file from an unknown signer"),
+            Err(e) => assert_eq!(e, Error::UnknownSigner),
+        }
+    }
 }
@@ -6,7 +6,7 @@ use nom::*;
 use nom::{
     bits::streaming::take as take_bits,
     bytes::streaming::take,
-    combinator::{cond, map, map_opt, peek, verify},
+    combinator::{cond, map, map_opt, peek, rest, verify},
     error::{Error as NomError, ErrorKind},
     multi::{count, length_count, length_data},
     number::streaming::{be_u16, be_u32, be_u8},
@@ -29,6 +29,7 @@ use crate::data::{
         tunnel_id,
     },
 };
+use crate::util::try_serialize_with_len;
 
 //
 // Utils
@@ -199,8 +200,7 @@ fn gen_compressed_ri<'a>(
     input: (&'a mut [u8], usize),
     ri: &RouterInfo,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    let mut buf = Vec::new();
-    gen_router_info((&mut buf, 0), ri)?;
+    let buf = try_serialize_with_len(0, |input| gen_router_info(input, ri))?;
     let mut e = GzEncoder::new(Vec::new(), Compression::best());
     match e.write(&buf) {
         Ok(n) if n < buf.len() => Err(GenError::CustomError(1)),
@@ -258,7 +258,7 @@ fn database_store(i: &[u8]) -> IResult<&[u8], MessagePayload> {
     ))
 }
 
-fn gen_database_store_data<'a>(
+pub(super) fn gen_database_store_data<'a>(
     input: (&'a mut [u8], usize),
     data: &DatabaseStoreData,
 ) -> Result<(&'a mut [u8], usize), GenError> {
@@ -583,7 +583,10 @@ fn gen_tunnel_gateway<'a>(
     input: (&'a mut [u8], usize),
     tg: &TunnelGateway,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    do_gen!(input, gen_tunnel_id(&tg.tid) >> gen_slice!(tg.data))
+    do_gen!(
+        input,
+        gen_tunnel_id(&tg.tid) >> gen_be_u16!(tg.data.len()) >> gen_slice!(tg.data)
+    )
 }
 
 // Data
@@ -740,7 +743,16 @@ fn ntcp2_header(i: &[u8]) -> IResult<&[u8], (u8, u32, I2PDate)> {
     tuple((be_u8, be_u32, short_expiry))(i)
 }
 
-fn payload(msg_type: u8) -> impl Fn(&[u8]) -> IResult<&[u8], MessagePayload> {
+fn short_header(i: &[u8]) -> IResult<&[u8], (u8, I2PDate)> {
+    // (msg_type, expiration)
+    tuple((be_u8, short_expiry))(i)
+}
+
+/// Parses a payload of the given type.
+///
+/// Payloads of unhandled types are kept as opaque bytes; `size` is their
+/// length, or `None` if they extend to the end of the input.
+fn payload(msg_type: u8, size: Option<u16>) -> impl Fn(&[u8]) -> IResult<&[u8], MessagePayload> {
     move |i: &[u8]| match msg_type {
         1 => database_store(i),
         2 => database_lookup(i),
@@ -754,57 +766,91 @@ fn payload(msg_type: u8) -> impl Fn(&[u8]) -> IResult<&[u8], MessagePayload> {
         22 => tunnel_build_reply(i),
         23 => variable_tunnel_build(i),
         24 => variable_tunnel_build_reply(i),
-        _ => unimplemented!(),
+        _ => {
+            let (i, data) = match size {
+                Some(size) => take(size)(i)?,
+                None => rest(i)?,
+            };
+            Ok((i, MessagePayload::Unknown(msg_type, data.to_vec())))
+        }
     }
 }
 
 pub fn message(i: &[u8]) -> IResult<&[u8], Message> {
+    verify(unvalidated_message, |msg: &Message| msg.validate().is_ok())(i)
+}
+
+fn unvalidated_message(i: &[u8]) -> IResult<&[u8], Message> {
     let (i, (msg_type, id, expiration, size, cs)) = header(i)?;
     map(
         preceded(
             peek(verify(take(size), move |buf| checksum(buf) == cs)),
-            payload(msg_type),
+            payload(msg_type, Some(size)),
         ),
         move |payload| Message {
             id,
             expiration,
             payload,
+            raw: None,
         },
     )(i)
 }
 
 pub fn ntcp2_message(i: &[u8]) -> IResult<&[u8], Message> {
+    verify(unvalidated_ntcp2_message, |msg: &Message| {
+        msg.validate().is_ok()
+    })(i)
+}
+
+fn unvalidated_ntcp2_message(i: &[u8]) -> IResult<&[u8], Message> {
     let (i, hdr) = ntcp2_header(i)?;
-    let (i, payload) = payload(hdr.0)(i)?;
+    let (i, payload) = payload(hdr.0, None)(i)?;
     Ok((
         i,
         Message {
             id: hdr.1,
             expiration: hdr.2,
             payload,
+            raw: None,
+        },
+    ))
+}
+
+/// Parses a message with the short header used by SSU. The header has no
+/// message ID, so the parsed message has an ID of zero.
+pub fn short_message(i: &[u8]) -> IResult<&[u8], Message> {
+    verify(unvalidated_short_message, |msg: &Message| {
+        msg.validate().is_ok()
+    })(i)
+}
+
+fn unvalidated_short_message(i: &[u8]) -> IResult<&[u8], Message> {
+    let (i, (msg_type, expiration)) = short_header(i)?;
+    let (i, payload) = payload(msg_type, None)(i)?;
+    Ok((
+        i,
+        Message {
+            id: 0,
+            expiration,
+            payload,
+            raw: None,
         },
     ))
 }
 
+/// Returns the parser for messages framed with the given header.
+pub fn message_with_header(format: HeaderFormat) -> fn(&[u8]) -> IResult<&[u8], Message> {
+    match format {
+        HeaderFormat::Standard => message,
+        HeaderFormat::Short => short_message,
+    }
+}
+
 fn gen_message_type<'a>(
     input: (&'a mut [u8], usize),
     msg: &Message,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    let msg_type = match msg.payload {
-        MessagePayload::DatabaseStore(_) => 1,
-        MessagePayload::DatabaseLookup(_) => 2,
-        MessagePayload::DatabaseSearchReply(_) => 3,
-        MessagePayload::DeliveryStatus(_) => 10,
-        MessagePayload::Garlic(_) => 11,
-        MessagePayload::TunnelData(_) => 18,
-        MessagePayload::TunnelGateway(_) => 19,
-        MessagePayload::Data(_) => 20,
-        MessagePayload::TunnelBuild(_) => 21,
-        MessagePayload::TunnelBuildReply(_) => 22,
-        MessagePayload::VariableTunnelBuild(_) => 23,
-        MessagePayload::VariableTunnelBuildReply(_) => 24,
-    };
-    gen_be_u8!(input, msg_type)
+    gen_be_u8!(input, msg.payload.type_code())
 }
 
 fn gen_payload<'a>(
@@ -826,6 +872,7 @@ fn gen_payload<'a>(
         MessagePayload::VariableTunnelBuildReply(ref vtbr) => {
             gen_variable_tunnel_build_reply(input, vtbr)
         }
+        MessagePayload::Unknown(_, ref data) => gen_slice!(input, &data[..]),
     }
 }
 
@@ -858,6 +905,16 @@ pub fn gen_ntcp2_message<'a>(
     )
 }
 
+pub fn gen_short_message<'a>(
+    input: (&'a mut [u8], usize),
+    msg: &Message,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_message_type(msg) >> gen_short_expiry(&msg.expiration) >> gen_payload(&msg.payload)
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -939,6 +996,60 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_build_request_record_offsets() {
+        let brr = BuildRequestRecord {
+            receive_tid: TunnelId(0x0102_0304),
+            our_ident: Hash([0x11; 32]),
+            next_tid: TunnelId(0x0506_0708),
+            next_ident: Hash([0x22; 32]),
+            layer_key: SessionKey([0x33; 32]),
+            iv_key: SessionKey([0x44; 32]),
+            reply_key: SessionKey([0x55; 32]),
+            reply_iv: [0x66; 16],
+            hop_type: ParticipantType::OutboundEndpoint,
+            request_time: 0x090a_0b0c,
+            send_msg_id: 0x0d0e_0f10,
+        };
+
+        let mut res = vec![0; 222];
+        match gen_build_request_record((&mut res, 0), &brr) {
+            Ok((_, sz)) => assert_eq!(sz, 222),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        // Field offsets from the spec
+        assert_eq!(&res[0..4], &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(&res[4..36], &[0x11; 32][..]);
+        assert_eq!(&res[36..40], &[0x05, 0x06, 0x07, 0x08]);
+        assert_eq!(&res[40..72], &[0x22; 32][..]);
+        assert_eq!(&res[72..104], &[0x33; 32][..]);
+        assert_eq!(&res[104..136], &[0x44; 32][..]);
+        assert_eq!(&res[136..168], &[0x55; 32][..]);
+        assert_eq!(&res[168..184], &[0x66; 16][..]);
+        assert_eq!(res[184], 0b0100_0000);
+        assert_eq!(&res[185..189], &[0x09, 0x0a, 0x0b, 0x0c]);
+        assert_eq!(&res[189..193], &[0x0d, 0x0e, 0x0f, 0x10]);
+
+        // Padding is ignored when parsing
+        match build_request_record(&res) {
+            Ok((i, m)) => {
+                assert!(i.is_empty());
+                assert_eq!(m, brr);
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        // The inbound gateway flag is the high bit
+        let brr = BuildRequestRecord {
+            hop_type: ParticipantType::InboundGateway,
+            ..brr
+        };
+        gen_build_request_record((&mut res, 0), &brr).unwrap();
+        assert_eq!(res[184], 0b1000_0000);
+        assert_eq!(build_request_record(&res).map(|(_, v)| v), Ok(brr));
+    }
+
     #[test]
     fn test_build_request_record_flags() {
         macro_rules! eval {
@@ -1118,6 +1229,7 @@ mod tests {
                 id: 0,
                 expiration: I2PDate::from_system_time(UNIX_EPOCH),
                 payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                raw: None,
             },
             [
                 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 14, 44, 0, 0, 0, 10, 0, 1, 2, 3, 4, 5,
@@ -1133,6 +1245,7 @@ mod tests {
                     msg_id: 0x7b3f_bba9,
                     time_stamp: I2PDate::from_system_time(UNIX_EPOCH)
                 }),
+                raw: None,
             },
             [
                 0x0a, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -1154,6 +1267,7 @@ mod tests {
                 id: 0,
                 expiration: I2PDate::from_system_time(UNIX_EPOCH),
                 payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                raw: None,
             },
             [20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,]
         );
@@ -1166,6 +1280,7 @@ mod tests {
                     msg_id: 0x7b3f_bba9,
                     time_stamp: I2PDate::from_system_time(UNIX_EPOCH)
                 }),
+                raw: None,
             },
             [
                 0x0a, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00, 0x00, 0x00, 0x7b, 0x3f, 0xbb, 0xa9, 0x00,
@@ -1173,4 +1288,106 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_short_message() {
+        macro_rules! eval {
+            ($value:expr, $expected:expr) => {
+                bake_and_eat!(gen_short_message, short_message, $value, $expected)
+            };
+        }
+
+        eval!(
+            Message {
+                id: 0,
+                expiration: I2PDate(0x1234_5678 * 1_000),
+                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                raw: None,
+            },
+            [20, 0x12, 0x34, 0x56, 0x78, 0, 0, 0, 10, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+        );
+
+        // The parser can be chosen by header format
+        let buf = [20, 0, 0, 0, 0, 0, 0, 0, 1, 42];
+        let (_, msg) = message_with_header(HeaderFormat::Short)(&buf).unwrap();
+        match msg.payload {
+            MessagePayload::Data(data) => assert_eq!(data, [42]),
+            _ => panic!("Unexpected payload"),
+        }
+        assert!(message_with_header(HeaderFormat::Standard)(&buf).is_err());
+    }
+
+    #[test]
+    fn test_message_header_big_endian() {
+        let msg = Message {
+            id: 0x0102_0304,
+            expiration: I2PDate(0x0102_0304_0506_0708),
+            // A four-byte length and 254 bytes of data give a size of 0x0102
+            payload: MessagePayload::Data(vec![0; 254]),
+            raw: None,
+        };
+        let mut buf = vec![0; msg.serialized_len()];
+        gen_message((&mut buf, 0), &msg).unwrap();
+        assert_eq!(&buf[1..5], &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(
+            &buf[5..13],
+            &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]
+        );
+        assert_eq!(&buf[13..15], &[0x01, 0x02]);
+        assert_eq!(&buf[16..20], &[0x00, 0x00, 0x00, 0xfe]);
+        assert_eq!(message(&buf).unwrap().1, msg);
+
+        let msg = Message {
+            id: 0x0102_0304,
+            expiration: I2PDate(0x0506_0708 * 1_000),
+            payload: MessagePayload::Data(vec![0; 254]),
+            raw: None,
+        };
+        let mut buf = vec![0; msg.ntcp2_serialized_len()];
+        gen_ntcp2_message((&mut buf, 0), &msg).unwrap();
+        assert_eq!(&buf[1..5], &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(&buf[5..9], &[0x05, 0x06, 0x07, 0x08]);
+        assert_eq!(ntcp2_message(&buf).unwrap().1, msg);
+    }
+
+    #[test]
+    fn test_unknown_message() {
+        let msg = Message {
+            id: 0x1234_5678,
+            expiration: I2PDate::from_system_time(UNIX_EPOCH),
+            payload: MessagePayload::Unknown(42, vec![1, 2, 3]),
+            raw: None,
+        };
+        assert_eq!(msg.payload.message_type(), None);
+
+        let mut buf = serialize(|input| gen_message(input, &msg));
+        assert_eq!(buf[0], 42);
+        assert_eq!(buf.len(), msg.serialized_len());
+
+        // The payload is kept opaque, and the rest of the input is untouched
+        buf.extend_from_slice(&[4, 5]);
+        match message(&buf) {
+            Ok((rest, parsed)) => {
+                assert_eq!(rest, &[4, 5]);
+                assert_eq!(parsed.id, msg.id);
+                match parsed.payload {
+                    MessagePayload::Unknown(42, ref data) => assert_eq!(data, &[1, 2, 3]),
+                    ref p => panic!("Unexpected payload: {:?}", p),
+                }
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        let buf = serialize(|input| gen_ntcp2_message(input, &msg));
+        match ntcp2_message(&buf) {
+            Ok((rest, parsed)) => {
+                assert!(rest.is_empty());
+                match parsed.payload {
+                    MessagePayload::Unknown(42, ref data) => assert_eq!(data, &[1, 2, 3]),
+                    ref p => panic!("Unexpected payload: {:?}", p),
+                }
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
 }
@@ -9,14 +9,16 @@
 //! [I2NP specification](https://geti2p.net/spec/i2np)
 
 use rand::{rngs::OsRng, thread_rng, Rng};
+use std::collections::{HashSet, VecDeque};
 use std::fmt;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::crypto::{self, elgamal, SessionKey};
 use crate::data::{
     Certificate, Hash, I2PDate, LeaseSet, ReadError, RouterInfo, SessionTag, TunnelId,
 };
-use crate::util::serialize;
+use crate::util::{serialize, serialize_with_len};
 
 #[allow(clippy::double_parens)]
 #[allow(clippy::needless_pass_by_value)]
@@ -24,6 +26,75 @@ pub(crate) mod frame;
 
 const MESSAGE_EXPIRATION_MS: u64 = 60 * 1000;
 
+// Message IDs are reserved for as long as messages using them can be valid
+const MESSAGE_ID_TTL: Duration = Duration::from_millis(MESSAGE_EXPIRATION_MS);
+const MAX_RESERVED_MESSAGE_IDS: usize = 65536;
+
+lazy_static! {
+    static ref MESSAGE_IDS: Mutex<MessageIdAllocator> = Mutex::new(MessageIdAllocator::new(
+        MESSAGE_ID_TTL,
+        MAX_RESERVED_MESSAGE_IDS
+    ));
+}
+
+/// Hands out random message IDs, avoiding any that were handed out recently.
+///
+/// IDs are recycled once they have been reserved for the TTL, or when more than
+/// `max_reserved` IDs are reserved (oldest first).
+pub struct MessageIdAllocator {
+    ttl: Duration,
+    max_reserved: usize,
+    reserved: HashSet<u32>,
+    by_age: VecDeque<(Instant, u32)>,
+}
+
+impl MessageIdAllocator {
+    pub fn new(ttl: Duration, max_reserved: usize) -> Self {
+        MessageIdAllocator {
+            ttl,
+            max_reserved,
+            reserved: HashSet::new(),
+            by_age: VecDeque::new(),
+        }
+    }
+
+    /// Returns a new ID that is not currently reserved, and reserves it.
+    pub fn allocate(&mut self) -> u32 {
+        self.allocate_at(Instant::now())
+    }
+
+    fn allocate_at(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+
+        let mut rng = thread_rng();
+        let id = loop {
+            let id = rng.gen();
+            if !self.reserved.contains(&id) {
+                break id;
+            }
+        };
+
+        self.reserved.insert(id);
+        self.by_age.push_back((now, id));
+        id
+    }
+
+    /// Returns true if the given ID was handed out within the TTL.
+    pub fn is_reserved(&self, id: u32) -> bool {
+        self.reserved.contains(&id)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(reserved_at, id)) = self.by_age.front() {
+            if now.duration_since(reserved_at) < self.ttl && self.by_age.len() < self.max_reserved {
+                break;
+            }
+            self.by_age.pop_front();
+            self.reserved.remove(&id);
+        }
+    }
+}
+
 /// BuildRequestRecord errors
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum BuildRequestError {
@@ -45,6 +116,50 @@ impl<T> From<nom::Err<T>> for BuildRequestError {
     }
 }
 
+/// Message consistency errors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageError {
+    /// The declared type of a structure does not match its contents.
+    TypeMismatch,
+    /// A tunnel build message has an unsupported number of records.
+    InvalidRecordCount(usize),
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::TypeMismatch => "Declared type does not match contents".fmt(f),
+            MessageError::InvalidRecordCount(n) => write!(f, "Invalid record count: {}", n),
+        }
+    }
+}
+
+/// DatabaseStore verification errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreError {
+    /// The stored structure's signature is invalid.
+    Crypto(crypto::Error),
+    /// The store key is not the hash of the stored structure.
+    InvalidKey,
+}
+
+impl From<crypto::Error> for StoreError {
+    fn from(e: crypto::Error) -> Self {
+        StoreError::Crypto(e)
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Crypto(e) => e.fmt(f),
+            StoreError::InvalidKey => "Key does not match the stored structure".fmt(f),
+        }
+    }
+}
+
 //
 // Common structures
 //
@@ -176,6 +291,27 @@ impl DatabaseStore {
             data: DatabaseStoreData::LS(Box::new(ls)),
         }
     }
+
+    /// Checks that the store key is the hash of the stored structure, and that
+    /// the structure is correctly signed.
+    ///
+    /// The structure has already been decompressed and parsed by the time the
+    /// message is received, so this should be called before storing it.
+    pub fn verify(&self) -> Result<(), StoreError> {
+        let hash = match self.data {
+            DatabaseStoreData::RI(ref ri) => ri.router_id.hash(),
+            DatabaseStoreData::LS(ref ls) => ls.dest.hash(),
+        };
+        if self.key != hash {
+            return Err(StoreError::InvalidKey);
+        }
+
+        match self.data {
+            DatabaseStoreData::RI(ref ri) => ri.verify()?,
+            DatabaseStoreData::LS(ref ls) => ls.verify()?,
+        }
+        Ok(())
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -280,6 +416,21 @@ pub struct DeliveryStatus {
     time_stamp: I2PDate,
 }
 
+impl DeliveryStatus {
+    /// Creates an acknowledgment of the given message ID, stamped with the
+    /// current time.
+    pub fn new(msg_id: u32) -> Self {
+        DeliveryStatus {
+            msg_id,
+            time_stamp: I2PDate::from_system_time(SystemTime::now()),
+        }
+    }
+
+    pub fn msg_id(&self) -> u32 {
+        self.msg_id
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 impl fmt::Display for DeliveryStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -355,6 +506,177 @@ pub enum MessagePayload {
     TunnelBuildReply(Box<[[u8; 528]; 8]>),
     VariableTunnelBuild(Vec<[u8; 528]>),
     VariableTunnelBuildReply(Vec<[u8; 528]>),
+
+    /// A message type we do not parse, carried as its type code and raw
+    /// payload.
+    Unknown(u8, Vec<u8>),
+}
+
+/// The I2NP message types that we parse into a structured payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    DatabaseStore,
+    DatabaseLookup,
+    DatabaseSearchReply,
+    DeliveryStatus,
+    Garlic,
+    TunnelData,
+    TunnelGateway,
+    Data,
+    TunnelBuild,
+    TunnelBuildReply,
+    VariableTunnelBuild,
+    VariableTunnelBuildReply,
+}
+
+const HANDLED_MESSAGE_TYPES: [MessageType; 12] = [
+    MessageType::DatabaseStore,
+    MessageType::DatabaseLookup,
+    MessageType::DatabaseSearchReply,
+    MessageType::DeliveryStatus,
+    MessageType::Garlic,
+    MessageType::TunnelData,
+    MessageType::TunnelGateway,
+    MessageType::Data,
+    MessageType::TunnelBuild,
+    MessageType::TunnelBuildReply,
+    MessageType::VariableTunnelBuild,
+    MessageType::VariableTunnelBuildReply,
+];
+
+/// Returns the message types that are parsed into a structured payload.
+///
+/// Messages of any other type are parsed as `MessagePayload::Unknown`.
+pub fn handled_message_types() -> &'static [MessageType] {
+    &HANDLED_MESSAGE_TYPES
+}
+
+impl MessageType {
+    /// Returns the type code used on the wire.
+    pub fn code(self) -> u8 {
+        match self {
+            MessageType::DatabaseStore => 1,
+            MessageType::DatabaseLookup => 2,
+            MessageType::DatabaseSearchReply => 3,
+            MessageType::DeliveryStatus => 10,
+            MessageType::Garlic => 11,
+            MessageType::TunnelData => 18,
+            MessageType::TunnelGateway => 19,
+            MessageType::Data => 20,
+            MessageType::TunnelBuild => 21,
+            MessageType::TunnelBuildReply => 22,
+            MessageType::VariableTunnelBuild => 23,
+            MessageType::VariableTunnelBuildReply => 24,
+        }
+    }
+
+    /// Returns the handled message type with the given wire code, if any.
+    pub fn from_code(code: u8) -> Option<Self> {
+        HANDLED_MESSAGE_TYPES
+            .iter()
+            .find(|t| t.code() == code)
+            .cloned()
+    }
+}
+
+impl GarlicCloveDeliveryInstructions {
+    fn serialized_len(&self) -> usize {
+        let mut len = 1;
+        if self.encrypted {
+            len += 32;
+        }
+        if self.delivery_type != 0 {
+            len += 32;
+        }
+        if self.delivery_type == 3 {
+            len += 4;
+        }
+        if self.delay_set {
+            len += 4;
+        }
+        len
+    }
+}
+
+impl GarlicClove {
+    fn serialized_len(&self) -> usize {
+        self.delivery_instructions.serialized_len()
+            + self.msg.serialized_len()
+            + 4
+            + 8
+            + self.cert.serialized_len()
+    }
+}
+
+impl MessagePayload {
+    /// Returns the type of this payload, or `None` if it is not a type we
+    /// parse.
+    pub fn message_type(&self) -> Option<MessageType> {
+        match *self {
+            MessagePayload::DatabaseStore(_) => Some(MessageType::DatabaseStore),
+            MessagePayload::DatabaseLookup(_) => Some(MessageType::DatabaseLookup),
+            MessagePayload::DatabaseSearchReply(_) => Some(MessageType::DatabaseSearchReply),
+            MessagePayload::DeliveryStatus(_) => Some(MessageType::DeliveryStatus),
+            MessagePayload::Garlic(_) => Some(MessageType::Garlic),
+            MessagePayload::TunnelData(_) => Some(MessageType::TunnelData),
+            MessagePayload::TunnelGateway(_) => Some(MessageType::TunnelGateway),
+            MessagePayload::Data(_) => Some(MessageType::Data),
+            MessagePayload::TunnelBuild(_) => Some(MessageType::TunnelBuild),
+            MessagePayload::TunnelBuildReply(_) => Some(MessageType::TunnelBuildReply),
+            MessagePayload::VariableTunnelBuild(_) => Some(MessageType::VariableTunnelBuild),
+            MessagePayload::VariableTunnelBuildReply(_) => {
+                Some(MessageType::VariableTunnelBuildReply)
+            }
+            MessagePayload::Unknown(..) => None,
+        }
+    }
+
+    /// Returns the type code used on the wire for this payload.
+    fn type_code(&self) -> u8 {
+        match *self {
+            MessagePayload::Unknown(code, _) => code,
+            _ => self.message_type().map(MessageType::code).unwrap(),
+        }
+    }
+
+    /// Returns the exact number of bytes this payload serializes to.
+    fn serialized_len(&self) -> usize {
+        match *self {
+            MessagePayload::DatabaseStore(ref ds) => {
+                let reply_len = if ds.reply.is_some() { 40 } else { 4 };
+                let data_len = match ds.data {
+                    // A stored RouterInfo is compressed, so we only know its
+                    // length once it has been.
+                    DatabaseStoreData::RI(_) => {
+                        serialize(|input| frame::gen_database_store_data(input, &ds.data)).len()
+                    }
+                    DatabaseStoreData::LS(ref ls) => ls.serialized_len(),
+                };
+                32 + 1 + reply_len + data_len
+            }
+            MessagePayload::DatabaseLookup(ref dl) => {
+                let reply_tid_len = if dl.reply_tid.is_some() { 4 } else { 0 };
+                let reply_enc_len = match dl.reply_enc {
+                    Some((_, ref tags)) => 32 + 1 + 32 * tags.len(),
+                    None => 0,
+                };
+                32 + 32 + 1 + reply_tid_len + 2 + 32 * dl.excluded_peers.len() + reply_enc_len
+            }
+            MessagePayload::DatabaseSearchReply(ref dsr) => 32 + 1 + 32 * dsr.peers.len() + 32,
+            MessagePayload::DeliveryStatus(_) => 4 + 8,
+            MessagePayload::Garlic(ref g) => {
+                let cloves_len: usize = g.cloves.iter().map(|c| c.serialized_len()).sum();
+                1 + cloves_len + g.cert.serialized_len() + 4 + 8
+            }
+            MessagePayload::TunnelData(_) => 4 + 1024,
+            MessagePayload::TunnelGateway(ref tg) => 4 + 2 + tg.data.len(),
+            MessagePayload::Data(ref d) => 4 + d.len(),
+            MessagePayload::TunnelBuild(_) | MessagePayload::TunnelBuildReply(_) => 8 * 528,
+            MessagePayload::VariableTunnelBuild(ref records)
+            | MessagePayload::VariableTunnelBuildReply(ref records) => 1 + records.len() * 528,
+            MessagePayload::Unknown(_, ref data) => data.len(),
+        }
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -389,6 +711,7 @@ impl fmt::Debug for MessagePayload {
             MessagePayload::VariableTunnelBuildReply(_) => {
                 "VariableTunnelBuildReply".fmt(formatter)
             }
+            MessagePayload::Unknown(code, _) => format!("Unknown (type: {})", code).fmt(formatter),
         }
     }
 }
@@ -415,15 +738,28 @@ impl fmt::Display for MessagePayload {
             MessagePayload::VariableTunnelBuildReply(_) => {
                 "VariableTunnelBuildReply".fmt(formatter)
             }
+            MessagePayload::Unknown(code, _) => format!("Unknown (type: {})", code).fmt(formatter),
         }
     }
 }
 
+/// The header an I2NP message is framed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderFormat {
+    /// The 16-byte header used by the transports.
+    Standard,
+    /// The 5-byte header used by SSU: type and short expiration, with no
+    /// message ID, size or checksum.
+    Short,
+}
+
 #[derive(Debug)]
 pub struct Message {
     pub(crate) id: u32,
     pub(crate) expiration: I2PDate,
     pub(crate) payload: MessagePayload,
+    /// The serialized message, if retained by the decoder it was parsed with.
+    pub(crate) raw: Option<Vec<u8>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -446,11 +782,12 @@ impl PartialEq for Message {
 impl Message {
     pub fn from_payload(payload: MessagePayload) -> Self {
         Message {
-            id: thread_rng().gen(),
+            id: MESSAGE_IDS.lock().unwrap().allocate(),
             expiration: I2PDate::from_system_time(
                 SystemTime::now() + Duration::from_millis(MESSAGE_EXPIRATION_MS),
             ),
             payload,
+            raw: None,
         }
     }
 
@@ -459,15 +796,87 @@ impl Message {
             id: 0,
             expiration: I2PDate(0x123_4567_87c0),
             payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            raw: None,
+        }
+    }
+
+    /// Returns the bytes this message was parsed from, if they were retained.
+    ///
+    /// This allows a received message to be forwarded without serializing it
+    /// again.
+    pub fn raw_bytes(&self) -> Option<&[u8]> {
+        self.raw.as_deref()
+    }
+
+    /// Checks that the message is internally consistent.
+    pub fn validate(&self) -> Result<(), MessageError> {
+        match self.payload {
+            MessagePayload::DatabaseStore(ref ds) => match (ds.ds_type, &ds.data) {
+                (0, DatabaseStoreData::RI(_)) | (1, DatabaseStoreData::LS(_)) => Ok(()),
+                _ => Err(MessageError::TypeMismatch),
+            },
+            MessagePayload::VariableTunnelBuild(ref records)
+            | MessagePayload::VariableTunnelBuildReply(ref records) => match records.len() {
+                1..=8 => Ok(()),
+                n => Err(MessageError::InvalidRecordCount(n)),
+            },
+            _ => Ok(()),
         }
     }
 
+    /// Returns the exact number of bytes this message serializes to with the
+    /// standard 16-byte header.
+    pub fn serialized_len(&self) -> usize {
+        16 + self.payload.serialized_len()
+    }
+
+    /// Returns the exact number of bytes this message serializes to with the
+    /// short 9-byte header used by NTCP2.
+    pub fn ntcp2_serialized_len(&self) -> usize {
+        9 + self.payload.serialized_len()
+    }
+
+    /// Returns the exact number of bytes this message serializes to with the
+    /// short 5-byte header used by SSU.
+    pub fn short_serialized_len(&self) -> usize {
+        5 + self.payload.serialized_len()
+    }
+
+    /// Serializes this message with the standard header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize_with_len(self.serialized_len(), |input| {
+            frame::gen_message(input, self)
+        })
+    }
+
+    /// Serializes this message with the short header used by SSU.
+    ///
+    /// The message ID is not included, and the expiration is truncated to
+    /// seconds.
+    pub fn gen_short(&self) -> Vec<u8> {
+        serialize_with_len(self.short_serialized_len(), |input| {
+            frame::gen_short_message(input, self)
+        })
+    }
+
+    /// Parses a message framed with the given header.
+    pub fn parse(data: &[u8], format: HeaderFormat) -> Result<Self, ReadError> {
+        let (_, msg) = frame::message_with_header(format)(data)?;
+        Ok(msg)
+    }
+
+    /// Parses a message framed with the short header used by SSU. The parsed
+    /// message has an ID of zero.
+    pub fn parse_short(data: &[u8]) -> Result<Self, ReadError> {
+        Message::parse(data, HeaderFormat::Short)
+    }
+
     pub fn size(&self) -> usize {
-        serialize(|input| frame::gen_message(input, self)).len()
+        self.serialized_len()
     }
 
     pub fn ntcp2_size(&self) -> usize {
-        serialize(|input| frame::gen_ntcp2_message(input, self)).len()
+        self.ntcp2_serialized_len()
     }
 }
 
@@ -495,6 +904,110 @@ mod tests {
         );
     }
 
+    #[test]
+    fn message_id_allocator() {
+        let ttl = Duration::from_secs(60);
+        let mut allocator = MessageIdAllocator::new(ttl, 100_000);
+        let start = Instant::now();
+
+        // No collisions within the TTL
+        let ids: Vec<_> = (0..50_000).map(|_| allocator.allocate_at(start)).collect();
+        let unique: HashSet<_> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len());
+        assert!(ids.iter().all(|id| allocator.is_reserved(*id)));
+
+        // IDs are recycled after the TTL
+        allocator.allocate_at(start + ttl);
+        assert!(ids.iter().all(|id| !allocator.is_reserved(*id)));
+    }
+
+    #[test]
+    fn message_id_allocator_bounded() {
+        let mut allocator = MessageIdAllocator::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+
+        let first = allocator.allocate_at(now);
+        for _ in 0..9 {
+            allocator.allocate_at(now);
+        }
+        assert!(allocator.is_reserved(first));
+
+        // The oldest ID is recycled once the bound is reached
+        allocator.allocate_at(now);
+        assert!(!allocator.is_reserved(first));
+        assert_eq!(allocator.reserved.len(), 10);
+    }
+
+    #[test]
+    fn message_validate() {
+        let ri = RouterInfo::new(crate::data::RouterSecretKeys::new().rid);
+
+        // Consistent messages are accepted
+        assert_eq!(Message::dummy_data().validate(), Ok(()));
+        let msg = Message::from_payload(MessagePayload::DatabaseStore(DatabaseStore::from_ri(
+            ri.clone(),
+            None,
+        )));
+        assert_eq!(msg.validate(), Ok(()));
+
+        // A DatabaseStore declaring a LeaseSet but carrying a RouterInfo
+        let msg = Message::from_payload(MessagePayload::DatabaseStore(DatabaseStore {
+            key: ri.router_id.hash(),
+            ds_type: 1,
+            reply: None,
+            data: DatabaseStoreData::RI(Box::new(ri)),
+        }));
+        assert_eq!(msg.validate(), Err(MessageError::TypeMismatch));
+
+        // Tunnel builds need between 1 and 8 records
+        let msg = Message::from_payload(MessagePayload::VariableTunnelBuild(vec![]));
+        assert_eq!(msg.validate(), Err(MessageError::InvalidRecordCount(0)));
+        let msg =
+            Message::from_payload(MessagePayload::VariableTunnelBuildReply(vec![[0; 528]; 9]));
+        assert_eq!(msg.validate(), Err(MessageError::InvalidRecordCount(9)));
+        let msg = Message::from_payload(MessagePayload::VariableTunnelBuild(vec![[0; 528]; 8]));
+        assert_eq!(msg.validate(), Ok(()));
+    }
+
+    #[test]
+    fn database_store_verify() {
+        let rsk = crate::data::RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid.clone());
+        ri.sign(&rsk.signing_private_key);
+
+        // A correctly-keyed and signed RouterInfo
+        let ds = DatabaseStore::from_ri(ri.clone(), None);
+        assert_eq!(ds.verify(), Ok(()));
+
+        // The key must be the hash of the RouterIdentity
+        let mut ds = DatabaseStore::from_ri(ri.clone(), None);
+        ds.key = Hash([0; 32]);
+        assert_eq!(ds.verify(), Err(StoreError::InvalidKey));
+
+        // The RouterInfo must be signed by its own identity
+        let mut bad_ri = ri;
+        bad_ri.sign(&crate::data::RouterSecretKeys::new().signing_private_key);
+        let ds = DatabaseStore::from_ri(bad_ri, None);
+        assert_eq!(
+            ds.verify(),
+            Err(StoreError::Crypto(crypto::Error::InvalidSignature))
+        );
+    }
+
+    #[test]
+    fn message_validated_on_parse() {
+        let msg = Message::from_payload(MessagePayload::VariableTunnelBuild(vec![]));
+        let buf = serialize(|input| frame::gen_message(input, &msg));
+        assert!(frame::message(&buf).is_err());
+
+        let buf = serialize(|input| frame::gen_ntcp2_message(input, &msg));
+        assert!(frame::ntcp2_message(&buf).is_err());
+
+        let msg = Message::from_payload(MessagePayload::VariableTunnelBuild(vec![[0; 528]]));
+        let buf = serialize(|input| frame::gen_message(input, &msg));
+        assert!(frame::message(&buf).is_ok());
+    }
+
     macro_rules! check_size {
         ($size_func:ident, $header_size:expr) => {{
             assert_eq!(Message::dummy_data().$size_func(), $header_size + 4 + 10);
@@ -509,6 +1022,216 @@ mod tests {
         }};
     }
 
+    fn check_serialized_len(msg: Message) {
+        let buf = serialize(|input| frame::gen_message(input, &msg));
+        assert_eq!(msg.serialized_len(), buf.len());
+        assert_eq!(msg.to_bytes(), buf);
+
+        let buf = serialize(|input| frame::gen_ntcp2_message(input, &msg));
+        assert_eq!(msg.ntcp2_serialized_len(), buf.len());
+    }
+
+    #[test]
+    fn handled_types() {
+        let types = handled_message_types();
+        for t in &[
+            MessageType::DatabaseStore,
+            MessageType::DatabaseLookup,
+            MessageType::DatabaseSearchReply,
+            MessageType::DeliveryStatus,
+            MessageType::Garlic,
+            MessageType::TunnelData,
+            MessageType::TunnelGateway,
+            MessageType::Data,
+            MessageType::TunnelBuild,
+            MessageType::TunnelBuildReply,
+            MessageType::VariableTunnelBuild,
+            MessageType::VariableTunnelBuildReply,
+        ] {
+            assert!(types.contains(t));
+            assert_eq!(MessageType::from_code(t.code()), Some(*t));
+        }
+        assert_eq!(MessageType::from_code(0), None);
+        assert_eq!(MessageType::from_code(42), None);
+
+        assert_eq!(
+            MessagePayload::Data(vec![]).message_type(),
+            Some(MessageType::Data)
+        );
+        assert_eq!(MessagePayload::Unknown(42, vec![]).message_type(), None);
+    }
+
+    #[test]
+    fn message_serialized_len() {
+        let (_, ri) = crate::data::frame::router_info(crate::tests::ROUTER_INFO).unwrap();
+        let peer = Hash([1; 32]);
+
+        check_serialized_len(Message::dummy_data());
+        check_serialized_len(Message::from_payload(MessagePayload::DeliveryStatus(
+            DeliveryStatus {
+                msg_id: 0,
+                time_stamp: I2PDate::from_system_time(SystemTime::now()),
+            },
+        )));
+        check_serialized_len(Message::from_payload(MessagePayload::DatabaseStore(
+            DatabaseStore::from_ri(ri.clone(), None),
+        )));
+        check_serialized_len(Message::from_payload(MessagePayload::DatabaseStore(
+            DatabaseStore::from_ri(
+                ri,
+                Some(ReplyPath {
+                    token: 1,
+                    tid: TunnelId(2),
+                    gateway: peer.clone(),
+                }),
+            ),
+        )));
+        check_serialized_len(DatabaseLookup::create_msg(
+            peer.clone(),
+            Hash([2; 32]),
+            DatabaseLookupType::RouterInfo,
+        ));
+        check_serialized_len(Message::from_payload(MessagePayload::DatabaseLookup(
+            DatabaseLookup {
+                key: peer.clone(),
+                from: Hash([2; 32]),
+                lookup_type: DatabaseLookupType::Exploratory,
+                reply_tid: Some(TunnelId(3)),
+                excluded_peers: vec![Hash([4; 32]), Hash([5; 32])],
+                reply_enc: Some((SessionKey([6; 32]), vec![SessionTag([7; 32])])),
+            },
+        )));
+        check_serialized_len(Message::from_payload(MessagePayload::DatabaseSearchReply(
+            DatabaseSearchReply {
+                key: peer.clone(),
+                peers: vec![Hash([2; 32]), Hash([3; 32])],
+                from: Hash([4; 32]),
+            },
+        )));
+        check_serialized_len(Message::from_payload(MessagePayload::Garlic(Garlic {
+            cloves: vec![GarlicClove {
+                delivery_instructions: GarlicCloveDeliveryInstructions {
+                    encrypted: false,
+                    delivery_type: 3,
+                    delay_set: true,
+                    session_key: None,
+                    to_hash: Some(peer),
+                    tid: Some(TunnelId(5)),
+                    delay: Some(6),
+                },
+                msg: Message::dummy_data(),
+                clove_id: 7,
+                expiration: I2PDate::from_system_time(SystemTime::now()),
+                cert: Certificate::Null,
+            }],
+            cert: Certificate::Null,
+            msg_id: 8,
+            expiration: I2PDate::from_system_time(SystemTime::now()),
+        })));
+        check_serialized_len(Message::from_payload(MessagePayload::TunnelData(
+            TunnelData::new(TunnelId(1), &[0; 1024]),
+        )));
+        check_serialized_len(Message::from_payload(MessagePayload::TunnelGateway(
+            TunnelGateway {
+                tid: TunnelId(1),
+                data: vec![2; 30],
+            },
+        )));
+        check_serialized_len(Message::from_payload(MessagePayload::TunnelBuild(
+            Box::new([[0; 528]; 8]),
+        )));
+        check_serialized_len(Message::from_payload(MessagePayload::VariableTunnelBuild(
+            vec![[0; 528]; 3],
+        )));
+    }
+
+    #[test]
+    fn empty_payloads() {
+        for t in handled_message_types() {
+            // Returns the empty instance of each type that permits one
+            let payload = match t {
+                MessageType::DatabaseSearchReply => {
+                    MessagePayload::DatabaseSearchReply(DatabaseSearchReply {
+                        key: Hash([1; 32]),
+                        peers: vec![],
+                        from: Hash([2; 32]),
+                    })
+                }
+                MessageType::Garlic => MessagePayload::Garlic(Garlic {
+                    cloves: vec![],
+                    cert: Certificate::Null,
+                    msg_id: 3,
+                    expiration: I2PDate(0x123_4567_87c0),
+                }),
+                MessageType::TunnelGateway => MessagePayload::TunnelGateway(TunnelGateway {
+                    tid: TunnelId(4),
+                    data: vec![],
+                }),
+                MessageType::Data => MessagePayload::Data(vec![]),
+                // Fixed-size payloads, and variable tunnel builds which must
+                // have at least one record
+                MessageType::DatabaseStore
+                | MessageType::DatabaseLookup
+                | MessageType::DeliveryStatus
+                | MessageType::TunnelData
+                | MessageType::TunnelBuild
+                | MessageType::TunnelBuildReply
+                | MessageType::VariableTunnelBuild
+                | MessageType::VariableTunnelBuildReply => continue,
+            };
+            let payload_len = payload.serialized_len();
+            let msg = Message::from_payload(payload);
+
+            let standard = msg.to_bytes();
+            assert_eq!(standard.len(), msg.serialized_len());
+            assert_eq!(
+                u16::from_be_bytes([standard[13], standard[14]]) as usize,
+                payload_len
+            );
+            let parsed = Message::parse(&standard, HeaderFormat::Standard).unwrap();
+            assert_eq!(parsed.payload.message_type(), Some(*t));
+            assert_eq!(parsed.to_bytes(), standard);
+
+            let short = msg.gen_short();
+            assert_eq!(short.len(), msg.short_serialized_len());
+            assert_eq!(Message::parse_short(&short).unwrap().gen_short(), short);
+
+            let ntcp2 = serialize(|input| frame::gen_ntcp2_message(input, &msg));
+            assert_eq!(ntcp2.len(), msg.ntcp2_serialized_len());
+            let (_, parsed) = frame::ntcp2_message(&ntcp2).unwrap();
+            assert_eq!(
+                serialize(|input| frame::gen_ntcp2_message(input, &parsed)),
+                ntcp2
+            );
+        }
+
+        // The length fields of empty payloads are written as zero
+        let msg = Message::from_payload(MessagePayload::Data(vec![]));
+        assert_eq!(&msg.to_bytes()[16..], &[0; 4]);
+        let msg = Message::from_payload(MessagePayload::TunnelGateway(TunnelGateway {
+            tid: TunnelId(4),
+            data: vec![],
+        }));
+        assert_eq!(&msg.to_bytes()[16..], &[0, 0, 0, 4, 0, 0]);
+
+        // Messages of unhandled types may have no payload at all
+        let msg = Message::from_payload(MessagePayload::Unknown(42, vec![]));
+        let standard = msg.to_bytes();
+        assert_eq!(standard.len(), 16);
+        assert_eq!(&standard[13..15], &[0, 0]);
+        match Message::parse(&standard, HeaderFormat::Standard)
+            .unwrap()
+            .payload
+        {
+            MessagePayload::Unknown(42, data) => assert!(data.is_empty()),
+            other => panic!("Unexpected payload: {:?}", other),
+        }
+        match Message::parse_short(&msg.gen_short()).unwrap().payload {
+            MessagePayload::Unknown(42, data) => assert!(data.is_empty()),
+            other => panic!("Unexpected payload: {:?}", other),
+        }
+    }
+
     #[test]
     fn message_size() {
         check_size!(size, 16)
@@ -518,4 +1241,47 @@ mod tests {
     fn message_ntcp2_size() {
         check_size!(ntcp2_size, 9)
     }
+
+    #[test]
+    fn variable_tunnel_build_size() {
+        for &count in &[1, 4, 8] {
+            let payloads = vec![
+                MessagePayload::VariableTunnelBuild(vec![[0; 528]; count]),
+                MessagePayload::VariableTunnelBuildReply(vec![[0; 528]; count]),
+            ];
+            for payload in payloads {
+                let msg = Message::from_payload(payload);
+                assert_eq!(msg.size(), 16 + 1 + count * 528);
+                assert_eq!(msg.size(), msg.to_bytes().len());
+                assert_eq!(msg.ntcp2_size(), 9 + 1 + count * 528);
+                assert_eq!(
+                    msg.ntcp2_size(),
+                    serialize(|input| frame::gen_ntcp2_message(input, &msg)).len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn message_short_header() {
+        let msg = Message {
+            id: 0,
+            expiration: I2PDate(0x1234_5678 * 1_000),
+            payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            raw: None,
+        };
+
+        let short = msg.gen_short();
+        assert_eq!(short.len(), msg.short_serialized_len());
+        assert_eq!(Message::parse_short(&short).unwrap(), msg);
+
+        // The short header is 11 bytes smaller than the standard one
+        let standard = msg.to_bytes();
+        assert_eq!(standard.len() - short.len(), 11);
+        assert_eq!(
+            Message::parse(&standard, HeaderFormat::Standard).unwrap(),
+            msg
+        );
+        assert!(Message::parse_short(&standard).is_err());
+    }
 }
@@ -4,6 +4,7 @@ use std::fmt;
 use std::time::Duration;
 
 use crate::crypto;
+use crate::data::RouterInfoError;
 
 pub enum Error {
     Lookup(LookupError),
@@ -62,6 +63,7 @@ impl fmt::Display for LookupError {
 pub enum StoreError {
     Crypto(crypto::Error),
     Expired(Duration),
+    Invalid(RouterInfoError),
     InvalidKey,
     PublishedInFuture,
     WrongNetwork,
@@ -73,6 +75,12 @@ impl From<crypto::Error> for StoreError {
     }
 }
 
+impl From<RouterInfoError> for StoreError {
+    fn from(e: RouterInfoError) -> Self {
+        StoreError::Invalid(e)
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -81,6 +89,7 @@ impl fmt::Display for StoreError {
             StoreError::Expired(age) => {
                 format!("Too old (published {} seconds ago)", age.as_secs()).fmt(f)
             }
+            StoreError::Invalid(e) => e.fmt(f),
             StoreError::InvalidKey => "Key does not match RouterInfo's RouterIdentity".fmt(f),
             StoreError::PublishedInFuture => "Published in future".fmt(f),
             StoreError::WrongNetwork => "Not in our network".fmt(f),
@@ -35,6 +35,9 @@ const ROUTER_INFO_EXPIRATION: u64 = 27 * 60 * 60;
 
 /// Interval on which we expire RouterInfos.
 const EXPIRE_RI_INTERVAL: u64 = 5 * 60;
+/// How long we wait for a fresh RouterInfo from a connected peer whose
+/// RouterInfo has expired, before closing our connection to them.
+const EXPIRED_PEER_GRACE: u64 = 10 * 60;
 /// Interval on which we expire LeaseSets.
 const EXPIRE_LS_INTERVAL: u64 = 60;
 /// If we know fewer than this many routers, we will reseed.
@@ -225,17 +228,22 @@ impl Future for Engine {
                     // Handle the network message
                     if let Some((from, msg)) = next_ib {
                         match msg.payload {
-                            MessagePayload::DatabaseStore(ds) => match ds.data {
-                                DatabaseStoreData::RI(ri) => {
-                                    self.netdb
-                                        .store_router_info(ds.key, *ri, false)
-                                        .expect("Failed to store RouterInfo");
-                                }
-                                DatabaseStoreData::LS(ls) => {
-                                    self.netdb
-                                        .store_lease_set(ds.key, *ls)
-                                        .expect("Failed to store LeaseSet");
+                            MessagePayload::DatabaseStore(ds) => match ds.verify() {
+                                Err(e) => {
+                                    warn!("Dropping invalid DatabaseStore from {}: {}", from, e)
                                 }
+                                Ok(()) => match ds.data {
+                                    DatabaseStoreData::RI(ri) => {
+                                        self.netdb
+                                            .store_router_info(ds.key, *ri, false)
+                                            .expect("Failed to store RouterInfo");
+                                    }
+                                    DatabaseStoreData::LS(ls) => {
+                                        self.netdb
+                                            .store_lease_set(ds.key, *ls)
+                                            .expect("Failed to store LeaseSet");
+                                    }
+                                },
                             },
                             MessagePayload::DatabaseSearchReply(dsr) => {
                                 if let Some(pending) = self
@@ -440,6 +448,7 @@ impl LocalNetworkDatabase {
             return Err(StoreError::InvalidKey);
         }
         ri.verify()?;
+        ri.validate()?;
         if ri
             .network_id()
             .map(|net_id| *net_id != *NET_ID)
@@ -453,6 +462,9 @@ impl LocalNetworkDatabase {
             router_info_is_current(&ri)?;
         }
 
+        // A fresh RouterInfo keeps our connection to the peer open
+        self.ctx.comms.read().unwrap().cancel_close(&key);
+
         // If anyone was waiting on this RouterInfo, notify them
         if let Some(pending) = self.pending_ri.remove(&key) {
             for p in pending {
@@ -484,15 +496,21 @@ impl LocalNetworkDatabase {
         let comms = ctx.as_ref().map(|ctx| ctx.comms.read().unwrap());
 
         let before = self.ri_ds.len();
-        self.ri_ds.retain(|_, ri| {
-            // Don't expire RIs for peers we are connected to.
+        self.ri_ds.retain(|hash, ri| {
+            let current = router_info_is_current(ri).is_ok();
+
+            // Don't expire RIs for peers we are connected to. If the peer may
+            // have changed, close the connection unless we hear from them.
             if let Some(comms) = comms.as_ref() {
-                if comms.is_established(&ri.router_id.hash()) {
+                if comms.is_established(hash) {
+                    if !current {
+                        comms.schedule_close(hash, Duration::from_secs(EXPIRED_PEER_GRACE));
+                    }
                     return true;
                 }
             }
 
-            router_info_is_current(ri).is_ok()
+            current
         });
         let expired = before - self.ri_ds.len();
         if expired > 0 {
@@ -520,8 +538,11 @@ mod tests {
         ROUTER_INFO_EXPIRATION,
     };
     use crate::crypto;
-    use crate::data::{Hash, I2PDate, RouterInfo, RouterSecretKeys, OPT_NET_ID};
-    use crate::router::mock::mock_context;
+    use crate::data::{
+        Hash, I2PDate, I2PString, RouterAddress, RouterInfo, RouterInfoError, RouterSecretKeys,
+        OPT_NET_ID,
+    };
+    use crate::router::mock::{mock_context, mock_context_and_comms};
 
     #[test]
     fn xor_metric() {
@@ -590,6 +611,19 @@ mod tests {
         ri.options.0.insert(OPT_NET_ID.clone(), old_netid);
         ri.sign(&rsk.signing_private_key);
 
+        // Storing a RouterInfo with an address that has already expired should
+        // fail
+        let mut expired =
+            RouterAddress::new(&I2PString::new("NTCP"), "127.0.0.1:0".parse().unwrap());
+        expired.set_expiration(ri.published);
+        let mut invalid_ri = ri.clone();
+        invalid_ri.set_addresses(vec![expired]);
+        invalid_ri.sign(&rsk.signing_private_key);
+        assert_eq!(
+            netdb.store_router_info(key.clone(), invalid_ri, false),
+            Err(StoreError::Invalid(RouterInfoError::AddressExpired(0)))
+        );
+
         // Storing the new RouterInfo should return no data
         assert_eq!(
             netdb.store_router_info(key.clone(), ri.clone(), false),
@@ -604,6 +638,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn expired_peer_connection_closes() {
+        let (ctx, comms) = mock_context_and_comms();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx.clone(), tx);
+
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid.clone());
+        ri.sign(&rsk.signing_private_key);
+        let key = ri.router_id.hash();
+        netdb
+            .store_router_info(key.clone(), ri.clone(), false)
+            .unwrap();
+        comms.read().unwrap().connect(key.clone());
+
+        // While the RouterInfo is current, the connection stays open
+        netdb.expire_router_infos(Some(ctx.clone()));
+        assert!(!comms.read().unwrap().is_closing(&key));
+
+        // Once it expires, the connection is scheduled for close, and we keep
+        // the RouterInfo while we are still connected
+        ri.published = I2PDate::from_system_time(
+            SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+        );
+        ri.sign(&rsk.signing_private_key);
+        netdb.ri_ds.insert(key.clone(), ri);
+        netdb.expire_router_infos(Some(ctx));
+        assert!(comms.read().unwrap().is_closing(&key));
+        assert_eq!(netdb.known_routers(), 1);
+
+        // A fresh RouterInfo cancels the close
+        let mut fresh = RouterInfo::new(rsk.rid);
+        fresh.sign(&rsk.signing_private_key);
+        netdb.store_router_info(key.clone(), fresh, false).unwrap();
+        assert!(!comms.read().unwrap().is_closing(&key));
+    }
+
     #[test]
     fn ri_expiry() {
         let rsk = RouterSecretKeys::new();
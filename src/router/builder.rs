@@ -8,7 +8,7 @@ use std::sync::{Arc, RwLock};
 use super::{types::CommSystem, Context, Distributor, Router};
 use crate::data::{ReadError, RouterInfo, RouterSecretKeys};
 use crate::netdb::{client::Client as NetDbClient, Engine as NetDbEngine};
-use crate::router::config;
+use crate::router::{config, profile::ProfileManager};
 use crate::transport;
 use crate::tunnel;
 
@@ -148,10 +148,9 @@ impl Builder {
 
         let comms = match self.comms {
             Some(comms) => comms,
-            None => Arc::new(RwLock::new(transport::Manager::from_config(
-                &settings,
-                distributor,
-            ))),
+            None => Arc::new(RwLock::new(
+                transport::Manager::from_config(&settings, distributor).map_err(ReadError::from)?,
+            )),
         };
 
         let tunnel_participant = Some(tunnel::Participant::new(
@@ -162,6 +161,7 @@ impl Builder {
 
         let mut ri = RouterInfo::new(keys.rid.clone());
         ri.set_addresses(comms.read().unwrap().addresses());
+        ri.set_reachability(comms.read().unwrap().reachability());
         ri.sign(&keys.signing_private_key);
 
         match settings.get_string(config::RI_FILE) {
@@ -173,12 +173,25 @@ impl Builder {
             Err(e) => panic!("{}", e),
         }
 
+        let (profiles, profiles_file) = match settings.get_string(config::PROFILES_FILE) {
+            Ok(profiles_file) => (ProfileManager::load(&profiles_file), Some(profiles_file)),
+            Err(ConfigError::NotFound(key)) => {
+                info!(
+                    "Config option {} not set, not keeping peer profiles across restarts",
+                    key
+                );
+                (ProfileManager::new(), None)
+            }
+            Err(e) => panic!("{}", e),
+        };
+
         let ctx = Arc::new(Context {
             config: RwLock::new(settings),
             keys,
             ri: Arc::new(RwLock::new(ri)),
             netdb: netdb_client,
             comms,
+            profiles,
         });
 
         let netdb_engine = Some(NetDbEngine::new(
@@ -197,6 +210,7 @@ impl Builder {
 
         Ok(Router {
             ctx,
+            profiles_file,
             netdb_engine,
             tunnel_listener,
             tunnel_participant,
@@ -3,11 +3,15 @@ pub use config::Config;
 // Router
 pub const ROUTER_KEYFILE: &str = "router.keyfile";
 pub const RI_FILE: &str = "router.infofile";
+pub const PROFILES_FILE: &str = "router.profilesfile";
 
 // Reseeding
 pub const RESEED_ENABLE: &str = "reseed.enable";
 
 // Transports
 pub const NTCP_LISTEN: &str = "transport.ntcp.listen";
+pub const NTCP_MAX_CLOCK_SKEW: &str = "transport.ntcp.max_clock_skew";
+pub const NTCP_UNREACHABLE: &str = "transport.ntcp.unreachable";
 pub const NTCP2_LISTEN: &str = "transport.ntcp2.listen";
 pub const NTCP2_KEYFILE: &str = "transport.ntcp2.keyfile";
+pub const NTCP2_UNREACHABLE: &str = "transport.ntcp2.unreachable";
@@ -5,14 +5,16 @@
 
 use config::Config;
 use futures::{future, sync::mpsc, Future};
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tokio::io;
 
 use super::types::{CommSystem, Distributor, DistributorResult};
-use crate::data::{Hash, RouterAddress, RouterInfo, RouterSecretKeys};
+use crate::data::{Hash, Reachability, RouterAddress, RouterInfo, RouterSecretKeys};
 use crate::i2np::Message;
 use crate::netdb::{client::Client as NetDbClient, mock::MockNetDb};
-use crate::router::Context;
+use crate::router::{profile::ProfileManager, Context};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
@@ -36,11 +38,25 @@ impl Distributor for MockDistributor {
     }
 }
 
-pub(super) struct MockCommSystem;
+#[derive(Default)]
+pub struct MockCommSystem {
+    established: Mutex<HashSet<Hash>>,
+    closing: Mutex<HashSet<Hash>>,
+}
 
 impl MockCommSystem {
-    pub(super) fn new() -> Self {
-        MockCommSystem {}
+    pub fn new() -> Self {
+        MockCommSystem::default()
+    }
+
+    /// Pretends that we have established a session with the given peer.
+    pub fn connect(&self, hash: Hash) {
+        self.established.lock().unwrap().insert(hash);
+    }
+
+    /// Returns true if the session with the given peer is scheduled to close.
+    pub fn is_closing(&self, hash: &Hash) -> bool {
+        self.closing.lock().unwrap().contains(hash)
     }
 }
 
@@ -49,12 +65,30 @@ impl CommSystem for MockCommSystem {
         vec![]
     }
 
+    fn reachability(&self) -> Reachability {
+        Reachability::Unreachable
+    }
+
     fn start(&mut self, _ctx: Arc<Context>) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         Box::new(future::ok(()))
     }
 
-    fn is_established(&self, _hash: &Hash) -> bool {
-        false
+    fn is_established(&self, hash: &Hash) -> bool {
+        self.established.lock().unwrap().contains(hash)
+    }
+
+    fn clock_skew(&self, _hash: &Hash) -> Option<i64> {
+        None
+    }
+
+    fn schedule_close(&self, hash: &Hash, _after: Duration) {
+        if self.is_established(hash) {
+            self.closing.lock().unwrap().insert(hash.clone());
+        }
+    }
+
+    fn cancel_close(&self, hash: &Hash) {
+        self.closing.lock().unwrap().remove(hash);
     }
 
     fn send(
@@ -67,18 +101,25 @@ impl CommSystem for MockCommSystem {
 }
 
 pub fn mock_context() -> Arc<Context> {
+    mock_context_and_comms().0
+}
+
+pub fn mock_context_and_comms() -> (Arc<Context>, Arc<RwLock<MockCommSystem>>) {
     let (tx, _) = mpsc::unbounded();
-    mock_context_with_netdb(NetDbClient::new(tx))
+    let comms = Arc::new(RwLock::new(MockCommSystem::new()));
+    let ctx = mock_context_with(NetDbClient::new(tx), comms.clone());
+    (ctx, comms)
 }
 
 pub fn mock_context_and_netdb() -> (Arc<Context>, MockNetDb) {
     let (client_tx, client_rx) = mpsc::unbounded();
-    let ctx = mock_context_with_netdb(NetDbClient::new(client_tx));
+    let comms = Arc::new(RwLock::new(MockCommSystem::new()));
+    let ctx = mock_context_with(NetDbClient::new(client_tx), comms);
     let netdb = MockNetDb::new(ctx.clone(), client_rx);
     (ctx, netdb)
 }
 
-fn mock_context_with_netdb(netdb: NetDbClient) -> Arc<Context> {
+fn mock_context_with(netdb: NetDbClient, comms: Arc<RwLock<MockCommSystem>>) -> Arc<Context> {
     let keys = RouterSecretKeys::new();
     let mut ri = RouterInfo::new(keys.rid.clone());
     ri.sign(&keys.signing_private_key);
@@ -88,6 +129,7 @@ fn mock_context_with_netdb(netdb: NetDbClient) -> Arc<Context> {
         keys,
         ri: Arc::new(RwLock::new(ri)),
         netdb,
-        comms: Arc::new(RwLock::new(MockCommSystem::new())),
+        comms,
+        profiles: ProfileManager::new(),
     })
 }
@@ -1,10 +1,11 @@
 use futures::{
     future::{self, lazy},
     sync::mpsc,
-    Future, Sink,
+    Future, Sink, Stream,
 };
 use std::sync::{Arc, RwLock};
-use tokio::{io, spawn};
+use std::time::{Duration, Instant};
+use tokio::{io, spawn, timer::Interval};
 
 use crate::data::{Hash, RouterInfo, RouterSecretKeys};
 use crate::i2np::{Message, MessagePayload};
@@ -16,10 +17,15 @@ type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 mod builder;
 pub mod config;
 pub mod mock;
+pub mod profile;
 pub mod types;
 
 pub use self::builder::Builder;
 use self::config::Config;
+use self::profile::ProfileManager;
+
+/// How often we write out peer profiles, if we keep them across restarts.
+const PROFILE_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 pub(crate) type DistributorTx = mpsc::Sender<(Hash, Message)>;
 
@@ -76,6 +82,8 @@ impl types::Distributor for Distributor {
 /// An I2P router.
 pub struct Router {
     ctx: Arc<Context>,
+    /// Where peer profiles are kept across restarts, if anywhere.
+    profiles_file: Option<String>,
     netdb_engine: Option<netdb::Engine>,
     tunnel_listener: Option<tunnel::Listener>,
     tunnel_participant: Option<tunnel::Participant>,
@@ -87,6 +95,7 @@ pub struct Context {
     pub ri: Arc<RwLock<RouterInfo>>,
     pub netdb: netdb::client::Client,
     pub comms: Arc<RwLock<dyn types::CommSystem>>,
+    pub profiles: ProfileManager,
 }
 
 impl Router {
@@ -119,6 +128,21 @@ impl Router {
             .take()
             .expect("Can only call start() once");
 
+        let profile_saver = self.profiles_file.clone().map(|path| {
+            let profiles = self.ctx.profiles.clone();
+            Interval::new(
+                Instant::now() + PROFILE_SAVE_INTERVAL,
+                PROFILE_SAVE_INTERVAL,
+            )
+            .map_err(|e| error!("Profile save timer error: {}", e))
+            .for_each(move |_| {
+                if let Err(e) = profiles.save(&path) {
+                    warn!("Could not write peer profiles to {}: {}", path, e);
+                }
+                Ok(())
+            })
+        });
+
         lazy(|| {
             // Start the transport system
             spawn(comms_engine);
@@ -132,6 +156,11 @@ impl Router {
             // Start network database operations
             spawn(netdb_engine);
 
+            // Periodically write out what we have learned about our peers
+            if let Some(profile_saver) = profile_saver {
+                spawn(profile_saver);
+            }
+
             Ok(())
         })
     }
@@ -154,4 +183,38 @@ impl Handle {
     ) -> Result<IoFuture<()>, (RouterInfo, Message)> {
         self.ctx.comms.read().unwrap().send(peer, msg)
     }
+
+    /// Returns the peer's clock minus ours, in seconds, if we have a session
+    /// with them and they have told us their time. Peers that are more than
+    /// a minute out can be dropped with [`types::CommSystem::schedule_close`].
+    pub fn clock_skew(&self, peer: &Hash) -> Option<i64> {
+        self.ctx.comms.read().unwrap().clock_skew(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Future, Stream};
+
+    use super::{types::Distributor as _, Distributor};
+    use crate::data::Hash;
+    use crate::i2np::{Message, MessagePayload};
+
+    #[test]
+    fn distribute_unknown_message() {
+        let (netdb_tx, netdb_rx) = mpsc::channel(1);
+        let (acceptor_tx, acceptor_rx) = mpsc::channel(1);
+        let (processor_tx, processor_rx) = mpsc::channel(1);
+        let distributor = Distributor::new(netdb_tx, acceptor_tx, processor_tx);
+
+        // An unhandled message type goes to the default handler
+        let msg = Message::from_payload(MessagePayload::Unknown(42, vec![1, 2, 3]));
+        assert!(distributor.handle(Hash([1; 32]), msg).wait().is_ok());
+
+        // Nothing was forwarded to the subsystems
+        drop(distributor);
+        assert!(netdb_rx.collect().wait().unwrap().is_empty());
+        assert!(acceptor_rx.collect().wait().unwrap().is_empty());
+        assert!(processor_rx.collect().wait().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,158 @@
+use cookie_factory::*;
+use nom::{
+    bytes::streaming::tag,
+    combinator::{map, verify},
+    multi::length_count,
+    number::streaming::{be_u32, be_u64, be_u8},
+    sequence::{preceded, tuple},
+    IResult,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{PeerProfile, PROFILES_VERSION};
+use crate::data::{
+    frame::{gen_hash, hash},
+    Hash,
+};
+
+// Average RTT
+
+// A presence flag, then the RTT in nanoseconds (zero if absent)
+fn avg_rtt(i: &[u8]) -> IResult<&[u8], Option<Duration>> {
+    map(
+        tuple((verify(be_u8, |present| *present <= 1), be_u64)),
+        |(present, nanos)| match present {
+            1 => Some(Duration::from_nanos(nanos)),
+            _ => None,
+        },
+    )(i)
+}
+
+fn gen_avg_rtt<'a>(
+    input: (&'a mut [u8], usize),
+    avg_rtt: &Option<Duration>,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    match avg_rtt {
+        Some(rtt) => do_gen!(input, gen_be_u8!(1) >> gen_be_u64!(rtt.as_nanos() as u64)),
+        None => do_gen!(input, gen_be_u8!(0) >> gen_be_u64!(0u64)),
+    }
+}
+
+// PeerProfile
+
+fn profile_record(i: &[u8]) -> IResult<&[u8], (Hash, PeerProfile)> {
+    map(
+        tuple((hash, be_u64, be_u64, be_u64, avg_rtt)),
+        |(peer, successes, failures, bytes, avg_rtt)| {
+            (
+                peer,
+                PeerProfile {
+                    successes,
+                    failures,
+                    avg_rtt,
+                    bytes,
+                },
+            )
+        },
+    )(i)
+}
+
+#[rustfmt::skip]
+fn gen_profile_record<'a>(
+    input: (&'a mut [u8], usize),
+    &(peer, profile): &(&Hash, &PeerProfile),
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_hash(peer) >>
+        gen_be_u64!(profile.successes) >>
+        gen_be_u64!(profile.failures) >>
+        gen_be_u64!(profile.bytes) >>
+        gen_avg_rtt(&profile.avg_rtt)
+    )
+}
+
+// Profiles file
+
+pub fn profiles(i: &[u8]) -> IResult<&[u8], HashMap<Hash, PeerProfile>> {
+    map(
+        preceded(
+            tag([PROFILES_VERSION]),
+            length_count(be_u32, profile_record),
+        ),
+        |records| records.into_iter().collect(),
+    )(i)
+}
+
+#[rustfmt::skip]
+pub fn gen_profiles<'a>(
+    input: (&'a mut [u8], usize),
+    profiles: &HashMap<Hash, PeerProfile>,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u8!(PROFILES_VERSION) >>
+        gen_be_u32!(profiles.len() as u32) >>
+        gen_many_ref!(profiles, gen_profile_record)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let mut single = HashMap::new();
+        single.insert(
+            Hash([0x11; 32]),
+            PeerProfile {
+                successes: 1,
+                failures: 2,
+                avg_rtt: Some(Duration::from_nanos(0x0102_0304)),
+                bytes: 0x0a0b,
+            },
+        );
+        let mut expected = vec![PROFILES_VERSION, 0, 0, 0, 1];
+        expected.extend_from_slice(&[0x11; 32]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x0a, 0x0b]);
+        expected.extend_from_slice(&[1, 0, 0, 0, 0, 0x01, 0x02, 0x03, 0x04]);
+
+        let mut buf = vec![0; expected.len()];
+        gen_profiles((&mut buf, 0), &single).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(profiles(&buf), Ok((&[][..], single)));
+
+        // Several peers, with and without an RTT
+        let mut several = HashMap::new();
+        for i in 1..=3u8 {
+            several.insert(
+                Hash([i; 32]),
+                PeerProfile {
+                    successes: u64::from(i),
+                    failures: 0,
+                    avg_rtt: if i % 2 == 0 {
+                        Some(Duration::from_millis(u64::from(i) * 100))
+                    } else {
+                        None
+                    },
+                    bytes: u64::from(i) << 40,
+                },
+            );
+        }
+        let buf = crate::util::serialize(|input| gen_profiles(input, &several));
+        assert_eq!(buf.len(), 1 + 4 + 3 * 65);
+        assert_eq!(profiles(&buf), Ok((&[][..], several)));
+
+        // An unknown version is refused
+        let mut buf = buf;
+        buf[0] = PROFILES_VERSION + 1;
+        assert!(profiles(&buf).is_err());
+    }
+}
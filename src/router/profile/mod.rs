@@ -0,0 +1,304 @@
+//! Peer profiles, built from observed behavior and used for peer selection.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::data::Hash;
+use crate::util::serialize;
+
+mod frame;
+
+/// The version of the format that [`ProfileManager::save`] writes. Files with
+/// any other version are not loaded.
+const PROFILES_VERSION: u8 = 1;
+
+/// Something we observed a peer doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileEvent {
+    /// We connected to the peer, with the given round-trip time.
+    Connected(Duration),
+    /// We failed to connect to the peer, or the session failed.
+    Failed,
+    /// We transferred the given number of bytes with the peer.
+    Transferred(usize),
+}
+
+/// What we know about how a peer has behaved.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerProfile {
+    successes: u64,
+    failures: u64,
+    avg_rtt: Option<Duration>,
+    bytes: u64,
+}
+
+impl PeerProfile {
+    pub fn new() -> Self {
+        PeerProfile::default()
+    }
+
+    /// Updates the profile with an observed event.
+    pub fn record(&mut self, event: ProfileEvent) {
+        match event {
+            ProfileEvent::Connected(rtt) => {
+                self.successes += 1;
+                self.avg_rtt = Some(match self.avg_rtt {
+                    // New samples have a weight of 1/4
+                    Some(avg) => (avg * 3 + rtt) / 4,
+                    None => rtt,
+                });
+            }
+            ProfileEvent::Failed => self.failures += 1,
+            ProfileEvent::Transferred(bytes) => self.bytes += bytes as u64,
+        }
+    }
+
+    /// Returns the moving average of the peer's round-trip time, if we have
+    /// measured it.
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        self.avg_rtt
+    }
+
+    /// Returns the fraction of our attempts with this peer that succeeded, or
+    /// zero if we have not tried yet.
+    pub fn success_rate(&self) -> f64 {
+        let attempts = self.successes + self.failures;
+        if attempts == 0 {
+            0.0
+        } else {
+            self.successes as f64 / attempts as f64
+        }
+    }
+
+    /// How quickly the peer responds. Higher is faster; zero if unknown.
+    ///
+    /// This is the number of round trips per second at the average RTT.
+    pub fn speed_score(&self) -> f64 {
+        match self.avg_rtt {
+            Some(rtt) if rtt > Duration::from_millis(0) => 1.0 / rtt.as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+
+    /// How much traffic the peer can reliably handle. Higher is better; zero if
+    /// the peer has never succeeded or carried any traffic.
+    ///
+    /// This grows logarithmically with the kilobytes transferred, scaled by
+    /// the success rate.
+    pub fn capacity_score(&self) -> f64 {
+        self.success_rate() * (1.0 + self.bytes as f64 / 1024.0).ln()
+    }
+}
+
+/// Tracks the profiles of all peers we have interacted with.
+#[derive(Clone, Default)]
+pub struct ProfileManager {
+    profiles: Arc<Mutex<HashMap<Hash, PeerProfile>>>,
+}
+
+impl ProfileManager {
+    pub fn new() -> Self {
+        ProfileManager::default()
+    }
+
+    /// Loads the profiles previously written out with [`ProfileManager::save`].
+    ///
+    /// If the file is missing or corrupt, we start fresh with no profiles.
+    pub fn load(path: &str) -> Self {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Could not read peer profiles from {}: {}", path, e);
+                }
+                return ProfileManager::new();
+            }
+        };
+
+        match frame::profiles(&data) {
+            Ok((rest, profiles)) if rest.is_empty() => ProfileManager {
+                profiles: Arc::new(Mutex::new(profiles)),
+            },
+            _ => {
+                warn!("Peer profiles in {} are corrupt, starting fresh", path);
+                ProfileManager::new()
+            }
+        }
+    }
+
+    /// Writes out the profiles of all peers, so they can be restored with
+    /// [`ProfileManager::load`] after a restart.
+    ///
+    /// The profiles are written to a temporary file that then replaces the
+    /// file at `path`, so a crash partway through leaves the old profiles.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let data = {
+            let profiles = self.profiles.lock().unwrap();
+            serialize(|input| frame::gen_profiles(input, &profiles))
+        };
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Updates the profile of the given peer with an observed event.
+    pub fn record(&self, peer: &Hash, event: ProfileEvent) {
+        self.profiles
+            .lock()
+            .unwrap()
+            .entry(peer.clone())
+            .or_default()
+            .record(event);
+    }
+
+    /// Returns a snapshot of the given peer's profile.
+    pub fn profile(&self, peer: &Hash) -> Option<PeerProfile> {
+        self.profiles.lock().unwrap().get(peer).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    use super::{PeerProfile, ProfileEvent, ProfileManager, PROFILES_VERSION};
+    use crate::data::Hash;
+
+    #[test]
+    fn speed_score() {
+        let mut profile = PeerProfile::new();
+        assert_eq!(profile.speed_score(), 0.0);
+
+        profile.record(ProfileEvent::Connected(Duration::from_millis(500)));
+        assert_eq!(profile.avg_rtt(), Some(Duration::from_millis(500)));
+        assert_eq!(profile.speed_score(), 2.0);
+
+        // Faster round trips increase the score
+        let before = profile.speed_score();
+        profile.record(ProfileEvent::Connected(Duration::from_millis(100)));
+        assert_eq!(profile.avg_rtt(), Some(Duration::from_millis(400)));
+        assert!(profile.speed_score() > before);
+
+        // Slower round trips decrease it
+        let before = profile.speed_score();
+        profile.record(ProfileEvent::Connected(Duration::from_millis(2000)));
+        assert!(profile.speed_score() < before);
+    }
+
+    #[test]
+    fn capacity_score() {
+        let mut profile = PeerProfile::new();
+        assert_eq!(profile.capacity_score(), 0.0);
+
+        profile.record(ProfileEvent::Connected(Duration::from_millis(100)));
+        assert_eq!(profile.capacity_score(), 0.0);
+
+        // Traffic increases the score
+        profile.record(ProfileEvent::Transferred(1024));
+        let before = profile.capacity_score();
+        assert!(before > 0.0);
+        profile.record(ProfileEvent::Transferred(10 * 1024));
+        assert!(profile.capacity_score() > before);
+
+        // Failures decrease it
+        let before = profile.capacity_score();
+        profile.record(ProfileEvent::Failed);
+        assert_eq!(profile.success_rate(), 0.5);
+        assert!(profile.capacity_score() < before);
+    }
+
+    #[test]
+    fn profile_manager() {
+        let manager = ProfileManager::new();
+        let alice = Hash([1; 32]);
+        let bob = Hash([2; 32]);
+        assert_eq!(manager.profile(&alice), None);
+
+        manager.record(&alice, ProfileEvent::Connected(Duration::from_millis(100)));
+        manager.record(&alice, ProfileEvent::Transferred(2048));
+        manager.record(&bob, ProfileEvent::Failed);
+
+        let alice_profile = manager.profile(&alice).unwrap();
+        let bob_profile = manager.profile(&bob).unwrap();
+        assert!(alice_profile.speed_score() > bob_profile.speed_score());
+        assert!(alice_profile.capacity_score() > bob_profile.capacity_score());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("profiles.dat");
+        let path = path.to_str().unwrap();
+
+        let manager = ProfileManager::new();
+        let peers: Vec<_> = (1..=4).map(|i| Hash([i; 32])).collect();
+        manager.record(
+            &peers[0],
+            ProfileEvent::Connected(Duration::from_millis(100)),
+        );
+        manager.record(&peers[0], ProfileEvent::Transferred(2048));
+        manager.record(&peers[1], ProfileEvent::Failed);
+        manager.record(
+            &peers[2],
+            ProfileEvent::Connected(Duration::from_micros(12345)),
+        );
+        manager.record(&peers[2], ProfileEvent::Failed);
+        manager.record(&peers[3], ProfileEvent::Transferred(100));
+        manager.save(path).unwrap();
+        assert!(!dir.path().join("profiles.dat.tmp").exists());
+
+        let loaded = ProfileManager::load(path);
+        for peer in &peers {
+            let before = manager.profile(peer).unwrap();
+            let after = loaded.profile(peer).unwrap();
+            assert_eq!(after, before);
+            assert_eq!(after.speed_score(), before.speed_score());
+            assert_eq!(after.capacity_score(), before.capacity_score());
+        }
+        assert_eq!(loaded.profile(&Hash([5; 32])), None);
+    }
+
+    #[test]
+    fn load_missing_or_corrupt() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("profiles.dat");
+        let path = path.to_str().unwrap();
+        let peer = Hash([1; 32]);
+
+        // Missing file
+        assert_eq!(ProfileManager::load(path).profile(&peer), None);
+
+        let manager = ProfileManager::new();
+        manager.record(&peer, ProfileEvent::Failed);
+        manager.save(path).unwrap();
+        let data = std::fs::read(path).unwrap();
+        assert_eq!(data[0], PROFILES_VERSION);
+        assert!(ProfileManager::load(path).profile(&peer).is_some());
+
+        // Truncated record
+        std::fs::write(path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(ProfileManager::load(path).profile(&peer), None);
+
+        // Trailing data
+        let mut extended = data.clone();
+        extended.push(0);
+        std::fs::write(path, &extended).unwrap();
+        assert_eq!(ProfileManager::load(path).profile(&peer), None);
+
+        // Invalid RTT flag, after the version, count, hash and counters
+        let mut invalid = data.clone();
+        invalid[1 + 4 + 32 + 24] = 2;
+        std::fs::write(path, &invalid).unwrap();
+        assert_eq!(ProfileManager::load(path).profile(&peer), None);
+
+        // Unknown version
+        let mut future = data;
+        future[0] = PROFILES_VERSION + 1;
+        std::fs::write(path, &future).unwrap();
+        assert_eq!(ProfileManager::load(path).profile(&peer), None);
+    }
+}
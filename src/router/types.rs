@@ -2,10 +2,11 @@
 
 use futures::{sync::mpsc, Future};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io;
 
 use super::Context;
-use crate::data::{Hash, RouterAddress, RouterInfo};
+use crate::data::{Hash, Reachability, RouterAddress, RouterInfo};
 use crate::i2np::Message;
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
@@ -23,6 +24,9 @@ pub trait CommSystem: Send + Sync {
     /// Returns the addresses of the underlying transports.
     fn addresses(&self) -> Vec<RouterAddress>;
 
+    /// Returns whether peers can connect to us, to advertise in our caps.
+    fn reachability(&self) -> Reachability;
+
     /// Start the comm system.
     ///
     /// This returns a Future that must be polled in order to drive network
@@ -32,6 +36,17 @@ pub trait CommSystem: Send + Sync {
     /// Returns true if there is an open session with the given peer.
     fn is_established(&self, hash: &Hash) -> bool;
 
+    /// Returns the peer's clock minus ours, in seconds, as last measured on
+    /// our session with them.
+    fn clock_skew(&self, hash: &Hash) -> Option<i64>;
+
+    /// Gracefully closes any session with the given peer once `after` has
+    /// passed, unless [`CommSystem::cancel_close`] is called first.
+    fn schedule_close(&self, hash: &Hash, after: Duration);
+
+    /// Cancels a close scheduled with [`CommSystem::schedule_close`].
+    fn cancel_close(&self, hash: &Hash);
+
     /// Send an I2NP message to a peer.
     ///
     /// Returns an Err giving back the message if it cannot be sent.
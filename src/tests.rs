@@ -3,3 +3,77 @@ pub const RI_SIGTYPE_1: &[u8; 746] = include_bytes!("../assets/sigType-1.router.
 pub const RI_SIGTYPE_2: &[u8; 778] = include_bytes!("../assets/sigType-2.router.info");
 
 pub const I2PSEEDS_SU3: &[u8; 71025] = include_bytes!("../assets/i2pseeds.su3");
+
+/// The bytes of an NTCP handshake between two routers with fixed keys and
+/// clocks, in each direction.
+pub const NTCP_HANDSHAKE_ALICE_TO_BOB: &[u8; 752] =
+    include_bytes!("../assets/ntcp-handshake-alice-to-bob.bin");
+pub const NTCP_HANDSHAKE_BOB_TO_ALICE: &[u8; 368] =
+    include_bytes!("../assets/ntcp-handshake-bob-to-alice.bin");
+
+/// A SessionRequest to the router in `ROUTER_INFO`, with X = 0..256. This was
+/// generated outside the crate with Python's hashlib, following the NTCP
+/// specification: `X || (SHA256(X) ^ SHA256(router.info[:391]))`.
+pub const NTCP_SESSION_REQUEST: &[u8; 288] = include_bytes!("../assets/ntcp-session-request.bin");
+
+/// Asserts that two byte strings are equal.
+///
+/// On mismatch, this reports the first differing offset and a hex dump of the
+/// bytes around it, which is easier to read than a full `Debug` comparison.
+pub fn assert_bytes_eq(expected: &[u8], actual: &[u8]) {
+    if expected == actual {
+        return;
+    }
+
+    let offset = expected
+        .iter()
+        .zip(actual)
+        .position(|(e, a)| e != a)
+        .unwrap_or_else(|| expected.len().min(actual.len()));
+
+    // Show a row either side of the row containing the first difference
+    let start = (offset / 16).saturating_sub(1) * 16;
+    let end = start + 48;
+    pretty_assertions::assert_eq!(
+        hex_window(expected, start, end),
+        hex_window(actual, start, end),
+        "bytes differ at offset {} (expected {} bytes, got {})",
+        offset,
+        expected.len(),
+        actual.len()
+    );
+}
+
+fn hex_window(bytes: &[u8], start: usize, end: usize) -> String {
+    let end = end.min(bytes.len());
+    if start >= end {
+        return String::new();
+    }
+
+    bytes[start..end]
+        .chunks(16)
+        .enumerate()
+        .map(|(i, row)| {
+            let hex: Vec<_> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{:08x}: {}\n", start + i * 16, hex.join(" "))
+        })
+        .collect()
+}
+
+#[test]
+fn bytes_eq() {
+    assert_bytes_eq(&[1, 2, 3], &[1, 2, 3]);
+    assert_eq!(
+        hex_window(&[0; 20], 0, 48),
+        "00000000: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+         00000010: 00 00 00 00\n"
+    );
+}
+
+#[test]
+#[should_panic(expected = "bytes differ at offset 17 (expected 20 bytes, got 20)")]
+fn bytes_ne() {
+    let mut actual = [0; 20];
+    actual[17] = 1;
+    assert_bytes_eq(&[0; 20], &actual);
+}
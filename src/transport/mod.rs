@@ -1,25 +1,73 @@
 //! Transports used for point-to-point communication between I2P routers.
 
-use futures::{future::lazy, Future, Poll, Sink, StartSend};
+use futures::{
+    future::{self, lazy},
+    AsyncSink, Future, Poll, Sink, StartSend,
+};
+use rand::{thread_rng, Rng};
 use std::iter::once;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::{executor::spawn, io};
 
 use crate::crypto::dh::DHSessionKeyBuilder;
-use crate::data::{Hash, RouterAddress, RouterInfo};
-use crate::i2np::Message;
+use crate::data::{Hash, Reachability, RouterAddress, RouterInfo};
+use crate::i2np::{HeaderFormat, Message};
 use crate::router::{
     config,
     types::{CommSystem, Distributor},
     Context,
 };
+use crate::util::{Expirable, ExpirySweeper, LogLimiter};
 
 pub mod ntcp;
 pub mod ntcp2;
 mod session;
 
+/// How many peers or IPs we rate-limit connection error logging for at once.
+const LOG_LIMITER_MAX_KEYS: usize = 1024;
+
+lazy_static! {
+    /// Limits logging of connection errors for each peer.
+    static ref PEER_ERRORS: LogLimiter<Hash> =
+        LogLimiter::new(Duration::from_secs(60), LOG_LIMITER_MAX_KEYS);
+    /// Limits logging of inbound connection errors for each remote IP.
+    static ref IP_ERRORS: LogLimiter<IpAddr> =
+        LogLimiter::new(Duration::from_secs(60), LOG_LIMITER_MAX_KEYS);
+}
+
+/// How often we sweep expired entries out of the registered caches, by default.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
+/// Describes a failure to bind a transport's listener.
+fn bind_error(style: &str, addr: &SocketAddr, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("Could not bind {} listener to {}: {}", style, addr, e),
+    )
+}
+
+/// Returns the time from `earlier` to `now`.
+///
+/// If the system clock has stepped backwards in between, this returns an error
+/// so that only the connection relying on it fails, instead of the router.
+fn clock_elapsed(earlier: SystemTime, now: SystemTime) -> io::Result<Duration> {
+    now.duration_since(earlier)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "system clock moved backwards"))
+}
+
+/// Returns the time since the UNIX epoch, or an error if the system clock is
+/// set before it.
+fn unix_time(now: SystemTime) -> io::Result<Duration> {
+    clock_elapsed(UNIX_EPOCH, now)
+}
+
 /// A bid from a transport indicating how much it thinks it will "cost" to
 /// send a particular message.
 struct Bid {
@@ -43,21 +91,203 @@ impl Sink for Bid {
     }
 }
 
+/// How to choose between transports that return equal bids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Always pick the first transport, in the order NTCP, NTCP2.
+    Deterministic,
+    /// Pick one of the tied transports at random, spreading load across them.
+    Random,
+}
+
+impl Default for TieBreak {
+    fn default() -> Self {
+        TieBreak::Deterministic
+    }
+}
+
+/// Picks the lowest bid, breaking ties according to the given policy.
+fn select_bid<I: Iterator<Item = Bid>>(bids: I, tie_break: TieBreak) -> Option<Bid> {
+    match tie_break {
+        TieBreak::Deterministic => bids.min_by_key(|b| b.bid),
+        TieBreak::Random => {
+            let mut bids: Vec<_> = bids.collect();
+            let lowest = bids.iter().map(|b| b.bid).min()?;
+            bids.retain(|b| b.bid == lowest);
+            let i = thread_rng().gen_range(0..bids.len());
+            Some(bids.swap_remove(i))
+        }
+    }
+}
+
+/// The transports we support.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportStyle {
+    Ntcp,
+    Ntcp2,
+}
+
+/// The transport protocol, and the version of it, in use on a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransportVersion {
+    pub style: TransportStyle,
+    pub version: u8,
+}
+
+/// Which side opened a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// The peer connected to us.
+    Inbound,
+    /// We connected to the peer.
+    Outbound,
+}
+
+/// A snapshot of an established connection, for diagnostics.
+#[derive(Clone, Debug)]
+pub struct PeerDiagnostics {
+    /// The peer's identity hash, in base32.
+    pub peer: String,
+    pub style: TransportStyle,
+    pub direction: ConnectionDirection,
+    pub established: SystemTime,
+    /// The round-trip time measured during the handshake, if known.
+    pub rtt: Option<Duration>,
+    /// The total size of the I2NP messages received from the peer.
+    pub bytes_in: u64,
+    /// The total size of the I2NP messages handed to the connection for the
+    /// peer.
+    pub bytes_out: u64,
+    /// The number of frames waiting to be written to the connection.
+    pub queue_depth: usize,
+    /// The peer's clock minus ours, in seconds, if the peer has told us its
+    /// time.
+    pub clock_skew: Option<i64>,
+}
+
+/// How many connections a transport is holding, for monitoring.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Connections that are established.
+    pub established: usize,
+    /// Established connections that have had nothing sent on them recently.
+    pub idle: usize,
+    /// Connections that are still being opened.
+    pub pending: usize,
+}
+
+/// Counters for the traffic and handshakes on a transport, for monitoring.
+#[derive(Debug, Default)]
+pub struct TransportStats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    handshakes: AtomicU64,
+}
+
+/// The values of a transport's counters at a point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransportStatsSnapshot {
+    /// I2NP message bytes received.
+    pub bytes_in: u64,
+    /// I2NP message bytes sent.
+    pub bytes_out: u64,
+    /// Handshakes that completed, establishing a session.
+    pub handshakes: u64,
+}
+
+impl TransportStats {
+    fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn handshake_completed(&self) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> TransportStatsSnapshot {
+        TransportStatsSnapshot {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            handshakes: self.handshakes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the current values of the counters, and sets them to zero.
+    ///
+    /// Each counter is swapped atomically, so every update is counted in
+    /// exactly one snapshot, even while sessions are running.
+    pub fn snapshot_and_reset(&self) -> TransportStatsSnapshot {
+        TransportStatsSnapshot {
+            bytes_in: self.bytes_in.swap(0, Ordering::Relaxed),
+            bytes_out: self.bytes_out.swap(0, Ordering::Relaxed),
+            handshakes: self.handshakes.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// A hook on the outgoing path, given each message and the hash of the peer it
+/// is for before we pick a transport. It returns the message to send in its
+/// place, or `None` to drop it.
+pub type OutgoingMiddleware = Box<dyn Fn(Hash, Message) -> Option<(Hash, Message)> + Send + Sync>;
+
+/// Moves the messages queued for a peer on one transport onto another.
+struct Handover {
+    peer: RouterInfo,
+    retire: Box<dyn FnOnce() -> Vec<Message> + Send>,
+    sink: Box<dyn Sink<SinkItem = (RouterInfo, Message), SinkError = io::Error> + Send>,
+}
+
+impl Handover {
+    /// Retires the old session and queues its pending messages on the new
+    /// transport. Returns the number of messages that were moved.
+    fn run(mut self) -> io::Result<usize> {
+        let msgs = (self.retire)();
+        let moved = msgs.len();
+        for msg in msgs {
+            // Transport sinks are backed by unbounded channels
+            if let AsyncSink::NotReady(_) = self.sink.start_send((self.peer.clone(), msg))? {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Transport refused migrated message",
+                ));
+            }
+        }
+        Ok(moved)
+    }
+}
+
 /// Coordinates the sending and receiving of frames over the various supported
 /// transports.
 pub struct Manager<D: Distributor> {
     ntcp: ntcp::Manager<D>,
     ntcp2: ntcp2::Manager<D>,
+    tie_break: TieBreak,
+    ctx: Option<Arc<Context>>,
+    middlewares: Vec<OutgoingMiddleware>,
+    sweeper: ExpirySweeper,
 }
 
 trait Transport {
     fn is_established(&self, hash: &Hash) -> bool;
 
+    /// Returns the size of the largest I2NP message we can send in one frame.
+    fn mtu(&self) -> usize;
+
     fn bid(&self, peer: &RouterInfo, msg_size: usize) -> Option<Bid>;
 }
 
 impl<D: Distributor> Manager<D> {
-    pub fn from_config(config: &config::Config, distributor: D) -> Self {
+    /// Creates the transports from the given config.
+    ///
+    /// If the NTCP2 keyfile does not exist, new keys are generated and written
+    /// to it. If it exists but cannot be read, an error is returned and the
+    /// file is left untouched.
+    pub fn from_config(config: &config::Config, distributor: D) -> io::Result<Self> {
         let ntcp_addr = config
             .get_string(config::NTCP_LISTEN)
             .expect("Must configure an NTCP address")
@@ -70,46 +300,282 @@ impl<D: Distributor> Manager<D> {
             .unwrap();
         let ntcp2_keyfile = config.get_string(config::NTCP2_KEYFILE).unwrap();
 
-        let ntcp_manager = ntcp::Manager::new(ntcp_addr, distributor.clone());
-        let ntcp2_manager =
+        let mut ntcp_manager = ntcp::Manager::new(ntcp_addr, distributor.clone());
+        if let Ok(max_skew) = config.get_int(config::NTCP_MAX_CLOCK_SKEW) {
+            ntcp_manager.set_max_clock_skew(Duration::from_secs(max_skew as u64));
+        }
+        if let Ok(true) = config.get_bool(config::NTCP_UNREACHABLE) {
+            ntcp_manager.set_reachability(Reachability::Unreachable);
+        }
+        let mut ntcp2_manager =
             match ntcp2::Manager::from_file(ntcp2_addr, &ntcp2_keyfile, distributor.clone()) {
                 Ok(ret) => ret,
-                Err(_) => {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    info!("Writing new NTCP2 keys to {}", ntcp2_keyfile);
                     let ntcp2_manager = ntcp2::Manager::new(ntcp2_addr, distributor);
-                    ntcp2_manager.to_file(&ntcp2_keyfile).unwrap();
+                    ntcp2_manager.to_file(&ntcp2_keyfile)?;
                     ntcp2_manager
                 }
+                Err(e) => return Err(e),
             };
+        if let Ok(true) = config.get_bool(config::NTCP2_UNREACHABLE) {
+            ntcp2_manager.set_reachability(Reachability::Unreachable);
+        }
+        Ok(Manager::with_transports(ntcp_manager, ntcp2_manager))
+    }
+
+    fn with_transports(ntcp: ntcp::Manager<D>, ntcp2: ntcp2::Manager<D>) -> Self {
+        let sweeper = ExpirySweeper::new(SWEEP_INTERVAL);
+        let peer_errors: &'static LogLimiter<Hash> = &PEER_ERRORS;
+        let ip_errors: &'static LogLimiter<IpAddr> = &IP_ERRORS;
+        sweeper.register(Arc::new(Mutex::new(peer_errors)));
+        sweeper.register(Arc::new(Mutex::new(ip_errors)));
         Manager {
-            ntcp: ntcp_manager,
-            ntcp2: ntcp2_manager,
+            ntcp,
+            ntcp2,
+            tie_break: TieBreak::default(),
+            ctx: None,
+            middlewares: vec![],
+            sweeper,
+        }
+    }
+
+    /// Sets how to choose between transports that bid equally for a message.
+    pub fn set_tie_break(&mut self, tie_break: TieBreak) {
+        self.tie_break = tie_break;
+    }
+
+    /// Adds a middleware to the outgoing path. Middlewares are run in the order
+    /// they were added, until one drops the message.
+    ///
+    /// Middlewares can transform messages, but not redirect them: a message
+    /// returned with a different hash than it was given is dropped.
+    pub fn add_outgoing_middleware(&mut self, middleware: OutgoingMiddleware) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Registers a cache to have its expired entries swept out periodically,
+    /// once the transports have started.
+    pub fn register_expirable(&self, cache: Arc<Mutex<dyn Expirable>>) {
+        self.sweeper.register(cache);
+    }
+
+    /// Sets how often registered caches are swept. Must be called before the
+    /// transports are started.
+    pub fn set_sweep_interval(&mut self, interval: Duration) {
+        self.sweeper.set_interval(interval);
+    }
+
+    /// Sets the clock the sweeper uses to decide which entries of the
+    /// registered caches have expired. Nothing else in the transports reads
+    /// this clock.
+    pub fn set_sweeper_clock(&mut self, clock: fn() -> SystemTime) {
+        self.sweeper.set_clock(clock);
+    }
+
+    /// Runs the outgoing middlewares over a message for the given peer.
+    fn apply_middlewares(&self, peer: &Hash, msg: Message) -> Option<Message> {
+        self.middlewares.iter().try_fold(msg, |msg, middleware| {
+            match middleware(peer.clone(), msg) {
+                Some((hash, msg)) if hash == *peer => Some(msg),
+                Some(_) => {
+                    warn!("Outgoing middleware tried to redirect a message, dropping it");
+                    None
+                }
+                None => None,
+            }
+        })
+    }
+
+    /// Replaces the RouterInfo we publish, for example after our addresses or
+    /// caps change.
+    ///
+    /// This is the RouterInfo shared through the router context, so that
+    /// connections we open afterwards and the netdb see the new info. The swap
+    /// is atomic: readers see either the old info or the new one.
+    ///
+    /// The RouterInfo must be for our own identity, and signed by us. It can
+    /// only be replaced once the transports have started.
+    pub fn set_published_info(&mut self, ri: RouterInfo) -> io::Result<()> {
+        let ctx = match self.ctx {
+            Some(ref ctx) => ctx,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Transports have not started",
+                ))
+            }
+        };
+        if ri.router_id.hash() != ctx.keys.rid.hash() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "RouterInfo is for another router",
+            ));
+        }
+        if let Err(e) = ri.verify() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid RouterInfo signature: {}", e),
+            ));
+        }
+        *ctx.ri.write().unwrap() = ri;
+        Ok(())
+    }
+
+    /// Returns a snapshot of the RouterInfo we publish, once the transports
+    /// have started.
+    pub fn published_info(&self) -> Option<RouterInfo> {
+        self.ctx.as_ref().map(|ctx| ctx.ri.read().unwrap().clone())
+    }
+
+    /// Returns the size of the largest I2NP message that the given transport
+    /// can carry, after its framing overhead.
+    pub fn transport_mtu(&self, style: TransportStyle) -> usize {
+        match style {
+            TransportStyle::Ntcp => self.ntcp.mtu(),
+            TransportStyle::Ntcp2 => self.ntcp2.mtu(),
+        }
+    }
+
+    /// Returns a snapshot of each connection we have established, over all
+    /// transports.
+    pub fn diagnostics(&self) -> Vec<PeerDiagnostics> {
+        let mut diagnostics = self.ntcp.diagnostics();
+        diagnostics.extend(self.ntcp2.diagnostics());
+        diagnostics
+    }
+
+    /// Sends a copy of an I2NP message to each of the given peers, for example
+    /// when flooding a netdb store.
+    ///
+    /// Each copy is bid for separately, so peers we are already connected to
+    /// reuse their existing sessions. Returns one result per peer, in order;
+    /// an Err gives back the copy that no transport would send.
+    pub fn send_fanout(
+        &self,
+        peers: &[RouterInfo],
+        msg: Message,
+    ) -> Vec<Result<IoFuture<()>, (Hash, Message)>> {
+        // Message is not Clone, so each copy is parsed from a single
+        // serialization of it.
+        let buf = msg.to_bytes();
+        peers
+            .iter()
+            .map(|peer| {
+                let msg = Message::parse(&buf, HeaderFormat::Standard)
+                    .expect("Could not re-parse a serialized message");
+                self.send(peer.clone(), msg)
+                    .map_err(|(peer, msg)| (peer.router_id.hash(), msg))
+            })
+            .collect()
+    }
+
+    /// Migrates a peer we are connected to onto a different transport.
+    ///
+    /// The new session is established first. The old session is then retired:
+    /// any messages still waiting for it are moved to the new transport, and
+    /// it closes once it has written the messages already queued on it.
+    pub fn migrate_peer(&self, peer: RouterInfo, to: TransportStyle) -> io::Result<IoFuture<()>> {
+        let hash = peer.router_id.hash();
+        let handover = self.handover(&peer, to);
+
+        let connect: IoFuture<()> = match to {
+            TransportStyle::Ntcp if !self.ntcp.is_established(&hash) => {
+                Box::new(self.ntcp.connect_to(peer)?)
+            }
+            TransportStyle::Ntcp2 if !self.ntcp2.is_established(&hash) => {
+                Box::new(self.ntcp2.connect_to(peer)?)
+            }
+            _ => Box::new(future::ok(())),
+        };
+
+        Ok(Box::new(connect.and_then(move |_| {
+            let moved = handover.run()?;
+            debug!("Migrated {} pending messages to {:?}", moved, to);
+            Ok(())
+        })))
+    }
+
+    fn handover(&self, peer: &RouterInfo, to: TransportStyle) -> Handover {
+        let hash = peer.router_id.hash();
+        match to {
+            TransportStyle::Ntcp => Handover {
+                peer: peer.clone(),
+                retire: Box::new(self.ntcp2.retirer(hash)),
+                sink: Box::new(self.ntcp.sink()),
+            },
+            TransportStyle::Ntcp2 => Handover {
+                peer: peer.clone(),
+                retire: Box::new(self.ntcp.retirer(hash)),
+                sink: Box::new(self.ntcp2.sink()),
+            },
         }
     }
 }
 
 impl<D: Distributor> CommSystem for Manager<D> {
+    /// Returns the addresses in our published RouterInfo, or the addresses of
+    /// our reachable transports if we have not published one yet.
     fn addresses(&self) -> Vec<RouterAddress> {
-        vec![self.ntcp.address(), self.ntcp2.address()]
+        match self.ctx {
+            Some(ref ctx) => ctx.ri.read().unwrap().addresses().to_vec(),
+            None => {
+                let mut addrs = vec![];
+                if self.ntcp.reachability() == Reachability::Reachable {
+                    addrs.push(self.ntcp.address());
+                }
+                if self.ntcp2.reachability() == Reachability::Reachable {
+                    addrs.push(self.ntcp2.address());
+                }
+                addrs
+            }
+        }
+    }
+
+    /// We are reachable if peers can connect to us over any transport.
+    fn reachability(&self) -> Reachability {
+        if self.ntcp.reachability() == Reachability::Reachable
+            || self.ntcp2.reachability() == Reachability::Reachable
+        {
+            Reachability::Reachable
+        } else {
+            Reachability::Unreachable
+        }
     }
 
     fn start(&mut self, ctx: Arc<Context>) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        // Publish through the RouterInfo shared with the rest of the router
+        self.ctx = Some(ctx.clone());
+
         self.ntcp.set_context(ctx.clone());
         self.ntcp2.set_context(ctx.clone());
 
-        let listener = self
+        // Bind both listeners before we start either of them
+        let listeners = self
             .ntcp
             .listen(ctx.keys.rid.clone(), ctx.keys.signing_private_key.clone())
-            .map_err(|e| {
-                error!("NTCP listener error: {}", e);
-            });
-
-        let listener2 = self.ntcp2.listen(&ctx.keys.rid).map_err(|e| {
+            .and_then(|listener| Ok((listener, self.ntcp2.listen(&ctx.keys.rid)?)));
+        let (listener, listener2) = match listeners {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                error!("{}", e);
+                return Box::new(future::err(()));
+            }
+        };
+        let listener = listener.map_err(|e| {
+            error!("NTCP listener error: {}", e);
+        });
+        let listener2 = listener2.map_err(|e| {
             error!("NTCP2 listener error: {}", e);
         });
+        let sweeper = self.sweeper.run();
+        let sessions = self.ntcp.sweeper().join(self.ntcp2.sweeper()).map(|_| ());
 
         Box::new(lazy(|| {
             spawn(listener);
             spawn(listener2);
+            spawn(sweeper);
+            spawn(sessions);
             Ok(())
         }))
     }
@@ -118,16 +584,38 @@ impl<D: Distributor> CommSystem for Manager<D> {
         self.ntcp.is_established(hash) || self.ntcp2.is_established(hash)
     }
 
+    fn clock_skew(&self, hash: &Hash) -> Option<i64> {
+        self.ntcp
+            .clock_skew(hash)
+            .or_else(|| self.ntcp2.clock_skew(hash))
+    }
+
+    fn schedule_close(&self, hash: &Hash, after: Duration) {
+        self.ntcp.schedule_close(hash, after);
+        self.ntcp2.schedule_close(hash, after);
+    }
+
+    fn cancel_close(&self, hash: &Hash) {
+        self.ntcp.cancel_close(hash);
+        self.ntcp2.cancel_close(hash);
+    }
+
     /// Send an I2NP message to a peer over one of our transports.
     ///
     /// Returns an Err giving back the message if it cannot be sent over any of
     /// our transports.
+    ///
+    /// Messages dropped by an outgoing middleware are treated as sent.
     fn send(&self, peer: RouterInfo, msg: Message) -> Result<IoFuture<()>, (RouterInfo, Message)> {
-        match once(self.ntcp.bid(&peer, msg.size()))
+        let msg = match self.apply_middlewares(&peer.router_id.hash(), msg) {
+            Some(msg) => msg,
+            None => return Ok(Box::new(future::ok(()))),
+        };
+
+        let bids = once(self.ntcp.bid(&peer, msg.size()))
             .chain(once(self.ntcp2.bid(&peer, msg.ntcp2_size())))
-            .flatten()
-            .min_by_key(|b| b.bid)
-        {
+            .flatten();
+        match select_bid(bids, self.tie_break) {
             Some(bid) => Ok(Box::new(bid.send((peer, msg)).map(|_| ()).map_err(|_| {
                 io::Error::new(io::ErrorKind::Other, "Error in transport::Engine")
             }))),
@@ -139,17 +627,50 @@ impl<D: Distributor> CommSystem for Manager<D> {
 #[cfg(test)]
 mod tests {
     use futures::Async;
-    use std::net::SocketAddr;
+    use rand::Rng;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
+    use std::time::Instant;
     use tempfile::tempdir;
     use tokio::io::{self, AsyncRead, AsyncWrite, Read, Write};
 
     use super::*;
+    use crate::data::{I2PDate, RouterSecretKeys};
     use crate::router::mock::MockDistributor;
 
+    /// Network conditions that a cable applies to everything written to it.
+    struct Conditions {
+        latency: Duration,
+        loss: f64,
+    }
+
+    /// Writes to one end of a cable that have not yet reached the other end,
+    /// with the time at which they will arrive.
+    #[derive(Default)]
+    struct InFlight(VecDeque<(Instant, Vec<u8>)>);
+
+    impl InFlight {
+        /// Moves the writes that have arrived by `now` into `arrived`, dropping
+        /// each one with probability `loss`.
+        fn deliver(&mut self, now: Instant, loss: f64, arrived: &mut Vec<u8>) {
+            let mut rng = rand::thread_rng();
+            while self.0.front().map_or(false, |(at, _)| *at <= now) {
+                let (_, data) = self.0.pop_front().unwrap();
+                if !rng.gen_bool(loss) {
+                    arrived.extend_from_slice(&data);
+                }
+            }
+        }
+    }
+
     pub struct NetworkCable {
-        alice_to_bob: Vec<u8>,
-        bob_to_alice: Vec<u8>,
+        pub alice_to_bob: Vec<u8>,
+        pub bob_to_alice: Vec<u8>,
+        conditions: Option<Conditions>,
+        to_bob: InFlight,
+        to_alice: InFlight,
+        closed: bool,
     }
 
     impl NetworkCable {
@@ -157,8 +678,56 @@ mod tests {
             Arc::new(Mutex::new(NetworkCable {
                 alice_to_bob: Vec::new(),
                 bob_to_alice: Vec::new(),
+                conditions: None,
+                to_bob: InFlight::default(),
+                to_alice: InFlight::default(),
+                closed: false,
             }))
         }
+
+        /// Creates a cable that delays every write by `latency`, and then drops
+        /// it with probability `loss` when the other end reads.
+        pub fn with_conditions(latency: Duration, loss: f64) -> Arc<Mutex<Self>> {
+            assert!((0.0..=1.0).contains(&loss), "loss must be a probability");
+            let cable = NetworkCable::new();
+            cable.lock().unwrap().conditions = Some(Conditions { latency, loss });
+            cable
+        }
+
+        fn alice_sends(&mut self, buf: &[u8]) {
+            match self.conditions {
+                Some(ref c) => {
+                    let at = Instant::now() + c.latency;
+                    self.to_bob.0.push_back((at, buf.to_vec()));
+                }
+                None => self.alice_to_bob.extend_from_slice(buf),
+            }
+        }
+
+        fn bob_sends(&mut self, buf: &[u8]) {
+            match self.conditions {
+                Some(ref c) => {
+                    let at = Instant::now() + c.latency;
+                    self.to_alice.0.push_back((at, buf.to_vec()));
+                }
+                None => self.bob_to_alice.extend_from_slice(buf),
+            }
+        }
+
+        /// Closes the cable. Each end reads EOF once it has read everything
+        /// that was sent before the close.
+        pub fn close(&mut self) {
+            self.closed = true;
+        }
+
+        /// Moves everything that has crossed the cable into the read buffers.
+        fn deliver(&mut self) {
+            if let Some(ref c) = self.conditions {
+                let now = Instant::now();
+                self.to_bob.deliver(now, c.loss, &mut self.alice_to_bob);
+                self.to_alice.deliver(now, c.loss, &mut self.bob_to_alice);
+            }
+        }
     }
 
     pub struct AliceNet {
@@ -174,9 +743,12 @@ mod tests {
     impl Read for AliceNet {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let mut cable = self.cable.lock().unwrap();
+            cable.deliver();
             let n_in = cable.bob_to_alice.len();
             let n_out = buf.len();
-            if n_in == 0 {
+            if n_in == 0 && cable.closed {
+                Ok(0)
+            } else if n_in == 0 {
                 Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
             } else if n_out < n_in {
                 buf.copy_from_slice(&cable.bob_to_alice[..n_out]);
@@ -192,8 +764,7 @@ mod tests {
 
     impl Write for AliceNet {
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            let mut cable = self.cable.lock().unwrap();
-            cable.alice_to_bob.extend_from_slice(buf);
+            self.cable.lock().unwrap().alice_sends(buf);
             Ok(buf.len())
         }
 
@@ -205,6 +776,7 @@ mod tests {
     impl AsyncRead for AliceNet {}
     impl AsyncWrite for AliceNet {
         fn shutdown(&mut self) -> io::Result<Async<()>> {
+            self.cable.lock().unwrap().close();
             Ok(().into())
         }
     }
@@ -222,9 +794,12 @@ mod tests {
     impl Read for BobNet {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let mut cable = self.cable.lock().unwrap();
+            cable.deliver();
             let n_in = cable.alice_to_bob.len();
             let n_out = buf.len();
-            if n_in == 0 {
+            if n_in == 0 && cable.closed {
+                Ok(0)
+            } else if n_in == 0 {
                 Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
             } else if n_out < n_in {
                 buf.copy_from_slice(&cable.alice_to_bob[..n_out]);
@@ -240,8 +815,7 @@ mod tests {
 
     impl Write for BobNet {
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            let mut cable = self.cable.lock().unwrap();
-            cable.bob_to_alice.extend_from_slice(buf);
+            self.cable.lock().unwrap().bob_sends(buf);
             Ok(buf.len())
         }
 
@@ -253,10 +827,215 @@ mod tests {
     impl AsyncRead for BobNet {}
     impl AsyncWrite for BobNet {
         fn shutdown(&mut self) -> io::Result<Async<()>> {
+            self.cable.lock().unwrap().close();
             Ok(().into())
         }
     }
 
+    /// A transport that always bids the same, and counts the messages it is
+    /// given.
+    struct EqualBidder {
+        sent: Arc<AtomicUsize>,
+    }
+
+    impl EqualBidder {
+        fn new() -> Self {
+            EqualBidder {
+                sent: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn bid(&self) -> Bid {
+            Bid {
+                bid: 10,
+                sink: Box::new(CountingSink(self.sent.clone())),
+            }
+        }
+    }
+
+    struct CountingSink(Arc<AtomicUsize>);
+
+    impl Sink for CountingSink {
+        type SinkItem = (RouterInfo, Message);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, _: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn send_via(a: &EqualBidder, b: &EqualBidder, tie_break: TieBreak, ri: &RouterInfo) {
+        let bid = select_bid(once(a.bid()).chain(once(b.bid())), tie_break).unwrap();
+        let msg = Message::dummy_data();
+        bid.send((ri.clone(), msg)).wait().unwrap();
+    }
+
+    #[test]
+    fn clock_moved_backwards() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(5);
+        assert_eq!(clock_elapsed(now, later).unwrap(), Duration::from_secs(5));
+
+        // A timer started before the clock stepped back
+        match clock_elapsed(later, now) {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::Other);
+                assert_eq!(e.to_string(), "system clock moved backwards");
+            }
+            Ok(d) => panic!("Unexpected elapsed time: {:?}", d),
+        }
+
+        // A clock set before the epoch
+        assert!(unix_time(UNIX_EPOCH + Duration::from_secs(1)).is_ok());
+        assert!(unix_time(UNIX_EPOCH - Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn outgoing_middleware() {
+        let dir = tempdir().unwrap();
+        let config = keyfile_config(&dir.path().join("test.ntcp2.keys.dat"));
+        let mut manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+
+        // Peers without addresses, so that no transport bids for them
+        let blocked = RouterInfo::new(RouterSecretKeys::new().rid);
+        let other = RouterInfo::new(RouterSecretKeys::new().rid);
+        let blocked_hash = blocked.router_id.hash();
+
+        let calls = Arc::new(Mutex::new(vec![]));
+        let logger = |name: &'static str| -> OutgoingMiddleware {
+            let calls = calls.clone();
+            Box::new(move |hash, msg| {
+                calls.lock().unwrap().push((name, hash.clone()));
+                Some((hash, msg))
+            })
+        };
+        manager.add_outgoing_middleware(logger("first"));
+        manager.add_outgoing_middleware(Box::new(move |hash, msg| {
+            if hash == blocked_hash {
+                None
+            } else {
+                Some((hash, msg))
+            }
+        }));
+        manager.add_outgoing_middleware(logger("last"));
+
+        // Messages to the blocked peer are dropped, and report success
+        let res = manager.send(blocked.clone(), Message::dummy_data());
+        assert!(res.ok().unwrap().wait().is_ok());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![("first", blocked.router_id.hash())]
+        );
+
+        // Messages to other peers continue on to bidding
+        calls.lock().unwrap().clear();
+        match manager.send(other.clone(), Message::dummy_data()) {
+            Err((peer, msg)) => {
+                assert_eq!(peer, other);
+                assert_eq!(msg, Message::dummy_data());
+            }
+            Ok(_) => panic!("Sent a message without any transport bidding"),
+        }
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                ("first", other.router_id.hash()),
+                ("last", other.router_id.hash())
+            ]
+        );
+
+        // Middlewares can't redirect messages
+        manager.add_outgoing_middleware(Box::new(|_, msg| Some((Hash([0; 32]), msg))));
+        assert!(manager.send(other, Message::dummy_data()).is_ok());
+    }
+
+    #[test]
+    fn transport_stats_snapshot_and_reset() {
+        let stats = TransportStats::default();
+        stats.received(100);
+        stats.received(20);
+        stats.sent(50);
+        stats.handshake_completed();
+
+        let expected = TransportStatsSnapshot {
+            bytes_in: 120,
+            bytes_out: 50,
+            handshakes: 1,
+        };
+        assert_eq!(stats.snapshot(), expected);
+        assert_eq!(stats.snapshot_and_reset(), expected);
+        assert_eq!(stats.snapshot(), TransportStatsSnapshot::default());
+
+        // Counting carries on from zero
+        stats.received(7);
+        stats.handshake_completed();
+        stats.handshake_completed();
+        assert_eq!(
+            stats.snapshot_and_reset(),
+            TransportStatsSnapshot {
+                bytes_in: 7,
+                bytes_out: 0,
+                handshakes: 2,
+            }
+        );
+        assert_eq!(stats.snapshot(), TransportStatsSnapshot::default());
+    }
+
+    #[test]
+    fn select_bid_tie_break() {
+        let (_, ri) = crate::data::frame::router_info(crate::tests::ROUTER_INFO).unwrap();
+
+        // Deterministic always picks the first transport
+        let (a, b) = (EqualBidder::new(), EqualBidder::new());
+        for _ in 0..100 {
+            send_via(&a, &b, TieBreak::Deterministic, &ri);
+        }
+        assert_eq!(a.sent.load(Ordering::SeqCst), 100);
+        assert_eq!(b.sent.load(Ordering::SeqCst), 0);
+
+        // Random spreads messages roughly evenly
+        let (a, b) = (EqualBidder::new(), EqualBidder::new());
+        for _ in 0..1000 {
+            send_via(&a, &b, TieBreak::Random, &ri);
+        }
+        let sent_a = a.sent.load(Ordering::SeqCst);
+        let sent_b = b.sent.load(Ordering::SeqCst);
+        assert_eq!(sent_a + sent_b, 1000);
+        assert!(sent_a > 400 && sent_b > 400);
+    }
+
+    #[test]
+    fn manager_start_bind_error() {
+        let dir = tempdir().unwrap();
+        let in_use = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let ntcp_addr = in_use.local_addr().unwrap();
+
+        let config = config::Config::builder()
+            .set_override(config::NTCP_LISTEN, ntcp_addr.to_string())
+            .unwrap()
+            .set_override(config::NTCP2_LISTEN, "127.0.0.2:0")
+            .unwrap()
+            .set_override(
+                config::NTCP2_KEYFILE,
+                dir.path().join("test.ntcp2.keys.dat").to_str(),
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+
+        // The comm system fails to start, rather than running without NTCP
+        assert_eq!(
+            manager.start(crate::router::mock::mock_context()).wait(),
+            Err(())
+        );
+    }
+
     #[test]
     fn manager_addresses() {
         let dir = tempdir().unwrap();
@@ -276,10 +1055,305 @@ mod tests {
             .unwrap();
 
         let distributor = MockDistributor::new();
-        let manager = Manager::from_config(&config, distributor);
+        let manager = Manager::from_config(&config, distributor).unwrap();
         let addrs = manager.addresses();
 
         assert_eq!(addrs[0].addr(), Some(ntcp_addr));
         assert_eq!(addrs[1].addr(), Some(ntcp2_addr));
     }
+
+    #[test]
+    fn unreachable_transports() {
+        let dir = tempdir().unwrap();
+        let ntcp2_keyfile = dir.path().join("test.ntcp2.keys.dat");
+
+        let config = keyfile_config(&ntcp2_keyfile);
+        let manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+        assert_eq!(manager.addresses().len(), 2);
+        assert_eq!(manager.reachability(), Reachability::Reachable);
+
+        // Only the NTCP2 address is offered while NTCP is unreachable
+        let config = config::Config::builder()
+            .add_source(keyfile_config(&ntcp2_keyfile))
+            .set_override(config::NTCP_UNREACHABLE, true)
+            .unwrap()
+            .build()
+            .unwrap();
+        let manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+        let addrs = manager.addresses();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0], manager.ntcp2.address());
+        assert_eq!(manager.reachability(), Reachability::Reachable);
+
+        // With neither transport reachable, we offer no addresses
+        let config = config::Config::builder()
+            .add_source(keyfile_config(&ntcp2_keyfile))
+            .set_override(config::NTCP_UNREACHABLE, true)
+            .unwrap()
+            .set_override(config::NTCP2_UNREACHABLE, true)
+            .unwrap()
+            .build()
+            .unwrap();
+        let manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+        assert!(manager.addresses().is_empty());
+        assert_eq!(manager.reachability(), Reachability::Unreachable);
+    }
+
+    #[test]
+    fn transport_mtus() {
+        let dir = tempdir().unwrap();
+        let config = keyfile_config(&dir.path().join("test.ntcp2.keys.dat"));
+        let manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+
+        // The largest frame, minus the size field and checksum
+        assert_eq!(manager.transport_mtu(TransportStyle::Ntcp), 16384 - 6);
+        // The largest frame, minus the AEAD tag and the block header
+        assert_eq!(manager.transport_mtu(TransportStyle::Ntcp2), 65535 - 16 - 3);
+    }
+
+    #[test]
+    fn send_fanout() {
+        use std::thread;
+        use tokio::runtime::Runtime;
+
+        let dir = tempdir().unwrap();
+        let config = keyfile_config(&dir.path().join("test.ntcp2.keys.dat"));
+        let mut manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+        let ctx = crate::router::mock::mock_context();
+        let own_hash = ctx.keys.rid.hash();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(manager.start(ctx)).unwrap();
+
+        // Three peers listening for NTCP on loopback
+        let mut peers = vec![];
+        let mut infos = vec![];
+        let mut received = vec![];
+        for _ in 0..3 {
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let peer_ctx = crate::router::mock::mock_context();
+            let distributor = MockDistributor::new();
+            received.push(distributor.received.clone());
+            let peer = ntcp::Manager::new(addr, distributor);
+            let mut ri = RouterInfo::new(peer_ctx.keys.rid.clone());
+            ri.set_addresses(vec![peer.address()]);
+            ri.sign(&peer_ctx.keys.signing_private_key);
+            rt.spawn(
+                peer.listen(
+                    peer_ctx.keys.rid.clone(),
+                    peer_ctx.keys.signing_private_key.clone(),
+                )
+                .unwrap()
+                .map_err(|e| panic!("Listener error: {}", e)),
+            );
+            peers.push(peer);
+            infos.push(ri);
+        }
+
+        // A peer without addresses gets its copy back
+        let unreachable = RouterInfo::new(RouterSecretKeys::new().rid);
+        infos.push(unreachable.clone());
+
+        let mut sends = manager.send_fanout(&infos, Message::dummy_data());
+        assert_eq!(sends.len(), 4);
+        match sends.pop().unwrap() {
+            Err((hash, msg)) => {
+                assert_eq!(hash, unreachable.router_id.hash());
+                assert_eq!(msg, Message::dummy_data());
+            }
+            Ok(_) => panic!("Sent a message without any transport bidding"),
+        }
+        let sends: Vec<_> = sends.into_iter().map(Result::unwrap).collect();
+        rt.block_on(future::join_all(sends)).unwrap();
+
+        // Each peer receives its own copy. The handshakes run on a busy
+        // machine alongside the other tests, so give them plenty of time.
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while Instant::now() < deadline {
+            if received.iter().all(|r| !r.lock().unwrap().is_empty()) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        for r in &received {
+            let r = r.lock().unwrap();
+            assert_eq!(r.len(), 1);
+            assert_eq!(r[0].0, own_hash);
+        }
+    }
+
+    /// Entries that expire at the given times.
+    struct TtlCache(Vec<(SystemTime, u32)>);
+
+    impl Expirable for TtlCache {
+        fn sweep_expired(&mut self, now: SystemTime) {
+            self.0.retain(|(expires, _)| *expires > now);
+        }
+    }
+
+    fn fixed_clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1000)
+    }
+
+    #[test]
+    fn expiry_sweep() {
+        use std::thread;
+        use tokio::runtime::Runtime;
+
+        let dir = tempdir().unwrap();
+        let config = keyfile_config(&dir.path().join("test.ntcp2.keys.dat"));
+        let mut manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+        manager.set_sweep_interval(Duration::from_millis(50));
+        manager.set_sweeper_clock(fixed_clock);
+
+        // One entry has expired by the clock, and the other has not
+        let cache = Arc::new(Mutex::new(TtlCache(vec![
+            (UNIX_EPOCH + Duration::from_secs(995), 1),
+            (UNIX_EPOCH + Duration::from_secs(1060), 2),
+        ])));
+        manager.register_expirable(cache.clone());
+
+        // Nothing is swept until the transports start
+        assert_eq!(cache.lock().unwrap().0.len(), 2);
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(manager.start(crate::router::mock::mock_context()))
+            .unwrap();
+        for _ in 0..100 {
+            if cache.lock().unwrap().0.len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(
+            cache.lock().unwrap().0,
+            vec![(UNIX_EPOCH + Duration::from_secs(1060), 2)]
+        );
+    }
+
+    #[test]
+    fn set_published_info() {
+        use tokio::runtime::Runtime;
+
+        let dir = tempdir().unwrap();
+        let config = keyfile_config(&dir.path().join("test.ntcp2.keys.dat"));
+        let mut manager = Manager::from_config(&config, MockDistributor::new()).unwrap();
+        let ctx = crate::router::mock::mock_context();
+
+        let signed = |keys: &RouterSecretKeys, port: u16| {
+            let mut ri = RouterInfo::new(keys.rid.clone());
+            ri.set_addresses(vec![RouterAddress::new(
+                &ntcp::NTCP_STYLE,
+                format!("127.0.0.1:{}", port).parse().unwrap(),
+            )]);
+            ri.sign(&keys.signing_private_key);
+            ri
+        };
+
+        // Until the transports start, there is nothing to replace
+        match manager.set_published_info(signed(&ctx.keys, 1000)) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotConnected),
+            Ok(()) => panic!("Replaced RouterInfo before starting"),
+        }
+        assert_eq!(manager.published_info(), None);
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(manager.start(ctx.clone())).unwrap();
+        let original = ctx.ri.read().unwrap().clone();
+        assert_eq!(manager.published_info(), Some(original.clone()));
+
+        // Another router's RouterInfo is refused
+        let foreign = signed(&RouterSecretKeys::new(), 1000);
+        // So is ours, if it is unsigned or its signature doesn't verify
+        let unsigned = RouterInfo::new(ctx.keys.rid.clone());
+        let mut tampered = signed(&ctx.keys, 1000);
+        tampered.published = I2PDate(tampered.published.0 + 1);
+        for ri in vec![foreign, unsigned, tampered] {
+            match manager.set_published_info(ri) {
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+                Ok(()) => panic!("Published an invalid RouterInfo"),
+            }
+        }
+        assert_eq!(*ctx.ri.read().unwrap(), original);
+
+        // A RouterInfo we signed replaces the one the rest of the router sees
+        let ri = signed(&ctx.keys, 1001);
+        manager.set_published_info(ri.clone()).unwrap();
+        assert_eq!(*ctx.ri.read().unwrap(), ri);
+        assert_eq!(manager.published_info(), Some(ri.clone()));
+        assert_eq!(manager.addresses(), ri.addresses());
+
+        // Swap back and forth while another thread reads
+        let infos = vec![signed(&ctx.keys, 1002), signed(&ctx.keys, 1003)];
+        let writer = {
+            let infos = infos.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    manager.set_published_info(infos[i % 2].clone()).unwrap();
+                }
+                manager
+            })
+        };
+        let mut reads = 0;
+        while reads < 200 || !writer.is_finished() {
+            // Readers only ever see a complete RouterInfo that we published
+            let snapshot = ctx.ri.read().unwrap().clone();
+            assert!(snapshot == ri || infos.contains(&snapshot));
+            assert!(snapshot.verify().is_ok());
+            assert!(!snapshot.addresses().is_empty());
+            reads += 1;
+        }
+        let manager = writer.join().unwrap();
+        assert_eq!(manager.published_info(), Some(infos[1].clone()));
+    }
+
+    fn keyfile_config(ntcp2_keyfile: &std::path::Path) -> config::Config {
+        config::Config::builder()
+            .set_override(config::NTCP_LISTEN, "127.0.0.1:0")
+            .unwrap()
+            .set_override(config::NTCP2_LISTEN, "127.0.0.2:0")
+            .unwrap()
+            .set_override(config::NTCP2_KEYFILE, ntcp2_keyfile.to_str())
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn manager_missing_ntcp2_keyfile() {
+        let dir = tempdir().unwrap();
+        let ntcp2_keyfile = dir.path().join("test.ntcp2.keys.dat");
+        let config = keyfile_config(&ntcp2_keyfile);
+
+        // New keys are generated and written out
+        Manager::from_config(&config, MockDistributor::new()).unwrap();
+        let data = std::fs::read(&ntcp2_keyfile).unwrap();
+        assert_eq!(data.len(), 80);
+
+        // The same keys are loaded next time
+        let reloaded = Manager::from_config(&config, MockDistributor::new()).unwrap();
+        let copy = dir.path().join("copy.ntcp2.keys.dat");
+        reloaded.ntcp2.to_file(copy.to_str().unwrap()).unwrap();
+        assert_eq!(std::fs::read(&copy).unwrap(), data);
+        assert_eq!(std::fs::read(&ntcp2_keyfile).unwrap(), data);
+    }
+
+    #[test]
+    fn manager_corrupt_ntcp2_keyfile() {
+        let dir = tempdir().unwrap();
+        let ntcp2_keyfile = dir.path().join("test.ntcp2.keys.dat");
+        let config = keyfile_config(&ntcp2_keyfile);
+
+        let corrupt = vec![0x42; 50];
+        std::fs::write(&ntcp2_keyfile, &corrupt).unwrap();
+
+        match Manager::from_config(&config, MockDistributor::new()) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("Loaded a corrupt keyfile"),
+        }
+        assert_eq!(std::fs::read(&ntcp2_keyfile).unwrap(), corrupt);
+    }
 }
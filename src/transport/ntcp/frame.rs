@@ -1,14 +1,14 @@
 use cookie_factory::*;
 use nom::{
     bytes::streaming::{tag, take},
-    combinator::{map, peek, success},
+    combinator::{map, peek, rest, success},
     multi::length_value,
-    number::streaming::{be_u16, be_u32},
-    sequence::{pair, terminated},
+    number::streaming::{be_u16, be_u32, be_u8},
+    sequence::{pair, terminated, tuple},
     IResult,
 };
 
-use super::Frame;
+use super::{Frame, NTCP_MTU};
 use crate::i2np::frame::{gen_message, message};
 use crate::i2np::Message;
 
@@ -38,7 +38,7 @@ pub fn gen_padding(
 //
 
 // Adler-32 checksum as specified on https://tools.ietf.org/html/rfc1950#page-6
-fn adler(input: &[u8]) -> [u8; 4] {
+pub(super) fn adler(input: &[u8]) -> [u8; 4] {
     let mut s1: u32 = 1;
     let mut s2: u32 = 0;
     for x in input {
@@ -115,6 +115,32 @@ fn gen_timestamp_frame(
     )
 }
 
+/// Returns true if the checksum at the end of the given complete frame matches
+/// its contents.
+pub fn checksum_valid(frame: &[u8]) -> bool {
+    let (data, cs) = frame.split_at(frame.len() - 4);
+    adler(data)[..] == *cs
+}
+
+/// The largest frame we send or accept.
+pub const MAX_FRAME_LEN: usize = NTCP_MTU;
+
+/// The size of the largest message that fits in one frame, after the size
+/// field and checksum.
+pub const MAX_MESSAGE_LEN: usize = MAX_FRAME_LEN - 6;
+
+/// Returns the total on-the-wire length of the frame starting at `i`, or `None`
+/// if the size field is not yet available.
+pub fn frame_len(i: &[u8]) -> Option<usize> {
+    if i.len() < 2 {
+        return None;
+    }
+    match (usize::from(i[0]) << 8) | usize::from(i[1]) {
+        0 => Some(16),
+        size => Some(size + 6 + padding_len(size + 6)),
+    }
+}
+
 pub fn frame(i: &[u8]) -> IResult<&[u8], Frame> {
     let (i, (cs, sz)) = pair(get_adler, be_u16)(i)?;
     match sz {
@@ -132,6 +158,17 @@ pub fn frame(i: &[u8]) -> IResult<&[u8], Frame> {
     }
 }
 
+/// Returns the number of bytes `gen_frame` will write for the given frame.
+pub fn encoded_len(frame: &Frame) -> usize {
+    match *frame {
+        Frame::Standard(ref msg) => {
+            let content_len = msg.size() + 6;
+            content_len + padding_len(content_len)
+        }
+        Frame::TimeSync(_) => 16,
+    }
+}
+
 pub fn gen_frame<'a>(
     input: (&'a mut [u8], usize),
     frame: &Frame,
@@ -142,9 +179,91 @@ pub fn gen_frame<'a>(
     }
 }
 
+//
+// Fragments
+//
+
+/// Marks the data of a standard frame as a fragment. This is the I2NP type
+/// code reserved for future extension, so no message we handle starts with it.
+pub const FRAGMENT_MARKER: u8 = 0xff;
+
+const FRAGMENT_HEADER_LEN: usize = 7;
+
+/// The largest amount of a message that fits in one fragment frame.
+pub const MAX_FRAGMENT_PAYLOAD: usize = NTCP_MTU - 6 - FRAGMENT_HEADER_LEN;
+
+/// Part of a serialized I2NP message that was too large for a single frame.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Fragment {
+    /// Shared by all fragments of the same message.
+    pub id: u32,
+    pub index: u8,
+    pub count: u8,
+    pub data: Vec<u8>,
+}
+
+// 0      2      3    7       8       9         size+2         12      size+6
+// +------+------+----+-------+-------+------------+---------+-------+
+// | size | 0xff | id | index | count |    data    | padding | adler |
+// +------+------+----+-------+-------+------------+---------+-------+
+//  short   byte  long  byte    byte    octets       octets    octets
+
+#[rustfmt::skip]
+pub fn gen_fragment_frame<'a>(
+    input: (&'a mut [u8], usize),
+    fragment: &Fragment,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let size = FRAGMENT_HEADER_LEN + fragment.data.len();
+    do_gen!(
+        input,
+        start: gen_be_u16!(size) >>
+               gen_be_u8!(FRAGMENT_MARKER) >>
+               gen_be_u32!(fragment.id) >>
+               gen_be_u8!(fragment.index) >>
+               gen_be_u8!(fragment.count) >>
+               gen_slice!(fragment.data) >>
+               gen_padding(size + 6) >>
+        end:   gen_adler(start, end)
+    )
+}
+
+/// Returns the number of bytes `gen_fragment_frame` will write for the given
+/// fragment.
+pub fn fragment_encoded_len(fragment: &Fragment) -> usize {
+    let content_len = FRAGMENT_HEADER_LEN + fragment.data.len() + 6;
+    content_len + padding_len(content_len)
+}
+
+/// Returns true if the given complete frame carries a fragment.
+pub fn is_fragment_frame(frame: &[u8]) -> bool {
+    frame[0..2] != [0, 0] && frame[2] == FRAGMENT_MARKER
+}
+
+fn fragment(i: &[u8]) -> IResult<&[u8], Fragment> {
+    let (i, (_, id, index, count, data)) =
+        tuple((tag([FRAGMENT_MARKER]), be_u32, be_u8, be_u8, rest))(i)?;
+    Ok((
+        i,
+        Fragment {
+            id,
+            index,
+            count,
+            data: data.to_vec(),
+        },
+    ))
+}
+
+pub fn fragment_frame(i: &[u8]) -> IResult<&[u8], Fragment> {
+    let (i, (cs, size)) = pair(get_adler, be_u16)(i)?;
+    terminated(
+        length_value(success(size), fragment),
+        pair(padding((size + 6) as usize), tag(cs)),
+    )(i)
+}
+
 #[cfg(test)]
 mod tests {
-    use nom::Err;
+    use nom::{combinator::complete, Err};
 
     use super::*;
 
@@ -251,6 +370,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encoded_len_matches_gen_frame() {
+        for frame in [Frame::TimeSync(42), Frame::Standard(Message::dummy_data())] {
+            let mut buf = vec![0; 1024];
+            let (_, written) = gen_frame((&mut buf[..], 0), &frame).unwrap();
+            assert_eq!(encoded_len(&frame), written);
+        }
+    }
+
     #[test]
     fn gen_timestamp_frame_valid() {
         let mut buf = vec![0u8; 16];
@@ -281,4 +409,101 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn timesync_frame_truncated() {
+        // A timesync frame with only two bytes of timestamp
+        let data = [0x00, 0x00, 0x01, 0x02];
+
+        // More data may still arrive
+        match frame(&data) {
+            Err(Err::Incomplete(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // If it is the whole frame, it is rejected
+        match complete(frame)(&data) {
+            Err(Err::Error(e)) => assert_eq!(e.input, &data[..]),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // A full frame still parses
+        let mut buf = vec![0u8; 16];
+        gen_timestamp_frame((&mut buf[..], 0), 12_345_678).unwrap();
+        match frame(&buf) {
+            Ok((rest, Frame::TimeSync(ts))) => {
+                assert!(rest.is_empty());
+                assert_eq!(ts, 12_345_678);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn fragment_frame_round_trip() {
+        let fragment = Fragment {
+            id: 0x0102_0304,
+            index: 1,
+            count: 3,
+            data: vec![0x42; 100],
+        };
+        let mut buf = vec![0u8; fragment_encoded_len(&fragment)];
+        let (_, sz) = gen_fragment_frame((&mut buf[..], 0), &fragment).unwrap();
+        assert_eq!(sz, 128);
+        assert_eq!(
+            &buf[0..8],
+            &[0x00, 0x6b, 0xff, 0x01, 0x02, 0x03, 0x04, 0x01]
+        );
+        assert_eq!(frame_len(&buf), Some(sz));
+        assert!(checksum_valid(&buf));
+        assert!(is_fragment_frame(&buf));
+
+        match fragment_frame(&buf) {
+            Ok((rest, parsed)) => {
+                assert!(rest.is_empty());
+                assert_eq!(parsed, fragment);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // A standard frame is not a fragment
+        let mut buf = vec![0u8; 1024];
+        let (_, sz) =
+            gen_frame((&mut buf[..], 0), &Frame::Standard(Message::dummy_data())).unwrap();
+        assert!(!is_fragment_frame(&buf[..sz]));
+        assert!(fragment_frame(&buf[..sz]).is_err());
+    }
+
+    #[test]
+    fn max_frame_len() {
+        let size = (MAX_MESSAGE_LEN as u16).to_be_bytes();
+        assert_eq!(frame_len(&size), Some(MAX_FRAME_LEN));
+        let size = (MAX_MESSAGE_LEN as u16 + 1).to_be_bytes();
+        assert!(frame_len(&size).unwrap() > MAX_FRAME_LEN);
+
+        // A full fragment also fits
+        let fragment = Fragment {
+            id: 0,
+            index: 0,
+            count: 2,
+            data: vec![0; MAX_FRAGMENT_PAYLOAD],
+        };
+        assert_eq!(fragment_encoded_len(&fragment), MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn test_frame_len() {
+        assert_eq!(frame_len(&[]), None);
+        assert_eq!(frame_len(&[0x00]), None);
+        assert_eq!(frame_len(&[0x00, 0x00]), Some(16));
+        assert_eq!(frame_len(&[0x00, 0x0a]), Some(16));
+        assert_eq!(frame_len(&[0x00, 0x0b]), Some(32));
+        assert_eq!(frame_len(&[0x00, 0x1e, 0x14]), Some(48));
+
+        let mut buf = vec![0u8; 16];
+        let sz = gen_timestamp_frame((&mut buf[..], 0), 12_345_678)
+            .map(|tup| tup.1)
+            .unwrap();
+        assert_eq!(frame_len(&buf), Some(sz));
+    }
 }
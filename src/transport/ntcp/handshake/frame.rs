@@ -4,8 +4,10 @@ use nom::IResult;
 use nom::{
     bytes::streaming::take,
     combinator::map,
+    error::{Error, ErrorKind},
     number::streaming::{be_u16, be_u32},
     sequence::pair,
+    Err, Offset,
 };
 
 use super::super::frame::{gen_padding, padding, padding_len};
@@ -93,6 +95,7 @@ pub fn gen_session_created_dec<'a>(
 // - RI cert type and length are in bytes 386-388
 // - If there is a KeyCert, its types are in bytes 389-392
 //   - If no KeyCert, these bytes will be tsA
+// - sz must be exactly the length of RI_A, because it determines len(pad)
 
 pub fn gen_session_confirm_sig_msg<'a>(
     input: (&'a mut [u8], usize),
@@ -113,7 +116,11 @@ pub fn gen_session_confirm_sig_msg<'a>(
 }
 
 pub fn session_confirm_a(i: &[u8]) -> IResult<&[u8], HandshakeFrame> {
-    let (i, (size, ri_a)) = pair(be_u16, router_identity)(i)?;
+    let (ri_start, size) = be_u16(i)?;
+    let (i, ri_a) = router_identity(ri_start)?;
+    if ri_start.offset(i) != usize::from(size) {
+        return Err(Err::Error(Error::new(ri_start, ErrorKind::LengthValue)));
+    }
     let (i, (ts_a, sig)) = separated_pair(
         be_u32,
         padding(size as usize + 6 + ri_a.signing_key.sig_type().sig_len() as usize),
@@ -171,3 +178,198 @@ pub fn gen_session_confirm_b<'a>(
         end:   gen_padding(end - start)
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Signature;
+    use crate::data::{frame::router_info, RouterSecretKeys};
+    use crate::tests::{assert_bytes_eq, NTCP_SESSION_REQUEST, ROUTER_INFO};
+    use crate::util::serialize;
+
+    /// Serializes the given frame with its generator, parses it back with its
+    /// parser, and checks that nothing changed. SessionConfirmB is parsed using
+    /// the given RouterIdentity.
+    fn roundtrip(frame: HandshakeFrame, ri_b: &RouterIdentity) {
+        let (parsed, rest) = match frame {
+            HandshakeFrame::SessionRequest(ref sr) => {
+                let buf = serialize(|input| gen_session_request(input, sr));
+                assert_eq!(buf.len(), 288);
+                let (rest, parsed) = session_request(&buf).unwrap();
+                (parsed, rest.len())
+            }
+            HandshakeFrame::SessionCreated(ref sc) => {
+                // The encrypted part is handled here in cleartext
+                let dec = serialize(|input| gen_session_created_dec(input, sc));
+                assert_eq!(dec.len(), 48);
+                let buf = serialize(|input| {
+                    gen_session_created_enc(input, &sc.dh_y, array_ref![dec, 0, 48])
+                });
+                assert_eq!(buf.len(), 304);
+                let (rest, (dh_y, ct)) = session_created_enc(&buf).unwrap();
+                let (_, (hash, ts_b)) = session_created_dec(&ct).unwrap();
+                (
+                    HandshakeFrame::SessionCreated(SessionCreated { dh_y, hash, ts_b }),
+                    rest.len(),
+                )
+            }
+            HandshakeFrame::SessionConfirmA(ref sca) => {
+                let buf = serialize(|input| gen_session_confirm_a(input, sca));
+                assert_eq!(buf.len() % 16, 0);
+                let (rest, parsed) = session_confirm_a(&buf).unwrap();
+                (parsed, rest.len())
+            }
+            HandshakeFrame::SessionConfirmB(ref scb) => {
+                let buf = serialize(|input| gen_session_confirm_b(input, scb));
+                assert_eq!(buf.len() % 16, 0);
+                let (rest, parsed) = session_confirm_b(ri_b)(&buf).unwrap();
+                (parsed, rest.len())
+            }
+        };
+        assert_eq!(rest, 0);
+        assert_eq!(parsed, frame);
+    }
+
+    fn dummy_sig(sk: &RouterSecretKeys) -> Signature {
+        sk.signing_private_key.sign(&[0x42; 32]).unwrap()
+    }
+
+    #[test]
+    fn gen_session_request_fixture() {
+        let (_, ri_b) = router_info(ROUTER_INFO).unwrap();
+        let dh_x: Vec<u8> = (0..=255).collect();
+        let mut hash = Hash::digest(&dh_x);
+        hash.xor(&ri_b.router_id.hash());
+
+        let sr = SessionRequest { dh_x, hash };
+        let buf = serialize(|input| gen_session_request(input, &sr));
+        assert_bytes_eq(NTCP_SESSION_REQUEST, &buf);
+
+        match session_request(NTCP_SESSION_REQUEST) {
+            Ok((rest, HandshakeFrame::SessionRequest(parsed))) => {
+                assert!(rest.is_empty());
+                assert_eq!(parsed, sr);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn roundtrip_session_request() {
+        let sk = RouterSecretKeys::new();
+        roundtrip(
+            HandshakeFrame::SessionRequest(SessionRequest {
+                dh_x: vec![0x11; 256],
+                hash: Hash([0x22; 32]),
+            }),
+            &sk.rid,
+        );
+    }
+
+    #[test]
+    fn roundtrip_session_created() {
+        let sk = RouterSecretKeys::new();
+        roundtrip(
+            HandshakeFrame::SessionCreated(SessionCreated {
+                dh_y: vec![0x33; 256],
+                hash: Hash([0x44; 32]),
+                ts_b: 0x1234_5678,
+            }),
+            &sk.rid,
+        );
+    }
+
+    #[test]
+    fn roundtrip_session_confirm_a() {
+        let sk = RouterSecretKeys::new();
+        roundtrip(
+            HandshakeFrame::SessionConfirmA(Box::new(SessionConfirmA {
+                ri_a: sk.rid.clone(),
+                ts_a: 0x8765_4321,
+                sig: dummy_sig(&sk),
+            })),
+            &sk.rid,
+        );
+    }
+
+    #[test]
+    fn session_created_big_endian() {
+        let sc = SessionCreated {
+            dh_y: vec![0; 256],
+            hash: Hash([0; 32]),
+            ts_b: 0x0102_0304,
+        };
+        let dec = serialize(|input| gen_session_created_dec(input, &sc));
+        assert_eq!(&dec[32..36], &[0x01, 0x02, 0x03, 0x04]);
+
+        let (_, (_, ts_b)) = session_created_dec(&dec).unwrap();
+        assert_eq!(ts_b, 0x0102_0304);
+    }
+
+    #[test]
+    fn session_confirm_a_big_endian() {
+        let sk = RouterSecretKeys::new();
+        let ri_len = serialize(|input| gen_router_identity(input, &sk.rid)).len();
+        let sca = SessionConfirmA {
+            ri_a: sk.rid.clone(),
+            ts_a: 0x0102_0304,
+            sig: dummy_sig(&sk),
+        };
+        let buf = serialize(|input| gen_session_confirm_a(input, &sca));
+        assert_eq!(&buf[0..2], &(ri_len as u16).to_be_bytes());
+        assert_eq!(&buf[2 + ri_len..6 + ri_len], &[0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn session_confirm_a_size_mismatch() {
+        let sk = RouterSecretKeys::new();
+        let sca = SessionConfirmA {
+            ri_a: sk.rid.clone(),
+            ts_a: 0x0102_0304,
+            sig: dummy_sig(&sk),
+        };
+        let buf = serialize(|input| gen_session_confirm_a(input, &sca));
+        let size = u16::from_be_bytes([buf[0], buf[1]]);
+
+        // Sizes that would move the padding, by whole blocks so that the frame
+        // still decrypts
+        for bad_size in [size + 16, size - 16] {
+            let mut bad = buf.clone();
+            bad[0..2].copy_from_slice(&bad_size.to_be_bytes());
+            bad.extend_from_slice(&[0; 16]);
+            match session_confirm_a(&bad) {
+                Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::LengthValue),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn session_confirm_sig_msg_big_endian() {
+        let sk = RouterSecretKeys::new();
+        let buf = serialize(|input| {
+            gen_session_confirm_sig_msg(
+                input,
+                &[0; 256],
+                &[0; 256],
+                &sk.rid,
+                0x0102_0304,
+                0x0506_0708,
+            )
+        });
+        assert_eq!(buf.len(), 552);
+        assert_eq!(&buf[544..548], &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(&buf[548..552], &[0x05, 0x06, 0x07, 0x08]);
+    }
+
+    #[test]
+    fn roundtrip_session_confirm_b() {
+        let sk = RouterSecretKeys::new();
+        roundtrip(
+            HandshakeFrame::SessionConfirmB(SessionConfirmB {
+                sig: dummy_sig(&sk),
+            }),
+            &sk.rid,
+        );
+    }
+}
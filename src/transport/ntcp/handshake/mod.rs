@@ -2,24 +2,127 @@ use bytes::BytesMut;
 use cookie_factory::GenError;
 use futures::{sink, stream::StreamFuture, try_ready, Async, Future, Poll, Sink, Stream};
 use nom::{Err, Offset};
+use std::fmt;
 use std::iter::repeat;
 use std::ops::AddAssign;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::{
     codec::{Decoder, Encoder, Framed, FramedParts},
     io::{self, AsyncRead, AsyncWrite},
+    timer::Delay,
 };
 
-use super::{Codec, NTCP_MTU};
-use crate::crypto::{Aes256, Signature, SigningPrivateKey, AES_BLOCK_SIZE};
+use super::{dh_workers_error, Codec, Direction, WireTap, NTCP_MTU};
+use crate::crypto::{
+    dh::{is_valid_pub, DhFuture, DhWorkers},
+    Aes256, SessionKey, Signature, SigningPrivateKey, AES_BLOCK_SIZE,
+};
 use crate::data::{Hash, RouterIdentity};
-use crate::transport::DHSessionKeyBuilder;
-use crate::util::serialize;
+use crate::transport::{clock_elapsed, unix_time, DHSessionKeyBuilder};
+use crate::util::{serialize, serialize_with_len};
 
 #[allow(clippy::double_parens)]
 #[allow(clippy::needless_pass_by_value)]
 mod frame;
 
+/// The maximum clock skew we allow between ourselves and a peer.
+pub(super) const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// How long we wait for SessionConfirmB after sending SessionConfirmA.
+const CONFIRM_B_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The error for converting a handshake codec that has not set up its cipher.
+fn incomplete_handshake() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Handshake has not established a cipher",
+    )
+}
+
+/// The error for a peer that closed the connection partway through a
+/// handshake frame.
+fn closed_during_handshake(state: HandshakeState) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("peer closed connection during handshake ({:?})", state),
+    )
+}
+
+/// Errors that can cause an NTCP handshake to fail.
+///
+/// These are converted to `io::Error`s at the codec and handshake boundaries,
+/// and can be recovered with `HandshakeError::from_io`. Failures that are not
+/// specific to the handshake remain plain `io::Error`s.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// A hash sent by the peer does not match our view of the handshake.
+    InvalidHash(&'static str),
+    /// The peer's signature over the handshake, in the named frame, does not
+    /// verify.
+    BadSignature(&'static str),
+    /// The peer sent bytes that do not form a valid handshake frame.
+    ParseFailure(String),
+    /// The peer's clock differs from ours by more than we allow, in seconds.
+    Skew(u64),
+    /// The peer did not progress through the handshake in time.
+    Timeout(&'static str),
+    /// The underlying connection failed.
+    Io(io::Error),
+}
+
+impl HandshakeError {
+    /// Returns the `HandshakeError` wrapped by the given `io::Error`, if any.
+    pub fn from_io(e: &io::Error) -> Option<&HandshakeError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+
+    /// Returns the kind of `io::Error` that this error is reported as.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            HandshakeError::InvalidHash(_) | HandshakeError::ParseFailure(_) => {
+                io::ErrorKind::InvalidData
+            }
+            HandshakeError::BadSignature(_) | HandshakeError::Skew(_) => {
+                io::ErrorKind::ConnectionRefused
+            }
+            HandshakeError::Timeout(_) => io::ErrorKind::TimedOut,
+            HandshakeError::Io(e) => e.kind(),
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::InvalidHash(what) => write!(f, "Invalid {}", what),
+            HandshakeError::BadSignature(frame) => write!(f, "Invalid {} signature", frame),
+            HandshakeError::ParseFailure(e) => write!(f, "parse error: {}", e),
+            HandshakeError::Skew(skew) => write!(f, "Peer clock skew too large ({}s)", skew),
+            HandshakeError::Timeout(what) => write!(f, "timeout {}", what),
+            HandshakeError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HandshakeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        match e {
+            HandshakeError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
 macro_rules! try_poll {
     ($conn:expr, $expected:ident) => {
         match try_ready!($conn.poll().map_err(|(e, _)| e)) {
@@ -44,27 +147,48 @@ macro_rules! try_poll {
 // Establishment handshake
 //
 
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub struct SessionRequest {
     dh_x: Vec<u8>,
     hash: Hash,
 }
 
+impl SessionRequest {
+    /// Creates a SessionRequest for the peer with the given identity hash.
+    pub fn new(dh_x: Vec<u8>, peer_hash: &Hash) -> Self {
+        let mut hash = Hash::digest(&dh_x[..]);
+        hash.xor(peer_hash);
+        SessionRequest { dh_x, hash }
+    }
+
+    /// Returns the IV that Alice encrypts with and Bob decrypts with.
+    fn iv(&self) -> [u8; AES_BLOCK_SIZE] {
+        let mut iv = [0u8; AES_BLOCK_SIZE];
+        iv.copy_from_slice(&self.hash.0[AES_BLOCK_SIZE..]);
+        iv
+    }
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub struct SessionCreated {
     dh_y: Vec<u8>,
     hash: Hash,
     ts_b: u32,
 }
 
+#[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct SessionConfirmA {
     ri_a: RouterIdentity,
     ts_a: u32,
     sig: Signature,
 }
 
+#[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct SessionConfirmB {
     sig: Signature,
 }
 
+#[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum HandshakeFrame {
     SessionRequest(SessionRequest),
     SessionCreated(SessionCreated),
@@ -86,32 +210,42 @@ pub struct InboundHandshakeCodec {
     dh_x: Vec<u8>,
     iv_enc: [u8; AES_BLOCK_SIZE],
     iv_dec: [u8; AES_BLOCK_SIZE],
+    session_key: Option<SessionKey>,
     state: HandshakeState,
     aes: Option<Aes256>,
     decrypted: usize,
+    wire_tap: Option<WireTap>,
 }
 
 impl InboundHandshakeCodec {
-    fn new(dh_key_builder: DHSessionKeyBuilder, iv_enc: [u8; AES_BLOCK_SIZE]) -> Self {
+    fn new(
+        dh_key_builder: DHSessionKeyBuilder,
+        iv_enc: [u8; AES_BLOCK_SIZE],
+        wire_tap: Option<WireTap>,
+    ) -> Self {
         let iv_dec = [0u8; AES_BLOCK_SIZE];
         InboundHandshakeCodec {
             dh_key_builder,
             dh_x: vec![],
             iv_enc,
             iv_dec,
+            session_key: None,
             state: HandshakeState::SessionRequest,
             aes: None,
             decrypted: 0,
+            wire_tap,
         }
     }
 }
 
-impl From<InboundHandshakeCodec> for Codec {
-    fn from(established: InboundHandshakeCodec) -> Self {
-        Codec {
-            aes: established.aes.unwrap(),
-            decrypted: established.decrypted,
-        }
+impl TryFrom<InboundHandshakeCodec> for Codec {
+    type Error = io::Error;
+
+    fn try_from(established: InboundHandshakeCodec) -> io::Result<Self> {
+        let aes = established.aes.ok_or_else(incomplete_handshake)?;
+        let mut codec = Codec::new(aes, established.decrypted);
+        codec.wire_tap = established.wire_tap;
+        Ok(codec)
     }
 }
 
@@ -125,6 +259,13 @@ impl Decoder for InboundHandshakeCodec {
             let res = match self.state {
                 HandshakeState::SessionRequest => frame::session_request(buf),
                 HandshakeState::SessionConfirmA => {
+                    // Decrypt any new whole blocks in-place. A trailing partial
+                    // block stays encrypted until the rest of it arrives, and
+                    // the frame is then re-parsed from the start, so split
+                    // reads need no further tracking.
+                    if let Some(ref tap) = self.wire_tap {
+                        tap.observe_blocks(&buf[self.decrypted..]);
+                    }
                     match self
                         .aes
                         .as_mut()
@@ -134,6 +275,8 @@ impl Decoder for InboundHandshakeCodec {
                         Some(end) => self.decrypted += end,
                         None => return Ok(None),
                     };
+                    // The RouterIdentity is variable-length, so we only know
+                    // that the whole padded frame is here once it parses.
                     frame::session_confirm_a(&buf[0..self.decrypted])
                 }
                 _ => return Ok(None),
@@ -143,10 +286,7 @@ impl Decoder for InboundHandshakeCodec {
             match res {
                 Err(Err::Incomplete(_)) => return Ok(None),
                 Err(Err::Error(e)) | Err(Err::Failure(e)) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("parse error: {:?}", e),
-                    ));
+                    return Err(HandshakeError::ParseFailure(format!("{:?}", e.code)).into());
                 }
                 Ok((i, frame)) => (buf.offset(i), frame),
             }
@@ -154,8 +294,17 @@ impl Decoder for InboundHandshakeCodec {
 
         // Save dh_x and iv_dec for later usage
         if let HandshakeFrame::SessionRequest(ref sr) = f {
+            if let Some(ref tap) = self.wire_tap {
+                tap.observe(Direction::Inbound, &buf[..consumed]);
+            }
+            if !is_valid_pub(&sr.dh_x) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid SessionRequest DH value",
+                ));
+            }
             self.dh_x = sr.dh_x.clone();
-            self.iv_dec.copy_from_slice(&sr.hash.0[AES_BLOCK_SIZE..]);
+            self.iv_dec = sr.iv();
         }
 
         // Update the buffer
@@ -173,6 +322,15 @@ impl Decoder for InboundHandshakeCodec {
 
         Ok(Some(f))
     }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<HandshakeFrame>> {
+        match self.decode(buf)? {
+            Some(f) => Ok(Some(f)),
+            None if buf.is_empty() => Ok(None),
+            // The peer went away partway through a frame
+            None => Err(closed_during_handshake(self.state)),
+        }
+    }
 }
 
 impl Encoder for InboundHandshakeCodec {
@@ -185,10 +343,13 @@ impl Encoder for InboundHandshakeCodec {
 
         let res = match (self.state, frame) {
             (HandshakeState::SessionCreated, HandshakeFrame::SessionCreated(ref sc)) => {
-                // Set up cryptor
-                let session_key = self
-                    .dh_key_builder
-                    .build_session_key(array_ref![self.dh_x, 0, 256]);
+                // Set up cryptor, using the session key if it was derived for us
+                let session_key = match self.session_key.take() {
+                    Some(session_key) => session_key,
+                    None => self
+                        .dh_key_builder
+                        .build_session_key(array_ref![self.dh_x, 0, 256]),
+                };
                 self.aes = Some(Aes256::new(&session_key, &self.iv_enc, &self.iv_dec));
                 // Serialise inner part of SessionCreated
                 let mut tmp = [0u8; 48];
@@ -247,6 +408,10 @@ impl Encoder for InboundHandshakeCodec {
 
         match res {
             Ok(()) => {
+                if let Some(ref tap) = self.wire_tap {
+                    tap.observe(Direction::Outbound, &buf[start..]);
+                }
+
                 // Update the state machine
                 self.state = match self.state {
                     HandshakeState::SessionCreated => HandshakeState::SessionConfirmA,
@@ -273,11 +438,15 @@ impl Encoder for InboundHandshakeCodec {
 
 pub struct OutboundHandshakeCodec {
     dh_key_builder: DHSessionKeyBuilder,
+    /// The session key, if it was derived for us before SessionCreated was
+    /// decoded.
+    session_key: Option<SessionKey>,
     iv_enc: [u8; AES_BLOCK_SIZE],
     ri_remote: RouterIdentity,
     state: HandshakeState,
     aes: Option<Aes256>,
     decrypted: usize,
+    wire_tap: Option<WireTap>,
 }
 
 impl OutboundHandshakeCodec {
@@ -285,24 +454,29 @@ impl OutboundHandshakeCodec {
         dh_key_builder: DHSessionKeyBuilder,
         iv_enc: [u8; AES_BLOCK_SIZE],
         ri_remote: RouterIdentity,
+        wire_tap: Option<WireTap>,
     ) -> Self {
         OutboundHandshakeCodec {
             dh_key_builder,
+            session_key: None,
             iv_enc,
             ri_remote,
             state: HandshakeState::SessionRequest,
             aes: None,
             decrypted: 0,
+            wire_tap,
         }
     }
 }
 
-impl From<OutboundHandshakeCodec> for Codec {
-    fn from(established: OutboundHandshakeCodec) -> Self {
-        Codec {
-            aes: established.aes.unwrap(),
-            decrypted: established.decrypted,
-        }
+impl TryFrom<OutboundHandshakeCodec> for Codec {
+    type Error = io::Error;
+
+    fn try_from(established: OutboundHandshakeCodec) -> io::Result<Self> {
+        let aes = established.aes.ok_or_else(incomplete_handshake)?;
+        let mut codec = Codec::new(aes, established.decrypted);
+        codec.wire_tap = established.wire_tap;
+        Ok(codec)
     }
 }
 
@@ -318,16 +492,26 @@ impl Decoder for OutboundHandshakeCodec {
                     match frame::session_created_enc(buf) {
                         Err(Err::Incomplete(_)) => return Ok(None),
                         Err(Err::Error(e)) | Err(Err::Failure(e)) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("parse error: {:?}", e),
-                            ));
+                            return Err(
+                                HandshakeError::ParseFailure(format!("{:?}", e.code)).into()
+                            );
                         }
                         Ok((i, mut sce)) => {
-                            // Set up cryptor
-                            let session_key = self
-                                .dh_key_builder
-                                .build_session_key(array_ref![sce.0, 0, 256]);
+                            if !is_valid_pub(&sce.0) {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "Invalid SessionCreated DH value",
+                                ));
+                            }
+
+                            // Set up cryptor, using the session key if it was
+                            // derived for us
+                            let session_key = match self.session_key.take() {
+                                Some(session_key) => session_key,
+                                None => self
+                                    .dh_key_builder
+                                    .build_session_key(array_ref![sce.0, 0, 256]),
+                            };
                             self.aes = Some(Aes256::new(
                                 &session_key,
                                 &self.iv_enc,
@@ -338,16 +522,17 @@ impl Decoder for OutboundHandshakeCodec {
                                 Some(end) if end == sce.1.len() => {
                                     match frame::session_created_dec(&sce.1) {
                                         Err(Err::Incomplete(_)) => {
-                                            return Err(io::Error::new(
-                                                io::ErrorKind::Other,
-                                                "incomplete parse error".to_string(),
-                                            ));
+                                            return Err(HandshakeError::ParseFailure(
+                                                "incomplete SessionCreated".to_owned(),
+                                            )
+                                            .into());
                                         }
                                         Err(Err::Error(e)) | Err(Err::Failure(e)) => {
-                                            return Err(io::Error::new(
-                                                io::ErrorKind::Other,
-                                                format!("parse error: {:?}", e),
-                                            ));
+                                            return Err(HandshakeError::ParseFailure(format!(
+                                                "{:?}",
+                                                e.code
+                                            ))
+                                            .into());
                                         }
                                         Ok((_, scd)) => Ok((
                                             i,
@@ -375,6 +560,9 @@ impl Decoder for OutboundHandshakeCodec {
                     }
                 }
                 HandshakeState::SessionConfirmB => {
+                    if let Some(ref tap) = self.wire_tap {
+                        tap.observe_blocks(&buf[self.decrypted..]);
+                    }
                     match self
                         .aes
                         .as_mut()
@@ -393,15 +581,19 @@ impl Decoder for OutboundHandshakeCodec {
             match res {
                 Err(Err::Incomplete(_)) => return Ok(None),
                 Err(Err::Error(e)) | Err(Err::Failure(e)) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("parse error: {:?}", e),
-                    ));
+                    return Err(HandshakeError::ParseFailure(format!("{:?}", e.code)).into());
                 }
                 Ok((i, frame)) => (buf.offset(i), frame),
             }
         };
 
+        // SessionCreated is decrypted out-of-place
+        if self.state == HandshakeState::SessionCreated {
+            if let Some(ref tap) = self.wire_tap {
+                tap.observe(Direction::Inbound, &buf[..consumed]);
+            }
+        }
+
         // Update the buffer
         buf.split_to(consumed);
         if self.state == HandshakeState::SessionConfirmB {
@@ -417,6 +609,15 @@ impl Decoder for OutboundHandshakeCodec {
 
         Ok(Some(f))
     }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<HandshakeFrame>> {
+        match self.decode(buf)? {
+            Some(f) => Ok(Some(f)),
+            None if buf.is_empty() => Ok(None),
+            // The peer went away partway through a frame
+            None => Err(closed_during_handshake(self.state)),
+        }
+    }
 }
 
 impl Encoder for OutboundHandshakeCodec {
@@ -465,6 +666,10 @@ impl Encoder for OutboundHandshakeCodec {
 
         match res {
             Ok(()) => {
+                if let Some(ref tap) = self.wire_tap {
+                    tap.observe(Direction::Outbound, &buf[start..]);
+                }
+
                 // Update the state machine
                 self.state = match self.state {
                     HandshakeState::SessionRequest => HandshakeState::SessionCreated,
@@ -499,7 +704,8 @@ fn gen_session_confirm_sig_msg(state: &SharedHandshakeState, own_ri: bool) -> Ve
     } else {
         state.ri_remote.as_ref().unwrap()
     };
-    serialize(|input| {
+    let len = state.dh_x.len() + state.dh_y.len() + 32 + 4 + 4;
+    serialize_with_len(len, |input| {
         frame::gen_session_confirm_sig_msg(
             input,
             &state.dh_x,
@@ -511,6 +717,131 @@ fn gen_session_confirm_sig_msg(state: &SharedHandshakeState, own_ri: bool) -> Ve
     })
 }
 
+/// Builds the SessionConfirmB that Bob sends to complete an inbound handshake.
+///
+/// Bob signs X|Y|H(Alice's RouterIdentity)|tsA|tsB, which differs from the
+/// message Alice signed in SessionConfirmA only in whose identity is hashed.
+/// She verifies it with Bob's signing key.
+fn gen_session_confirm_b(state: &SharedHandshakeState) -> io::Result<SessionConfirmB> {
+    let msg = gen_session_confirm_sig_msg(state, false);
+    match state.own_key.sign(&msg) {
+        Ok(sig) => Ok(SessionConfirmB { sig }),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Could not create SessionConfirmB signature",
+        )),
+    }
+}
+
+/// How we treat a peer whose clock differs from our own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SkewPolicy {
+    /// Skews beyond this are logged, but the handshake carries on.
+    pub warn: Duration,
+    /// Skews beyond this fail the handshake.
+    pub fail: Duration,
+}
+
+impl SkewPolicy {
+    /// Fails the handshake on any skew beyond `max_skew`, without warning
+    /// first.
+    pub fn strict(max_skew: Duration) -> Self {
+        SkewPolicy {
+            warn: max_skew,
+            fail: max_skew,
+        }
+    }
+}
+
+impl Default for SkewPolicy {
+    fn default() -> Self {
+        SkewPolicy::strict(DEFAULT_MAX_CLOCK_SKEW)
+    }
+}
+
+/// Checks a peer's timestamp against our own clock. Skews in the warning band
+/// are logged, and only those beyond the fail threshold are errors.
+fn check_skew(peer_ts: u32, now: u32, policy: SkewPolicy) -> io::Result<()> {
+    let skew = u64::from(if peer_ts > now {
+        peer_ts - now
+    } else {
+        now - peer_ts
+    });
+    if skew > policy.fail.as_secs() {
+        return Err(HandshakeError::Skew(skew).into());
+    }
+    if skew > policy.warn.as_secs() {
+        warn!("Peer clock skew is large ({}s), continuing anyway", skew);
+    }
+    Ok(())
+}
+
+/// Checks the handshake timestamps once Bob receives SessionConfirmA.
+///
+/// `ts_a` is Alice's clock when she received SessionCreated, and must be within
+/// the fail threshold of `policy` from Bob's clock `now`. `ts_b` is Bob's clock
+/// when he received SessionRequest; since Alice could only have taken `ts_a`
+/// after that, and SessionConfirmA took at most one more `rtt` to arrive,
+/// anything longer means the handshake was delayed or replayed.
+fn check_timestamps(
+    ts_a: u32,
+    ts_b: u32,
+    now: u32,
+    rtt: Duration,
+    policy: SkewPolicy,
+) -> io::Result<()> {
+    check_skew(ts_a, now, policy)?;
+
+    // Timestamps are rounded to the nearest second
+    let max_elapsed = rtt.as_secs() + 2;
+    if now < ts_b || u64::from(now - ts_b) > max_elapsed {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Handshake timestamps inconsistent with RTT ({} to {} in {:?})",
+                ts_b, now, rtt
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// How Alice adjusts `ts_a` to account for the time SessionConfirmA will take
+/// to reach Bob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampStrategy {
+    /// Add a fixed duration.
+    FixedFudge(Duration),
+    /// Add half of the RTT measured during SessionRequest / SessionCreated.
+    HalfRtt,
+}
+
+impl Default for TimestampStrategy {
+    fn default() -> Self {
+        TimestampStrategy::FixedFudge(Duration::from_millis(500))
+    }
+}
+
+impl TimestampStrategy {
+    /// Returns the timestamp in seconds for the given time and measured RTT.
+    fn timestamp(self, now: SystemTime, rtt: Duration) -> io::Result<u32> {
+        let fudge = match self {
+            TimestampStrategy::FixedFudge(fudge) => fudge,
+            TimestampStrategy::HalfRtt => rtt / 2,
+        };
+        let mut ts = unix_time(now)?;
+        ts.add_assign(fudge);
+        Ok(ts.as_secs() as u32)
+    }
+}
+
+/// Returns the given time in seconds, rounded to the nearest second.
+fn handshake_now(now: SystemTime) -> io::Result<u32> {
+    let mut now = unix_time(now)?;
+    now.add_assign(Duration::from_millis(500));
+    Ok(now.as_secs() as u32)
+}
+
 struct SharedHandshakeState {
     own_ri: RouterIdentity,
     own_key: SigningPrivateKey,
@@ -519,6 +850,10 @@ struct SharedHandshakeState {
     dh_y: Vec<u8>,
     ts_a: u32,
     ts_b: u32,
+    skew_policy: SkewPolicy,
+    rtt: Option<Duration>,
+    /// Where we read the time from, for timestamps and RTT measurement.
+    clock: fn() -> SystemTime,
 }
 
 //
@@ -531,17 +866,33 @@ where
     T: AsyncWrite,
 {
     SessionRequest(StreamFuture<Framed<T, InboundHandshakeCodec>>),
+    SessionKey(
+        DhFuture<SessionKey>,
+        Option<Framed<T, InboundHandshakeCodec>>,
+    ),
     SessionCreated((sink::Send<Framed<T, InboundHandshakeCodec>>, SystemTime)),
     SessionConfirmA((StreamFuture<Framed<T, InboundHandshakeCodec>>, SystemTime)),
     SessionConfirmB(sink::Send<Framed<T, InboundHandshakeCodec>>),
 }
 
+/// The inbound side of the NTCP handshake, the counterpart of [`OBHandshake`].
+///
+/// It drives an accepted connection through the handshake, refusing peers
+/// whose SessionRequest was not meant for us, and resolves to the peer's
+/// RouterIdentity and the connection framed with a data-phase [`Codec`].
+///
+/// SessionRequest doesn't identify Alice: its HXxorHB only shows that she knows
+/// who we are. Her identity is bound to the session by the signature in
+/// SessionConfirmA, which she makes with the signing key of the RouterIdentity
+/// she presents there.
 pub struct IBHandshake<T>
 where
     T: AsyncWrite,
 {
     shared: SharedHandshakeState,
     state: IBHandshakeState<T>,
+    dh_key_builder: DHSessionKeyBuilder,
+    dh_workers: Option<DhWorkers>,
 }
 
 impl<T> IBHandshake<T>
@@ -550,14 +901,42 @@ where
     T: Send + 'static,
 {
     pub fn new(stream: T, own_ri: RouterIdentity, own_key: SigningPrivateKey) -> Self {
+        IBHandshake::with_wire_tap(stream, own_ri, own_key, None)
+    }
+
+    /// Starts an inbound handshake that reports the bytes it reads and writes
+    /// to the given wire tap.
+    pub(super) fn with_wire_tap(
+        stream: T,
+        own_ri: RouterIdentity,
+        own_key: SigningPrivateKey,
+        wire_tap: Option<WireTap>,
+    ) -> Self {
         // Generate a new DH pair
-        let dh_key_builder = DHSessionKeyBuilder::new();
+        IBHandshake::with_dh(
+            stream,
+            own_ri,
+            own_key,
+            DHSessionKeyBuilder::new(),
+            wire_tap,
+        )
+    }
+
+    /// Starts an inbound handshake using the given DH pair, and reporting the
+    /// bytes it reads and writes to the given wire tap.
+    pub(super) fn with_dh(
+        stream: T,
+        own_ri: RouterIdentity,
+        own_key: SigningPrivateKey,
+        dh_key_builder: DHSessionKeyBuilder,
+        wire_tap: Option<WireTap>,
+    ) -> Self {
         let dh_y = dh_key_builder.get_pub();
         let mut iv_enc = [0u8; AES_BLOCK_SIZE];
         iv_enc.copy_from_slice(&dh_y[dh_y.len() - AES_BLOCK_SIZE..]);
 
         // TODO: Find a way to refer to the codec from here, to deduplicate state
-        let codec = InboundHandshakeCodec::new(dh_key_builder, iv_enc);
+        let codec = InboundHandshakeCodec::new(dh_key_builder.clone(), iv_enc, wire_tap);
         let state = IBHandshakeState::SessionRequest(codec.framed(stream).into_future());
         IBHandshake {
             shared: SharedHandshakeState {
@@ -568,17 +947,66 @@ where
                 dh_y,
                 ts_a: 0,
                 ts_b: 0,
+                skew_policy: SkewPolicy::default(),
+                rtt: None,
+                clock: SystemTime::now,
             },
             state,
+            dh_key_builder,
+            dh_workers: None,
         }
     }
 
-    fn transmute_framed(framed: Framed<T, InboundHandshakeCodec>) -> Framed<T, Codec> {
+    /// Sets the clock skews we warn about, and those we refuse.
+    pub fn set_skew_policy(&mut self, policy: SkewPolicy) {
+        self.shared.skew_policy = policy;
+    }
+
+    /// Derives the session key on the given workers, instead of on the thread
+    /// polling the handshake.
+    pub fn set_dh_workers(&mut self, dh_workers: DhWorkers) {
+        self.dh_workers = Some(dh_workers);
+    }
+
+    /// Reads the time from the given clock instead of the system clock, so
+    /// that a handshake can be reproduced exactly.
+    pub fn set_clock(&mut self, clock: fn() -> SystemTime) {
+        self.shared.clock = clock;
+    }
+
+    fn send_session_created(
+        shared: &mut SharedHandshakeState,
+        conn: Framed<T, InboundHandshakeCodec>,
+    ) -> io::Result<IBHandshakeState<T>> {
+        let now = (shared.clock)();
+        let mut ts_b = unix_time(now)?;
+        ts_b.add_assign(Duration::from_millis(500));
+        shared.ts_b = ts_b.as_secs() as u32;
+
+        // Part 2
+        debug!("Sending SessionCreated");
+        let mut xy = Vec::from(&shared.dh_x[..]);
+        xy.extend_from_slice(&shared.dh_y);
+        let sc = HandshakeFrame::SessionCreated(SessionCreated {
+            dh_y: shared.dh_y.clone(),
+            hash: Hash::digest(&xy),
+            ts_b: shared.ts_b,
+        });
+
+        Ok(IBHandshakeState::SessionCreated((conn.send(sc), now)))
+    }
+
+    fn transmute_framed(
+        framed: Framed<T, InboundHandshakeCodec>,
+        rtt: Option<Duration>,
+    ) -> io::Result<Framed<T, Codec>> {
         let parts = framed.into_parts();
-        let mut new_parts = FramedParts::new(parts.io, Codec::from(parts.codec));
+        let mut codec = Codec::try_from(parts.codec)?;
+        codec.rtt = rtt;
+        let mut new_parts = FramedParts::new(parts.io, codec);
         new_parts.read_buf = parts.read_buf;
         new_parts.write_buf = parts.write_buf;
-        Framed::from_parts(new_parts)
+        Ok(Framed::from_parts(new_parts))
     }
 }
 
@@ -603,30 +1031,29 @@ where
                     let mut hxxorhb = Hash::digest(&sr.dh_x[..]);
                     hxxorhb.xor(&self.shared.own_ri.hash());
                     if hxxorhb != sr.hash {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Invalid SessionRequest HXxorHB",
-                        ));
+                        return Err(HandshakeError::InvalidHash("SessionRequest HXxorHB").into());
                     }
                     // TODO check replays
-                    let now = SystemTime::now();
-                    let mut ts_b = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-                    ts_b.add_assign(Duration::from_millis(500));
                     // Update local state
                     self.shared.dh_x = sr.dh_x;
-                    self.shared.ts_b = ts_b.as_secs() as u32;
 
-                    // Part 2
-                    debug!("Sending SessionCreated");
-                    let mut xy = Vec::from(&self.shared.dh_x[..]);
-                    xy.extend_from_slice(&self.shared.dh_y);
-                    let sc = HandshakeFrame::SessionCreated(SessionCreated {
-                        dh_y: self.shared.dh_y.clone(),
-                        hash: Hash::digest(&xy),
-                        ts_b: self.shared.ts_b,
-                    });
-
-                    IBHandshakeState::SessionCreated((conn.send(sc), now))
+                    match self.dh_workers {
+                        Some(ref workers) => {
+                            let session_key = workers.session_key(
+                                self.dh_key_builder.clone(),
+                                *array_ref![self.shared.dh_x, 0, 256],
+                            );
+                            IBHandshakeState::SessionKey(session_key, Some(conn))
+                        }
+                        None => IBHandshake::send_session_created(&mut self.shared, conn)?,
+                    }
+                }
+                IBHandshakeState::SessionKey(ref mut f, ref mut conn) => {
+                    let session_key = try_ready!(f.poll().map_err(dh_workers_error));
+                    // Hand the session key to the codec for SessionCreated
+                    let mut parts = conn.take().unwrap().into_parts();
+                    parts.codec.session_key = Some(session_key);
+                    IBHandshake::send_session_created(&mut self.shared, Framed::from_parts(parts))?
                 }
                 IBHandshakeState::SessionCreated((ref mut f, rtt_timer)) => {
                     let conn = try_ready!(f.poll());
@@ -638,12 +1065,24 @@ where
                     // Part 3
                     debug!("Received SessionConfirmA");
                     // Get peer skew
-                    let rtt = rtt_timer.elapsed().expect("Time went backwards?");
+                    let now = (self.shared.clock)();
+                    let rtt = clock_elapsed(rtt_timer, now)?;
                     debug!("Peer RTT: {:?}", rtt);
+                    self.shared.rtt = Some(rtt);
+                    // Check the timestamps
+                    check_timestamps(
+                        sca.ts_a,
+                        self.shared.ts_b,
+                        handshake_now(now)?,
+                        rtt,
+                        self.shared.skew_policy,
+                    )?;
                     // Update local state
                     self.shared.ri_remote = Some(sca.ri_a);
                     self.shared.ts_a = sca.ts_a;
-                    // Generate message to be verified
+                    // Generate message to be verified. Alice signs it with the
+                    // key of the identity she presented, which binds that
+                    // identity to this session.
                     let msg = gen_session_confirm_sig_msg(&self.shared, true);
                     if let Err(e) = self
                         .shared
@@ -653,26 +1092,13 @@ where
                         .signing_key
                         .verify(&msg, &sca.sig)
                     {
-                        return Err(io::Error::new(
-                            io::ErrorKind::ConnectionRefused,
-                            format!("Invalid SessionConfirmA signature: {:?}", e),
-                        ));
+                        debug!("Invalid SessionConfirmA signature: {:?}", e);
+                        return Err(HandshakeError::BadSignature("SessionConfirmA").into());
                     }
 
                     // Part 4
                     debug!("Sending SessionConfirmB");
-                    // Generate message to be signed
-                    let msg = gen_session_confirm_sig_msg(&self.shared, false);
-                    let sig = match self.shared.own_key.sign(&msg) {
-                        Ok(sig) => sig,
-                        Err(_) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "Could not create SessionConfirmB signature",
-                            ));
-                        }
-                    };
-                    let scb = HandshakeFrame::SessionConfirmB(SessionConfirmB { sig });
+                    let scb = HandshakeFrame::SessionConfirmB(gen_session_confirm_b(&self.shared)?);
 
                     IBHandshakeState::SessionConfirmB(conn.send(scb))
                 }
@@ -680,7 +1106,7 @@ where
                     let conn = try_ready!(f.poll());
                     return Ok(Async::Ready((
                         self.shared.ri_remote.take().unwrap(),
-                        IBHandshake::transmute_framed(conn),
+                        IBHandshake::transmute_framed(conn, self.shared.rtt)?,
                     )));
                 }
             }
@@ -698,17 +1124,32 @@ where
     T: AsyncWrite,
 {
     SessionRequest((sink::Send<Framed<T, OutboundHandshakeCodec>>, SystemTime)),
+    /// Reading Y from SessionCreated, so that the session key can be derived on
+    /// the DH workers before the rest of the frame is decoded.
+    SessionCreatedY((Option<FramedParts<T, OutboundHandshakeCodec>>, SystemTime)),
+    SessionKey(
+        (
+            DhFuture<SessionKey>,
+            Option<FramedParts<T, OutboundHandshakeCodec>>,
+            SystemTime,
+        ),
+    ),
     SessionCreated((StreamFuture<Framed<T, OutboundHandshakeCodec>>, SystemTime)),
     SessionConfirmA(sink::Send<Framed<T, OutboundHandshakeCodec>>),
-    SessionConfirmB(StreamFuture<Framed<T, OutboundHandshakeCodec>>),
+    SessionConfirmB((StreamFuture<Framed<T, OutboundHandshakeCodec>>, Delay)),
 }
 
+/// The outbound side of the NTCP handshake, which resolves to the peer's
+/// RouterIdentity and the connection framed with a data-phase [`Codec`].
 pub struct OBHandshake<T>
 where
     T: AsyncWrite,
 {
     shared: SharedHandshakeState,
     state: OBHandshakeState<T>,
+    ts_strategy: TimestampStrategy,
+    confirm_b_timeout: Duration,
+    dh_workers: Option<DhWorkers>,
 }
 
 impl<T> OBHandshake<T>
@@ -723,24 +1164,41 @@ where
         ri_remote: RouterIdentity,
     ) -> Self {
         // Generate a new DH pair
-        let dh_key_builder = DHSessionKeyBuilder::new();
+        OBHandshake::with_dh(
+            stream,
+            own_ri,
+            own_key,
+            ri_remote,
+            DHSessionKeyBuilder::new(),
+            None,
+        )
+    }
+
+    /// Starts a handshake using the given DH pair instead of a fresh one, and
+    /// reporting the bytes it reads and writes to the given wire tap.
+    pub(super) fn with_dh(
+        stream: T,
+        own_ri: RouterIdentity,
+        own_key: SigningPrivateKey,
+        ri_remote: RouterIdentity,
+        dh_key_builder: DHSessionKeyBuilder,
+        wire_tap: Option<WireTap>,
+    ) -> Self {
         let dh_x = dh_key_builder.get_pub();
-        let mut hxxorhb = Hash::digest(&dh_x[..]);
-        hxxorhb.xor(&ri_remote.hash());
-        let mut iv_enc = [0u8; AES_BLOCK_SIZE];
-        iv_enc.copy_from_slice(&hxxorhb.0[AES_BLOCK_SIZE..]);
+        let sr = SessionRequest::new(dh_x.clone(), &ri_remote.hash());
+        let iv_enc = sr.iv();
 
         // TODO: Find a way to refer to the codec from here, to deduplicate state
-        let codec = OutboundHandshakeCodec::new(dh_key_builder, iv_enc, ri_remote.clone());
+        let codec =
+            OutboundHandshakeCodec::new(dh_key_builder, iv_enc, ri_remote.clone(), wire_tap);
         let conn = codec.framed(stream);
 
         // Part 1
         debug!("Sending SessionRequest");
-        let sr = HandshakeFrame::SessionRequest(SessionRequest {
-            dh_x: dh_x.clone(),
-            hash: hxxorhb,
-        });
-        let state = OBHandshakeState::SessionRequest((conn.send(sr), SystemTime::now()));
+        let state = OBHandshakeState::SessionRequest((
+            conn.send(HandshakeFrame::SessionRequest(sr)),
+            SystemTime::now(),
+        ));
 
         OBHandshake {
             shared: SharedHandshakeState {
@@ -751,17 +1209,59 @@ where
                 dh_y: vec![],
                 ts_a: 0,
                 ts_b: 0,
+                skew_policy: SkewPolicy::default(),
+                rtt: None,
+                clock: SystemTime::now,
             },
             state,
+            ts_strategy: TimestampStrategy::default(),
+            confirm_b_timeout: CONFIRM_B_TIMEOUT,
+            dh_workers: None,
         }
     }
 
-    fn transmute_framed(framed: Framed<T, OutboundHandshakeCodec>) -> Framed<T, Codec> {
+    /// Sets the clock skews we warn about, and those we refuse.
+    pub fn set_skew_policy(&mut self, policy: SkewPolicy) {
+        self.shared.skew_policy = policy;
+    }
+
+    /// Derives the session key on the given workers, instead of on the thread
+    /// polling the handshake.
+    pub fn set_dh_workers(&mut self, dh_workers: DhWorkers) {
+        self.dh_workers = Some(dh_workers);
+    }
+
+    /// Sets how long we wait for SessionConfirmB after sending SessionConfirmA.
+    pub fn set_confirm_b_timeout(&mut self, timeout: Duration) {
+        self.confirm_b_timeout = timeout;
+    }
+
+    /// Sets how `ts_a` is adjusted for the time SessionConfirmA takes to arrive.
+    pub fn set_timestamp_strategy(&mut self, ts_strategy: TimestampStrategy) {
+        self.ts_strategy = ts_strategy;
+    }
+
+    /// Reads the time from the given clock instead of the system clock, so
+    /// that a handshake can be reproduced exactly.
+    pub fn set_clock(&mut self, clock: fn() -> SystemTime) {
+        self.shared.clock = clock;
+        // The RTT timer started when we queued SessionRequest
+        if let OBHandshakeState::SessionRequest((_, ref mut rtt_timer)) = self.state {
+            *rtt_timer = clock();
+        }
+    }
+
+    fn transmute_framed(
+        framed: Framed<T, OutboundHandshakeCodec>,
+        rtt: Option<Duration>,
+    ) -> io::Result<Framed<T, Codec>> {
         let parts = framed.into_parts();
-        let mut new_parts = FramedParts::new(parts.io, Codec::from(parts.codec));
+        let mut codec = Codec::try_from(parts.codec)?;
+        codec.rtt = rtt;
+        let mut new_parts = FramedParts::new(parts.io, codec);
         new_parts.read_buf = parts.read_buf;
         new_parts.write_buf = parts.write_buf;
-        Framed::from_parts(new_parts)
+        Ok(Framed::from_parts(new_parts))
     }
 }
 
@@ -778,6 +1278,36 @@ where
             self.state = match self.state {
                 OBHandshakeState::SessionRequest((ref mut f, rtt_timer)) => {
                     let conn = try_ready!(f.poll());
+                    match self.dh_workers {
+                        Some(_) => {
+                            OBHandshakeState::SessionCreatedY((Some(conn.into_parts()), rtt_timer))
+                        }
+                        None => OBHandshakeState::SessionCreated((conn.into_future(), rtt_timer)),
+                    }
+                }
+                OBHandshakeState::SessionCreatedY((ref mut parts, rtt_timer)) => {
+                    let p = parts.as_mut().unwrap();
+                    while p.read_buf.len() < 256 {
+                        let mut buf = [0u8; 256];
+                        let want = 256 - p.read_buf.len();
+                        let n = try_ready!(p.io.poll_read(&mut buf[..want]));
+                        if n == 0 {
+                            return Err(closed_during_handshake(HandshakeState::SessionCreated));
+                        }
+                        p.read_buf.extend_from_slice(&buf[..n]);
+                    }
+                    let session_key = self.dh_workers.as_ref().unwrap().session_key(
+                        p.codec.dh_key_builder.clone(),
+                        *array_ref![p.read_buf, 0, 256],
+                    );
+                    OBHandshakeState::SessionKey((session_key, parts.take(), rtt_timer))
+                }
+                OBHandshakeState::SessionKey((ref mut f, ref mut parts, rtt_timer)) => {
+                    let session_key = try_ready!(f.poll().map_err(dh_workers_error));
+                    // Hand the session key to the codec for SessionCreated
+                    let mut parts = parts.take().unwrap();
+                    parts.codec.session_key = Some(session_key);
+                    let conn = Framed::from_parts(parts);
                     OBHandshakeState::SessionCreated((conn.into_future(), rtt_timer))
                 }
                 OBHandshakeState::SessionCreated((ref mut f, rtt_timer)) => {
@@ -786,25 +1316,23 @@ where
                     // Part 2
                     debug!("Received SessionCreated");
                     // Get peer skew
-                    let rtt = rtt_timer.elapsed().expect("Time went backwards?");
+                    let now = (self.shared.clock)();
+                    let rtt = clock_elapsed(rtt_timer, now)?;
                     debug!("Peer RTT: {:?}", rtt);
-                    let now = SystemTime::now();
-                    let mut ts_a = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-                    ts_a.add_assign(Duration::from_millis(500));
+                    self.shared.rtt = Some(rtt);
+                    let ts_a = self.ts_strategy.timestamp(now, rtt)?;
+                    check_skew(sc.ts_b, ts_a, self.shared.skew_policy)?;
                     // Update local state
                     self.shared.dh_y = sc.dh_y;
-                    self.shared.ts_a = ts_a.as_secs() as u32;
+                    self.shared.ts_a = ts_a;
                     self.shared.ts_b = sc.ts_b;
 
                     // Generate message to be signed
                     let msg = gen_session_confirm_sig_msg(&self.shared, false);
                     // Check part 2 (which happens to be hash of first part of signed message)
                     let hxy = Hash::digest(&msg[..512]);
-                    if hxy != sc.hash {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Invalid SessionCreated hash",
-                        ));
+                    if !hxy.ct_eq(&sc.hash) {
+                        return Err(HandshakeError::InvalidHash("SessionCreated hash").into());
                     }
 
                     // Part 3
@@ -827,9 +1355,14 @@ where
                 }
                 OBHandshakeState::SessionConfirmA(ref mut f) => {
                     let conn = try_ready!(f.poll());
-                    OBHandshakeState::SessionConfirmB(conn.into_future())
+                    let timeout = Delay::new(Instant::now() + self.confirm_b_timeout);
+                    OBHandshakeState::SessionConfirmB((conn.into_future(), timeout))
                 }
-                OBHandshakeState::SessionConfirmB(ref mut f) => {
+                OBHandshakeState::SessionConfirmB((ref mut f, ref mut timeout)) => {
+                    // Peers can stall after receiving SessionConfirmA
+                    if let Ok(Async::Ready(())) = timeout.poll() {
+                        return Err(HandshakeError::Timeout("waiting for SessionConfirmB").into());
+                    }
                     let (conn, scb) = try_poll!(f, SessionConfirmB);
 
                     // Part 4
@@ -844,15 +1377,12 @@ where
                         .signing_key
                         .verify(&msg, &scb.sig)
                     {
-                        return Err(io::Error::new(
-                            io::ErrorKind::ConnectionRefused,
-                            format!("Invalid SessionConfirmB signature: {:?}", e),
-                        ));
+                        debug!("Invalid SessionConfirmB signature: {:?}", e);
+                        return Err(HandshakeError::BadSignature("SessionConfirmB").into());
                     }
-
                     return Ok(Async::Ready((
                         self.shared.ri_remote.take().unwrap(),
-                        OBHandshake::transmute_framed(conn),
+                        OBHandshake::transmute_framed(conn, self.shared.rtt)?,
                     )));
                 }
             }
@@ -862,12 +1392,40 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{IBHandshake, IBHandshakeState, OBHandshake, OBHandshakeState};
+    use super::{
+        check_skew, check_timestamps, gen_session_confirm_b, gen_session_confirm_sig_msg,
+        HandshakeError, HandshakeFrame, HandshakeState, IBHandshake, IBHandshakeState,
+        InboundHandshakeCodec, OBHandshake, OBHandshakeState, OutboundHandshakeCodec,
+        SessionConfirmA, SharedHandshakeState, SkewPolicy, TimestampStrategy,
+        DEFAULT_MAX_CLOCK_SKEW,
+    };
     use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
+    use crate::transport::{TransportStyle, TransportVersion};
+
+    use bytes::BytesMut;
+    use futures::{lazy, Async, Future, Sink, Stream};
+    use rand::rngs::mock::StepRng;
+    use std::io::{self, Read};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tokio::{
+        codec::{Decoder, Encoder},
+        runtime::current_thread,
+        timer::Timeout,
+    };
 
-    use futures::{Async, Future};
-
-    use crate::data::RouterSecretKeys;
+    use crate::crypto::{
+        dh::{DHSessionKeyBuilder, DhWorkers},
+        Aes256, SessionKey, SigType, SigningPrivateKey, SigningPublicKey, AES_BLOCK_SIZE,
+    };
+    use crate::data::{frame::router_identity, Hash, I2PDate, RouterIdentity, RouterSecretKeys};
+    use crate::i2np::{Message, MessagePayload};
+    use crate::tests::{assert_bytes_eq, NTCP_HANDSHAKE_ALICE_TO_BOB, NTCP_HANDSHAKE_BOB_TO_ALICE};
+    use crate::transport::ntcp::{
+        testing::make_session_request, Codec, Direction, Frame, WireCapture, WireTap,
+    };
+    use crate::util::serialize;
 
     macro_rules! test_poll {
         ($node:expr) => {
@@ -888,6 +1446,208 @@ mod tests {
         };
     }
 
+    #[test]
+    fn session_request_decode() {
+        let bob = RouterSecretKeys::new().rid.hash();
+        let alice_dh = DHSessionKeyBuilder::new();
+        let dh_x = alice_dh.get_pub();
+
+        let sr = match make_session_request(dh_x.clone(), &bob) {
+            HandshakeFrame::SessionRequest(sr) => sr,
+            _ => panic!("Expected a SessionRequest"),
+        };
+        let mut hxxorhb = Hash::digest(&dh_x);
+        hxxorhb.xor(&bob);
+        assert_eq!(sr.hash, hxxorhb);
+
+        let mut buf = BytesMut::from(serialize(|input| {
+            super::frame::gen_session_request(input, &sr)
+        }));
+        let mut codec = InboundHandshakeCodec::new(DHSessionKeyBuilder::new(), [0; 16], None);
+        match codec.decode(&mut buf) {
+            Ok(Some(HandshakeFrame::SessionRequest(decoded))) => assert_eq!(decoded, sr),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(buf.is_empty());
+        assert_eq!(codec.dh_x, dh_x);
+        assert_eq!(&codec.iv_dec[..], &hxxorhb.0[16..]);
+    }
+
+    #[test]
+    fn incomplete_codec_conversion() {
+        let ib = InboundHandshakeCodec::new(DHSessionKeyBuilder::new(), [0; 16], None);
+        match Codec::try_from(ib) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("Converted an incomplete inbound handshake"),
+        }
+
+        let ob = OutboundHandshakeCodec::new(
+            DHSessionKeyBuilder::new(),
+            [0; 16],
+            RouterSecretKeys::new().rid,
+            None,
+        );
+        match Codec::try_from(ob) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("Converted an incomplete outbound handshake"),
+        }
+    }
+
+    #[test]
+    fn session_confirm_a_byte_at_a_time() {
+        let alice = RouterSecretKeys::new();
+        let bob = RouterSecretKeys::new().rid;
+        let key = SessionKey([7; 32]);
+        let (iv_a, iv_b) = ([1; 16], [2; 16]);
+
+        let mut ob = OutboundHandshakeCodec::new(DHSessionKeyBuilder::new(), [0; 16], bob, None);
+        ob.state = HandshakeState::SessionConfirmA;
+        ob.aes = Some(Aes256::new(&key, &iv_a, &iv_b));
+        let sca = SessionConfirmA {
+            ri_a: alice.rid.clone(),
+            ts_a: 42,
+            sig: alice.signing_private_key.sign(b"message").unwrap(),
+        };
+        let mut ct = BytesMut::new();
+        ob.encode(HandshakeFrame::SessionConfirmA(Box::new(sca)), &mut ct)
+            .unwrap();
+        assert_eq!(ct.len() % AES_BLOCK_SIZE, 0);
+
+        let mut ib = InboundHandshakeCodec::new(DHSessionKeyBuilder::new(), [0; 16], None);
+        ib.state = HandshakeState::SessionConfirmA;
+        ib.aes = Some(Aes256::new(&key, &iv_b, &iv_a));
+
+        // Until the last byte arrives, only whole blocks are decrypted
+        let mut buf = BytesMut::new();
+        for (i, b) in ct.iter().enumerate() {
+            buf.extend_from_slice(&[*b]);
+            match ib.decode(&mut buf) {
+                Ok(None) => {
+                    assert!(i < ct.len() - 1);
+                    assert_eq!(ib.decrypted, (i + 1) / AES_BLOCK_SIZE * AES_BLOCK_SIZE);
+                }
+                Ok(Some(HandshakeFrame::SessionConfirmA(decoded))) => {
+                    assert_eq!(i, ct.len() - 1);
+                    assert_eq!(decoded.ri_a.hash(), alice.rid.hash());
+                    assert_eq!(decoded.ts_a, 42);
+                }
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+        assert!(buf.is_empty());
+        assert_eq!(ib.decrypted, 0);
+        assert_eq!(ib.state, HandshakeState::SessionConfirmB);
+    }
+
+    #[test]
+    fn closed_mid_session_request() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable.clone());
+
+        let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+        let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+
+        // Alice -> SessionRequest, which is cut short before the cable closes
+        test_poll!(alice);
+        {
+            let mut cable = cable.lock().unwrap();
+            let half = cable.alice_to_bob.len() / 2;
+            cable.alice_to_bob.truncate(half);
+            cable.close();
+        }
+
+        // Bob <- half a SessionRequest, then EOF
+        match bob.poll() {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+                assert_eq!(
+                    e.to_string(),
+                    "peer closed connection during handshake (SessionRequest)"
+                );
+            }
+            Ok(_) => panic!("Handshake continued after the peer closed"),
+        }
+    }
+
+    #[test]
+    fn decode_eof_partial_frame() {
+        let mut codec = InboundHandshakeCodec::new(DHSessionKeyBuilder::new(), [0; 16], None);
+
+        // With nothing buffered, EOF just ends the stream
+        let mut buf = BytesMut::new();
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&[0; 100]);
+        let e = codec.decode_eof(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn session_confirm_a_split_across_reads() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable.clone());
+
+        let mut alice = OBHandshake::new(alice_net, alice_rid.clone(), alice_sk, bob_rid.clone());
+        let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+        test_poll!(alice);
+        test_poll!(bob);
+        test_poll!(alice);
+        test_state!(alice, SessionConfirmB, bob, SessionConfirmA);
+
+        // Bob reads SessionConfirmA a few bytes at a time
+        let sca = std::mem::take(&mut cable.lock().unwrap().alice_to_bob);
+        let pieces: Vec<_> = sca.chunks(7).collect();
+        for piece in &pieces[..pieces.len() - 1] {
+            cable.lock().unwrap().alice_to_bob.extend_from_slice(piece);
+            test_poll!(bob);
+        }
+        cable
+            .lock()
+            .unwrap()
+            .alice_to_bob
+            .extend_from_slice(pieces[pieces.len() - 1]);
+        match bob.poll() {
+            Ok(Async::Ready((ri_a, _))) => assert_eq!(ri_a.hash(), alice_rid.hash()),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn session_created_zero_dh_y() {
+        let mut codec = OutboundHandshakeCodec::new(
+            DHSessionKeyBuilder::new(),
+            [0; 16],
+            RouterSecretKeys::new().rid,
+            None,
+        );
+        codec.state = HandshakeState::SessionCreated;
+
+        let mut buf = BytesMut::from(&[0u8; 256 + 48][..]);
+        match codec.decode(&mut buf) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(codec.aes.is_none());
+    }
+
     #[test]
     fn ntcp_handshake() {
         // Generate key material
@@ -900,6 +1660,9 @@ mod tests {
             (sk.rid, sk.signing_private_key)
         };
 
+        let alice_hash = alice_rid.hash();
+        let bob_hash = bob_rid.hash();
+
         // Set up the network
         let cable = NetworkCable::new();
         let alice_net = AliceNet::new(cable.clone());
@@ -931,10 +1694,853 @@ mod tests {
         // Alice <- SessionConfirmB
         let alice_conn = alice.poll();
 
-        // Both halves should now be ready
+        // Both halves should now be ready, with Alice having accepted Bob's
+        // SessionConfirmB signature
+        match (alice_conn, bob_conn) {
+            (Ok(Async::Ready((ri_b, alice_conn))), Ok(Async::Ready((ri_a, bob_conn)))) => {
+                assert_eq!(ri_a.hash(), alice_hash);
+                assert_eq!(ri_b.hash(), bob_hash);
+
+                let ntcp_v1 = TransportVersion {
+                    style: TransportStyle::Ntcp,
+                    version: 1,
+                };
+                assert_eq!(alice_conn.into_parts().codec.transport_version(), ntcp_v1);
+                assert_eq!(bob_conn.into_parts().codec.transport_version(), ntcp_v1);
+            }
+            _ => panic!(),
+        }
+    }
+
+    /// Builds a RouterIdentity and signing key from fixed key material.
+    fn fixed_identity(seed: u8) -> (RouterIdentity, SigningPrivateKey) {
+        let sk = SigningPrivateKey::from_bytes(SigType::Ed25519, &[seed; 32]).unwrap();
+        // ElGamal public key, then KeyCertificate padding before the signing key
+        let mut data = vec![seed; 256 + 96];
+        data.extend_from_slice(SigningPublicKey::from_secret(&sk).unwrap().as_bytes());
+        // KeyCertificate for Ed25519 and ElGamal
+        data.extend_from_slice(&[5, 0, 4, 0, 7, 0, 0]);
+        let (_, rid) = router_identity(&data).unwrap();
+        (rid, sk)
+    }
+
+    fn fixed_clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    }
+
+    #[test]
+    fn ntcp_handshake_golden() {
+        let (alice_rid, alice_sk) = fixed_identity(0xa1);
+        let (bob_rid, bob_sk) = fixed_identity(0xb2);
+        let alice_dh = DHSessionKeyBuilder::from_rng(&mut StepRng::new(
+            0x0123_4567_89ab_cdef,
+            0x1111_1111_1111_1111,
+        ));
+        let bob_dh = DHSessionKeyBuilder::from_rng(&mut StepRng::new(
+            0xfedc_ba98_7654_3210,
+            0x0101_0101_0101_0101,
+        ));
+
+        // Capture everything each side puts on and takes off the wire
+        let alice_capture = WireCapture::new();
+        let bob_capture = WireCapture::new();
+        let alice_tap = WireTap(Arc::new(Mutex::new(alice_capture.tap())));
+        let bob_tap = WireTap(Arc::new(Mutex::new(bob_capture.tap())));
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        let mut alice = OBHandshake::with_dh(
+            alice_net,
+            alice_rid,
+            alice_sk,
+            bob_rid.clone(),
+            alice_dh,
+            Some(alice_tap),
+        );
+        let mut bob = IBHandshake::with_dh(bob_net, bob_rid, bob_sk, bob_dh, Some(bob_tap));
+        alice.set_clock(fixed_clock);
+        bob.set_clock(fixed_clock);
+
+        test_poll!(alice);
+        test_poll!(bob);
+        test_poll!(alice);
+        let bob_conn = bob.poll();
+        let alice_conn = alice.poll();
         match (alice_conn, bob_conn) {
             (Ok(Async::Ready(_)), Ok(Async::Ready(_))) => (),
+            _ => panic!("Handshake did not complete"),
+        }
+
+        // Any change to how the handshake is serialized shows up here
+        assert_bytes_eq(NTCP_HANDSHAKE_ALICE_TO_BOB, &alice_capture.outbound());
+        assert_bytes_eq(NTCP_HANDSHAKE_BOB_TO_ALICE, &bob_capture.outbound());
+        assert_bytes_eq(&alice_capture.outbound(), &bob_capture.inbound());
+        assert_bytes_eq(&bob_capture.outbound(), &alice_capture.inbound());
+    }
+
+    #[test]
+    fn ntcp_handshake_dh_workers() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let alice_hash = alice_rid.hash();
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        // Polling the session key requires a task
+        lazy(move || {
+            let workers = DhWorkers::new(1);
+            let bob_dh = workers.keypair().wait().unwrap();
+
+            let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+            let mut bob = IBHandshake::with_dh(bob_net, bob_rid, bob_sk, bob_dh, None);
+            alice.set_dh_workers(workers.clone());
+            bob.set_dh_workers(workers);
+
+            // Alice -> SessionRequest
+            test_poll!(alice);
+
+            // Bob <- SessionRequest, and derives the session key on the workers
+            // Bob -> SessionCreated, once the session key is ready
+            test_poll!(bob);
+            let start = Instant::now();
+            while let IBHandshakeState::SessionKey(..) = bob.state {
+                assert!(start.elapsed() < Duration::from_secs(60));
+                thread::sleep(Duration::from_millis(10));
+                test_poll!(bob);
+            }
+            test_state!(alice, SessionCreatedY, bob, SessionConfirmA);
+
+            // Alice <- Y from SessionCreated, and derives the session key on the
+            // workers
+            // Alice -> SessionConfirmA, once the session key is ready
+            test_poll!(alice);
+            while let OBHandshakeState::SessionKey(..) = alice.state {
+                assert!(start.elapsed() < Duration::from_secs(60));
+                thread::sleep(Duration::from_millis(10));
+                test_poll!(alice);
+            }
+            test_state!(alice, SessionConfirmB, bob, SessionConfirmA);
+
+            // The rest of the handshake is unchanged
+            let bob_conn = bob.poll();
+            let alice_conn = alice.poll();
+            match (alice_conn, bob_conn) {
+                (Ok(Async::Ready(_)), Ok(Async::Ready((ri_a, _)))) => {
+                    assert_eq!(ri_a.hash(), alice_hash);
+                }
+                _ => panic!(),
+            }
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn handshake_skew() {
+        let max_skew = SkewPolicy::strict(Duration::from_secs(60));
+        assert!(check_skew(1000, 1000, max_skew).is_ok());
+        assert!(check_skew(1060, 1000, max_skew).is_ok());
+        assert!(check_skew(940, 1000, max_skew).is_ok());
+        assert!(check_skew(1061, 1000, max_skew).is_err());
+        assert!(check_skew(939, 1000, max_skew).is_err());
+    }
+
+    #[test]
+    fn handshake_skew_policy() {
+        let policy = SkewPolicy {
+            warn: Duration::from_secs(30),
+            fail: Duration::from_secs(60),
+        };
+
+        // Within the warning threshold
+        assert!(check_skew(1030, 1000, policy).is_ok());
+        assert!(check_skew(970, 1000, policy).is_ok());
+
+        // Warned about, but accepted
+        assert!(check_skew(1031, 1000, policy).is_ok());
+        assert!(check_skew(940, 1000, policy).is_ok());
+
+        // Refused
+        match check_skew(1061, 1000, policy) {
+            Err(e) => match HandshakeError::from_io(&e) {
+                Some(HandshakeError::Skew(61)) => (),
+                e => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Accepted a skew beyond the fail threshold"),
+        }
+
+        // A strict policy has no warning band
+        let strict = SkewPolicy::strict(Duration::from_secs(60));
+        assert!(check_skew(1060, 1000, strict).is_ok());
+        assert!(check_skew(1061, 1000, strict).is_err());
+    }
+
+    #[test]
+    fn session_created_skewed_ts_b() {
+        // Alice's clock runs two minutes ahead of Bob's
+        fn alice_clock() -> SystemTime {
+            fixed_clock() + Duration::from_secs(120)
+        }
+
+        let run = |policy| {
+            let (alice_rid, alice_sk) = {
+                let sk = RouterSecretKeys::new();
+                (sk.rid, sk.signing_private_key)
+            };
+            let (bob_rid, bob_sk) = {
+                let sk = RouterSecretKeys::new();
+                (sk.rid, sk.signing_private_key)
+            };
+            let capture = WireCapture::new();
+            let tap = WireTap(Arc::new(Mutex::new(capture.tap())));
+
+            let cable = NetworkCable::new();
+            let alice_net = AliceNet::new(cable.clone());
+            let bob_net = BobNet::new(cable);
+
+            let mut alice = OBHandshake::with_dh(
+                alice_net,
+                alice_rid,
+                alice_sk,
+                bob_rid.clone(),
+                DHSessionKeyBuilder::new(),
+                Some(tap),
+            );
+            let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+            alice.set_clock(alice_clock);
+            alice.set_skew_policy(policy);
+            bob.set_clock(fixed_clock);
+
+            test_poll!(alice);
+            test_poll!(bob);
+            let res = alice.poll().map(|a| a.map(|_| ()));
+            (res, capture.outbound().len())
+        };
+
+        // Alice gives up before signing SessionConfirmA
+        let (res, sent) = run(SkewPolicy::default());
+        match res {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+                assert_eq!(e.to_string(), "Peer clock skew too large (120s)");
+            }
+            Ok(_) => panic!("Accepted a skewed SessionCreated"),
+        }
+        assert_eq!(sent, 288);
+
+        // With a larger tolerance, she carries on
+        let (res, sent) = run(SkewPolicy::strict(Duration::from_secs(180)));
+        assert!(matches!(res, Ok(Async::NotReady)));
+        assert!(sent > 288);
+
+        // The skew is in the warning band, so she carries on
+        let (res, sent) = run(SkewPolicy {
+            warn: DEFAULT_MAX_CLOCK_SKEW,
+            fail: Duration::from_secs(180),
+        });
+        assert!(matches!(res, Ok(Async::NotReady)));
+        assert!(sent > 288);
+
+        // The skew is past the fail threshold, so she gives up
+        let (res, sent) = run(SkewPolicy {
+            warn: DEFAULT_MAX_CLOCK_SKEW,
+            fail: Duration::from_secs(90),
+        });
+        match res {
+            Err(e) => match HandshakeError::from_io(&e) {
+                Some(HandshakeError::Skew(120)) => (),
+                e => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Accepted a skewed SessionCreated"),
+        }
+        assert_eq!(sent, 288);
+    }
+
+    #[test]
+    fn handshake_skew_warning() {
+        // Alice's clock runs 45s ahead of Bob's
+        fn alice_clock() -> SystemTime {
+            fixed_clock() + Duration::from_secs(45)
+        }
+
+        let warn = SkewPolicy {
+            warn: Duration::from_secs(30),
+            fail: Duration::from_secs(60),
+        };
+
+        let run = |bob_policy| {
+            let (alice_rid, alice_sk) = {
+                let sk = RouterSecretKeys::new();
+                (sk.rid, sk.signing_private_key)
+            };
+            let (bob_rid, bob_sk) = {
+                let sk = RouterSecretKeys::new();
+                (sk.rid, sk.signing_private_key)
+            };
+            let cable = NetworkCable::new();
+            let alice_net = AliceNet::new(cable.clone());
+            let bob_net = BobNet::new(cable);
+
+            let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+            let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+            alice.set_clock(alice_clock);
+            alice.set_skew_policy(warn);
+            bob.set_clock(fixed_clock);
+            bob.set_skew_policy(bob_policy);
+
+            test_poll!(alice);
+            test_poll!(bob);
+            test_poll!(alice);
+            bob.poll().map(|res| res.map(|_| ()))
+        };
+
+        // The skew is in the warning band, so the handshake completes
+        assert!(matches!(run(warn), Ok(Async::Ready(()))));
+
+        // Without a warning band, Bob refuses the same skew
+        match run(SkewPolicy::strict(Duration::from_secs(30))) {
+            Err(e) => match HandshakeError::from_io(&e) {
+                Some(HandshakeError::Skew(45)) => (),
+                e => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Accepted a skew beyond the fail threshold"),
+        }
+    }
+
+    #[test]
+    fn handshake_timestamp_strategy() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_000_600);
+        let rtt = Duration::from_millis(200);
+
+        // The default rounds to the nearest second
+        assert_eq!(
+            TimestampStrategy::default().timestamp(now, rtt).unwrap(),
+            1001
+        );
+        assert_eq!(
+            TimestampStrategy::FixedFudge(Duration::from_millis(0))
+                .timestamp(now, rtt)
+                .unwrap(),
+            1000
+        );
+
+        // Half the RTT is added instead
+        assert_eq!(
+            TimestampStrategy::HalfRtt.timestamp(now, rtt).unwrap(),
+            1000
+        );
+        assert_eq!(
+            TimestampStrategy::HalfRtt
+                .timestamp(now, Duration::from_millis(800))
+                .unwrap(),
+            1001
+        );
+
+        // A clock before the epoch fails the handshake instead of panicking
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        assert!(TimestampStrategy::default()
+            .timestamp(before_epoch, rtt)
+            .is_err());
+    }
+
+    #[test]
+    fn handshake_timestamps() {
+        let max_skew = SkewPolicy::strict(Duration::from_secs(60));
+        let rtt = Duration::from_millis(300);
+
+        // Alice's clock is 30s ahead, and the handshake took one RTT
+        assert!(check_timestamps(1030, 1000, 1001, rtt, max_skew).is_ok());
+
+        // Alice's clock is too far ahead
+        assert!(check_timestamps(1070, 1000, 1001, rtt, max_skew).is_err());
+
+        // Both clocks agree, but SessionConfirmA took far longer than the RTT
+        assert!(check_timestamps(1020, 1000, 1020, rtt, max_skew).is_err());
+
+        // SessionConfirmA can't arrive before SessionRequest
+        assert!(check_timestamps(990, 1000, 990, rtt, max_skew).is_err());
+    }
+
+    #[test]
+    fn session_confirm_a_identity_binding() {
+        let run = |alice_rid, alice_sk| {
+            let (bob_rid, bob_sk) = {
+                let sk = RouterSecretKeys::new();
+                (sk.rid, sk.signing_private_key)
+            };
+            let cable = NetworkCable::new();
+            let alice_net = AliceNet::new(cable.clone());
+            let bob_net = BobNet::new(cable);
+
+            let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+            let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+
+            test_poll!(alice);
+            test_poll!(bob);
+            test_poll!(alice);
+            bob.poll().map(|res| res.map(|(ri_a, _)| ri_a.hash()))
+        };
+
+        let x = RouterSecretKeys::new();
+        let y = RouterSecretKeys::new();
+
+        // Alice presents identity X, but signs with the key of identity Y
+        match run(x.rid.clone(), y.signing_private_key) {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+                assert!(e
+                    .to_string()
+                    .starts_with("Invalid SessionConfirmA signature"));
+            }
+            _ => panic!("Accepted SessionConfirmA signed by another identity"),
+        }
+
+        // Signed with the key of the identity she presents, she is accepted as it
+        match run(x.rid.clone(), x.signing_private_key) {
+            Ok(Async::Ready(hash)) => assert_eq!(hash, x.rid.hash()),
+            _ => panic!("Refused a correctly signed SessionConfirmA"),
+        }
+    }
+
+    #[test]
+    fn tampered_handshake_errors() {
+        // Runs a handshake, flipping a byte of the encrypted part of either
+        // SessionCreated or SessionConfirmB before Alice receives it
+        let run = |tamper_confirm_b: bool| {
+            let (alice_rid, alice_sk) = {
+                let sk = RouterSecretKeys::new();
+                (sk.rid, sk.signing_private_key)
+            };
+            let (bob_rid, bob_sk) = {
+                let sk = RouterSecretKeys::new();
+                (sk.rid, sk.signing_private_key)
+            };
+            let cable = NetworkCable::new();
+            let alice_net = AliceNet::new(cable.clone());
+            let bob_net = BobNet::new(cable.clone());
+
+            let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+            let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+
+            test_poll!(alice);
+            test_poll!(bob);
+            if tamper_confirm_b {
+                test_poll!(alice);
+                assert!(bob.poll().unwrap().is_ready());
+                cable.lock().unwrap().bob_to_alice[0] ^= 0xff;
+            } else {
+                // The encrypted hash follows Y
+                cable.lock().unwrap().bob_to_alice[256] ^= 0xff;
+            }
+            match alice.poll() {
+                Err(e) => e,
+                Ok(_) => panic!("Accepted a tampered handshake"),
+            }
+        };
+
+        let e = run(false);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        match HandshakeError::from_io(&e) {
+            Some(HandshakeError::InvalidHash(_)) => (),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        let e = run(true);
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        match HandshakeError::from_io(&e) {
+            Some(HandshakeError::BadSignature("SessionConfirmB")) => (),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ntcp_confirm_b_timeout() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        let res = current_thread::block_on_all(
+            lazy(move || {
+                let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+                alice.set_confirm_b_timeout(Duration::from_millis(50));
+                let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+
+                // Alice -> SessionRequest
+                test_poll!(alice);
+                // Bob <- SessionRequest
+                // Bob -> SessionCreated
+                test_poll!(bob);
+                // Alice <- SessionCreated
+                // Alice -> SessionConfirmA
+                test_poll!(alice);
+                test_state!(alice, SessionConfirmB, bob, SessionConfirmA);
+
+                // Bob never sends SessionConfirmB
+                Ok(alice)
+            })
+            .and_then(|alice| alice),
+        );
+
+        match res {
+            Ok(_) => panic!("Handshake completed without SessionConfirmB"),
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(e.to_string(), "timeout waiting for SessionConfirmB");
+            }
+        }
+    }
+
+    #[test]
+    fn ntcp_handshake_total_loss() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+
+        // Nothing makes it across the cable
+        let cable = NetworkCable::with_conditions(Duration::from_millis(0), 1.0);
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        let alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+        let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+
+        let res = current_thread::block_on_all(Timeout::new(alice, Duration::from_millis(100)));
+        match res {
+            Ok(_) => panic!("Handshake completed over a dead cable"),
+            Err(e) => assert!(e.is_elapsed()),
+        }
+
+        // Bob never saw the SessionRequest
+        current_thread::block_on_all(lazy(move || {
+            test_poll!(bob);
+            match bob.state {
+                IBHandshakeState::SessionRequest(_) => Ok::<(), ()>(()),
+                _ => panic!("Bob received a lost SessionRequest"),
+            }
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn ntcp_handshake_latency() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+
+        let latency = Duration::from_millis(50);
+        let cable = NetworkCable::with_conditions(latency, 0.0);
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+        let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+
+        // Alice -> SessionRequest
+        test_poll!(alice);
+
+        // Bob sees nothing until the latency has passed
+        test_poll!(bob);
+        test_state!(alice, SessionCreated, bob, SessionRequest);
+        thread::sleep(latency);
+
+        // Bob <- SessionRequest
+        // Bob -> SessionCreated
+        test_poll!(bob);
+        test_state!(alice, SessionCreated, bob, SessionConfirmA);
+        thread::sleep(latency);
+
+        // Alice <- SessionCreated
+        // Alice -> SessionConfirmA
+        test_poll!(alice);
+        test_state!(alice, SessionConfirmB, bob, SessionConfirmA);
+        thread::sleep(latency);
+
+        // Bob <- SessionConfirmA
+        // Bob -> SessionConfirmB
+        let bob_conn = bob.poll();
+        thread::sleep(latency);
+
+        // Alice <- SessionConfirmB
+        let alice_conn = alice.poll();
+
+        // Each side's RTT covers a trip across the cable and back
+        match (alice_conn, bob_conn) {
+            (Ok(Async::Ready((_, alice_conn))), Ok(Async::Ready((_, bob_conn)))) => {
+                let alice_rtt = alice_conn.into_parts().codec.rtt.unwrap();
+                let bob_rtt = bob_conn.into_parts().codec.rtt.unwrap();
+                assert!(alice_rtt >= latency * 2);
+                assert!(bob_rtt >= latency * 2);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn ntcp_session_confirm_b() {
+        let alice_keys = RouterSecretKeys::new();
+        let bob_keys = RouterSecretKeys::new();
+        let dh_x = DHSessionKeyBuilder::new().get_pub();
+        let dh_y = DHSessionKeyBuilder::new().get_pub();
+
+        let alice = SharedHandshakeState {
+            own_ri: alice_keys.rid.clone(),
+            own_key: alice_keys.signing_private_key.clone(),
+            ri_remote: Some(bob_keys.rid.clone()),
+            dh_x: dh_x.clone(),
+            dh_y: dh_y.clone(),
+            ts_a: 1234,
+            ts_b: 5678,
+            skew_policy: SkewPolicy::default(),
+            rtt: None,
+            clock: SystemTime::now,
+        };
+        let bob = SharedHandshakeState {
+            own_ri: bob_keys.rid.clone(),
+            own_key: bob_keys.signing_private_key,
+            ri_remote: Some(alice_keys.rid),
+            dh_x,
+            dh_y,
+            ts_a: 1234,
+            ts_b: 5678,
+            skew_policy: SkewPolicy::default(),
+            rtt: None,
+            clock: SystemTime::now,
+        };
+
+        // Alice accepts Bob's signature
+        let scb = gen_session_confirm_b(&bob).unwrap();
+        let msg = gen_session_confirm_sig_msg(&alice, true);
+        assert_eq!(bob_keys.rid.signing_key.verify(&msg, &scb.sig), Ok(()));
+
+        // But not if the handshake state differs
+        let mut other = alice;
+        other.ts_b += 1;
+        let msg = gen_session_confirm_sig_msg(&other, true);
+        assert!(bob_keys.rid.signing_key.verify(&msg, &scb.sig).is_err());
+    }
+
+    type Seen = Arc<Mutex<Vec<(Direction, usize)>>>;
+
+    fn recording_tap() -> (WireTap, Seen) {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_tap = seen.clone();
+        let tap = WireTap(Arc::new(Mutex::new(Box::new(
+            move |dir: Direction, bytes: &[u8]| seen_tap.lock().unwrap().push((dir, bytes.len())),
+        ))));
+        (tap, seen)
+    }
+
+    fn total(seen: &[(Direction, usize)], direction: Direction) -> usize {
+        seen.iter()
+            .filter(|(dir, _)| *dir == direction)
+            .map(|(_, len)| len)
+            .sum()
+    }
+
+    #[test]
+    fn ntcp_wire_tap() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (alice_tap, alice_seen) = recording_tap();
+        let (bob_tap, bob_seen) = recording_tap();
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        let mut alice = OBHandshake::with_dh(
+            alice_net,
+            alice_rid,
+            alice_sk,
+            bob_rid.clone(),
+            DHSessionKeyBuilder::new(),
+            Some(alice_tap),
+        );
+        let mut bob = IBHandshake::with_wire_tap(bob_net, bob_rid, bob_sk, Some(bob_tap));
+
+        // Run the handshake
+        test_poll!(alice);
+        test_poll!(bob);
+        test_poll!(alice);
+        let bob_conn = bob.poll();
+        let alice_conn = alice.poll();
+        let (alice_conn, bob_conn) = match (alice_conn, bob_conn) {
+            (Ok(Async::Ready((_, a))), Ok(Async::Ready((_, b)))) => (a, b),
+            _ => panic!(),
+        };
+
+        // The SessionRequest is seen on both sides
+        assert_eq!(alice_seen.lock().unwrap()[0], (Direction::Outbound, 288));
+        assert_eq!(bob_seen.lock().unwrap()[0], (Direction::Inbound, 288));
+
+        // Send a data frame
+        alice_conn
+            .send(Frame::Standard(Message::dummy_data()))
+            .wait()
+            .unwrap();
+        match bob_conn.into_future().wait() {
+            Ok((Some(Frame::Standard(_)), _)) => (),
             _ => panic!(),
         }
+        assert_eq!(
+            alice_seen.lock().unwrap().last(),
+            Some(&(Direction::Outbound, 48))
+        );
+
+        // Each side saw every byte the other sent
+        let alice_seen = alice_seen.lock().unwrap();
+        let bob_seen = bob_seen.lock().unwrap();
+        assert_eq!(
+            total(&alice_seen, Direction::Outbound),
+            total(&bob_seen, Direction::Inbound)
+        );
+        assert_eq!(
+            total(&bob_seen, Direction::Outbound),
+            total(&alice_seen, Direction::Inbound)
+        );
+    }
+
+    #[test]
+    fn first_data_frames_after_handshake() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let (bob_rid, bob_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
+        let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+
+        let data_frame = |id: u32, len: usize| {
+            Frame::Standard(Message {
+                id,
+                expiration: I2PDate(0x123_4567_87c0),
+                payload: MessagePayload::Data((0..len).map(|i| i as u8).collect()),
+                raw: None,
+            })
+        };
+        let assert_data = |frame: Option<Frame>, id: u32, len: usize| match frame {
+            Some(Frame::Standard(Message {
+                id: got,
+                payload: MessagePayload::Data(data),
+                ..
+            })) => {
+                assert_eq!(got, id);
+                assert_eq!(data, (0..len).map(|i| i as u8).collect::<Vec<_>>());
+            }
+            _ => panic!("Expected a data frame"),
+        };
+
+        test_poll!(alice);
+        test_poll!(bob);
+        test_poll!(alice);
+        let bob_conn = match bob.poll() {
+            Ok(Async::Ready((_, conn))) => conn,
+            _ => panic!(),
+        };
+
+        // Bob's first data frame is sent straight after SessionConfirmB, so it
+        // can arrive in the same read. It must be decrypted with the CBC state
+        // that SessionConfirmB left behind.
+        let bob_conn = bob_conn.send(data_frame(1, 37)).wait().unwrap();
+        let alice_conn = match alice.poll() {
+            Ok(Async::Ready((_, conn))) => conn,
+            _ => panic!(),
+        };
+        let (frame, alice_conn) = alice_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_data(frame, 1, 37);
+
+        // Alice's first data frame chains from the end of SessionConfirmA
+        let alice_conn = alice_conn.send(data_frame(2, 21)).wait().unwrap();
+        let (frame, bob_conn) = bob_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_data(frame, 2, 21);
+
+        // The chaining continues for later frames in both directions
+        let alice_conn = alice_conn.send(data_frame(3, 100)).wait().unwrap();
+        let (frame, bob_conn) = bob_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_data(frame, 3, 100);
+        bob_conn.send(data_frame(4, 5)).wait().unwrap();
+        let (frame, _) = alice_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_data(frame, 4, 5);
+    }
+
+    #[test]
+    fn ntcp_handshake_with_dh() {
+        let (alice_rid, alice_sk) = {
+            let sk = RouterSecretKeys::new();
+            (sk.rid, sk.signing_private_key)
+        };
+        let bob_rid = RouterSecretKeys::new().rid;
+        let dh = DHSessionKeyBuilder::new();
+
+        let mut sent = vec![];
+        for _ in 0..2 {
+            let cable = NetworkCable::new();
+            let alice_net = AliceNet::new(cable.clone());
+            let mut bob_net = BobNet::new(cable);
+
+            // Alice -> SessionRequest
+            let mut alice = OBHandshake::with_dh(
+                alice_net,
+                alice_rid.clone(),
+                alice_sk.clone(),
+                bob_rid.clone(),
+                dh.clone(),
+                None,
+            );
+            test_poll!(alice);
+
+            let mut received = vec![];
+            assert!(bob_net.read_to_end(&mut received).is_err());
+            assert_eq!(received.len(), 288);
+            sent.push(received);
+        }
+
+        // The same dh_x was sent both times
+        assert_eq!(&sent[0][..256], &dh.get_pub()[..]);
+        assert_eq!(sent[0], sent[1]);
     }
 }
@@ -5,30 +5,46 @@
 use bytes::BytesMut;
 use cookie_factory::GenError;
 use futures::{
+    future::{self, Either},
     stream::{SplitSink, SplitStream},
-    sync::mpsc,
+    sync::{mpsc, oneshot},
     try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
 };
-use nom::{Err, Offset};
+use log::Level;
+use nom::Err;
+use rand::{rngs::OsRng, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::iter::repeat;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::{
+    clock,
     codec::{Decoder, Encoder, Framed},
     io::{self, AsyncRead, AsyncWrite},
     net::tcp::{TcpListener, TcpStream},
     spawn,
-    timer::Timeout,
+    timer::{timeout, Delay, Timeout},
 };
 
 use super::{
-    session::{self, SessionContext, SessionManager, SessionRefs, SessionRx},
-    Bid, Transport,
+    bind_error,
+    session::{
+        self, Keepalive, SessionContext, SessionInfo, SessionManager, SessionRefs, SessionRx,
+        SessionState,
+    },
+    unix_time, Bid, ConnectionDirection, IoFuture, PeerDiagnostics, PoolStats, Transport,
+    TransportStats, TransportStyle, TransportVersion, IP_ERRORS, PEER_ERRORS,
+};
+use crate::crypto::{
+    dh::{DHSessionKeyBuilder, DhError, DhFuture, DhKeyPool, DhWorkers},
+    Aes256, SigningPrivateKey, AES_BLOCK_SIZE,
 };
-use crate::crypto::{Aes256, SigningPrivateKey};
-use crate::data::{Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo};
-use crate::i2np::Message;
+use crate::data::{
+    BandwidthTier, Hash, I2PString, Reachability, RouterAddress, RouterIdentity, RouterInfo,
+};
+use crate::i2np::{DeliveryStatus, HeaderFormat, Message, MessagePayload};
 use crate::router::{
     types::{Distributor, DistributorResult},
     Context,
@@ -38,24 +54,96 @@ use crate::router::{
 mod frame;
 
 mod handshake;
+pub use self::handshake::{HandshakeError, SkewPolicy};
+
+/// Constructors for NTCP handshake messages, for replay and security testing
+/// tools that need to drive a handshake by hand.
+pub mod testing {
+    pub use super::handshake::{HandshakeFrame, SessionRequest};
+    use crate::data::Hash;
+
+    /// Builds the SessionRequest that Alice with the DH public value `dh_x`
+    /// would send to the peer with the given identity hash.
+    pub fn make_session_request(dh_x: Vec<u8>, peer_hash: &Hash) -> HandshakeFrame {
+        HandshakeFrame::SessionRequest(SessionRequest::new(dh_x, peer_hash))
+    }
+}
 
 lazy_static! {
     pub(super) static ref NTCP_STYLE: I2PString = I2PString::new("NTCP");
+    /// Set on an NTCP address whose router accepts fragment frames.
+    static ref NTCP_OPT_FRAG: I2PString = I2PString::new("frag");
+    static ref NTCP_FRAG_VERSION: I2PString = I2PString::new("1");
 }
 
 // Max NTCP message size is 16kB
 const NTCP_MTU: usize = 16384;
 
+// Larger messages are split into fragments. The largest I2NP message, with its
+// 16-byte header and 16-bit size, needs this many.
+const MAX_FRAGMENTS: usize =
+    (16 + 0xffff + frame::MAX_FRAGMENT_PAYLOAD - 1) / frame::MAX_FRAGMENT_PAYLOAD;
+
+// NTCP has no version negotiation, so every connection is version 1
+const NTCP_VERSION: u8 = 1;
+
+// Max concurrent inbound connections from a single IP
+const MAX_INBOUND_PER_IP: usize = 4;
+
+// Max inbound handshakes we will start within INBOUND_HANDSHAKE_WINDOW
+const MAX_INBOUND_HANDSHAKES: usize = 100;
+const INBOUND_HANDSHAKE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of DH keypairs to keep pre-generated for outbound connections.
+const DH_POOL_SIZE: usize = 4;
+
+/// Number of threads to run DH computations on, off the reactor.
+const DH_WORKER_THREADS: usize = 2;
+
+/// Number of encoded bytes a session will queue for its socket before it
+/// stops taking frames from the peer's queue.
+const MAX_UNFLUSHED_BYTES: usize = 2 * NTCP_MTU;
+
+/// How long a self-test waits for the handshake with our own listener.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long we give a handshake to complete, by default
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reply to a peer's time-sync frame if its clock is this far from ours
+const TIMESYNC_REPLY_SKEW: Duration = Duration::from_secs(10);
+
+// Two peers with skewed clocks would otherwise reply to each other forever
+const TIMESYNC_REPLY_INTERVAL: Duration = Duration::from_secs(60);
+
 //
 // Message transport
 //
 
+#[allow(clippy::large_enum_variant)]
 pub enum Frame {
     Standard(Message),
     TimeSync(u32),
 }
 
-use std::fmt;
+impl Frame {
+    /// Returns the serialized I2NP message in a standard frame, if the codec
+    /// that decoded it was set to retain it.
+    pub fn standard_raw(&self) -> Option<&[u8]> {
+        match *self {
+            Frame::Standard(ref msg) => msg.raw_bytes(),
+            Frame::TimeSync(_) => None,
+        }
+    }
+
+    /// Returns the size of the I2NP message in a standard frame, or zero.
+    fn message_size(&self) -> usize {
+        match *self {
+            Frame::Standard(ref msg) => msg.size(),
+            Frame::TimeSync(_) => 0,
+        }
+    }
+}
 
 #[cfg(not(tarpaulin_include))]
 impl fmt::Debug for Frame {
@@ -67,63 +155,294 @@ impl fmt::Debug for Frame {
     }
 }
 
+/// The direction of the bytes handed to a wire tap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// An observer of the bytes read and written by NTCP connections.
+pub type WireTapFn = Box<dyn Fn(Direction, &[u8]) + Send>;
+
+/// A wire tap shared between all the codecs of a `Manager`.
+#[derive(Clone)]
+pub(super) struct WireTap(Arc<Mutex<WireTapFn>>);
+
+impl WireTap {
+    fn observe(&self, direction: Direction, bytes: &[u8]) {
+        (self.0.lock().unwrap())(direction, bytes)
+    }
+
+    /// Observes the complete blocks at the start of `buf`, which are about to
+    /// be decrypted in-place.
+    fn observe_blocks(&self, buf: &[u8]) {
+        let end = (buf.len() / AES_BLOCK_SIZE) * AES_BLOCK_SIZE;
+        if end > 0 {
+            self.observe(Direction::Inbound, &buf[..end]);
+        }
+    }
+}
+
+/// Records everything a wire tap sees, so that the exact bytes of a
+/// connection can be compared against a known-good capture.
+#[derive(Clone, Default)]
+pub struct WireCapture {
+    inbound: Arc<Mutex<Vec<u8>>>,
+    outbound: Arc<Mutex<Vec<u8>>>,
+}
+
+impl WireCapture {
+    pub fn new() -> Self {
+        WireCapture::default()
+    }
+
+    /// Returns a wire tap that appends to this capture.
+    pub fn tap(&self) -> WireTapFn {
+        let capture = self.clone();
+        Box::new(move |direction, bytes| match direction {
+            Direction::Inbound => capture.inbound.lock().unwrap().extend_from_slice(bytes),
+            Direction::Outbound => capture.outbound.lock().unwrap().extend_from_slice(bytes),
+        })
+    }
+
+    /// Returns the bytes read from the network so far.
+    pub fn inbound(&self) -> Vec<u8> {
+        self.inbound.lock().unwrap().clone()
+    }
+
+    /// Returns the bytes written to the network so far.
+    pub fn outbound(&self) -> Vec<u8> {
+        self.outbound.lock().unwrap().clone()
+    }
+}
+
+/// Errors that can occur while decoding or encoding NTCP frames.
+///
+/// These are converted to `io::Error`s at the `Codec` boundary, and can be
+/// recovered with `FrameError::from_io`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The encrypted stream is out of sync with our cipher state, which
+    /// usually means a key or IV mismatch with the peer.
+    Crypto(String),
+    /// The decrypted bytes do not form a valid frame.
+    Parse(String),
+}
+
+impl FrameError {
+    /// Returns the `FrameError` wrapped by the given `io::Error`, if any.
+    pub fn from_io(e: &io::Error) -> Option<&FrameError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Crypto(e) => write!(f, "crypto error: {}", e),
+            FrameError::Parse(e) => write!(f, "parse error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// A message being reassembled from fragment frames.
+struct Reassembly {
+    id: u32,
+    count: u8,
+    next: u8,
+    data: Vec<u8>,
+}
+
+fn frame_parse_error(e: Err<nom::error::Error<&[u8]>>) -> io::Error {
+    match e {
+        Err::Incomplete(_) => {
+            FrameError::Parse("frame contents longer than frame".to_owned()).into()
+        }
+        Err::Error(e) | Err::Failure(e) => FrameError::Parse(format!("{:?}", e.code)).into(),
+    }
+}
+
 pub struct Codec {
     aes: Aes256,
     decrypted: usize,
+    next_len: Option<usize>,
+    frames_encoded: u64,
+    frames_decoded: u64,
+    retain_raw: bool,
+    wire_tap: Option<WireTap>,
+    rtt: Option<Duration>,
+    next_fragment_id: u32,
+    reassembly: Option<Reassembly>,
+    /// Whether the peer accepts fragment frames, so we can send it messages
+    /// too large for one frame.
+    send_fragments: bool,
+    /// Whether the peer may send us fragment frames.
+    accept_fragments: bool,
 }
 
-impl Decoder for Codec {
-    type Item = Frame;
-    type Error = io::Error;
+impl Codec {
+    fn new(aes: Aes256, decrypted: usize) -> Self {
+        Codec {
+            aes,
+            decrypted,
+            next_len: None,
+            frames_encoded: 0,
+            frames_decoded: 0,
+            retain_raw: false,
+            wire_tap: None,
+            rtt: None,
+            next_fragment_id: 0,
+            reassembly: None,
+            send_fragments: false,
+            accept_fragments: false,
+        }
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
-        // Encrypt message in-place
-        match self.aes.decrypt_blocks(&mut buf[self.decrypted..]) {
-            Some(end) => self.decrypted += end,
-            None => {
-                if self.decrypted == 0 {
-                    return Ok(None);
+    /// Returns the transport protocol version in use on this connection.
+    pub fn transport_version(&self) -> TransportVersion {
+        TransportVersion {
+            style: TransportStyle::Ntcp,
+            version: NTCP_VERSION,
+        }
+    }
+
+    /// Sets whether decoded messages should keep a copy of their serialized
+    /// bytes, for callers that will forward them unchanged.
+    pub fn set_retain_raw(&mut self, retain_raw: bool) {
+        self.retain_raw = retain_raw;
+    }
+
+    /// Sets whether fragment frames may be sent to and accepted from the peer.
+    /// Without them, a message must fit in a single frame.
+    fn set_fragments(&mut self, send: bool, accept: bool) {
+        self.send_fragments = send;
+        self.accept_fragments = accept;
+    }
+
+    /// Returns the number of frames this codec has encoded and decoded, in
+    /// that order. Over a reliable connection, each side's encoded count
+    /// should match the other side's decoded count.
+    pub fn frame_counts(&self) -> (u64, u64) {
+        (self.frames_encoded, self.frames_decoded)
+    }
+
+    /// Drops a decoded frame from the start of the buffer, without copying it.
+    fn consume(&mut self, buf: &mut BytesMut, frame_len: usize) {
+        buf.advance(frame_len);
+        self.decrypted -= frame_len;
+        self.next_len = None;
+        self.frames_decoded += 1;
+    }
+
+    /// Adds a fragment to the message being reassembled, returning the message
+    /// once all of its fragments have arrived.
+    ///
+    /// The fragments of a message are sent back-to-back, so anything other
+    /// than the next fragment in order is an error.
+    fn reassemble(&mut self, fragment: frame::Fragment) -> Result<Option<Message>, FrameError> {
+        let mut partial = match self.reassembly.take() {
+            Some(partial)
+                if partial.id == fragment.id
+                    && partial.count == fragment.count
+                    && partial.next == fragment.index =>
+            {
+                partial
+            }
+            None if fragment.index == 0
+                && fragment.count > 1
+                && usize::from(fragment.count) <= MAX_FRAGMENTS =>
+            {
+                Reassembly {
+                    id: fragment.id,
+                    count: fragment.count,
+                    next: 0,
+                    data: Vec::with_capacity(
+                        usize::from(fragment.count) * frame::MAX_FRAGMENT_PAYLOAD,
+                    ),
                 }
             }
-        };
-
-        // Parse a frame
-        let (consumed, f) = match frame::frame(&buf[0..self.decrypted]) {
-            Err(Err::Incomplete(_)) => return Ok(None),
-            Err(Err::Error(e)) | Err(Err::Failure(e)) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("parse error: {:?}", e),
-                ));
+            _ => {
+                return Err(FrameError::Parse(format!(
+                    "unexpected fragment {}/{} of message {}",
+                    fragment.index, fragment.count, fragment.id
+                )));
             }
-            Ok((i, frame)) => (buf.offset(i), frame),
         };
 
-        buf.split_to(consumed);
-        self.decrypted -= consumed;
+        partial.data.extend_from_slice(&fragment.data);
+        partial.next += 1;
+        if partial.next < partial.count {
+            self.reassembly = Some(partial);
+            return Ok(None);
+        }
 
-        Ok(Some(f))
+        let mut msg = Message::parse(&partial.data, HeaderFormat::Standard)
+            .map_err(|e| FrameError::Parse(format!("reassembled message: {}", e)))?;
+        if self.retain_raw {
+            msg.raw = Some(partial.data);
+        }
+        Ok(Some(msg))
     }
-}
 
-impl Encoder for Codec {
-    type Item = Frame;
-    type Error = io::Error;
+    /// Splits a message that is too large for one frame into fragment frames.
+    fn encode_fragments(&mut self, msg: &Message, buf: &mut BytesMut) -> io::Result<()> {
+        let data = msg.to_bytes();
+        let count = (data.len() + frame::MAX_FRAGMENT_PAYLOAD - 1) / frame::MAX_FRAGMENT_PAYLOAD;
+        if count > MAX_FRAGMENTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message ({}) too large to fragment", data.len()),
+            ));
+        }
 
-    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        let id = self.next_fragment_id;
+        self.next_fragment_id = self.next_fragment_id.wrapping_add(1);
+        for (index, chunk) in data.chunks(frame::MAX_FRAGMENT_PAYLOAD).enumerate() {
+            let fragment = frame::Fragment {
+                id,
+                index: index as u8,
+                count: count as u8,
+                data: chunk.to_vec(),
+            };
+            self.encode_with(frame::fragment_encoded_len(&fragment), buf, |input| {
+                frame::gen_fragment_frame(input, &fragment)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Generates a frame into `len` new bytes at the end of the buffer, and
+    /// encrypts it in-place.
+    fn encode_with<G>(&mut self, len: usize, buf: &mut BytesMut, gen: G) -> io::Result<()>
+    where
+        G: Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError>,
+    {
         let start = buf.len();
-        buf.extend(repeat(0).take(NTCP_MTU));
+        buf.extend(repeat(0).take(len));
 
-        match frame::gen_frame((buf, start), &frame).map(|tup| tup.1) {
+        match gen((&mut buf[..], start)).map(|tup| tup.1) {
             Ok(sz) => {
                 buf.truncate(sz);
                 // Encrypt message in-place
                 match self.aes.encrypt_blocks(&mut buf[start..]) {
-                    Some(end) if start + end == sz => Ok(()),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "invalid serialization",
-                    )),
+                    Some(end) if start + end == sz => {
+                        if let Some(ref tap) = self.wire_tap {
+                            tap.observe(Direction::Outbound, &buf[start..]);
+                        }
+                        self.frames_encoded += 1;
+                        Ok(())
+                    }
+                    _ => Err(FrameError::Crypto("partial block encryption".to_owned()).into()),
                 }
             }
             Err(e) => match e {
@@ -142,10 +461,142 @@ impl Encoder for Codec {
     }
 }
 
+impl Decoder for Codec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+        // Decrypt any new complete blocks in-place
+        if let Some(ref tap) = self.wire_tap {
+            tap.observe_blocks(&buf[self.decrypted..]);
+        }
+        if let Some(end) = self.aes.decrypt_blocks(&mut buf[self.decrypted..]) {
+            self.decrypted += end;
+        }
+
+        loop {
+            // Wait until the whole frame has been decrypted, so we only parse it once
+            let frame_len = match self.next_len {
+                Some(len) => len,
+                None => match frame::frame_len(&buf[0..self.decrypted]) {
+                    // Don't wait for the rest of a frame we would reject anyway
+                    Some(len) if len > frame::MAX_FRAME_LEN || len % AES_BLOCK_SIZE != 0 => {
+                        return Err(
+                            FrameError::Parse(format!("invalid frame length {}", len)).into()
+                        );
+                    }
+                    Some(len) => {
+                        self.next_len = Some(len);
+                        len
+                    }
+                    None => return Ok(None),
+                },
+            };
+            if self.decrypted < frame_len {
+                return Ok(None);
+            }
+
+            // A bad checksum means we decrypted garbage
+            if !frame::checksum_valid(&buf[0..frame_len]) {
+                return Err(FrameError::Crypto("frame checksum mismatch".to_owned()).into());
+            }
+
+            // Fragments are held back until the whole message has arrived
+            if self.accept_fragments && frame::is_fragment_frame(&buf[0..frame_len]) {
+                let fragment = match frame::fragment_frame(&buf[0..frame_len]) {
+                    Ok((_, fragment)) => fragment,
+                    Err(e) => return Err(frame_parse_error(e)),
+                };
+                self.consume(buf, frame_len);
+                match self.reassemble(fragment)? {
+                    Some(msg) => return Ok(Some(Frame::Standard(msg))),
+                    None => continue,
+                }
+            }
+
+            // Parse a frame
+            let mut f = match frame::frame(&buf[0..frame_len]) {
+                Ok((_, frame)) => frame,
+                Err(e) => return Err(frame_parse_error(e)),
+            };
+
+            // The message follows the two-byte size field
+            if self.retain_raw {
+                if let Frame::Standard(ref mut msg) = f {
+                    let size = (usize::from(buf[0]) << 8) | usize::from(buf[1]);
+                    msg.raw = Some(buf[2..2 + size].to_vec());
+                }
+            }
+
+            self.consume(buf, frame_len);
+            return Ok(Some(f));
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        // Messages larger than the MTU are split across several frames, if the
+        // peer can reassemble them
+        let len = frame::encoded_len(&frame);
+        if len > frame::MAX_FRAME_LEN {
+            match frame {
+                Frame::Standard(ref msg) if self.send_fragments => {
+                    return self.encode_fragments(msg, buf);
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "message ({}) larger than MTU ({})",
+                            frame.message_size(),
+                            frame::MAX_MESSAGE_LEN
+                        ),
+                    ));
+                }
+            }
+        }
+
+        self.encode_with(len, buf, |input| frame::gen_frame(input, &frame))
+    }
+}
+
 //
 // Session handling
 //
 
+/// Returns true if the given peer has advertised that it accepts fragment
+/// frames.
+fn accepts_fragments(peer: &RouterInfo) -> bool {
+    peer.address(&NTCP_STYLE, |ra| {
+        ra.option(&NTCP_OPT_FRAG) == Some(&NTCP_FRAG_VERSION)
+    })
+    .is_some()
+}
+
+/// Returns what we learned about a connection while establishing it, and
+/// enables fragment frames if the peer has negotiated them.
+///
+/// We advertise fragments, so a peer that dials us may send them. We only send
+/// them to peers whose RouterInfo advertises them, which we only have for
+/// connections we open.
+fn session_info<T>(
+    conn: Framed<T, Codec>,
+    direction: ConnectionDirection,
+    peer_fragments: bool,
+) -> (Framed<T, Codec>, SessionInfo) {
+    let mut parts = conn.into_parts();
+    parts.codec.set_fragments(
+        peer_fragments,
+        peer_fragments || direction == ConnectionDirection::Inbound,
+    );
+    let rtt = parts.codec.rtt;
+    (Framed::from_parts(parts), SessionInfo::new(direction, rtt))
+}
+
 struct Session<T, C, D>
 where
     T: AsyncRead + AsyncWrite,
@@ -158,7 +609,12 @@ where
     distributor: D,
     pending_ib: Option<DistributorResult>,
     outbound: SessionRx<Frame>,
+    timestamps: SessionRx<Frame>,
     cached_ob_frame: Option<Frame>,
+    keepalive: Keepalive,
+    /// Wakes the session when it needs to check for idleness. `None` if there
+    /// is no timer to use.
+    keepalive_timer: Option<Delay>,
 }
 
 impl<T, C, D> Session<T, C, D>
@@ -172,18 +628,67 @@ where
         ri: RouterIdentity,
         upstream: Framed<T, C>,
         session_refs: SessionRefs<Frame, D>,
+        info: SessionInfo,
     ) -> Self {
         let (downstream, upstream) = upstream.split();
         let (tx, rx) = mpsc::unbounded();
-        let ctx = SessionContext::new(ri.hash(), session_refs.state, tx);
+        let (timestamps_tx, timestamps) = mpsc::unbounded();
+        let keepalive = session_refs.state.keepalive();
+        let max_batch = session_refs.state.max_write_batch();
+        let ctx = SessionContext::new(ri.hash(), session_refs.state, tx, timestamps_tx, info);
         Session {
             ib: InboundSession::new(ctx, upstream),
-            ob: OutboundSession::new(downstream),
+            ob: OutboundSession::new(downstream, max_batch),
             distributor: session_refs.distributor,
             pending_ib: None,
             outbound: rx,
+            timestamps,
             cached_ob_frame: None,
+            keepalive,
+            keepalive_timer: Some(Delay::new(clock::now())),
+        }
+    }
+
+    /// Queues a time-sync frame if we have sent nothing for a while, and fails
+    /// if we have received nothing for even longer.
+    fn check_keepalive(&mut self) -> io::Result<()> {
+        let now = clock::now();
+        let received = self.ib.last_received;
+        let since_received = now.saturating_duration_since(received);
+        if since_received >= self.keepalive.idle_timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Nothing received from {} for {:?}",
+                    self.ib.ctx.hash, since_received
+                ),
+            ));
+        }
+
+        if now.saturating_duration_since(self.ob.last_sent) >= self.keepalive.interval {
+            if let Ok(ts) = unix_time(SystemTime::now()) {
+                debug!("Sending keepalive to {}", self.ib.ctx.hash);
+                self.ib
+                    .ctx
+                    .send_timestamp(Frame::TimeSync(ts.as_secs() as u32));
+            }
+            // Don't send another before this one is written
+            self.ob.last_sent = now;
+        }
+
+        // Wake up when the next check is due
+        if let Some(timer) = self.keepalive_timer.as_mut() {
+            let next = (self.ob.last_sent + self.keepalive.interval)
+                .min(received + self.keepalive.idle_timeout);
+            if timer.deadline() != next {
+                timer.reset(next);
+            }
+            if let Err(e) = timer.poll() {
+                warn!("Keepalive timer failed for {}: {}", self.ib.ctx.hash, e);
+                self.keepalive_timer = None;
+            }
         }
+        Ok(())
     }
 }
 
@@ -198,6 +703,8 @@ where
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<(), io::Error> {
+        self.check_keepalive()?;
+
         // Write cached frame, if any
         let mut write_ready = true;
         if let Some(frame) = self.cached_ob_frame.take() {
@@ -211,21 +718,43 @@ where
         }
 
         // Write frames
+        let mut retired = false;
         while write_ready {
-            match self.outbound.poll().unwrap() {
-                Async::Ready(Some(frame)) => match self.ob.start_send(frame)? {
-                    AsyncSink::Ready => (),
-                    AsyncSink::NotReady(frame) => {
-                        self.cached_ob_frame = Some(frame);
-                        write_ready = false;
+            // Time-sync frames are small and time-sensitive, so they skip
+            // ahead of any queued messages
+            let next = match self.timestamps.poll().unwrap() {
+                Async::Ready(Some(frame)) => Async::Ready(Some(frame)),
+                _ => self.outbound.poll().unwrap(),
+            };
+            match next {
+                Async::Ready(Some(frame)) => {
+                    self.ib.ctx.stats.dequeued(frame.message_size());
+                    match self.ob.start_send(frame)? {
+                        AsyncSink::Ready => (),
+                        AsyncSink::NotReady(frame) => {
+                            self.cached_ob_frame = Some(frame);
+                            write_ready = false;
+                        }
                     }
-                },
-                _ => break,
+                }
+                Async::Ready(None) => {
+                    // The session has been retired
+                    retired = true;
+                    break;
+                }
+                Async::NotReady => break,
             }
         }
 
         // Flush frames
-        self.ob.poll_complete()?;
+        let flushed = self.ob.poll_complete()?.is_ready();
+
+        // Close a retired session once everything queued has been written,
+        // shutting down our side of the socket so the peer sees it end
+        if retired && flushed {
+            try_ready!(self.ob.close());
+            return Ok(Async::Ready(()));
+        }
 
         // Read frames
         loop {
@@ -238,7 +767,15 @@ where
 
             let f = try_ready!(self.ib.poll());
             if let Some((from, msg)) = f {
-                self.pending_ib = Some(self.distributor.handle(from, msg));
+                // Echoes of our RTT probes are for us, not the router
+                if let MessagePayload::DeliveryStatus(ref ds) = msg.payload {
+                    if self.ib.ctx.stats.echoed(ds.msg_id()) {
+                        continue;
+                    }
+                }
+                if let Some(msg) = self.ib.ctx.stats.offer(msg) {
+                    self.pending_ib = Some(self.distributor.handle(from, msg));
+                }
             } else {
                 // EOF was reached. The remote peer has disconnected.
                 return Ok(Async::Ready(()));
@@ -255,6 +792,9 @@ where
 {
     ctx: SessionContext<Frame>,
     upstream: SplitStream<Framed<T, C>>,
+    last_timesync_reply: Option<Instant>,
+    /// When we last received a frame of any kind.
+    last_received: Instant,
 }
 
 impl<T, C> InboundSession<T, C>
@@ -264,7 +804,35 @@ where
     C: Encoder<Item = Frame, Error = io::Error>,
 {
     fn new(ctx: SessionContext<Frame>, upstream: SplitStream<Framed<T, C>>) -> Self {
-        InboundSession { ctx, upstream }
+        InboundSession {
+            ctx,
+            upstream,
+            last_timesync_reply: None,
+            last_received: clock::now(),
+        }
+    }
+
+    /// Measures the peer's clock skew from its time-sync frame, and tells the
+    /// peer our time if its clock is too far out.
+    fn handle_timesync(&mut self, ts: u32) {
+        let our_ts = match unix_time(SystemTime::now()) {
+            Ok(now) => now.as_secs(),
+            Err(_) => return,
+        };
+        let skew = i64::from(ts) - our_ts as i64;
+        self.ctx.stats.observed_skew(skew);
+        debug!("Clock skew with {} is {}s", self.ctx.hash, skew);
+
+        let now = Instant::now();
+        let replied_recently = self
+            .last_timesync_reply
+            .map_or(false, |last| now < last + TIMESYNC_REPLY_INTERVAL);
+        if skew.unsigned_abs() > TIMESYNC_REPLY_SKEW.as_secs()
+            && !replied_recently
+            && self.ctx.send_timestamp(Frame::TimeSync(our_ts as u32))
+        {
+            self.last_timesync_reply = Some(now);
+        }
     }
 }
 
@@ -279,23 +847,20 @@ where
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
         loop {
-            match try_ready!(self.upstream.poll()) {
-                Some(frame) => match frame {
-                    Frame::Standard(msg) => {
-                        return Ok(Async::Ready(Some((self.ctx.hash.clone(), msg))));
-                    }
-                    frame => {
-                        // TODO: Do something
-                        debug!(
-                            "Dropping unhandled frame from {}: {:?}",
-                            self.ctx.hash, frame
-                        );
-                    }
-                },
+            let frame = match try_ready!(self.upstream.poll()) {
+                Some(frame) => frame,
                 None => {
                     // EOF was reached. The remote peer has disconnected.
                     return Ok(Async::Ready(None));
                 }
+            };
+            self.last_received = clock::now();
+            match frame {
+                Frame::Standard(msg) => {
+                    self.ctx.stats.received(msg.size());
+                    return Ok(Async::Ready(Some((self.ctx.hash.clone(), msg))));
+                }
+                Frame::TimeSync(ts) => self.handle_timesync(ts),
             }
         }
     }
@@ -308,6 +873,16 @@ where
     C: Encoder<Item = Frame, Error = io::Error>,
 {
     downstream: SplitSink<Framed<T, C>>,
+    unflushed: usize,
+    max_unflushed: usize,
+    /// The number of frames written since the last flush.
+    batched: usize,
+    max_batch: usize,
+    /// When we last sent a frame of any kind.
+    last_sent: Instant,
+    /// Whether we have started shutting down the socket, after which nothing
+    /// else may be sent.
+    closed: bool,
 }
 
 impl<T, C> OutboundSession<T, C>
@@ -316,8 +891,16 @@ where
     C: Decoder<Item = Frame, Error = io::Error>,
     C: Encoder<Item = Frame, Error = io::Error>,
 {
-    fn new(downstream: SplitSink<Framed<T, C>>) -> Self {
-        OutboundSession { downstream }
+    fn new(downstream: SplitSink<Framed<T, C>>, max_batch: usize) -> Self {
+        OutboundSession {
+            downstream,
+            unflushed: 0,
+            max_unflushed: MAX_UNFLUSHED_BYTES,
+            batched: 0,
+            max_batch,
+            last_sent: clock::now(),
+            closed: false,
+        }
     }
 }
 
@@ -331,11 +914,97 @@ where
     type SinkError = io::Error;
 
     fn start_send(&mut self, frame: Frame) -> StartSend<Frame, io::Error> {
-        self.downstream.start_send(frame)
+        if self.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("session is closed, cannot send {:?}", frame),
+            ));
+        }
+        let len = frame::encoded_len(&frame);
+
+        // Frames are coalesced into a single write until the batch is full.
+        // Refuse the frame until the socket has drained what we gave it
+        let batch_full = self.batched >= self.max_batch
+            || (self.unflushed > 0 && self.unflushed + len > self.max_unflushed);
+        if batch_full && !self.poll_complete()?.is_ready() {
+            return Ok(AsyncSink::NotReady(frame));
+        }
+
+        let res = self.downstream.start_send(frame)?;
+        if res.is_ready() {
+            self.unflushed += len;
+            self.batched += 1;
+            self.last_sent = clock::now();
+        }
+        Ok(res)
     }
 
     fn poll_complete(&mut self) -> Poll<(), io::Error> {
-        self.downstream.poll_complete()
+        try_ready!(self.downstream.poll_complete());
+        self.unflushed = 0;
+        self.batched = 0;
+        Ok(Async::Ready(()))
+    }
+
+    /// Flushes everything written, then shuts down the socket.
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.closed = true;
+        try_ready!(self.downstream.close());
+        self.unflushed = 0;
+        self.batched = 0;
+        Ok(Async::Ready(()))
+    }
+}
+
+/// An established connection to a peer.
+///
+/// The connection is run by the `Manager` that opened it, and lasts until it
+/// is closed by either side; this is a handle for using it directly.
+pub struct Connection {
+    hash: Hash,
+    state: SessionState<Frame>,
+}
+
+impl Connection {
+    /// Returns the hash of the peer's RouterIdentity.
+    pub fn peer_hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// Queues a message for sending to the peer. Fails if the connection has
+    /// been closed.
+    pub fn send(&self, msg: Message) -> io::Result<()> {
+        if self
+            .state
+            .send_established(&self.hash, Frame::Standard(msg))
+        {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Connection is closed",
+            ))
+        }
+    }
+
+    /// Returns the messages the peer sends from now on, which then no longer go
+    /// to the router. The stream ends when the connection is closed.
+    pub fn incoming(&self) -> impl Stream<Item = Message, Error = io::Error> {
+        self.state
+            .subscribe(&self.hash)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Incoming channel failed"))
+    }
+
+    /// Measures the round-trip time to the peer, by sending them a
+    /// DeliveryStatus message and timing its echo. Fails if the echo does not
+    /// arrive within `timeout`.
+    pub fn probe_rtt(&self, timeout: Duration) -> impl Future<Item = Duration, Error = io::Error> {
+        probe_rtt(self.state.clone(), self.hash.clone(), timeout)
+    }
+
+    /// Closes the connection, once everything already queued has been sent.
+    pub fn close(&self) {
+        self.state.retire(&self.hash);
     }
 }
 
@@ -343,86 +1012,683 @@ where
 // Connection management engine
 //
 
-pub struct Manager<D: Distributor> {
-    addr: SocketAddr,
-    session_manager: SessionManager<Frame, D>,
-    ctx: Option<Arc<Context>>,
+fn dh_workers_error(e: DhError) -> io::Error {
+    match e {
+        DhError::Busy => io::Error::new(io::ErrorKind::WouldBlock, "DH workers are busy"),
+        DhError::Failed => io::Error::new(io::ErrorKind::Other, "DH computation failed"),
+    }
 }
 
-impl<D: Distributor> Manager<D> {
-    pub fn new(addr: SocketAddr, distributor: D) -> Self {
-        Manager {
-            addr,
-            session_manager: session::new_manager(distributor),
-            ctx: None,
+/// Tracks the number of inbound connections from each remote IP.
+#[derive(Clone)]
+struct InboundLimiter {
+    max_per_ip: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl InboundLimiter {
+    fn new(max_per_ip: usize) -> Self {
+        InboundLimiter {
+            max_per_ip,
+            counts: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub fn set_context(&mut self, ctx: Arc<Context>) {
-        self.ctx = Some(ctx);
+    /// Returns a permit for a new connection from the given IP, or `None` if
+    /// that IP is already at the limit.
+    fn admit(&self, ip: IpAddr) -> Option<InboundPermit> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(InboundPermit {
+            ip,
+            counts: self.counts.clone(),
+        })
     }
+}
 
-    pub fn sink(&self) -> OutboundSink<D> {
-        let ctx = self
-            .ctx
-            .as_ref()
-            .cloned()
-            .expect("Should have called set_context()");
-        OutboundSink {
-            ctx,
-            session_refs: self.session_manager.refs(),
+/// Tracks the rate at which we start inbound handshakes, across all IPs.
+///
+/// Each handshake costs us a DH keypair and exchange, so under a flood we
+/// close new connections instead of starting more.
+#[derive(Clone)]
+struct HandshakeRateLimiter {
+    max_per_window: usize,
+    window: Duration,
+    recent: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl HandshakeRateLimiter {
+    fn new(max_per_window: usize, window: Duration) -> Self {
+        HandshakeRateLimiter {
+            max_per_window,
+            window,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(max_per_window))),
         }
     }
 
-    pub fn address(&self) -> RouterAddress {
-        RouterAddress::new(&NTCP_STYLE, self.addr)
+    /// Returns true if a new handshake may be started at `now`, and records
+    /// it. Returns false if we have already started too many recently.
+    fn admit(&self, now: Instant) -> bool {
+        let mut recent = self.recent.lock().unwrap();
+        while let Some(&oldest) = recent.front() {
+            if now.duration_since(oldest) < self.window {
+                break;
+            }
+            recent.pop_front();
+        }
+        if recent.len() >= self.max_per_window {
+            return false;
+        }
+        recent.push_back(now);
+        true
     }
+}
 
+/// Decides whether to start a handshake with a new inbound connection from
+/// the given IP. The connection should be closed if this returns `None`.
+fn admit_inbound(
+    ip: IpAddr,
+    now: Instant,
+    inbound_limiter: &InboundLimiter,
+    handshake_limiter: &HandshakeRateLimiter,
+) -> Option<InboundPermit> {
+    // Refuse connections from IPs that already have too many
+    let permit = match inbound_limiter.admit(ip) {
+        Some(permit) => permit,
+        None => {
+            warn!("Refusing connection from {}: too many connections", ip);
+            return None;
+        }
+    };
+    // Refuse all connections while we are handshaking too quickly
+    if !handshake_limiter.admit(now) {
+        warn!("Refusing connection from {}: too many handshakes", ip);
+        return None;
+    }
+    Some(permit)
+}
+
+/// Limits on the handshakes that a `Manager` runs.
+#[derive(Clone, Copy)]
+struct HandshakeLimits {
+    /// The clock skews we warn about, and those we refuse.
+    skew: SkewPolicy,
+    /// How long the whole handshake may take.
+    timeout: Duration,
+}
+
+fn handshake_timeout_error(e: timeout::Error<io::Error>) -> io::Error {
+    if e.is_elapsed() {
+        // Callers see the overall timeout as an `Other` error
+        io::Error::new(
+            io::ErrorKind::Other,
+            HandshakeError::Timeout("during handshake"),
+        )
+    } else if e.is_inner() {
+        e.into_inner().unwrap()
+    } else {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+fn probe_error(e: timeout::Error<oneshot::Canceled>) -> io::Error {
+    if e.is_elapsed() {
+        io::Error::new(io::ErrorKind::TimedOut, "RTT probe was not echoed")
+    } else if e.is_inner() {
+        io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Session ended during RTT probe",
+        )
+    } else {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+/// Sends an RTT probe to the given peer, and resolves to the time its echo
+/// took to arrive. The echo is matched to the probe by the DeliveryStatus
+/// message ID, which is a random nonce.
+fn probe_rtt(
+    state: SessionState<Frame>,
+    peer: Hash,
+    timeout: Duration,
+) -> impl Future<Item = Duration, Error = io::Error> {
+    let nonce: u32 = OsRng.gen();
+    let msg = Message::from_payload(MessagePayload::DeliveryStatus(DeliveryStatus::new(nonce)));
+    let sent = Instant::now();
+
+    match state.probe(&peer, nonce, Frame::Standard(msg)) {
+        Some(echo) => Either::A(
+            Timeout::new(echo, timeout)
+                .map(move |received| received.duration_since(sent))
+                .map_err(move |e| {
+                    state.cancel_probe(&peer, nonce);
+                    probe_error(e)
+                }),
+        ),
+        None => Either::B(future::err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "No connection to peer",
+        ))),
+    }
+}
+
+/// Held for the lifetime of an inbound connection.
+struct InboundPermit {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for InboundPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+pub struct Manager<D: Distributor> {
+    addr: SocketAddr,
+    session_manager: SessionManager<Frame, D>,
+    inbound_limiter: InboundLimiter,
+    handshake_limiter: HandshakeRateLimiter,
+    dh_pool: DhKeyPool,
+    dh_workers: DhWorkers,
+    limits: HandshakeLimits,
+    wire_tap: Option<WireTap>,
+    reachability: Reachability,
+    ctx: Option<Arc<Context>>,
+}
+
+impl<D: Distributor> Manager<D> {
+    pub fn new(addr: SocketAddr, distributor: D) -> Self {
+        Manager {
+            addr,
+            session_manager: session::new_manager(distributor),
+            inbound_limiter: InboundLimiter::new(MAX_INBOUND_PER_IP),
+            handshake_limiter: HandshakeRateLimiter::new(
+                MAX_INBOUND_HANDSHAKES,
+                INBOUND_HANDSHAKE_WINDOW,
+            ),
+            dh_pool: DhKeyPool::new(DH_POOL_SIZE),
+            dh_workers: DhWorkers::new(DH_WORKER_THREADS),
+            limits: HandshakeLimits {
+                skew: SkewPolicy::default(),
+                timeout: HANDSHAKE_TIMEOUT,
+            },
+            wire_tap: None,
+            reachability: Reachability::Reachable,
+            ctx: None,
+        }
+    }
+
+    /// Sets the maximum number of concurrent inbound connections we will
+    /// accept from a single IP. Takes effect for listeners started afterwards.
+    pub fn set_max_inbound_per_ip(&mut self, max_per_ip: usize) {
+        self.inbound_limiter = InboundLimiter::new(max_per_ip);
+    }
+
+    /// Sets the maximum number of inbound handshakes we will start within
+    /// `window`. Connections beyond this are closed before any DH computation.
+    /// Takes effect for listeners started afterwards.
+    pub fn set_max_inbound_handshake_rate(&mut self, max_per_window: usize, window: Duration) {
+        self.handshake_limiter = HandshakeRateLimiter::new(max_per_window, window);
+    }
+
+    /// Sets the number of DH keypairs to keep pre-generated for outbound
+    /// connections.
+    pub fn set_dh_pool_size(&mut self, size: usize) {
+        self.dh_pool = DhKeyPool::new(size);
+    }
+
+    /// Sets the number of threads that DH computations for handshakes are run
+    /// on. There must be at least one.
+    pub fn set_dh_worker_threads(&mut self, threads: usize) -> io::Result<()> {
+        if threads == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Need at least one DH worker thread",
+            ));
+        }
+        self.dh_workers = DhWorkers::new(threads);
+        Ok(())
+    }
+
+    /// Sets the maximum clock skew we will accept from peers during handshakes.
+    pub fn set_max_clock_skew(&mut self, max_skew: Duration) {
+        self.limits.skew = SkewPolicy::strict(max_skew);
+    }
+
+    /// Sets the clock skews we will warn about during handshakes, and those we
+    /// will refuse. The warning threshold must not exceed the fail threshold.
+    pub fn set_skew_policy(&mut self, policy: SkewPolicy) -> io::Result<()> {
+        if policy.warn > policy.fail {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Skew warning threshold is beyond the fail threshold",
+            ));
+        }
+        self.limits.skew = policy;
+        Ok(())
+    }
+
+    /// Sets how long we give inbound and outbound handshakes to complete.
+    /// Takes effect for connections started afterwards.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.limits.timeout = timeout;
+    }
+
+    /// Limits the number of outbound connections we will hold open. At the
+    /// limit, an idle connection with a peer in a lower bandwidth tier is
+    /// closed to make room for a new peer.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.session_manager.set_max_sessions(max_connections);
+    }
+
+    /// Limits the number of idle connections we will hold open. Beyond the
+    /// limit, the least-recently-used idle connections are closed.
+    pub fn set_max_idle_connections(&mut self, max_idle_connections: usize) {
+        self.session_manager
+            .set_max_idle_sessions(max_idle_connections);
+    }
+
+    /// Sets whether peers can connect to us. While we are unreachable, the
+    /// listener accepts no connections, and we only connect out to peers.
+    /// Takes effect for listeners started afterwards.
+    pub fn set_reachability(&mut self, reachability: Reachability) {
+        self.reachability = reachability;
+    }
+
+    /// Returns whether peers can connect to us.
+    pub fn reachability(&self) -> Reachability {
+        self.reachability
+    }
+
+    /// Sets the most frames a connection will coalesce into a single write to
+    /// the socket. Frames are always coalesced up to twice the MTU; a batch
+    /// size of one disables coalescing. Takes effect for connections
+    /// established afterwards.
+    pub fn set_max_write_batch(&mut self, frames: usize) {
+        self.session_manager.set_max_write_batch(frames);
+    }
+
+    /// Sets how long a connection may send nothing before we send a time-sync
+    /// frame to keep it alive, and how long it may receive nothing before we
+    /// close it. Takes effect for connections established afterwards.
+    pub fn set_keepalive(&mut self, interval: Duration, idle_timeout: Duration) {
+        self.session_manager.set_keepalive(Keepalive {
+            interval,
+            idle_timeout,
+        });
+    }
+
+    /// Returns how many connections we are holding open.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.session_manager.pool_stats()
+    }
+
+    /// Returns the counters for traffic and handshakes on this transport.
+    pub fn stats(&self) -> Arc<TransportStats> {
+        self.session_manager.stats()
+    }
+
+    /// Returns the number of established connections with peers in each
+    /// bandwidth tier.
+    pub fn connections_by_tier(&self) -> HashMap<Option<BandwidthTier>, usize> {
+        self.session_manager.sessions_by_tier()
+    }
+
+    /// Returns a snapshot of each connection we have established.
+    pub fn diagnostics(&self) -> Vec<PeerDiagnostics> {
+        self.session_manager.diagnostics(TransportStyle::Ntcp)
+    }
+
+    /// Returns the peer's clock minus ours, in seconds, as last measured from
+    /// the time-sync frames they have sent us.
+    pub fn clock_skew(&self, peer: &Hash) -> Option<i64> {
+        self.session_manager.clock_skew(peer)
+    }
+
+    /// Sends our current time to the given peer, ahead of any messages already
+    /// queued for them. Returns false if we have no connection to the peer.
+    pub fn send_timestamp(&self, peer: &Hash) -> bool {
+        match unix_time(SystemTime::now()) {
+            Ok(now) => self
+                .session_manager
+                .send_timestamp(peer, Frame::TimeSync(now.as_secs() as u32)),
+            Err(_) => false,
+        }
+    }
+
+    /// Measures the round-trip time to the given peer, by sending them a
+    /// DeliveryStatus message with a random ID and timing its echo.
+    ///
+    /// The probe is queued behind any messages already waiting for the peer.
+    /// Fails if we have no connection to the peer, or if the echo does not
+    /// arrive within `timeout`.
+    pub fn probe_rtt(
+        &self,
+        peer: &Hash,
+        timeout: Duration,
+    ) -> impl Future<Item = Duration, Error = io::Error> {
+        probe_rtt(self.session_manager.refs().state, peer.clone(), timeout)
+    }
+
+    /// Gracefully closes our connection to the given peer once `after` has
+    /// passed, unless [`Manager::cancel_close`] is called first.
+    pub fn schedule_close(&self, peer: &Hash, after: Duration) {
+        self.session_manager.schedule_close(peer, after)
+    }
+
+    /// Cancels a close scheduled with [`Manager::schedule_close`].
+    pub fn cancel_close(&self, peer: &Hash) {
+        self.session_manager.cancel_close(peer)
+    }
+
+    /// Returns a future that carries out scheduled closes and closes excess
+    /// idle connections, once a second.
+    pub fn sweeper(&self) -> impl Future<Item = (), Error = ()> {
+        self.session_manager.sweeper()
+    }
+
+    /// Sets an observer that is handed every buffer read from or written to
+    /// the network by connections started afterwards, for debugging.
+    ///
+    /// The observer sees the bytes as they are on the wire, so everything
+    /// after the first handshake message is encrypted.
+    pub fn set_wire_tap(&mut self, wire_tap: Option<WireTapFn>) {
+        self.wire_tap = wire_tap.map(|f| WireTap(Arc::new(Mutex::new(f))));
+    }
+
+    pub fn set_context(&mut self, ctx: Arc<Context>) {
+        self.session_manager.set_profiles(ctx.profiles.clone());
+        self.ctx = Some(ctx);
+    }
+
+    pub fn sink(&self) -> OutboundSink<D> {
+        let ctx = self
+            .ctx
+            .as_ref()
+            .cloned()
+            .expect("Should have called set_context()");
+        OutboundSink {
+            ctx,
+            session_refs: self.session_manager.refs(),
+            dh_pool: self.dh_pool.clone(),
+            dh_workers: self.dh_workers.clone(),
+            limits: self.limits,
+            wire_tap: self.wire_tap.clone(),
+        }
+    }
+
+    pub fn address(&self) -> RouterAddress {
+        let mut ra = RouterAddress::new(&NTCP_STYLE, self.addr);
+        ra.set_option(NTCP_OPT_FRAG.clone(), NTCP_FRAG_VERSION.clone());
+        ra
+    }
+
+    /// Binds to our address, and returns a Future that handles the
+    /// connections we receive.
+    ///
+    /// If we are unreachable, nothing is bound and the Future resolves
+    /// immediately. Returns an error if the address cannot be bound.
     pub fn listen(
         &self,
         own_ri: RouterIdentity,
         own_key: SigningPrivateKey,
-    ) -> impl Future<Item = (), Error = io::Error> {
-        info!("Listening on {}", self.addr);
+    ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
+        if self.reachability == Reachability::Unreachable {
+            info!("Unreachable, not listening on {}", self.addr);
+            return Ok(Either::A(future::ok(())));
+        }
 
         // Bind to the address
-        let listener = TcpListener::bind(&self.addr).unwrap();
+        let listener =
+            TcpListener::bind(&self.addr).map_err(|e| bind_error("NTCP", &self.addr, e))?;
+        info!("Listening on {}", self.addr);
 
         // Give each incoming connection the references it needs
         let session_refs = self.session_manager.refs();
         let conns = listener.incoming().zip(session_refs);
+        let inbound_limiter = self.inbound_limiter.clone();
+        let handshake_limiter = self.handshake_limiter.clone();
+        let dh_workers = self.dh_workers.clone();
+        let limits = self.limits;
+        let wire_tap = self.wire_tap.clone();
+        let own_hash = own_ri.hash();
 
         // For each incoming connection:
-        conns.for_each(move |(conn, session_refs)| {
+        Ok(Either::B(conns.for_each(move |(conn, session_refs)| {
+            let ip = match conn.peer_addr() {
+                Ok(addr) => addr.ip(),
+                Err(e) => {
+                    warn!("Dropping incoming connection: {}", e);
+                    return Ok(());
+                }
+            };
+            let permit =
+                match admit_inbound(ip, Instant::now(), &inbound_limiter, &handshake_limiter) {
+                    Some(permit) => permit,
+                    None => return Ok(()),
+                };
+
             info!("Incoming connection!");
-            // Execute the handshake
-            let conn = handshake::IBHandshake::new(conn, own_ri.clone(), own_key.clone());
+            // Generate a DH pair off the reactor, then execute the handshake
+            let own_ri = own_ri.clone();
+            let own_key = own_key.clone();
+            let wire_tap = wire_tap.clone();
+            let workers = dh_workers.clone();
+            let keypair = dh_workers.keypair().map_err(dh_workers_error);
+            let handshake = keypair.and_then(move |dh_key_builder| {
+                let mut handshake = handshake::IBHandshake::with_dh(
+                    conn,
+                    own_ri,
+                    own_key,
+                    dh_key_builder,
+                    wire_tap,
+                );
+                handshake.set_skew_policy(limits.skew);
+                handshake.set_dh_workers(workers);
+                handshake
+            });
+            let handshake =
+                Timeout::new(handshake, limits.timeout).map_err(handshake_timeout_error);
 
             // Once connected:
-            let process_conn = conn.and_then(|(ri, conn)| Session::new(ri, conn, session_refs));
-
-            spawn(process_conn.map_err(|_| ()));
+            let own_hash = own_hash.clone();
+            let process_conn = handshake
+                .and_then(move |(ri, conn)| {
+                    if ri.hash() == own_hash {
+                        // A self-test only needs the handshake to complete
+                        debug!("Completed self-test handshake from {}", ip);
+                        return future::Either::A(future::ok(()));
+                    }
+                    let (conn, info) = session_info(conn, ConnectionDirection::Inbound, false);
+                    future::Either::B(Session::new(ri, conn, session_refs, info))
+                })
+                .then(move |res| {
+                    drop(permit);
+                    res
+                });
+
+            spawn(process_conn.map_err(move |e| {
+                IP_ERRORS.log(
+                    ip,
+                    Level::Error,
+                    format_args!("Error on connection from {}: {}", ip, e),
+                )
+            }));
 
             Ok(())
-        })
+        })))
     }
 
+    /// Connects to a peer, resolving to a handle for the connection once the
+    /// handshake has completed.
     pub fn connect(
         &self,
         own_ri: RouterIdentity,
         own_key: SigningPrivateKey,
         peer_ri: RouterInfo,
+    ) -> io::Result<impl Future<Item = Connection, Error = io::Error>> {
+        self.connect_with_timeout(own_ri, own_key, peer_ri, self.limits.timeout)
+    }
+
+    /// Connects to a peer, failing if the handshake has not completed within
+    /// `timeout`.
+    pub fn connect_with_timeout(
+        &self,
+        own_ri: RouterIdentity,
+        own_key: SigningPrivateKey,
+        peer_ri: RouterInfo,
+        timeout: Duration,
+    ) -> io::Result<impl Future<Item = Connection, Error = io::Error>> {
+        connect_with_dh(
+            own_ri,
+            own_key,
+            peer_ri,
+            self.session_manager.refs(),
+            self.dh_pool.get_on(&self.dh_workers),
+            self.dh_workers.clone(),
+            HandshakeLimits {
+                timeout,
+                ..self.limits
+            },
+            self.wire_tap.clone(),
+        )
+    }
+
+    /// Connects to a peer using the given DH pair for the handshake, instead
+    /// of a fresh one. Intended for reproducing handshakes.
+    pub fn connect_with_dh(
+        &self,
+        own_ri: RouterIdentity,
+        own_key: SigningPrivateKey,
+        peer_ri: RouterInfo,
+        dh_key_builder: DHSessionKeyBuilder,
+    ) -> io::Result<impl Future<Item = Connection, Error = io::Error>> {
+        connect_with_dh(
+            own_ri,
+            own_key,
+            peer_ri,
+            self.session_manager.refs(),
+            Box::new(future::ok(dh_key_builder)),
+            self.dh_workers.clone(),
+            self.limits,
+            self.wire_tap.clone(),
+        )
+    }
+
+    /// Connects to the given peer using our own identity from the context.
+    ///
+    /// If we already have a session with the peer, or are connecting to it,
+    /// this waits for that session instead of opening a second connection.
+    pub(super) fn connect_to(
+        &self,
+        peer_ri: RouterInfo,
     ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
-        connect(own_ri, own_key, peer_ri, self.session_manager.refs())
+        let ctx = self.ctx.as_ref().expect("Should have called set_context()");
+        let hash = peer_ri.router_id.hash();
+        let state = self.session_manager.refs().state;
+        if !state.start_connect(&hash) {
+            return Ok(Either::A(state.connected(&hash)));
+        }
+
+        let connect = self
+            .connect(
+                ctx.keys.rid.clone(),
+                ctx.keys.signing_private_key.clone(),
+                peer_ri,
+            )
+            .map_err(|e| {
+                state.abandon_connect(&hash);
+                e
+            })?;
+        Ok(Either::B(connect.map(|_| ()).map_err(move |e| {
+            state.abandon_connect(&hash);
+            e
+        })))
+    }
+
+    /// Checks that our listener at `addr` is reachable, by connecting to it and
+    /// running the handshake against our own identity. Resolves to false if
+    /// the connection or the handshake fails.
+    ///
+    /// Neither side of the connection is kept as a session.
+    pub fn self_test(&self, addr: SocketAddr) -> IoFuture<bool> {
+        let ctx = self.ctx.as_ref().expect("Should have called set_context()");
+        let own_rid = ctx.keys.rid.clone();
+        let own_key = ctx.keys.signing_private_key.clone();
+        let skew = self.limits.skew;
+        let wire_tap = self.wire_tap.clone();
+        let workers = self.dh_workers.clone();
+
+        let dh_key_builder = self
+            .dh_pool
+            .get_on(&self.dh_workers)
+            .map_err(dh_workers_error);
+        let conn = TcpStream::connect(&addr).join(dh_key_builder).and_then(
+            move |(socket, dh_key_builder)| {
+                let mut handshake = handshake::OBHandshake::with_dh(
+                    socket,
+                    own_rid.clone(),
+                    own_key,
+                    own_rid,
+                    dh_key_builder,
+                    wire_tap,
+                );
+                handshake.set_skew_policy(skew);
+                handshake.set_dh_workers(workers);
+                handshake
+            },
+        );
+
+        Box::new(
+            Timeout::new(conn, SELF_TEST_TIMEOUT).then(move |res| match res {
+                Ok(_) => Ok(true),
+                Err(e) => {
+                    warn!("Self-test of {} failed: {}", addr, e);
+                    Ok(false)
+                }
+            }),
+        )
+    }
+
+    /// Returns a closure that retires the session with the given peer, and
+    /// returns the messages that were waiting for it to be established.
+    pub(super) fn retirer(&self, hash: Hash) -> impl FnOnce() -> Vec<Message> + Send {
+        let state = self.session_manager.refs().state;
+        move || {
+            state
+                .retire(&hash)
+                .into_iter()
+                .filter_map(|frame| match frame {
+                    Frame::Standard(msg) => Some(msg),
+                    Frame::TimeSync(_) => None,
+                })
+                .collect()
+        }
     }
 }
 
-fn connect<D: Distributor>(
+#[allow(clippy::too_many_arguments)]
+fn connect_with_dh<D: Distributor>(
     own_ri: RouterIdentity,
     own_key: SigningPrivateKey,
     peer_ri: RouterInfo,
     session_refs: SessionRefs<Frame, D>,
-) -> io::Result<impl Future<Item = (), Error = io::Error>> {
+    dh_key_builder: DhFuture<DHSessionKeyBuilder>,
+    dh_workers: DhWorkers,
+    limits: HandshakeLimits,
+    wire_tap: Option<WireTap>,
+) -> io::Result<impl Future<Item = Connection, Error = io::Error>> {
     let addr = match peer_ri.address(&NTCP_STYLE, |_| true) {
         Some(ra) => ra.addr().unwrap(),
         None => {
@@ -433,19 +1699,37 @@ fn connect<D: Distributor>(
         }
     };
 
-    // Connect to the peer
-    let conn = TcpStream::connect(&addr)
-        .and_then(|socket| handshake::OBHandshake::new(socket, own_ri, own_key, peer_ri.router_id));
+    // Connect to the peer, while the DH pair is generated
+    let peer_fragments = accepts_fragments(&peer_ri);
+    let dh_key_builder = dh_key_builder.map_err(dh_workers_error);
+    let socket = TcpStream::connect(&addr);
+    let conn = socket
+        .join(dh_key_builder)
+        .and_then(move |(socket, dh_key_builder)| {
+            let mut handshake = handshake::OBHandshake::with_dh(
+                socket,
+                own_ri,
+                own_key,
+                peer_ri.router_id,
+                dh_key_builder,
+                wire_tap,
+            );
+            handshake.set_skew_policy(limits.skew);
+            handshake.set_dh_workers(dh_workers);
+            handshake
+        });
 
     // Add a timeout
-    let timed = Timeout::new(conn, Duration::new(10, 0))
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+    let timed = Timeout::new(conn, limits.timeout).map_err(handshake_timeout_error);
 
     // Once connected:
-    Ok(timed.and_then(|(ri, conn)| {
-        let session = Session::new(ri, conn, session_refs);
+    Ok(timed.and_then(move |(ri, conn)| {
+        let (conn, info) = session_info(conn, ConnectionDirection::Outbound, peer_fragments);
+        let hash = ri.hash();
+        let state = session_refs.state.clone();
+        let session = Session::new(ri, conn, session_refs, info);
         spawn(session.map_err(|_| ()));
-        Ok(())
+        Ok(Connection { hash, state })
     }))
 }
 
@@ -454,15 +1738,27 @@ impl<D: Distributor> Transport for Manager<D> {
         self.session_manager.have_session(hash)
     }
 
+    fn mtu(&self) -> usize {
+        frame::MAX_MESSAGE_LEN
+    }
+
     fn bid(&self, peer: &RouterInfo, msg_size: usize) -> Option<Bid> {
-        if msg_size > NTCP_MTU {
+        peer.address(&NTCP_STYLE, |_| true)?;
+
+        // Larger messages need fragments, which we can't send over a session
+        // the peer opened
+        let hash = peer.router_id.hash();
+        if msg_size > self.mtu()
+            && (!accepts_fragments(peer)
+                || self.session_manager.direction(&hash) == Some(ConnectionDirection::Inbound))
+        {
             return None;
         }
 
-        peer.address(&NTCP_STYLE, |_| true)?;
-
+        // Messages for a peer we are already connecting to wait for that
+        // connection, rather than opening another
         Some(Bid {
-            bid: if self.is_established(&peer.router_id.hash()) {
+            bid: if self.is_established(&hash) || self.session_manager.is_connecting(&hash) {
                 25
             } else {
                 70
@@ -475,6 +1771,10 @@ impl<D: Distributor> Transport for Manager<D> {
 pub struct OutboundSink<D: Distributor> {
     ctx: Arc<Context>,
     session_refs: SessionRefs<Frame, D>,
+    dh_pool: DhKeyPool,
+    dh_workers: DhWorkers,
+    limits: HandshakeLimits,
+    wire_tap: Option<WireTap>,
 }
 
 impl<D: Distributor> Sink for OutboundSink<D> {
@@ -487,24 +1787,43 @@ impl<D: Distributor> Sink for OutboundSink<D> {
     ) -> StartSend<Self::SinkItem, Self::SinkError> {
         let session_refs = self.session_refs.clone();
 
-        match self
-            .session_refs
-            .state
-            .send(&peer.router_id.hash(), Frame::Standard(msg), || {
+        match self.session_refs.state.send(
+            &peer.router_id.hash(),
+            Frame::Standard(msg),
+            peer.bandwidth_tier(),
+            || {
                 // Connect to the peer
                 let own_rid = self.ctx.keys.rid.clone();
                 let own_key = self.ctx.keys.signing_private_key.clone();
                 let peer = peer.clone();
+                let hash = peer.router_id.hash();
                 let session_refs = session_refs.clone();
-                match connect(own_rid, own_key, peer, session_refs) {
+                let state = session_refs.state.clone();
+                let dh_key_builder = self.dh_pool.get_on(&self.dh_workers);
+                match connect_with_dh(
+                    own_rid,
+                    own_key,
+                    peer,
+                    session_refs,
+                    dh_key_builder,
+                    self.dh_workers.clone(),
+                    self.limits,
+                    self.wire_tap.clone(),
+                ) {
                     Ok(f) => {
-                        spawn(f.map_err(|e| {
-                            error!("Error while connecting: {}", e);
+                        spawn(f.map(|_| ()).map_err(move |e| {
+                            state.abandon_connect(&hash);
+                            PEER_ERRORS.log(
+                                hash,
+                                Level::Error,
+                                format_args!("Error while connecting: {}", e),
+                            );
                         }));
                     }
-                    Err(e) => error!("{}", e),
+                    Err(e) => PEER_ERRORS.log(hash, Level::Error, format_args!("{}", e)),
                 }
-            }) {
+            },
+        ) {
             Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
             Ok(AsyncSink::NotReady(Frame::Standard(msg))) => Ok(AsyncSink::NotReady((peer, msg))),
             Err(e) => Err(io::Error::new(
@@ -525,18 +1844,37 @@ impl<D: Distributor> Sink for OutboundSink<D> {
 mod tests {
     use bytes::BytesMut;
     use cookie_factory::GenError;
-    use futures::{lazy, Future, Sink};
+    use futures::{lazy, Async, Future, Sink, Stream};
     use nom::{Err, Offset};
     use std::iter::repeat;
     use tokio::{
         codec::{Decoder, Encoder},
-        io::{self, Read, Write},
+        io::{self, AsyncRead, AsyncWrite, Read, Write},
+    };
+
+    use std::net::IpAddr;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
     };
+    use std::time::{Duration, Instant, SystemTime};
 
-    use super::{frame, Frame, Manager, Session, NTCP_MTU};
-    use crate::i2np::Message;
+    use super::{
+        admit_inbound, frame, unix_time, Codec, Frame, FrameError, HandshakeError,
+        HandshakeRateLimiter, InboundLimiter, Manager, OutboundSession, Session, SkewPolicy,
+        NTCP_MTU, NTCP_STYLE,
+    };
+    use crate::crypto::{dh::DHSessionKeyBuilder, Aes256, SessionKey};
+    use crate::data::{Reachability, RouterAddress, RouterInfo, RouterSecretKeys};
+    use crate::i2np::{Message, MessagePayload};
     use crate::router::mock::{mock_context, MockDistributor};
-    use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
+    use crate::transport::{
+        self, ntcp2,
+        session::SessionInfo,
+        tests::{AliceNet, BobNet, NetworkCable},
+        ConnectionDirection, Transport, TransportStyle,
+    };
+    use crate::util::serialize;
 
     struct TestCodec;
 
@@ -632,7 +1970,12 @@ mod tests {
             assert!(received.is_empty());
 
             // Create a session
-            let mut session = Session::new(rid, alice_framed, manager.session_manager.refs());
+            let mut session = Session::new(
+                rid,
+                alice_framed,
+                manager.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Outbound, None),
+            );
 
             // Pass it through the session, now it's on the wire
             session.poll().unwrap();
@@ -647,40 +1990,1645 @@ mod tests {
     }
 
     #[test]
-    fn session_receive() {
+    fn session_timesync_reply() {
         let ctx = mock_context();
         let rid = ctx.keys.rid.clone();
         let hash = rid.hash();
 
         let cable = NetworkCable::new();
-        let bob_net = BobNet::new(cable.clone());
-        let bob_framed = TestCodec {}.framed(bob_net);
+        let mut alice = decoding_codec().framed(AliceNet::new(cable.clone()));
+        let bob_framed = decoding_codec().framed(BobNet::new(cable));
 
-        let distributor = MockDistributor::new();
-        let received = distributor.received.clone();
-        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
-        let mut session = Session::new(rid, bob_framed, manager.session_manager.refs());
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
 
         // Run on a task context
         lazy(move || {
-            let mut alice_net = AliceNet::new(cable);
-            assert!(alice_net.write_all(DUMMY_MSG_NTCP_DATA).is_ok());
-
-            // Check it has not yet been received
-            assert!(received.lock().unwrap().is_empty());
+            let mut session = Session::new(
+                rid,
+                bob_framed,
+                manager.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Inbound, None),
+            );
+            assert_eq!(manager.clock_skew(&hash), None);
+
+            // Alice's clock is five minutes behind ours
+            let skewed = unix_time(SystemTime::now()).unwrap().as_secs() as u32 - 300;
+            assert!(alice
+                .start_send(Frame::TimeSync(skewed))
+                .unwrap()
+                .is_ready());
+            assert!(alice.poll_complete().unwrap().is_ready());
+
+            // Bob measures the skew, and queues a reply with his time
+            assert!(!session.poll().unwrap().is_ready());
+            let skew = manager.clock_skew(&hash).unwrap();
+            assert!((-301..=-299).contains(&skew));
+            assert_eq!(manager.diagnostics()[0].clock_skew, Some(skew));
+            assert_eq!(manager.diagnostics()[0].queue_depth, 1);
 
-            // Pass it through the session
             session.poll().unwrap();
+            match alice.poll().unwrap() {
+                Async::Ready(Some(Frame::TimeSync(ts))) => assert!(ts >= skewed + 299),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+            assert_eq!(manager.diagnostics()[0].queue_depth, 0);
+
+            // Bob does not reply again straight away
+            assert!(alice
+                .start_send(Frame::TimeSync(skewed))
+                .unwrap()
+                .is_ready());
+            assert!(alice.poll_complete().unwrap().is_ready());
+            session.poll().unwrap();
+            assert_eq!(manager.diagnostics()[0].queue_depth, 0);
 
-            // The distributor should have received it now
-            let r = received.lock().unwrap();
-            assert_eq!(r.len(), 1);
-            assert_eq!(r[0].0, hash);
-            assert_eq!(r[0].1, *DUMMY_MSG);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn session_timestamp_priority() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let alice_framed = TestCodec {}.framed(alice_net);
+
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+
+        // Run on a task context
+        lazy(move || {
+            let refs = manager.session_manager.refs();
+            let mut session = Session::new(
+                rid,
+                alice_framed,
+                refs.clone(),
+                SessionInfo::new(ConnectionDirection::Outbound, None),
+            );
+
+            // Queue a backlog of messages, then a timestamp
+            for _ in 0..10 {
+                refs.state
+                    .send(&hash, Frame::Standard(Message::dummy_data()), None, || {
+                        panic!("Session should be established")
+                    })
+                    .unwrap();
+            }
+            assert!(manager.send_timestamp(&hash));
+            assert_eq!(manager.diagnostics()[0].queue_depth, 11);
+
+            // The timestamp is written first
+            session.poll().unwrap();
+            let mut bob_net = BobNet::new(cable);
+            let mut received = Vec::new();
+            assert!(bob_net.read_to_end(&mut received).is_err());
+            let (mut i, first) = frame::frame(&received).unwrap();
+            match first {
+                Frame::TimeSync(ts) => assert!(ts > 0),
+                f => panic!("Unexpected frame: {:?}", f),
+            }
+            for _ in 0..10 {
+                let (rest, f) = frame::frame(i).unwrap();
+                match f {
+                    Frame::Standard(msg) => assert_eq!(msg, *DUMMY_MSG),
+                    f => panic!("Unexpected frame: {:?}", f),
+                }
+                i = rest;
+            }
+            assert!(i.is_empty());
+            assert_eq!(manager.diagnostics()[0].queue_depth, 0);
 
             Ok::<(), ()>(())
         })
         .wait()
         .unwrap();
     }
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    struct MockNow(Arc<Mutex<Instant>>);
+
+    impl MockNow {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl tokio_timer::clock::Now for MockNow {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn session_keepalive() {
+        use tokio::runtime::current_thread;
+        use tokio_timer::clock::Clock;
+
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let mut alice = decoding_codec().framed(AliceNet::new(cable.clone()));
+        let bob_framed = decoding_codec().framed(BobNet::new(cable));
+
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        manager.set_keepalive(Duration::from_secs(10), Duration::from_secs(30));
+
+        let mock_now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        let mut rt = current_thread::Builder::new()
+            .clock(Clock::new_with_now(mock_now.clone()))
+            .build()
+            .unwrap();
+
+        let e = rt
+            .block_on(lazy(move || {
+                let mut session = Session::new(
+                    rid,
+                    bob_framed,
+                    manager.session_manager.refs(),
+                    SessionInfo::new(ConnectionDirection::Inbound, None),
+                );
+
+                // Nothing is sent while the session is fresh
+                assert!(!session.poll().unwrap().is_ready());
+                assert!(!alice.poll().unwrap().is_ready());
+                mock_now.advance(Duration::from_secs(9));
+                assert!(!session.poll().unwrap().is_ready());
+                assert!(!alice.poll().unwrap().is_ready());
+
+                // Once Bob has sent nothing for the interval, he sends a keepalive
+                mock_now.advance(Duration::from_secs(2));
+                assert!(!session.poll().unwrap().is_ready());
+                match alice.poll().unwrap() {
+                    Async::Ready(Some(Frame::TimeSync(ts))) => assert!(ts > 0),
+                    other => panic!("Unexpected result: {:?}", other),
+                }
+                assert!(!alice.poll().unwrap().is_ready());
+
+                // Frames from Alice keep the session open
+                mock_now.advance(Duration::from_secs(15));
+                let now = unix_time(SystemTime::now()).unwrap().as_secs() as u32;
+                assert!(alice.start_send(Frame::TimeSync(now)).unwrap().is_ready());
+                assert!(alice.poll_complete().unwrap().is_ready());
+                assert!(!session.poll().unwrap().is_ready());
+                match alice.poll().unwrap() {
+                    Async::Ready(Some(Frame::TimeSync(_))) => (),
+                    other => panic!("Unexpected result: {:?}", other),
+                }
+
+                mock_now.advance(Duration::from_secs(25));
+                assert!(!session.poll().unwrap().is_ready());
+
+                // Once Bob has received nothing for the idle timeout, he gives up
+                mock_now.advance(Duration::from_secs(5));
+                session.poll()
+            }))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn session_probe_rtt() {
+        use std::thread;
+        use tokio::runtime::current_thread;
+
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        let cable = NetworkCable::new();
+        let mut alice = decoding_codec().framed(AliceNet::new(cable.clone()));
+        let bob_framed = decoding_codec().framed(BobNet::new(cable));
+
+        let distributor = MockDistributor::new();
+        let received = distributor.received.clone();
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
+
+        // Probes need a connection
+        let e = current_thread::block_on_all(manager.probe_rtt(&hash, Duration::from_secs(10)))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+
+        // Run on a task context with a timer
+        let e = current_thread::block_on_all(lazy(move || {
+            let mut session = Session::new(
+                rid,
+                bob_framed,
+                manager.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Inbound, None),
+            );
+
+            // Bob sends a probe
+            let mut probe = manager.probe_rtt(&hash, Duration::from_secs(10));
+            assert!(!probe.poll().unwrap().is_ready());
+            session.poll().unwrap();
+            let echo = match alice.poll().unwrap() {
+                Async::Ready(Some(Frame::Standard(msg))) => msg,
+                other => panic!("Unexpected result: {:?}", other),
+            };
+            match echo.payload {
+                MessagePayload::DeliveryStatus(_) => (),
+                ref p => panic!("Unexpected payload: {:?}", p),
+            }
+
+            // Alice echoes it back
+            thread::sleep(Duration::from_millis(20));
+            assert!(alice.start_send(Frame::Standard(echo)).unwrap().is_ready());
+            assert!(alice.poll_complete().unwrap().is_ready());
+
+            // Bob measures the RTT, and does not pass the echo on
+            assert!(!session.poll().unwrap().is_ready());
+            match probe.poll().unwrap() {
+                Async::Ready(rtt) => {
+                    assert!(rtt >= Duration::from_millis(20));
+                    assert!(rtt < Duration::from_secs(10));
+                }
+                Async::NotReady => panic!("Probe should have completed"),
+            }
+            assert!(received.lock().unwrap().is_empty());
+
+            // Alice ignores the next probe
+            let probe = manager.probe_rtt(&hash, Duration::from_millis(50));
+            session.poll().unwrap();
+            match alice.poll().unwrap() {
+                Async::Ready(Some(Frame::Standard(_))) => (),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+
+            // Keep the session open while we wait
+            probe.then(move |res| {
+                drop(session);
+                res
+            })
+        }))
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn listen_bind_error() {
+        let in_use = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = in_use.local_addr().unwrap();
+
+        let ctx = mock_context();
+        let manager = Manager::new(addr, MockDistributor::new());
+        match manager.listen(ctx.keys.rid.clone(), ctx.keys.signing_private_key.clone()) {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+                assert!(e
+                    .to_string()
+                    .starts_with(&format!("Could not bind NTCP listener to {}: ", addr)));
+            }
+            Ok(_) => panic!("Listened on an address that is in use"),
+        }
+    }
+
+    #[test]
+    fn loopback_self_test() {
+        use tokio::runtime::Runtime;
+
+        // Find free ports, one of which we will listen on
+        let free_addr = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let addr = free_addr();
+        let unreachable = free_addr();
+
+        let ctx = mock_context();
+        let mut manager = Manager::new(addr, MockDistributor::new());
+        manager.set_context(ctx.clone());
+
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(
+            manager
+                .listen(ctx.keys.rid.clone(), ctx.keys.signing_private_key.clone())
+                .unwrap()
+                .map_err(|e| panic!("Listener error: {}", e)),
+        );
+
+        assert!(rt.block_on(manager.self_test(addr)).unwrap());
+        assert!(!rt.block_on(manager.self_test(unreachable)).unwrap());
+
+        // We never hold a session with ourselves
+        assert!(manager.diagnostics().is_empty());
+        assert!(!manager.is_established(&ctx.keys.rid.hash()));
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        use tokio::runtime::current_thread;
+
+        // Bob accepts connections, but never replies to SessionRequest
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let bob_addr = listener.local_addr().unwrap();
+        let bob_ctx = mock_context();
+        let bob = Manager::new(bob_addr, MockDistributor::new());
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+
+        let alice_ctx = mock_context();
+        let alice = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+
+        let start = Instant::now();
+        let e = match current_thread::block_on_all(lazy(|| {
+            alice
+                .connect_with_timeout(
+                    alice_ctx.keys.rid.clone(),
+                    alice_ctx.keys.signing_private_key.clone(),
+                    bob_ri,
+                    Duration::from_millis(200),
+                )
+                .unwrap()
+        })) {
+            Ok(_) => panic!("Handshake should have timed out"),
+            Err(e) => e,
+        };
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(e.to_string(), "timeout during handshake");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn echoing_peer_times_out() {
+        use std::io::{Read, Write};
+        use std::thread;
+        use tokio::runtime::current_thread;
+
+        // Bob echoes everything Alice sends straight back to her
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let bob_addr = listener.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if stream.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        let bob_ctx = mock_context();
+        let bob = Manager::new(bob_addr, MockDistributor::new());
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+
+        let alice_ctx = mock_context();
+        let alice = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+
+        // Each handshake state reads the one frame it expects, so the echoed
+        // SessionRequest cannot drive Alice round a loop. She waits for the
+        // rest of SessionCreated until the handshake times out.
+        let start = Instant::now();
+        let e = match current_thread::block_on_all(lazy(|| {
+            alice
+                .connect_with_timeout(
+                    alice_ctx.keys.rid.clone(),
+                    alice_ctx.keys.signing_private_key.clone(),
+                    bob_ri,
+                    Duration::from_millis(200),
+                )
+                .unwrap()
+        })) {
+            Ok(_) => panic!("Handshake with an echoing peer should fail"),
+            Err(e) => e,
+        };
+        match HandshakeError::from_io(&e) {
+            Some(HandshakeError::Timeout(_)) => (),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn connect_once_per_peer() {
+        use std::thread;
+        use tokio::runtime::Runtime;
+
+        // Bob accepts connections, but never replies to SessionRequest
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let bob_ctx = mock_context();
+        let bob = Manager::new(listener.local_addr().unwrap(), MockDistributor::new());
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+        let bob_hash = bob_ri.router_id.hash();
+
+        let mut alice = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        alice.set_context(mock_context());
+        alice.set_handshake_timeout(Duration::from_millis(500));
+        let dial_bid = alice.bid(&bob_ri, 100).unwrap().bid;
+
+        // The second message waits for the connection the first one started
+        let mut rt = Runtime::new().unwrap();
+        let sink = alice.sink();
+        let ri = bob_ri.clone();
+        rt.block_on(lazy(move || {
+            sink.send((ri.clone(), Message::dummy_data()))
+                .and_then(move |sink| sink.send((ri, Message::dummy_data())))
+                .map(|_| ())
+        }))
+        .unwrap();
+        assert!(alice.session_manager.is_connecting(&bob_hash));
+        assert!(alice.bid(&bob_ri, 100).unwrap().bid < dial_bid);
+
+        // So does an explicit connection, which fails along with it
+        let start = Instant::now();
+        assert!(rt.block_on(alice.connect_to(bob_ri).unwrap()).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!alice.session_manager.is_connecting(&bob_hash));
+
+        // Only one connection reaches Bob
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.incoming().take_while(Result::is_ok).count(), 1);
+    }
+
+    #[test]
+    fn loopback_diagnostics() {
+        use std::thread;
+        use tokio::runtime::Runtime;
+
+        // Find a free port for Bob to listen on
+        let bob_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let alice_ctx = mock_context();
+        let bob_ctx = mock_context();
+        let alice_hash = alice_ctx.keys.rid.hash();
+        let bob_hash = bob_ctx.keys.rid.hash();
+
+        let mut alice = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        alice.set_context(alice_ctx);
+
+        let bob_distributor = MockDistributor::new();
+        let bob_received = bob_distributor.received.clone();
+        let bob = Manager::new(bob_addr, bob_distributor);
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(
+            bob.listen(
+                bob_ctx.keys.rid.clone(),
+                bob_ctx.keys.signing_private_key.clone(),
+            )
+            .unwrap()
+            .map_err(|e| panic!("Listener error: {}", e)),
+        );
+
+        // Sending a message connects Alice to Bob
+        let sink = alice.sink();
+        rt.block_on(lazy(move || {
+            sink.send((bob_ri, Message::dummy_data()))
+                .map(|_| ())
+                .map_err(|e| panic!("Send error: {}", e))
+        }))
+        .unwrap();
+
+        // Wait for Bob to receive it
+        for _ in 0..100 {
+            if !bob_received.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(bob_received.lock().unwrap().len(), 1);
+
+        let alice_diags = alice.diagnostics();
+        assert_eq!(alice_diags.len(), 1);
+        let diag = &alice_diags[0];
+        assert_eq!(diag.peer, bob_hash.to_base32());
+        assert_eq!(diag.peer.len(), 52);
+        assert_eq!(diag.style, TransportStyle::Ntcp);
+        assert_eq!(diag.direction, ConnectionDirection::Outbound);
+        assert!(diag.established <= SystemTime::now());
+        assert!(diag.rtt.is_some());
+        assert!(diag.bytes_out > 0);
+        assert_eq!(diag.bytes_in, 0);
+        assert_eq!(diag.queue_depth, 0);
+
+        let bob_diags = bob.diagnostics();
+        assert_eq!(bob_diags.len(), 1);
+        let diag = &bob_diags[0];
+        assert_eq!(diag.peer, alice_hash.to_base32());
+        assert_eq!(diag.direction, ConnectionDirection::Inbound);
+        assert!(diag.rtt.is_some());
+        assert_eq!(diag.bytes_in, alice_diags[0].bytes_out);
+        assert_eq!(diag.bytes_out, 0);
+        assert_eq!(diag.queue_depth, 0);
+    }
+
+    #[test]
+    fn connects_do_not_block_reactor() {
+        use futures::future;
+        use tokio::{runtime::current_thread, timer::Interval};
+
+        // Find a free port for Bob to listen on
+        let bob_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let bob_ctx = mock_context();
+        let mut bob = Manager::new(bob_addr, MockDistributor::new());
+        bob.set_context(bob_ctx.clone());
+        bob.set_handshake_timeout(Duration::from_secs(60));
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+
+        // Several peers connect to Bob at once
+        let alices: Vec<_> = (0..3)
+            .map(|_| {
+                let mut alice =
+                    Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+                alice.set_handshake_timeout(Duration::from_secs(60));
+                (alice, RouterSecretKeys::new())
+            })
+            .collect();
+        // Both sides of every handshake run on a single reactor thread
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.spawn(
+            bob.listen(
+                bob_ctx.keys.rid.clone(),
+                bob_ctx.keys.signing_private_key.clone(),
+            )
+            .unwrap()
+            .map_err(|e| panic!("Listener error: {}", e)),
+        );
+
+        // An independent timer keeps firing on schedule while the handshakes'
+        // DH computations run
+        let max_lateness = Arc::new(Mutex::new(Duration::from_secs(0)));
+        let ticks = Arc::new(AtomicUsize::new(0));
+        {
+            let max_lateness = max_lateness.clone();
+            let ticks = ticks.clone();
+            rt.spawn(
+                Interval::new(Instant::now(), Duration::from_millis(10))
+                    .for_each(move |scheduled| {
+                        let mut max = max_lateness.lock().unwrap();
+                        *max = std::cmp::max(*max, Instant::now().duration_since(scheduled));
+                        ticks.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    })
+                    .map_err(|e| panic!("Timer error: {}", e)),
+            );
+        }
+
+        let connects = future::join_all(
+            alices
+                .iter()
+                .map(|(alice, keys)| {
+                    alice
+                        .connect(
+                            keys.rid.clone(),
+                            keys.signing_private_key.clone(),
+                            bob_ri.clone(),
+                        )
+                        .unwrap()
+                })
+                .collect::<Vec<_>>(),
+        );
+        let conns = rt.block_on(connects).unwrap();
+        assert_eq!(conns.len(), 3);
+
+        // The timer ticked throughout, rather than in bursts between the DH
+        // computations
+        assert!(ticks.load(Ordering::SeqCst) > 0);
+        assert!(*max_lateness.lock().unwrap() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn loopback_connection() {
+        use std::thread;
+        use tokio::runtime::Runtime;
+
+        // Find a free port for Bob to listen on
+        let bob_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let alice_ctx = mock_context();
+        let bob_ctx = mock_context();
+        let alice_hash = alice_ctx.keys.rid.hash();
+        let alice_ri = RouterInfo::new(alice_ctx.keys.rid.clone());
+
+        let alice_distributor = MockDistributor::new();
+        let alice_received = alice_distributor.received.clone();
+        let alice = Manager::new("127.0.0.1:0".parse().unwrap(), alice_distributor);
+
+        let bob_distributor = MockDistributor::new();
+        let bob_received = bob_distributor.received.clone();
+        let mut bob = Manager::new(bob_addr, bob_distributor);
+        bob.set_context(bob_ctx.clone());
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(
+            bob.listen(
+                bob_ctx.keys.rid.clone(),
+                bob_ctx.keys.signing_private_key.clone(),
+            )
+            .unwrap()
+            .map_err(|e| panic!("Listener error: {}", e)),
+        );
+
+        let conn = rt
+            .block_on(
+                alice
+                    .connect(
+                        alice_ctx.keys.rid.clone(),
+                        alice_ctx.keys.signing_private_key.clone(),
+                        bob_ri,
+                    )
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(conn.peer_hash(), &bob_ctx.keys.rid.hash());
+        let incoming = conn.incoming();
+
+        // Alice sends a message to Bob
+        conn.send(Message::dummy_data()).unwrap();
+        for _ in 0..100 {
+            if !bob_received.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        {
+            let r = bob_received.lock().unwrap();
+            assert_eq!(r.len(), 1);
+            assert_eq!(r[0].0, alice_hash);
+            assert_eq!(r[0].1, *DUMMY_MSG);
+        }
+
+        // Bob replies, and Alice receives it on the connection
+        let reply = Message::from_payload(MessagePayload::Data(vec![42; 10]));
+        let reply_id = reply.id;
+        let sink = bob.sink();
+        rt.block_on(lazy(move || sink.send((alice_ri, reply)).map(|_| ())))
+            .unwrap();
+        let (msg, incoming) = rt
+            .block_on(incoming.into_future().map_err(|(e, _)| e))
+            .unwrap();
+        assert_eq!(msg.unwrap().id, reply_id);
+        assert!(alice_received.lock().unwrap().is_empty());
+
+        // Once Alice closes the connection, Bob forgets it
+        conn.close();
+        assert!(rt.block_on(incoming.collect()).unwrap().is_empty());
+        for _ in 0..100 {
+            if !bob.is_established(&alice_hash) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!bob.is_established(&alice_hash));
+        assert_eq!(
+            conn.send(Message::dummy_data()).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+    }
+
+    #[test]
+    fn unreachable_listener() {
+        use tokio::runtime::Runtime;
+
+        // Find free ports for Alice and Bob to listen on
+        let free_addr = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let alice_addr = free_addr();
+        let bob_addr = free_addr();
+
+        let alice_ctx = mock_context();
+        let bob_ctx = mock_context();
+
+        let mut alice = Manager::new(alice_addr, MockDistributor::new());
+        assert_eq!(alice.reachability(), Reachability::Reachable);
+        alice.set_reachability(Reachability::Unreachable);
+
+        let bob = Manager::new(bob_addr, MockDistributor::new());
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+
+        let mut rt = Runtime::new().unwrap();
+
+        // Alice's listener finishes without binding, so nobody can reach her
+        rt.block_on(
+            alice
+                .listen(
+                    alice_ctx.keys.rid.clone(),
+                    alice_ctx.keys.signing_private_key.clone(),
+                )
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::net::TcpStream::connect(alice_addr).unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+
+        // But she can still connect to Bob
+        rt.spawn(
+            bob.listen(
+                bob_ctx.keys.rid.clone(),
+                bob_ctx.keys.signing_private_key.clone(),
+            )
+            .unwrap()
+            .map_err(|e| panic!("Listener error: {}", e)),
+        );
+        let conn = rt
+            .block_on(
+                alice
+                    .connect(
+                        alice_ctx.keys.rid.clone(),
+                        alice_ctx.keys.signing_private_key.clone(),
+                        bob_ri,
+                    )
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(conn.peer_hash(), &bob_ctx.keys.rid.hash());
+    }
+
+    #[test]
+    fn session_receive() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        let cable = NetworkCable::new();
+        let bob_net = BobNet::new(cable.clone());
+        let bob_framed = TestCodec {}.framed(bob_net);
+
+        let distributor = MockDistributor::new();
+        let received = distributor.received.clone();
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
+        let mut session = Session::new(
+            rid,
+            bob_framed,
+            manager.session_manager.refs(),
+            SessionInfo::new(ConnectionDirection::Outbound, None),
+        );
+
+        // Run on a task context
+        lazy(move || {
+            let mut alice_net = AliceNet::new(cable);
+            assert!(alice_net.write_all(DUMMY_MSG_NTCP_DATA).is_ok());
+
+            // Check it has not yet been received
+            assert!(received.lock().unwrap().is_empty());
+
+            // Pass it through the session
+            session.poll().unwrap();
+
+            // The distributor should have received it now
+            let r = received.lock().unwrap();
+            assert_eq!(r.len(), 1);
+            assert_eq!(r[0].0, hash);
+            assert_eq!(r[0].1, *DUMMY_MSG);
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn session_peer_close() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        let cable = NetworkCable::new();
+        let mut alice = decoding_codec().framed(AliceNet::new(cable.clone()));
+        let bob_framed = decoding_codec().framed(BobNet::new(cable));
+
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+
+        // Run on a task context
+        lazy(move || {
+            let mut session = Session::new(
+                rid,
+                bob_framed,
+                manager.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Inbound, None),
+            );
+            assert!(!session.poll().unwrap().is_ready());
+            assert!(manager.is_established(&hash));
+
+            // Alice closes the session
+            assert!(alice.close().unwrap().is_ready());
+
+            // Bob's session ends, and is forgotten
+            assert_eq!(session.poll().unwrap(), Async::Ready(()));
+            drop(session);
+            assert!(!manager.is_established(&hash));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn outbound_session_close() {
+        let cable = NetworkCable::new();
+        let (downstream, _) = decoding_codec()
+            .framed(AliceNet::new(cable.clone()))
+            .split();
+        let mut ob = OutboundSession::new(downstream, 16);
+
+        // Run on a task context
+        lazy(move || {
+            assert!(ob.start_send(Frame::TimeSync(42)).unwrap().is_ready());
+            assert!(ob.close().unwrap().is_ready());
+
+            // Nothing can be sent once the socket is shut down
+            let e = ob
+                .start_send(Frame::Standard(Message::dummy_data()))
+                .unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+
+            // The peer reads the frame sent before the shutdown, then EOF
+            let mut received = Vec::new();
+            BobNet::new(cable).read_to_end(&mut received).unwrap();
+            assert_eq!(received.len(), 16);
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    /// A connection that reads nothing, and only accepts writes while it is
+    /// marked as writable.
+    struct StalledNet {
+        writable: Arc<AtomicBool>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for StalledNet {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
+        }
+    }
+
+    impl Write for StalledNet {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writable.load(Ordering::SeqCst) {
+                self.written.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            } else {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for StalledNet {}
+    impl AsyncWrite for StalledNet {
+        fn shutdown(&mut self) -> io::Result<Async<()>> {
+            Ok(().into())
+        }
+    }
+
+    #[test]
+    fn session_backpressure() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        let writable = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let net = StalledNet {
+            writable: writable.clone(),
+            written: written.clone(),
+        };
+        let framed = TestCodec {}.framed(net);
+
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        let state = manager.session_manager.refs().state;
+        let mut session = Session::new(
+            rid,
+            framed,
+            manager.session_manager.refs(),
+            SessionInfo::new(ConnectionDirection::Outbound, None),
+        );
+        session.ob.max_unflushed = 2 * DUMMY_MSG_NTCP_DATA.len();
+
+        // Run on a task context
+        lazy(move || {
+            for _ in 0..5 {
+                state
+                    .send(&hash, Frame::Standard(Message::dummy_data()), None, || {
+                        panic!("Session should be established")
+                    })
+                    .unwrap();
+            }
+
+            // The socket accepts nothing, so the session takes frames from the
+            // queue until the bound, then holds on to the next one
+            assert!(!session.poll().unwrap().is_ready());
+            assert_eq!(session.ob.unflushed, 2 * DUMMY_MSG_NTCP_DATA.len());
+            assert!(session.cached_ob_frame.is_some());
+            assert!(written.lock().unwrap().is_empty());
+
+            // It stays stalled while the socket does
+            assert!(!session.poll().unwrap().is_ready());
+            assert_eq!(session.ob.unflushed, 2 * DUMMY_MSG_NTCP_DATA.len());
+            assert!(session.cached_ob_frame.is_some());
+
+            // Once the socket drains, the rest of the queue is sent
+            writable.store(true, Ordering::SeqCst);
+            assert!(!session.poll().unwrap().is_ready());
+            assert_eq!(session.ob.unflushed, 0);
+            assert!(session.cached_ob_frame.is_none());
+            assert_eq!(
+                written.lock().unwrap().as_slice(),
+                DUMMY_MSG_NTCP_DATA.repeat(5).as_slice()
+            );
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    /// A connection that reads nothing, accepts every write, and counts how
+    /// many writes it was given.
+    struct CountingNet {
+        writes: Arc<AtomicUsize>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for CountingNet {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
+        }
+    }
+
+    impl Write for CountingNet {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for CountingNet {}
+    impl AsyncWrite for CountingNet {
+        fn shutdown(&mut self) -> io::Result<Async<()>> {
+            Ok(().into())
+        }
+    }
+
+    #[test]
+    fn session_write_coalescing() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        let state = manager.session_manager.refs().state;
+
+        let new_session = |manager: &Manager<MockDistributor>| {
+            let writes = Arc::new(AtomicUsize::new(0));
+            let written = Arc::new(Mutex::new(Vec::new()));
+            let net = CountingNet {
+                writes: writes.clone(),
+                written: written.clone(),
+            };
+            let session = Session::new(
+                rid.clone(),
+                TestCodec {}.framed(net),
+                manager.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Outbound, None),
+            );
+            (session, writes, written)
+        };
+        let queue = || {
+            for _ in 0..5 {
+                state
+                    .send(&hash, Frame::Standard(Message::dummy_data()), None, || {
+                        panic!("Session should be established")
+                    })
+                    .unwrap();
+            }
+        };
+
+        // Run on a task context
+        lazy(move || {
+            // Every queued frame goes out in a single write
+            let (mut session, writes, written) = new_session(&manager);
+            queue();
+            assert!(!session.poll().unwrap().is_ready());
+            assert_eq!(writes.load(Ordering::SeqCst), 1);
+            assert_eq!(
+                written.lock().unwrap().as_slice(),
+                DUMMY_MSG_NTCP_DATA.repeat(5).as_slice()
+            );
+            drop(session);
+
+            // A smaller batch splits them up
+            manager.set_max_write_batch(2);
+            let (mut session, writes, written) = new_session(&manager);
+            queue();
+            assert!(!session.poll().unwrap().is_ready());
+            assert_eq!(writes.load(Ordering::SeqCst), 3);
+            assert_eq!(
+                written.lock().unwrap().as_slice(),
+                DUMMY_MSG_NTCP_DATA.repeat(5).as_slice()
+            );
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    fn encrypted(data: &[u8]) -> BytesMut {
+        let mut aes = Aes256::new(&SessionKey([0x42; 32]), &[0x24; 16], &[0x24; 16]);
+        let mut buf = BytesMut::from(data);
+        assert_eq!(aes.encrypt_blocks(&mut buf), Some(data.len()));
+        buf
+    }
+
+    fn decoding_codec() -> Codec {
+        Codec::new(
+            Aes256::new(&SessionKey([0x42; 32]), &[0x24; 16], &[0x24; 16]),
+            0,
+        )
+    }
+
+    #[test]
+    fn codec_decode() {
+        let mut buf = encrypted(DUMMY_MSG_NTCP_DATA);
+        match decoding_codec().decode(&mut buf) {
+            Ok(Some(Frame::Standard(msg))) => assert_eq!(msg, *DUMMY_MSG),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn codec_decode_raw() {
+        // Not retained by default
+        let mut buf = encrypted(DUMMY_MSG_NTCP_DATA);
+        let f = decoding_codec().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(f.standard_raw(), None);
+
+        let mut codec = decoding_codec();
+        codec.set_retain_raw(true);
+        let mut buf = encrypted(DUMMY_MSG_NTCP_DATA);
+        let f = codec.decode(&mut buf).unwrap().unwrap();
+        match f {
+            Frame::Standard(ref msg) => {
+                let fresh = serialize(|input| crate::i2np::frame::gen_message(input, msg));
+                assert_eq!(f.standard_raw(), Some(&fresh[..]));
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn codec_decode_crypto_error() {
+        // Corrupt the padding, as a key or IV mismatch would
+        let mut data = DUMMY_MSG_NTCP_DATA.to_vec();
+        data[40] ^= 0xff;
+
+        let mut buf = encrypted(&data);
+        let e = decoding_codec().decode(&mut buf).unwrap_err();
+        match FrameError::from_io(&e) {
+            Some(FrameError::Crypto(_)) => (),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn codec_decode_parse_error() {
+        // Corrupt the I2NP checksum, and fix up the frame checksum
+        let mut data = DUMMY_MSG_NTCP_DATA.to_vec();
+        data[17] ^= 0xff;
+        let cs = frame::adler(&data[..44]);
+        data[44..].copy_from_slice(&cs);
+
+        let mut buf = encrypted(&data);
+        let e = decoding_codec().decode(&mut buf).unwrap_err();
+        match FrameError::from_io(&e) {
+            Some(FrameError::Parse(_)) => (),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn codec_decode_invalid_length() {
+        for size in [frame::MAX_MESSAGE_LEN as u16 + 1, 0x8000, 0xffff] {
+            let mut codec = decoding_codec();
+            let mut data = [0; 16];
+            data[0..2].copy_from_slice(&size.to_be_bytes());
+            let mut buf = encrypted(&data);
+
+            // The frame is rejected as soon as its length has been decrypted
+            let e = codec.decode(&mut buf).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            match FrameError::from_io(&e) {
+                Some(FrameError::Parse(_)) => (),
+                other => panic!("Unexpected error: {:?}", other),
+            }
+            assert_eq!(buf.len(), 16);
+            assert_eq!(codec.next_len, None);
+            assert_eq!(codec.frame_counts(), (0, 0));
+        }
+
+        // An MTU-sized message is still accepted
+        let mut codec = decoding_codec();
+        let mut data = [0; 16];
+        data[0..2].copy_from_slice(&(frame::MAX_MESSAGE_LEN as u16).to_be_bytes());
+        let mut buf = encrypted(&data);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(codec.next_len, Some(frame::MAX_FRAME_LEN));
+    }
+
+    #[test]
+    fn codec_frame_counts() {
+        const N: usize = 20;
+
+        let cable = NetworkCable::new();
+        let mut alice = decoding_codec().framed(AliceNet::new(cable.clone()));
+        let mut bob = decoding_codec().framed(BobNet::new(cable));
+
+        // Run on a task context
+        lazy(move || {
+            for i in 0..N {
+                let frame = if i % 2 == 0 {
+                    Frame::Standard(Message::dummy_data())
+                } else {
+                    Frame::TimeSync(i as u32)
+                };
+                assert!(alice.start_send(frame).unwrap().is_ready());
+            }
+            assert!(alice.poll_complete().unwrap().is_ready());
+
+            let mut received = 0;
+            while let Async::Ready(Some(_)) = bob.poll().unwrap() {
+                received += 1;
+            }
+            assert_eq!(received, N);
+
+            assert_eq!(alice.into_parts().codec.frame_counts(), (N as u64, 0));
+            assert_eq!(bob.into_parts().codec.frame_counts(), (0, N as u64));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn codec_fragmented_message() {
+        let cable = NetworkCable::new();
+        let mut alice = decoding_codec();
+        alice.set_fragments(true, true);
+        let mut alice = alice.framed(AliceNet::new(cable.clone()));
+        let mut bob = decoding_codec();
+        bob.set_fragments(true, true);
+        let mut bob = bob.framed(BobNet::new(cable));
+
+        let data: Vec<u8> = (0..40 * 1024).map(|i| i as u8).collect();
+        let big = Message::from_payload(MessagePayload::Data(data.clone()));
+        let big_id = big.id;
+        assert!(big.size() > NTCP_MTU);
+
+        // Run on a task context
+        lazy(move || {
+            assert!(alice.start_send(Frame::Standard(big)).unwrap().is_ready());
+            assert!(alice
+                .start_send(Frame::Standard(Message::dummy_data()))
+                .unwrap()
+                .is_ready());
+            assert!(alice.poll_complete().unwrap().is_ready());
+
+            // The large message is reassembled, and the stream carries on after it
+            match bob.poll() {
+                Ok(Async::Ready(Some(Frame::Standard(msg)))) => match msg.payload {
+                    MessagePayload::Data(ref received) => {
+                        assert_eq!(msg.id, big_id);
+                        assert_eq!(received, &data);
+                    }
+                    ref other => panic!("Unexpected payload: {}", other),
+                },
+                other => panic!("Unexpected result: {:?}", other),
+            }
+            match bob.poll() {
+                Ok(Async::Ready(Some(Frame::Standard(msg)))) => assert_eq!(msg, *DUMMY_MSG),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+
+            // Three fragments, then the small message
+            assert_eq!(alice.into_parts().codec.frame_counts(), (4, 0));
+            assert_eq!(bob.into_parts().codec.frame_counts(), (0, 4));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn codec_fragments_need_negotiation() {
+        let big = || Message::from_payload(MessagePayload::Data(vec![0x42; 40 * 1024]));
+
+        // Without fragments, a large message is refused
+        let mut alice = decoding_codec();
+        let mut buf = BytesMut::new();
+        let e = alice.encode(Frame::Standard(big()), &mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(buf.is_empty());
+        assert_eq!(alice.frame_counts(), (0, 0));
+
+        // And a peer that has not negotiated them can't send us fragments
+        let mut alice = decoding_codec();
+        alice.set_fragments(true, false);
+        alice.encode(Frame::Standard(big()), &mut buf).unwrap();
+        let mut bob = decoding_codec();
+        bob.set_fragments(false, false);
+        let e = bob.decode(&mut buf).unwrap_err();
+        match FrameError::from_io(&e) {
+            Some(FrameError::Parse(_)) => (),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn skew_policy_thresholds() {
+        let mut manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let policy = SkewPolicy {
+            warn: Duration::from_secs(30),
+            fail: Duration::from_secs(60),
+        };
+        assert!(manager.set_skew_policy(policy).is_ok());
+        assert_eq!(manager.limits.skew, policy);
+
+        // A warning threshold beyond the fail threshold is refused
+        match manager.set_skew_policy(SkewPolicy {
+            warn: Duration::from_secs(90),
+            fail: Duration::from_secs(60),
+        }) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(()) => panic!("Accepted an inverted skew policy"),
+        }
+        assert_eq!(manager.limits.skew, policy);
+    }
+
+    #[test]
+    fn bid_large_message() {
+        let bob_ctx = mock_context();
+        let bob = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let mut bob_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        bob_ri.sign(&bob_ctx.keys.signing_private_key);
+
+        let mut alice = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        alice.set_context(mock_context());
+        let mtu = alice.mtu();
+        assert!(alice.bid(&bob_ri, mtu).is_some());
+        assert!(alice.bid(&bob_ri, mtu + 1).is_some());
+
+        // A peer that does not advertise fragments only gets messages that fit
+        // in one frame
+        let mut old_ri = RouterInfo::new(bob_ctx.keys.rid.clone());
+        old_ri.set_addresses(vec![RouterAddress::new(
+            &NTCP_STYLE,
+            "127.0.0.1:0".parse().unwrap(),
+        )]);
+        old_ri.sign(&bob_ctx.keys.signing_private_key);
+        assert!(alice.bid(&old_ri, mtu).is_some());
+        assert!(alice.bid(&old_ri, mtu + 1).is_none());
+    }
+
+    #[test]
+    fn inbound_limit_per_ip() {
+        let limiter = InboundLimiter::new(4);
+        let ip_a: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip_b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        // Connections up to the limit are accepted
+        let mut permits: Vec<_> = (0..4).map(|_| limiter.admit(ip_a).unwrap()).collect();
+
+        // Further connections from the same IP are refused
+        assert!(limiter.admit(ip_a).is_none());
+        assert!(limiter.admit(ip_a).is_none());
+
+        // A different IP is accepted
+        let permit_b = limiter.admit(ip_b);
+        assert!(permit_b.is_some());
+
+        // Closing a connection frees up a slot
+        permits.pop();
+        assert!(limiter.admit(ip_a).is_some());
+
+        // Once all connections close, the IP is no longer tracked
+        permits.clear();
+        drop(permit_b);
+        assert!(limiter.counts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn inbound_handshake_rate() {
+        let inbound_limiter = InboundLimiter::new(4);
+        let handshake_limiter = HandshakeRateLimiter::new(3, Duration::from_secs(1));
+        let keygens = AtomicUsize::new(0);
+        let start = Instant::now();
+
+        // Starting a handshake is what generates our DH keypair
+        let connect = |i: u8, now: Instant| {
+            let ip = IpAddr::from([10, 0, 0, i]);
+            admit_inbound(ip, now, &inbound_limiter, &handshake_limiter).map(|permit| {
+                let _ = DHSessionKeyBuilder::new();
+                keygens.fetch_add(1, Ordering::SeqCst);
+                permit
+            })
+        };
+
+        // Handshakes up to the rate are started, from any IP
+        let permits: Vec<_> = (0..3).map(|i| connect(i, start).unwrap()).collect();
+        assert_eq!(keygens.load(Ordering::SeqCst), 3);
+
+        // Further connections are refused before any DH computation, even
+        // from new IPs and after earlier connections have closed
+        drop(permits);
+        assert!(connect(3, start + Duration::from_millis(500)).is_none());
+        assert!(connect(4, start + Duration::from_millis(999)).is_none());
+        assert_eq!(keygens.load(Ordering::SeqCst), 3);
+
+        // Refused connections don't hold a per-IP slot
+        assert!(inbound_limiter.counts.lock().unwrap().is_empty());
+
+        // Once the window has passed, handshakes are started again
+        assert!(connect(5, start + Duration::from_secs(1)).is_some());
+        assert_eq!(keygens.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn inbound_handshake_rate_listener() {
+        use std::io::Read;
+        use std::net::TcpStream;
+        use tokio::runtime::Runtime;
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let ctx = mock_context();
+        let mut manager = Manager::new(addr, MockDistributor::new());
+        manager.set_max_inbound_handshake_rate(2, Duration::from_secs(60));
+
+        let rt = Runtime::new().unwrap();
+        rt.executor().spawn(
+            manager
+                .listen(ctx.keys.rid.clone(), ctx.keys.signing_private_key.clone())
+                .unwrap()
+                .map_err(|e| panic!("Listener error: {}", e)),
+        );
+
+        let connect = || {
+            let conn = TcpStream::connect(addr).unwrap();
+            conn.set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            conn
+        };
+
+        // Connections within the rate are held open, waiting for SessionRequest
+        let mut accepted: Vec<_> = (0..2).map(|_| connect()).collect();
+
+        // Connections beyond it are closed straight away
+        let mut refused = connect();
+        assert_eq!(refused.read(&mut [0; 1]).unwrap(), 0);
+
+        for conn in &mut accepted {
+            match conn.read(&mut [0; 1]) {
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                res => panic!("Unexpected read result: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn migrate_session_queue() {
+        let ctx = mock_context();
+        let ri = ctx.ri.read().unwrap().clone();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        // A second peer we have not yet connected to
+        let pending_ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        let pending_hash = pending_ri.router_id.hash();
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let alice_framed = TestCodec {}.framed(alice_net);
+
+        let distributor = MockDistributor::new();
+        let mut ntcp = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor.clone());
+        ntcp.set_context(ctx.clone());
+        let mut ntcp2 = ntcp2::Manager::new("127.0.0.1:1235".parse().unwrap(), distributor);
+        ntcp2.set_context(ctx);
+
+        // Run on a task context
+        lazy(move || {
+            let mut session = Session::new(
+                rid,
+                alice_framed,
+                ntcp.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Outbound, None),
+            );
+            let manager = transport::Manager::with_transports(ntcp, ntcp2);
+
+            // Queue messages on the established session, and for the pending one
+            let mut sink = manager.ntcp.sink();
+            for _ in 0..3 {
+                sink.start_send((ri.clone(), Message::dummy_data()))
+                    .unwrap();
+            }
+            sink.start_send((pending_ri.clone(), Message::dummy_data()))
+                .unwrap();
+            assert!(manager.ntcp.is_established(&hash));
+
+            // Hand both peers over
+            assert_eq!(
+                manager.handover(&ri, TransportStyle::Ntcp2).run().unwrap(),
+                0
+            );
+            assert_eq!(
+                manager
+                    .handover(&pending_ri, TransportStyle::Ntcp2)
+                    .run()
+                    .unwrap(),
+                1
+            );
+            assert!(!manager.ntcp.is_established(&hash));
+            assert!(manager.ntcp.retirer(pending_hash.clone())().is_empty());
+
+            // The pending message is now waiting for an NTCP2 session
+            assert_eq!(manager.ntcp2.retirer(pending_hash)().len(), 1);
+
+            // The old session writes everything queued on it, then closes
+            assert_eq!(session.poll().unwrap(), Async::Ready(()));
+            let mut bob_net = BobNet::new(cable);
+            let mut received = Vec::new();
+            bob_net.read_to_end(&mut received).unwrap();
+            assert_eq!(received, DUMMY_MSG_NTCP_DATA.repeat(3));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[cfg(all(test, feature = "nightly"))]
+    mod decode {
+        use bytes::BytesMut;
+        use test::Bencher;
+        use tokio::{
+            codec::{Decoder, Encoder},
+            io,
+        };
+
+        use super::super::{frame, Codec, Frame};
+        use crate::crypto::{Aes256, SessionKey};
+        use crate::i2np::{Message, MessagePayload};
+
+        const FRAMES: usize = 256;
+        const SEGMENT: usize = 1_460;
+
+        static KEY: SessionKey = SessionKey([0x42; 32]);
+        static IV: [u8; 16] = [0x24; 16];
+
+        /// Baseline that decrypts and delimits frames without parsing them.
+        struct NoopCodec {
+            aes: Aes256,
+            decrypted: usize,
+        }
+
+        impl Decoder for NoopCodec {
+            type Item = ();
+            type Error = io::Error;
+
+            fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<()>> {
+                if let Some(end) = self.aes.decrypt_blocks(&mut buf[self.decrypted..]) {
+                    self.decrypted += end;
+                }
+                match frame::frame_len(&buf[0..self.decrypted]) {
+                    Some(len) if len <= self.decrypted => {
+                        buf.advance(len);
+                        self.decrypted -= len;
+                        Ok(Some(()))
+                    }
+                    _ => Ok(None),
+                }
+            }
+        }
+
+        fn encrypted_frames(payload_len: usize) -> BytesMut {
+            let mut codec = Codec::new(Aes256::new(&KEY, &IV, &IV), 0);
+            let mut buf = BytesMut::new();
+            for _ in 0..FRAMES {
+                let msg = Message::from_payload(MessagePayload::Data(vec![0; payload_len]));
+                codec.encode(Frame::Standard(msg), &mut buf).unwrap();
+            }
+            buf
+        }
+
+        /// Feeds the frames to the decoder in TCP-segment-sized reads.
+        fn decode_all<C: Decoder<Error = io::Error>>(data: &[u8], mut codec: C) -> usize {
+            let mut buf = BytesMut::with_capacity(data.len());
+            let mut frames = 0;
+            for segment in data.chunks(SEGMENT) {
+                buf.extend_from_slice(segment);
+                while codec.decode(&mut buf).unwrap().is_some() {
+                    frames += 1;
+                }
+            }
+            frames
+        }
+
+        fn bench_noop(b: &mut Bencher, payload_len: usize) {
+            let data = encrypted_frames(payload_len);
+            b.bytes = data.len() as u64;
+            b.iter(|| {
+                let codec = NoopCodec {
+                    aes: Aes256::new(&KEY, &IV, &IV),
+                    decrypted: 0,
+                };
+                assert_eq!(decode_all(&data, codec), FRAMES);
+            });
+        }
+
+        fn bench_codec(b: &mut Bencher, payload_len: usize) {
+            let data = encrypted_frames(payload_len);
+            b.bytes = data.len() as u64;
+            b.iter(|| {
+                let codec = Codec::new(Aes256::new(&KEY, &IV, &IV), 0);
+                assert_eq!(decode_all(&data, codec), FRAMES);
+            });
+        }
+
+        #[bench]
+        fn small_frames_baseline(b: &mut Bencher) {
+            bench_noop(b, 64);
+        }
+
+        #[bench]
+        fn small_frames(b: &mut Bencher) {
+            bench_codec(b, 64);
+        }
+
+        #[bench]
+        fn big_frames_baseline(b: &mut Bencher) {
+            bench_noop(b, 16_000);
+        }
+
+        #[bench]
+        fn big_frames(b: &mut Bencher) {
+            bench_codec(b, 16_000);
+        }
+    }
 }
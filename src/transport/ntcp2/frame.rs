@@ -2,7 +2,7 @@ use cookie_factory::*;
 use nom::{
     bits::{bits, streaming::take as take_bits},
     bytes::streaming::{tag, take},
-    combinator::{complete, map},
+    combinator::{complete, map, verify},
     error::Error as NomError,
     multi::{length_data, length_value, many1},
     number::streaming::{be_u16, be_u32, be_u64, be_u8},
@@ -115,7 +115,7 @@ fn gen_message<'a>(
 // Termination
 
 fn termination(i: &[u8]) -> IResult<&[u8], Block> {
-    let (i, size) = be_u16(i)?;
+    let (i, size) = verify(be_u16, |size| *size >= 9)(i)?;
     map(
         tuple((be_u64, be_u8, take(size - 9))),
         |(valid_received, rsn, addl_data)| {
@@ -207,8 +207,15 @@ fn gen_block<'a>(
     }
 }
 
+/// Parses the blocks of a frame. Padding, if present, must be the last block.
 pub fn frame(i: &[u8]) -> IResult<&[u8], Frame> {
-    many1(complete(block))(i)
+    verify(many1(complete(block)), |frame: &Frame| {
+        frame
+            .iter()
+            .rev()
+            .skip(1)
+            .all(|block| !matches!(block, Block::Padding(_)))
+    })(i)
 }
 
 #[allow(clippy::ptr_arg)]
@@ -374,6 +381,7 @@ mod tests {
                 id: 0,
                 expiration: I2PDate::from_system_time(UNIX_EPOCH + Duration::new(1_524_874_654, 0)),
                 payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                raw: None,
             })),
             [
                 0x03, 0x00, 0x17, 0x14, 0x00, 0x00, 0x00, 0x00, 0x5a, 0xe3, 0xbd, 0x9e, 0x00, 0x00,
@@ -388,6 +396,11 @@ mod tests {
             Block::Termination(42, 7, vec![0xfe]),
             [0x04, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x07, 0xfe,]
         );
+
+        // Too short for the reason code
+        assert!(
+            block(&[0x04, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a]).is_err()
+        );
     }
 
     #[test]
@@ -420,6 +433,46 @@ mod tests {
         assert!(res1 != res2);
     }
 
+    #[test]
+    fn test_frame() {
+        let msg = Block::Message(Box::new(Message {
+            id: 0,
+            expiration: I2PDate::from_system_time(UNIX_EPOCH + Duration::new(1_524_874_654, 0)),
+            payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            raw: None,
+        }));
+        let frame_len = 26 + 3 + 10;
+
+        let mut res = vec![0; frame_len];
+        match gen_frame((&mut res, 0), &vec![msg, Block::Padding(10)]) {
+            Ok((_, n)) => assert_eq!(n, frame_len),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+        match frame(&res) {
+            Ok((rest, f)) => {
+                assert!(rest.is_empty());
+                assert_eq!(f.len(), 2);
+                assert_eq!(f[0].message_size(), 23);
+                assert_eq!(f[1], Block::Padding(10));
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        // Truncated padding is not parsed
+        match frame(&res[..frame_len - 1]) {
+            Ok((rest, f)) => {
+                assert_eq!(rest.len(), 12);
+                assert_eq!(f.len(), 1);
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        // Padding must be the last block
+        let mut res = vec![0; 2 * 13];
+        assert!(gen_frame((&mut res, 0), &vec![Block::Padding(10), Block::Padding(10)]).is_ok());
+        assert!(frame(&res).is_err());
+    }
+
     #[test]
     fn test_unknown() {
         eval_block!(
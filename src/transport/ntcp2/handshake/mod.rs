@@ -5,20 +5,19 @@ use nom::Err;
 use rand::{rngs::OsRng, Rng};
 use siphasher::sip::SipHasher;
 use std::net::SocketAddr;
-use std::ops::AddAssign;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::ops::{AddAssign, RangeInclusive};
+use std::time::{Duration, SystemTime};
 use tokio::{
     codec::{Decoder, Framed},
     io::{self, AsyncRead, AsyncWrite, ReadExact, WriteAll},
 };
 
 use super::{
-    frame, Block, Codec, NTCP2_MTU, NTCP2_NOISE_PROTOCOL_NAME, NTCP2_OPT_I, NTCP2_OPT_S,
-    NTCP2_OPT_V, NTCP2_STYLE, NTCP2_VERSION,
+    frame, is_usable_address, Block, Codec, Ntcp2AddressOptions, Ntcp2Config, PaddingPolicy,
+    NTCP2_MTU, NTCP2_NOISE_PROTOCOL_NAME, NTCP2_STYLE,
 };
-use crate::constants::I2P_BASE64;
-use crate::data::{RouterAddress, RouterIdentity, RouterInfo};
-use crate::transport::ntcp::NTCP_STYLE;
+use crate::data::{RouterIdentity, RouterInfo};
+use crate::transport::{clock_elapsed, ntcp::NTCP_STYLE, unix_time};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
@@ -27,6 +26,13 @@ const SESSION_REQUEST_CT_LEN: usize = 32 + SESSION_REQUEST_PT_LEN + 16;
 const SESSION_CREATED_PT_LEN: usize = 16;
 const SESSION_CREATED_CT_LEN: usize = 32 + SESSION_CREATED_PT_LEN + 16;
 
+/// The default range of padding lengths we add to SessionRequest.
+pub(super) const SESSION_REQUEST_PADDING: RangeInclusive<u16> = 0..=15;
+
+/// The most padding that still keeps SessionRequest within a 65535-byte
+/// message.
+pub(super) const MAX_SESSION_REQUEST_PADDING: u16 = u16::MAX - SESSION_REQUEST_CT_LEN as u16;
+
 macro_rules! try_poll {
     ($f:expr, $parent:expr, $noise:expr) => {
         match $f.poll()? {
@@ -60,6 +66,9 @@ enum IBHandshakeState<T> {
 pub struct IBHandshake<T> {
     noise: Option<Session>,
     sclen: usize,
+    versions: Vec<u8>,
+    version: u8,
+    padding: PaddingPolicy,
     state: IBHandshakeState<T>,
 }
 
@@ -69,6 +78,24 @@ where
     T: Send + 'static,
 {
     pub fn new(conn: T, static_key: &[u8], aesobfse_key: &[u8], aesobfse_iv: &[u8; 16]) -> Self {
+        IBHandshake::with_config(
+            conn,
+            static_key,
+            aesobfse_key,
+            aesobfse_iv,
+            &Ntcp2Config::default(),
+        )
+    }
+
+    /// Accepts only the versions in the given config, and pads SessionCreated
+    /// according to its padding policy.
+    pub fn with_config(
+        conn: T,
+        static_key: &[u8],
+        aesobfse_key: &[u8],
+        aesobfse_iv: &[u8; 16],
+        config: &Ntcp2Config,
+    ) -> Self {
         // Initialize our responder NoiseSession using a builder.
         let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
         let noise = builder
@@ -84,6 +111,9 @@ where
         IBHandshake {
             noise: Some(noise),
             sclen: 0,
+            versions: config.versions.clone(),
+            version: 0,
+            padding: config.padding_policy(),
             state,
         }
     }
@@ -114,10 +144,11 @@ where
                         Err(e) => {
                             return io_err!(Other, format!("SessionRequest parse error: {:?}", e));
                         }
-                        Ok((_, (ver, _, _, _))) if ver != 2 => {
+                        Ok((_, (ver, _, _, _))) if !self.versions.contains(&ver) => {
                             return io_err!(InvalidData, "Unsupported version");
                         }
-                        Ok((_, (_, padlen, sclen, ts_a))) => {
+                        Ok((_, (ver, padlen, sclen, ts_a))) => {
+                            self.version = ver;
                             (padlen as usize, sclen as usize, ts_a)
                         }
                     };
@@ -131,13 +162,13 @@ where
                     noise.set_h_data(2, &padding).unwrap();
 
                     let now = SystemTime::now();
-                    let mut ts_b = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
+                    let mut ts_b = unix_time(now)?;
                     ts_b.add_assign(Duration::from_millis(500));
                     let ts_b = ts_b.as_secs() as u32;
 
                     let mut rng = OsRng;
                     // TODO: Sample padding sizes from an appropriate distribution
-                    let sc_padlen = rng.gen_range(0..16);
+                    let sc_padlen = self.padding.sample(&mut rng);
 
                     // SessionCreated
                     let mut sc_buf = [0u8; SESSION_CREATED_PT_LEN];
@@ -179,7 +210,15 @@ where
                     // <- s, se
                     debug!("S <- s, se");
                     let mut buf = vec![0u8; msg.len()];
-                    let len = noise.read_message(&msg, &mut buf).unwrap();
+                    let len = match noise.read_message(&msg, &mut buf) {
+                        Ok(len) => len,
+                        Err(e) => {
+                            return io_err!(
+                                ConnectionRefused,
+                                format!("Could not decrypt SessionConfirmed: {:?}", e)
+                            );
+                        }
+                    };
 
                     // SessionConfirmed
                     let mut frames = match frame::session_confirmed(&buf[..len]) {
@@ -212,9 +251,21 @@ where
                             );
                         }
                     };
+                    if let Err(e) = ri_a.verify() {
+                        return io_err!(
+                            ConnectionRefused,
+                            format!("Invalid RouterInfo signature in SessionConfirmed: {}", e)
+                        );
+                    }
+                    if let Err(e) = ri_a.validate() {
+                        return io_err!(
+                            ConnectionRefused,
+                            format!("Invalid RouterInfo in SessionConfirmed: {}", e)
+                        );
+                    }
 
                     // Get peer skew
-                    let rtt = rtt_timer.elapsed().expect("Time went backwards?");
+                    let rtt = clock_elapsed(rtt_timer, SystemTime::now())?;
                     debug!("Peer RTT: {:?}", rtt);
 
                     // Prepare length obfuscation keys and IVs
@@ -261,6 +312,8 @@ where
                         dec_len_masker: SipHasher::new_with_keys(dk0, dk1),
                         dec_len_iv: div,
                         next_len: None,
+                        version: self.version,
+                        rtt: Some(rtt),
                     };
 
                     return Ok(Async::Ready((ri_a, codec.framed(conn))));
@@ -284,7 +337,10 @@ pub struct OBHandshake<T> {
     noise: Option<Session>,
     sc_buf: Vec<u8>,
     sc_len: usize,
+    sr_padding: RangeInclusive<u16>,
+    version: u8,
     peer_ri: RouterInfo,
+    rtt: Option<Duration>,
     state: OBHandshakeState<T>,
 }
 
@@ -302,49 +358,48 @@ where
     where
         F: FnOnce(&SocketAddr) -> IoFuture<T>,
     {
-        let filter = |ra: &RouterAddress| {
-            match ra.option(&NTCP2_OPT_V) {
-                Some(v) => {
-                    if !v.to_csv().contains(&NTCP2_VERSION) {
-                        return false;
-                    }
-                }
-                None => return false,
-            };
-            ra.option(&NTCP2_OPT_S).is_some() && ra.option(&NTCP2_OPT_I).is_some()
-        };
+        OBHandshake::with_config(conn, static_key, own_ri, peer_ri, &Ntcp2Config::default())
+    }
 
-        let ra = match peer_ri.address(&NTCP2_STYLE, filter) {
+    /// Uses our most preferred version in the given config that the peer
+    /// supports, and pads our messages according to its padding policy.
+    pub fn with_config<F>(
+        conn: F,
+        static_key: &[u8],
+        own_ri: &RouterInfo,
+        peer_ri: RouterInfo,
+        config: &Ntcp2Config,
+    ) -> Result<OBHandshake<T>, String>
+    where
+        F: FnOnce(&SocketAddr) -> IoFuture<T>,
+    {
+        let ra = match peer_ri.address(&NTCP2_STYLE, is_usable_address) {
             Some(ra) => ra,
-            None => match peer_ri.address(&NTCP_STYLE, filter) {
+            None => match peer_ri.address(&NTCP_STYLE, is_usable_address) {
                 Some(ra) => ra,
                 None => return Err("No valid NTCP2 addresses".to_string()),
             },
         };
 
         let addr = ra.addr().unwrap();
-        let remote_key = match ra.option(&NTCP2_OPT_S) {
-            Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
-                Ok(key) => key,
-                Err(e) => return Err(format!("Invalid static key in address: {}", e)),
-            },
-            None => return Err("No static key in address".to_string()),
+        let opts = match Ntcp2AddressOptions::from_address(&ra) {
+            Ok(opts) => opts,
+            Err(e) => return Err(format!("Invalid NTCP2 address: {}", e)),
+        };
+        let remote_key = opts.static_key;
+        let version = match config.version_for(&opts) {
+            Some(version) => version,
+            None => return Err("No NTCP2 version in common with peer".to_string()),
         };
+        let padding = config.padding_policy();
 
         let aesobfse_key = peer_ri.router_id.hash().0;
-        let mut aesobfse_iv = [0; 16];
-        match ra.option(&NTCP2_OPT_I) {
-            Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
-                Ok(iv) => aesobfse_iv.copy_from_slice(&iv),
-                Err(e) => return Err(format!("Invalid IV in address: {}", e)),
-            },
-            None => return Err("No IV in address".to_string()),
-        }
+        let aesobfse_iv = opts.iv;
 
         let sc_padlen = {
             let mut rng = OsRng;
             // TODO: Sample padding sizes from an appropriate distribution
-            rng.gen_range(0..16)
+            padding.sample(&mut rng)
         };
 
         let mut sc_buf = vec![0u8; NTCP2_MTU - 16];
@@ -383,10 +438,23 @@ where
             noise: Some(noise),
             sc_buf,
             sc_len,
+            sr_padding: match padding {
+                PaddingPolicy::None => 0..=0,
+                PaddingPolicy::Random => SESSION_REQUEST_PADDING,
+            },
+            version,
             peer_ri,
+            rtt: None,
             state,
         })
     }
+
+    /// Sets the range that the length of the padding in SessionRequest is
+    /// picked from uniformly at random.
+    pub fn set_session_request_padding(&mut self, padding: RangeInclusive<u16>) {
+        assert!(!padding.is_empty() && *padding.end() <= MAX_SESSION_REQUEST_PADDING);
+        self.sr_padding = padding;
+    }
 }
 
 impl<T> Future for OBHandshake<T>
@@ -405,19 +473,18 @@ where
                     let conn = try_poll!(f, self, noise);
 
                     let now = SystemTime::now();
-                    let mut ts_a = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
+                    let mut ts_a = unix_time(now)?;
                     ts_a.add_assign(Duration::from_millis(500));
                     let ts_a = ts_a.as_secs() as u32;
 
                     let mut rng = OsRng;
-                    // TODO: Sample padding sizes from an appropriate distribution
-                    let padlen = rng.gen_range(0..16);
+                    let padlen = rng.gen_range(self.sr_padding.clone());
 
                     // SessionRequest
                     let mut sr_buf = [0u8; SESSION_REQUEST_PT_LEN];
                     match frame::gen_session_request(
                         (&mut sr_buf, 0),
-                        2,
+                        self.version,
                         padlen,
                         self.sc_len as u16,
                         ts_a,
@@ -471,8 +538,9 @@ where
                     };
 
                     // Get peer skew
-                    let rtt = rtt_timer.elapsed().expect("Time went backwards?");
+                    let rtt = clock_elapsed(rtt_timer, SystemTime::now())?;
                     debug!("Peer RTT: {:?}", rtt);
+                    self.rtt = Some(rtt);
 
                     OBHandshakeState::SessionCreatedPadding(io::read_exact(conn, vec![0u8; padlen]))
                 }
@@ -537,6 +605,8 @@ where
                         dec_len_masker: SipHasher::new_with_keys(dk0, dk1),
                         dec_len_iv: div,
                         next_len: None,
+                        version: self.version,
+                        rtt: self.rtt,
                     };
 
                     return Ok(Async::Ready((
@@ -553,15 +623,20 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{IBHandshake, IBHandshakeState, OBHandshake, OBHandshakeState};
+    use super::{
+        IBHandshake, IBHandshakeState, OBHandshake, OBHandshakeState, SESSION_REQUEST_CT_LEN,
+    };
     use crate::transport::{
-        ntcp2::Manager,
+        ntcp2::{Block, Manager},
         tests::{AliceNet, BobNet, NetworkCable},
+        TransportStyle, TransportVersion,
     };
 
-    use futures::{done, Async, Future};
+    use futures::{done, Async, Future, Sink, Stream};
+    use std::sync::{Arc, Mutex};
 
-    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::data::{I2PDate, I2PString, RouterAddress, RouterInfo, RouterSecretKeys};
+    use crate::i2np::{Message, MessagePayload};
     use crate::router::mock::MockDistributor;
 
     macro_rules! test_poll {
@@ -583,15 +658,23 @@ mod tests {
         };
     }
 
-    #[test]
-    fn ntcp2_handshake() {
-        // Generate key material
+    /// Sets up a handshake between Alice and Bob over the given cable.
+    fn handshake(cable: Arc<Mutex<NetworkCable>>) -> (OBHandshake<AliceNet>, IBHandshake<BobNet>) {
         let alice_ri = {
             let sk = RouterSecretKeys::new();
             let mut ri = RouterInfo::new(sk.rid.clone());
             ri.sign(&sk.signing_private_key);
             ri
         };
+        handshake_with_ri(cable, alice_ri)
+    }
+
+    /// Sets up a handshake in which Alice sends the given RouterInfo.
+    fn handshake_with_ri(
+        cable: Arc<Mutex<NetworkCable>>,
+        alice_ri: RouterInfo,
+    ) -> (OBHandshake<AliceNet>, IBHandshake<BobNet>) {
+        // Generate key material
         let (
             bob_ri,
             bob_static_public_key,
@@ -615,24 +698,29 @@ mod tests {
         };
 
         // Set up the network
-        let cable = NetworkCable::new();
         let alice_net = AliceNet::new(cable.clone());
         let bob_net = BobNet::new(cable);
 
         // Set up the handshake
-        let mut alice = OBHandshake::new(
+        let alice = OBHandshake::new(
             |_| Box::new(done(Ok(alice_net))),
             &bob_static_public_key,
             &alice_ri,
             bob_ri,
         )
         .unwrap();
-        let mut bob = IBHandshake::new(
+        let bob = IBHandshake::new(
             bob_net,
             &bob_static_private_key,
             &bob_aesobfse_key,
             &bob_aesobfse_iv,
         );
+        (alice, bob)
+    }
+
+    #[test]
+    fn ntcp2_handshake() {
+        let (mut alice, mut bob) = handshake(NetworkCable::new());
         test_state!(alice, Connecting, bob, SessionRequest);
 
         // Connect Alice to Bob
@@ -652,13 +740,148 @@ mod tests {
         // Bob <- SessionConfirmed
         let bob_conn = bob.poll();
 
-        // Both halves should now be ready
+        // Both halves should now be ready, having negotiated version 2
         match (alice_conn, bob_conn) {
-            (Ok(Async::Ready(_)), Ok(Async::Ready(_))) => (),
+            (Ok(Async::Ready((_, alice_conn))), Ok(Async::Ready((_, bob_conn)))) => {
+                let ntcp2_v2 = TransportVersion {
+                    style: TransportStyle::Ntcp2,
+                    version: 2,
+                };
+                assert_eq!(alice_conn.into_parts().codec.transport_version(), ntcp2_v2);
+                assert_eq!(bob_conn.into_parts().codec.transport_version(), ntcp2_v2);
+            }
             _ => panic!(),
         }
     }
 
+    #[test]
+    fn tampered_router_info() {
+        // Alice changes her RouterInfo after signing it
+        let alice_ri = {
+            let sk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(sk.rid.clone());
+            ri.sign(&sk.signing_private_key);
+            ri.published = I2PDate(ri.published.0 + 1);
+            ri
+        };
+        let (mut alice, mut bob) = handshake_with_ri(NetworkCable::new(), alice_ri);
+        test_poll!(alice);
+        test_poll!(bob);
+        assert!(matches!(alice.poll(), Ok(Async::Ready(_))));
+
+        // Bob refuses the session
+        match bob.poll() {
+            Err(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused);
+                assert!(e
+                    .to_string()
+                    .starts_with("Invalid RouterInfo signature in SessionConfirmed"));
+            }
+            _ => panic!("Accepted a tampered RouterInfo"),
+        }
+    }
+
+    #[test]
+    fn inconsistent_router_info() {
+        // Alice signs a RouterInfo with an address that has already expired
+        let alice_ri = {
+            let sk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(sk.rid.clone());
+            let mut ra =
+                RouterAddress::new(&I2PString::new("NTCP2"), "127.0.0.1:0".parse().unwrap());
+            ra.set_expiration(ri.published);
+            ri.set_addresses(vec![ra]);
+            ri.sign(&sk.signing_private_key);
+            ri
+        };
+        let (mut alice, mut bob) = handshake_with_ri(NetworkCable::new(), alice_ri);
+        test_poll!(alice);
+        test_poll!(bob);
+        assert!(matches!(alice.poll(), Ok(Async::Ready(_))));
+
+        // Bob refuses the session
+        match bob.poll() {
+            Err(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused);
+                assert!(e
+                    .to_string()
+                    .starts_with("Invalid RouterInfo in SessionConfirmed"));
+            }
+            _ => panic!("Accepted an inconsistent RouterInfo"),
+        }
+    }
+
+    #[test]
+    fn data_phase_round_trip() {
+        let (mut alice, mut bob) = handshake(NetworkCable::new());
+        test_poll!(alice);
+        test_poll!(bob);
+        let alice_conn = match alice.poll() {
+            Ok(Async::Ready((_, conn))) => conn,
+            _ => panic!(),
+        };
+        let bob_conn = match bob.poll() {
+            Ok(Async::Ready((_, conn))) => conn,
+            _ => panic!(),
+        };
+
+        let frame = |id: u32| {
+            vec![
+                Block::Message(Box::new(Message::dummy_data())),
+                Block::Message(Box::new(Message {
+                    id,
+                    expiration: I2PDate(0x123_4567_87c0),
+                    payload: MessagePayload::Data(vec![42; 100]),
+                    raw: None,
+                })),
+                Block::Padding(16),
+            ]
+        };
+
+        // Each frame advances the length obfuscation IV, so send several in
+        // each direction
+        let alice_conn = alice_conn.send(frame(1)).wait().unwrap();
+        let alice_conn = alice_conn.send(frame(2)).wait().unwrap();
+        let (received, bob_conn) = bob_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(received, Some(frame(1)));
+        let (received, bob_conn) = bob_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(received, Some(frame(2)));
+
+        let bob_conn = bob_conn.send(frame(3)).wait().unwrap();
+        bob_conn.send(frame(4)).wait().unwrap();
+        let (received, alice_conn) = alice_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(received, Some(frame(3)));
+        let (received, _) = alice_conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(received, Some(frame(4)));
+    }
+
+    #[test]
+    fn session_request_padding() {
+        for &(min, max) in &[(0, 0), (1, 1), (32, 64), (1000, 1200)] {
+            for _ in 0..5 {
+                let cable = NetworkCable::new();
+                let (mut alice, mut bob) = handshake(cable.clone());
+                alice.set_session_request_padding(min..=max);
+
+                // Alice -> SessionRequest
+                test_poll!(alice);
+                let padlen = cable.lock().unwrap().alice_to_bob.len() - SESSION_REQUEST_CT_LEN;
+                assert!(padlen >= min as usize && padlen <= max as usize);
+
+                // Bob reads exactly the padding that Alice declared
+                test_poll!(bob);
+                test_state!(alice, SessionCreated, bob, SessionConfirmed);
+                assert!(cable.lock().unwrap().alice_to_bob.is_empty());
+
+                // The rest of the handshake is unaffected
+                match (alice.poll(), bob.poll()) {
+                    (Ok(Async::Ready(_)), Ok(Async::Ready(_))) => (),
+                    _ => panic!(),
+                }
+            }
+        }
+    }
+
     #[cfg(all(test, feature = "nightly"))]
     mod transfer {
         use futures::*;
@@ -30,22 +30,28 @@
 use bytes::BytesMut;
 use cookie_factory::GenError;
 use futures::{
+    future::{self, Either},
     stream::{SplitSink, SplitStream},
     sync::mpsc,
     try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
 };
 use i2p_snow::{self, Builder};
+use log::Level;
 use nom::Err;
 use rand::{rngs::OsRng, Rng};
 use siphasher::sip::SipHasher;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::fs::File;
 use std::hash::Hasher;
 use std::iter::repeat;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::ops::RangeInclusive;
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+use std::time::{Duration, SystemTime};
 use tokio::{
     codec::{Decoder, Encoder, Framed},
     io::{self, AsyncRead, AsyncWrite, Read, Write},
@@ -55,12 +61,16 @@ use tokio::{
 };
 
 use super::{
+    bind_error,
     ntcp::NTCP_STYLE,
-    session::{self, SessionContext, SessionManager, SessionRefs, SessionRx},
-    Bid, Transport,
+    session::{self, SessionContext, SessionInfo, SessionManager, SessionRefs, SessionRx},
+    unix_time, Bid, ConnectionDirection, PeerDiagnostics, PoolStats, Transport, TransportStats,
+    TransportStyle, TransportVersion, IP_ERRORS, PEER_ERRORS,
 };
 use crate::constants::I2P_BASE64;
-use crate::data::{Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo};
+use crate::data::{
+    BandwidthTier, Hash, I2PString, Reachability, RouterAddress, RouterIdentity, RouterInfo,
+};
 use crate::i2np::{DatabaseStore, Message, MessagePayload};
 use crate::router::{
     types::{Distributor, DistributorResult},
@@ -85,12 +95,227 @@ lazy_static! {
 // Max NTCP2 message size is ~64kB
 const NTCP2_MTU: usize = 65535;
 
+// Each data frame has a 16-byte AEAD tag, and each block a 3-byte header
+const NTCP2_FRAME_OVERHEAD: usize = 16 + 3;
+
+// The NTCP2 protocol version we speak, sent in SessionRequest
+const NTCP2_PROTOCOL_VERSION: u8 = 2;
+
 macro_rules! io_err {
     ($err_kind:ident, $err_msg:expr) => {
         Err(io::Error::new(io::ErrorKind::$err_kind, $err_msg))
     };
 }
 
+//
+// Router addresses
+//
+
+/// Errors that can occur while parsing the NTCP2 options of a RouterAddress.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressError {
+    /// A required option is not present.
+    Missing(&'static str),
+    /// An option is not valid I2P base64.
+    InvalidBase64(&'static str),
+    /// An option decodes to the wrong number of bytes.
+    InvalidLength {
+        option: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Missing(option) => write!(f, "missing option '{}'", option),
+            AddressError::InvalidBase64(option) => write!(f, "invalid base64 in '{}'", option),
+            AddressError::InvalidLength {
+                option,
+                expected,
+                actual,
+            } => write!(f, "'{}' is {} bytes, expected {}", option, actual, expected),
+        }
+    }
+}
+
+/// The NTCP2 parameters that a router publishes in its RouterAddress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ntcp2AddressOptions {
+    /// The router's static X25519 public key (`s`), for the Noise handshake.
+    pub static_key: [u8; 32],
+    /// The IV (`i`) for obfuscating the ephemeral key in SessionRequest.
+    pub iv: [u8; 16],
+    /// The NTCP2 versions the router supports (`v`).
+    pub versions: Vec<I2PString>,
+}
+
+impl Ntcp2AddressOptions {
+    /// Parses and validates the NTCP2 options in the given address.
+    pub fn from_address(ra: &RouterAddress) -> Result<Self, AddressError> {
+        let versions = match ra.option(&NTCP2_OPT_V) {
+            Some(v) => v.to_csv(),
+            None => return Err(AddressError::Missing("v")),
+        };
+
+        let mut static_key = [0; 32];
+        decode_option(ra, &NTCP2_OPT_S, "s", &mut static_key)?;
+        let mut iv = [0; 16];
+        decode_option(ra, &NTCP2_OPT_I, "i", &mut iv)?;
+
+        Ok(Ntcp2AddressOptions {
+            static_key,
+            iv,
+            versions,
+        })
+    }
+
+    /// Returns true if the router supports the NTCP2 version we speak.
+    pub fn is_supported(&self) -> bool {
+        self.versions.contains(&NTCP2_VERSION)
+    }
+}
+
+fn decode_option(
+    ra: &RouterAddress,
+    key: &I2PString,
+    name: &'static str,
+    buf: &mut [u8],
+) -> Result<(), AddressError> {
+    let val = match ra.option(key) {
+        Some(val) => val,
+        None => return Err(AddressError::Missing(name)),
+    };
+    let decoded = I2P_BASE64
+        .decode(val.0.as_bytes())
+        .map_err(|_| AddressError::InvalidBase64(name))?;
+    if decoded.len() != buf.len() {
+        return Err(AddressError::InvalidLength {
+            option: name,
+            expected: buf.len(),
+            actual: decoded.len(),
+        });
+    }
+    buf.copy_from_slice(&decoded);
+    Ok(())
+}
+
+/// Returns true if we can connect to the given address with NTCP2.
+fn is_usable_address(ra: &RouterAddress) -> bool {
+    match Ntcp2AddressOptions::from_address(ra) {
+        Ok(opts) => opts.is_supported(),
+        Err(_) => false,
+    }
+}
+
+//
+// Configuration
+//
+
+/// How much padding we add to the handshake messages we send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Add no padding.
+    None,
+    /// Add up to 15 bytes of random padding.
+    Random,
+}
+
+impl PaddingPolicy {
+    /// Picks a padding length for a message.
+    fn sample<R: Rng>(self, rng: &mut R) -> u16 {
+        match self {
+            PaddingPolicy::None => 0,
+            PaddingPolicy::Random => rng.gen_range(0..16),
+        }
+    }
+}
+
+/// How much padding we add to the data-phase frames we send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaddingStrategy {
+    /// Add no padding, even if the peer asks for it.
+    None,
+    /// Add just enough padding to meet the minimum the peer asks for.
+    Min,
+    /// Add between `min` and `max` bytes of random padding, or more if the peer
+    /// asks for it.
+    Random { min: u16, max: u16 },
+}
+
+impl Default for PaddingStrategy {
+    fn default() -> Self {
+        PaddingStrategy::Min
+    }
+}
+
+impl PaddingStrategy {
+    /// Picks a padding length for a frame with `payload` bytes of blocks.
+    /// `peer_min` is the minimum ratio of padding to payload that the peer
+    /// asked for, in 4.4 fixed-point.
+    fn sample<R: Rng>(self, rng: &mut R, payload: usize, peer_min: u8) -> usize {
+        let required = (payload * usize::from(peer_min) + 15) / 16;
+        match self {
+            PaddingStrategy::None => 0,
+            PaddingStrategy::Min => required,
+            PaddingStrategy::Random { min, max } => {
+                usize::from(rng.gen_range(min..=max)).max(required)
+            }
+        }
+    }
+}
+
+/// Returns the minimum padding ratio that a peer asks us to send, in 4.4
+/// fixed-point, from the contents of its Options block.
+fn requested_min_padding(options: &[u8]) -> Option<u8> {
+    // ver, tmin, tmax, rmin, ...
+    options.get(3).cloned()
+}
+
+/// The NTCP2 options we support, each in order of preference.
+///
+/// Our supported versions are advertised in our RouterAddress. When we open a
+/// connection, we use our most preferred version that the peer advertises.
+/// Peers don't advertise a padding policy, so our most preferred one is used
+/// for the padding we send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ntcp2Config {
+    pub versions: Vec<u8>,
+    pub padding: Vec<PaddingPolicy>,
+}
+
+impl Default for Ntcp2Config {
+    fn default() -> Self {
+        Ntcp2Config {
+            versions: vec![NTCP2_PROTOCOL_VERSION],
+            padding: vec![PaddingPolicy::Random, PaddingPolicy::None],
+        }
+    }
+}
+
+impl Ntcp2Config {
+    /// Returns our most preferred version out of those in a peer's address.
+    fn version_for(&self, opts: &Ntcp2AddressOptions) -> Option<u8> {
+        self.versions
+            .iter()
+            .find(|v| opts.versions.contains(&I2PString(v.to_string())))
+            .cloned()
+    }
+
+    /// Returns the padding policy we use for the messages we send.
+    fn padding_policy(&self) -> PaddingPolicy {
+        self.padding.first().cloned().unwrap_or(PaddingPolicy::None)
+    }
+
+    /// Returns the value of the `v` option in our RouterAddress.
+    fn advertised_versions(&self) -> I2PString {
+        let versions: Vec<_> = self.versions.iter().map(|v| v.to_string()).collect();
+        I2PString(versions.join(","))
+    }
+}
+
 //
 // Message transport
 //
@@ -111,6 +336,30 @@ pub enum Block {
     Unknown(u8, Vec<u8>),
 }
 
+impl Block {
+    /// Returns the number of bytes this block serializes to, including its
+    /// header.
+    fn size(&self) -> usize {
+        3 + match *self {
+            Block::DateTime(_) => 4,
+            Block::Options(ref options) => options.len(),
+            Block::RouterInfo(ref ri) => 1 + ri.0.to_bytes().len(),
+            Block::Message(ref msg) => msg.ntcp2_size(),
+            Block::Termination(_, _, ref addl_data) => 9 + addl_data.len(),
+            Block::Padding(size) => usize::from(size),
+            Block::Unknown(_, ref data) => data.len(),
+        }
+    }
+
+    /// Returns the size of the I2NP message in a Message block, or zero.
+    fn message_size(&self) -> usize {
+        match *self {
+            Block::Message(ref msg) => msg.ntcp2_size(),
+            _ => 0,
+        }
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 impl fmt::Debug for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -168,6 +417,18 @@ pub struct Codec {
     dec_len_masker: SipHasher,
     dec_len_iv: u64,
     next_len: Option<usize>,
+    version: u8,
+    rtt: Option<Duration>,
+}
+
+impl Codec {
+    /// Returns the transport protocol version negotiated for this connection.
+    pub fn transport_version(&self) -> TransportVersion {
+        TransportVersion {
+            style: TransportStyle::Ntcp2,
+            version: self.version,
+        }
+    }
 }
 
 impl Decoder for Codec {
@@ -215,6 +476,9 @@ impl Decoder for Codec {
                     Err(Err::Error(e)) | Err(Err::Failure(e)) => {
                         return io_err!(Other, format!("parse error: {:?}", e));
                     }
+                    Ok((rest, _)) if !rest.is_empty() => {
+                        return io_err!(Other, "received frame with trailing data");
+                    }
                     Ok((_, frame)) => frame,
                 };
 
@@ -279,6 +543,16 @@ impl Encoder for Codec {
 // Session handling
 //
 
+/// Returns what we learned about a connection while establishing it.
+fn session_info<T>(
+    conn: Framed<T, Codec>,
+    direction: ConnectionDirection,
+) -> (Framed<T, Codec>, SessionInfo) {
+    let parts = conn.into_parts();
+    let rtt = parts.codec.rtt;
+    (Framed::from_parts(parts), SessionInfo::new(direction, rtt))
+}
+
 struct Session<T, C, D>
 where
     T: AsyncRead + AsyncWrite,
@@ -291,6 +565,7 @@ where
     distributor: D,
     pending_ib: Option<DistributorResult>,
     outbound: SessionRx<Block>,
+    timestamps: SessionRx<Block>,
     cached_ob_block: Option<Block>,
 }
 
@@ -305,16 +580,21 @@ where
         ri: &RouterIdentity,
         upstream: Framed<T, C>,
         session_refs: SessionRefs<Block, D>,
+        info: SessionInfo,
+        padding: PaddingStrategy,
     ) -> Self {
         let (downstream, upstream) = upstream.split();
         let (tx, rx) = mpsc::unbounded();
-        let ctx = SessionContext::new(ri.hash(), session_refs.state, tx);
+        let (timestamps_tx, timestamps) = mpsc::unbounded();
+        let ctx = SessionContext::new(ri.hash(), session_refs.state, tx, timestamps_tx, info);
+        let peer_min_padding = Arc::new(AtomicU8::new(0));
         Session {
-            ib: InboundSession::new(ctx, upstream),
-            ob: OutboundSession::new(downstream),
+            ib: InboundSession::new(ctx, upstream, peer_min_padding.clone()),
+            ob: OutboundSession::new(downstream, padding, peer_min_padding),
             distributor: session_refs.distributor,
             pending_ib: None,
             outbound: rx,
+            timestamps,
             cached_ob_block: None,
         }
     }
@@ -344,21 +624,41 @@ where
         }
 
         // Write blocks
+        let mut retired = false;
         while write_ready {
-            match self.outbound.poll().unwrap() {
-                Async::Ready(Some(block)) => match self.ob.start_send(block)? {
-                    AsyncSink::Ready => (),
-                    AsyncSink::NotReady(block) => {
-                        self.cached_ob_block = Some(block);
-                        write_ready = false;
+            // Time-sync blocks are small and time-sensitive, so they skip
+            // ahead of any queued messages
+            let next = match self.timestamps.poll().unwrap() {
+                Async::Ready(Some(block)) => Async::Ready(Some(block)),
+                _ => self.outbound.poll().unwrap(),
+            };
+            match next {
+                Async::Ready(Some(block)) => {
+                    self.ib.ctx.stats.dequeued(block.message_size());
+                    match self.ob.start_send(block)? {
+                        AsyncSink::Ready => (),
+                        AsyncSink::NotReady(block) => {
+                            self.cached_ob_block = Some(block);
+                            write_ready = false;
+                        }
                     }
-                },
-                _ => break,
+                }
+                Async::Ready(None) => {
+                    // The session has been retired
+                    retired = true;
+                    break;
+                }
+                Async::NotReady => break,
             }
         }
 
         // Flush blocks
-        self.ob.poll_complete()?;
+        let flushed = self.ob.poll_complete()?.is_ready();
+
+        // Close a retired session once everything queued has been written
+        if retired && flushed {
+            return Ok(Async::Ready(()));
+        }
 
         // Read blocks
         loop {
@@ -389,6 +689,10 @@ where
     ctx: SessionContext<Block>,
     upstream: SplitStream<Framed<T, C>>,
     cached_msgs: VecDeque<Message>,
+    /// Set once the peer has sent a Termination block.
+    terminated: bool,
+    /// The minimum padding ratio the peer asked us to send.
+    peer_min_padding: Arc<AtomicU8>,
 }
 
 impl<T, C> InboundSession<T, C>
@@ -397,17 +701,23 @@ where
     C: Decoder<Item = Frame, Error = io::Error>,
     C: Encoder<Item = Frame, Error = io::Error>,
 {
-    fn new(ctx: SessionContext<Block>, upstream: SplitStream<Framed<T, C>>) -> Self {
+    fn new(
+        ctx: SessionContext<Block>,
+        upstream: SplitStream<Framed<T, C>>,
+        peer_min_padding: Arc<AtomicU8>,
+    ) -> Self {
         InboundSession {
             ctx,
             upstream,
             cached_msgs: VecDeque::new(),
+            terminated: false,
+            peer_min_padding,
         }
     }
 
     /// Handles a block at the session level. Optionally returns a message that
     /// should be distributed.
-    fn handle_block(&self, block: Block) -> Option<Message> {
+    fn handle_block(&mut self, block: Block) -> Option<Message> {
         match block {
             Block::RouterInfo(ri) => {
                 // Validate hash
@@ -428,14 +738,24 @@ where
 
                 Some(fake_ds)
             }
-            Block::Message(msg) => Some(*msg),
+            Block::Message(msg) => {
+                self.ctx.stats.received(msg.ntcp2_size());
+                Some(*msg)
+            }
+            Block::Options(ref options) => {
+                match requested_min_padding(options) {
+                    Some(min) => self.peer_min_padding.store(min, Ordering::Relaxed),
+                    None => debug!("Dropping short Options block from {}", self.ctx.hash),
+                }
+                None
+            }
             Block::Padding(_) => {
                 trace!("Dropping padding block from {}: {:?}", self.ctx.hash, block);
                 None
             }
             Block::Termination(_, _, _) => {
                 info!("Peer {} terminated session: {:?}", self.ctx.hash, block);
-                // TODO: Send a Termination in reply, then shut down
+                self.terminated = true;
                 None
             }
             Block::Unknown(_, _) => {
@@ -470,11 +790,20 @@ where
                 return Ok(Async::Ready(Some((self.ctx.hash.clone(), msg))));
             }
 
+            // The peer will not send anything more
+            if self.terminated {
+                return Ok(Async::Ready(None));
+            }
+
             // Read frames
             match try_ready!(self.upstream.poll()) {
                 Some(frame) => {
                     // TODO: Validate block ordering within the frame
                     for block in frame {
+                        if self.terminated {
+                            debug!("Dropping block after Termination: {:?}", block);
+                            continue;
+                        }
                         if let Some(msg) = self.handle_block(block) {
                             self.cached_msgs.push_back(msg);
                         }
@@ -497,6 +826,9 @@ where
 {
     downstream: SplitSink<Framed<T, C>>,
     cached_blocks: VecDeque<Block>,
+    padding: PaddingStrategy,
+    /// The minimum padding ratio the peer asked us to send.
+    peer_min_padding: Arc<AtomicU8>,
 }
 
 impl<T, C> OutboundSession<T, C>
@@ -505,12 +837,44 @@ where
     C: Decoder<Item = Frame, Error = io::Error>,
     C: Encoder<Item = Frame, Error = io::Error>,
 {
-    fn new(downstream: SplitSink<Framed<T, C>>) -> Self {
+    fn new(
+        downstream: SplitSink<Framed<T, C>>,
+        padding: PaddingStrategy,
+        peer_min_padding: Arc<AtomicU8>,
+    ) -> Self {
         OutboundSession {
             downstream,
             cached_blocks: VecDeque::new(),
+            padding,
+            peer_min_padding,
         }
     }
+
+    /// Adds a padding block to the end of the frame, if our padding strategy
+    /// calls for one.
+    fn pad(&self, frame: &mut Frame) {
+        let payload: usize = frame.iter().map(Block::size).sum();
+        let room = (NTCP2_MTU - NTCP2_FRAME_OVERHEAD).saturating_sub(payload);
+        let len = self
+            .padding
+            .sample(
+                &mut OsRng,
+                payload,
+                self.peer_min_padding.load(Ordering::Relaxed),
+            )
+            .min(room)
+            .min(usize::from(u16::MAX));
+        if len > 0 {
+            frame.push(Block::Padding(len as u16));
+        }
+    }
+}
+
+/// Returns the blocks of a frame that could not be sent, without its padding.
+fn unpadded(frame: Frame) -> impl DoubleEndedIterator<Item = Block> {
+    frame
+        .into_iter()
+        .filter(|block| !matches!(block, Block::Padding(_)))
 }
 
 impl<T, C> Sink for OutboundSession<T, C>
@@ -529,13 +893,13 @@ where
         if self.cached_blocks.len() >= BLOCKS_PER_FRAME {
             // Create frame from blocks
             // TODO: Limit frame size instead of blocks per frame
-            // TODO: Add padding
-            let frame = self.cached_blocks.drain(0..BLOCKS_PER_FRAME).collect();
+            let mut frame = self.cached_blocks.drain(0..BLOCKS_PER_FRAME).collect();
+            self.pad(&mut frame);
 
             match self.downstream.start_send(frame)? {
                 AsyncSink::Ready => Ok(AsyncSink::Ready),
                 AsyncSink::NotReady(frame) => {
-                    for block in frame.into_iter().rev() {
+                    for block in unpadded(frame).rev() {
                         self.cached_blocks.push_front(block);
                     }
                     // Guaranteed to return a block
@@ -553,11 +917,11 @@ where
         if !self.cached_blocks.is_empty() {
             // Create frame from blocks
             // TODO: Limit frame size instead of blocks per frame
-            // TODO: Add padding
-            let frame = self.cached_blocks.drain(..).collect();
+            let mut frame = self.cached_blocks.drain(..).collect();
+            self.pad(&mut frame);
 
             if let AsyncSink::NotReady(frame) = self.downstream.start_send(frame)? {
-                self.cached_blocks.extend(frame);
+                self.cached_blocks.extend(unpadded(frame));
                 return Ok(Async::NotReady);
             }
         }
@@ -577,6 +941,10 @@ pub struct Manager<D: Distributor> {
     static_public_key: Vec<u8>,
     aesobfse_iv: [u8; 16],
     session_manager: SessionManager<Block, D>,
+    sr_padding: RangeInclusive<u16>,
+    padding: PaddingStrategy,
+    config: Ntcp2Config,
+    reachability: Reachability,
     ctx: Option<Arc<Context>>,
 }
 
@@ -595,6 +963,10 @@ impl<D: Distributor> Manager<D> {
             static_public_key: dh.public,
             aesobfse_iv,
             session_manager: session::new_manager(distributor),
+            sr_padding: handshake::SESSION_REQUEST_PADDING,
+            padding: PaddingStrategy::default(),
+            config: Ntcp2Config::default(),
+            reachability: Reachability::Reachable,
             ctx: None,
         }
     }
@@ -604,6 +976,17 @@ impl<D: Distributor> Manager<D> {
         let mut data: Vec<u8> = Vec::new();
         keys.read_to_end(&mut data)?;
 
+        if data.len() != 80 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "NTCP2 keyfile {} is corrupt: expected 80 bytes, found {}",
+                    path,
+                    data.len()
+                ),
+            ));
+        }
+
         let mut static_private_key = Vec::with_capacity(32);
         let mut static_public_key = Vec::with_capacity(32);
         let mut aesobfse_iv = [0; 16];
@@ -618,6 +1001,10 @@ impl<D: Distributor> Manager<D> {
             static_public_key,
             aesobfse_iv,
             session_manager: session::new_manager(distributor),
+            sr_padding: handshake::SESSION_REQUEST_PADDING,
+            padding: PaddingStrategy::default(),
+            config: Ntcp2Config::default(),
+            reachability: Reachability::Reachable,
             ctx: None,
         })
     }
@@ -632,9 +1019,129 @@ impl<D: Distributor> Manager<D> {
     }
 
     pub fn set_context(&mut self, ctx: Arc<Context>) {
+        self.session_manager.set_profiles(ctx.profiles.clone());
         self.ctx = Some(ctx);
     }
 
+    /// Sets whether peers can connect to us. While we are unreachable, the
+    /// listener accepts no connections, and we only connect out to peers.
+    /// Takes effect for listeners started afterwards.
+    pub fn set_reachability(&mut self, reachability: Reachability) {
+        self.reachability = reachability;
+    }
+
+    /// Returns whether peers can connect to us.
+    pub fn reachability(&self) -> Reachability {
+        self.reachability
+    }
+
+    /// Sets the NTCP2 options we advertise and use on connections started
+    /// afterwards.
+    pub fn set_config(&mut self, config: Ntcp2Config) {
+        self.config = config;
+    }
+
+    /// Sets the range of padding lengths we add to the SessionRequest of
+    /// connections we open. Each connection picks a length uniformly at random
+    /// from `min..=max`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min > max`, or if `max` would make SessionRequest longer than
+    /// 65535 bytes.
+    pub fn set_session_request_padding(&mut self, min: u16, max: u16) {
+        assert!(min <= max, "Invalid SessionRequest padding range");
+        assert!(
+            max <= handshake::MAX_SESSION_REQUEST_PADDING,
+            "SessionRequest padding too large"
+        );
+        self.sr_padding = min..=max;
+    }
+
+    /// Sets how much padding we add to the data-phase frames of connections
+    /// established afterwards. A random strategy must not have `min > max`.
+    pub fn set_padding_strategy(&mut self, padding: PaddingStrategy) -> io::Result<()> {
+        if let PaddingStrategy::Random { min, max } = padding {
+            if min > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Padding minimum is beyond the maximum",
+                ));
+            }
+        }
+        self.padding = padding;
+        Ok(())
+    }
+
+    /// Limits the number of outbound connections we will hold open. At the
+    /// limit, an idle connection with a peer in a lower bandwidth tier is
+    /// closed to make room for a new peer.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.session_manager.set_max_sessions(max_connections);
+    }
+
+    /// Limits the number of idle connections we will hold open. Beyond the
+    /// limit, the least-recently-used idle connections are closed.
+    pub fn set_max_idle_connections(&mut self, max_idle_connections: usize) {
+        self.session_manager
+            .set_max_idle_sessions(max_idle_connections);
+    }
+
+    /// Returns how many connections we are holding open.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.session_manager.pool_stats()
+    }
+
+    /// Returns the counters for traffic and handshakes on this transport.
+    pub fn stats(&self) -> Arc<TransportStats> {
+        self.session_manager.stats()
+    }
+
+    /// Returns the number of established connections with peers in each
+    /// bandwidth tier.
+    pub fn connections_by_tier(&self) -> HashMap<Option<BandwidthTier>, usize> {
+        self.session_manager.sessions_by_tier()
+    }
+
+    /// Returns a snapshot of each connection we have established.
+    pub fn diagnostics(&self) -> Vec<PeerDiagnostics> {
+        self.session_manager.diagnostics(TransportStyle::Ntcp2)
+    }
+
+    /// Returns the peer's clock minus ours, in seconds, as last measured on
+    /// our connection with them.
+    pub fn clock_skew(&self, peer: &Hash) -> Option<i64> {
+        self.session_manager.clock_skew(peer)
+    }
+
+    /// Sends our current time to the given peer, ahead of any messages already
+    /// queued for them. Returns false if we have no connection to the peer.
+    pub fn send_timestamp(&self, peer: &Hash) -> bool {
+        match unix_time(SystemTime::now()) {
+            Ok(now) => self
+                .session_manager
+                .send_timestamp(peer, Block::DateTime(now.as_secs() as u32)),
+            Err(_) => false,
+        }
+    }
+
+    /// Gracefully closes our connection to the given peer once `after` has
+    /// passed, unless [`Manager::cancel_close`] is called first.
+    pub fn schedule_close(&self, peer: &Hash, after: Duration) {
+        self.session_manager.schedule_close(peer, after)
+    }
+
+    /// Cancels a close scheduled with [`Manager::schedule_close`].
+    pub fn cancel_close(&self, peer: &Hash) {
+        self.session_manager.cancel_close(peer)
+    }
+
+    /// Returns a future that carries out scheduled closes and closes excess
+    /// idle connections, once a second.
+    pub fn sweeper(&self) -> impl Future<Item = (), Error = ()> {
+        self.session_manager.sweeper()
+    }
+
     pub fn sink(&self) -> OutboundSink<D> {
         let ctx = self
             .ctx
@@ -644,13 +1151,16 @@ impl<D: Distributor> Manager<D> {
         OutboundSink {
             ctx,
             static_private_key: self.static_private_key.clone(),
+            sr_padding: self.sr_padding.clone(),
+            padding: self.padding,
+            config: self.config.clone(),
             session_refs: self.session_manager.refs(),
         }
     }
 
     pub fn address(&self) -> RouterAddress {
         let mut ra = RouterAddress::new(&NTCP2_STYLE, self.addr);
-        ra.set_option(NTCP2_OPT_V.clone(), NTCP2_VERSION.clone());
+        ra.set_option(NTCP2_OPT_V.clone(), self.config.advertised_versions());
         ra.set_option(
             NTCP2_OPT_S.clone(),
             I2PString(I2P_BASE64.encode(&self.static_public_key)),
@@ -662,30 +1172,60 @@ impl<D: Distributor> Manager<D> {
         ra
     }
 
-    pub fn listen(&self, own_rid: &RouterIdentity) -> impl Future<Item = (), Error = io::Error> {
-        info!("Listening on {}", self.addr);
+    /// Binds to our address, and returns a Future that handles the
+    /// connections we receive.
+    ///
+    /// If we are unreachable, nothing is bound and the Future resolves
+    /// immediately. Returns an error if the address cannot be bound.
+    pub fn listen(
+        &self,
+        own_rid: &RouterIdentity,
+    ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
+        if self.reachability == Reachability::Unreachable {
+            info!("Unreachable, not listening on {}", self.addr);
+            return Ok(Either::A(future::ok(())));
+        }
 
         // Bind to the address
-        let listener = TcpListener::bind(&self.addr).unwrap();
+        let listener =
+            TcpListener::bind(&self.addr).map_err(|e| bind_error("NTCP2", &self.addr, e))?;
+        info!("Listening on {}", self.addr);
         let static_key = self.static_private_key.clone();
         let aesobfse_key = own_rid.hash().0;
         let aesobfse_iv = self.aesobfse_iv;
+        let config = self.config.clone();
+        let padding = self.padding;
 
         // Give each incoming connection the references it needs
         let session_refs = self.session_manager.refs();
         let conns = listener.incoming().zip(session_refs);
 
         // For each incoming connection:
-        conns.for_each(move |(conn, session_refs)| {
+        Ok(Either::B(conns.for_each(move |(conn, session_refs)| {
+            let ip = match conn.peer_addr() {
+                Ok(addr) => addr.ip(),
+                Err(e) => {
+                    warn!("Dropping incoming connection: {}", e);
+                    return Ok(());
+                }
+            };
+
             info!("Incoming connection!");
             // Execute the handshake
-            let conn = handshake::IBHandshake::new(conn, &static_key, &aesobfse_key, &aesobfse_iv);
+            let conn = handshake::IBHandshake::with_config(
+                conn,
+                &static_key,
+                &aesobfse_key,
+                &aesobfse_iv,
+                &config,
+            );
 
             // Once connected:
             let process_conn = conn
-                .and_then(|(ri, conn)| {
+                .and_then(move |(ri, conn)| {
                     let peer_hash = ri.router_id.hash();
-                    let session = Session::new(&ri.router_id, conn, session_refs);
+                    let (conn, info) = session_info(conn, ConnectionDirection::Inbound);
+                    let session = Session::new(&ri.router_id, conn, session_refs, info, padding);
 
                     // Treat RouterInfo from handshake as a DatabaseStore
                     debug!(
@@ -705,9 +1245,15 @@ impl<D: Distributor> Manager<D> {
                 })
                 .and_then(|session| session);
 
-            spawn(process_conn.map_err(|e| error!("Error while listening: {:?}", e)));
+            spawn(process_conn.map_err(move |e| {
+                IP_ERRORS.log(
+                    ip,
+                    Level::Error,
+                    format_args!("Error on connection from {}: {:?}", ip, e),
+                )
+            }));
             Ok(())
-        })
+        })))
     }
 
     pub fn connect(
@@ -719,35 +1265,70 @@ impl<D: Distributor> Manager<D> {
             &self.static_private_key,
             own_ri,
             peer_ri,
+            self.sr_padding.clone(),
+            self.padding,
+            &self.config,
             self.session_manager.refs(),
         )
     }
+
+    /// Connects to the given peer using our own RouterInfo from the context.
+    pub(super) fn connect_to(
+        &self,
+        peer_ri: RouterInfo,
+    ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
+        let ctx = self.ctx.as_ref().expect("Should have called set_context()");
+        let own_ri = ctx.ri.read().unwrap();
+        self.connect(&own_ri, peer_ri)
+    }
+
+    /// Returns a closure that retires the session with the given peer, and
+    /// returns the messages that were waiting for it to be established.
+    pub(super) fn retirer(&self, hash: Hash) -> impl FnOnce() -> Vec<Message> + Send {
+        let state = self.session_manager.refs().state;
+        move || {
+            state
+                .retire(&hash)
+                .into_iter()
+                .filter_map(|block| match block {
+                    Block::Message(msg) => Some(*msg),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
 }
 
 fn connect<D: Distributor>(
     static_private_key: &[u8],
     own_ri: &RouterInfo,
     peer_ri: RouterInfo,
+    sr_padding: RangeInclusive<u16>,
+    padding: PaddingStrategy,
+    config: &Ntcp2Config,
     session_refs: SessionRefs<Block, D>,
 ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
     // Connect to the peer
-    let transport = match handshake::OBHandshake::new(
+    let mut transport = match handshake::OBHandshake::with_config(
         |sa| Box::new(TcpStream::connect(sa)),
         static_private_key,
         own_ri,
         peer_ri,
+        config,
     ) {
         Ok(t) => t,
         Err(e) => return io_err!(InvalidData, e),
     };
+    transport.set_session_request_padding(sr_padding);
 
     // Add a timeout
     let timed = Timeout::new(transport, Duration::new(10, 0))
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
 
     // Once connected:
-    Ok(timed.and_then(|(ri, conn)| {
-        let session = Session::new(&ri, conn, session_refs);
+    Ok(timed.and_then(move |(ri, conn)| {
+        let (conn, info) = session_info(conn, ConnectionDirection::Outbound);
+        let session = Session::new(&ri, conn, session_refs, info, padding);
         spawn(session.map_err(|_| ()));
         Ok(())
     }))
@@ -758,25 +1339,17 @@ impl<D: Distributor> Transport for Manager<D> {
         self.session_manager.have_session(hash)
     }
 
+    fn mtu(&self) -> usize {
+        NTCP2_MTU - NTCP2_FRAME_OVERHEAD
+    }
+
     fn bid(&self, peer: &RouterInfo, msg_size: usize) -> Option<Bid> {
-        if msg_size > NTCP2_MTU {
+        if msg_size > self.mtu() {
             return None;
         }
 
-        let filter = |ra: &RouterAddress| {
-            match ra.option(&NTCP2_OPT_V) {
-                Some(v) => {
-                    if !v.to_csv().contains(&NTCP2_VERSION) {
-                        return false;
-                    }
-                }
-                None => return false,
-            };
-            ra.option(&NTCP2_OPT_S).is_some() && ra.option(&NTCP2_OPT_I).is_some()
-        };
-
-        if peer.address(&NTCP2_STYLE, filter).is_none()
-            && peer.address(&NTCP_STYLE, filter).is_none()
+        if peer.address(&NTCP2_STYLE, is_usable_address).is_none()
+            && peer.address(&NTCP_STYLE, is_usable_address).is_none()
         {
             return None;
         }
@@ -795,6 +1368,9 @@ impl<D: Distributor> Transport for Manager<D> {
 pub struct OutboundSink<D: Distributor> {
     ctx: Arc<Context>,
     static_private_key: Vec<u8>,
+    sr_padding: RangeInclusive<u16>,
+    padding: PaddingStrategy,
+    config: Ntcp2Config,
     session_refs: SessionRefs<Block, D>,
 }
 
@@ -812,21 +1388,32 @@ impl<D: Distributor> Sink for OutboundSink<D> {
         match self.session_refs.state.send(
             &peer.router_id.hash(),
             Block::Message(Box::new(msg)),
+            peer.bandwidth_tier(),
             || {
                 // Connect to the peer
                 let session_refs = session_refs.clone();
+                let state = session_refs.state.clone();
+                let hash = peer.router_id.hash();
                 match connect(
                     &static_private_key,
                     &self.ctx.ri.read().unwrap(),
                     peer.clone(),
+                    self.sr_padding.clone(),
+                    self.padding,
+                    &self.config,
                     session_refs,
                 ) {
                     Ok(f) => {
-                        spawn(f.map_err(|e| {
-                            error!("Error while connecting: {}", e);
+                        spawn(f.map_err(move |e| {
+                            state.abandon_connect(&hash);
+                            PEER_ERRORS.log(
+                                hash,
+                                Level::Error,
+                                format_args!("Error while connecting: {}", e),
+                            );
                         }));
                     }
-                    Err(e) => error!("{}", e),
+                    Err(e) => PEER_ERRORS.log(hash, Level::Error, format_args!("{}", e)),
                 }
             },
         ) {
@@ -856,10 +1443,19 @@ mod tests {
     use std::iter::repeat;
     use tokio::codec::{Decoder, Encoder};
 
-    use super::{frame, Frame, Manager, Session, NTCP2_MTU};
+    use super::{
+        frame, is_usable_address, requested_min_padding, AddressError, Block, Frame, Manager,
+        Ntcp2AddressOptions, Ntcp2Config, PaddingPolicy, PaddingStrategy, Session, NTCP2_MTU,
+        NTCP2_OPT_I, NTCP2_OPT_S, NTCP2_OPT_V, NTCP2_STYLE,
+    };
+    use crate::data::{I2PString, RouterAddress};
     use crate::i2np::Message;
     use crate::router::mock::{mock_context, MockDistributor};
-    use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
+    use crate::transport::{
+        session::SessionInfo,
+        tests::{AliceNet, BobNet, NetworkCable},
+        ConnectionDirection,
+    };
 
     struct TestCodec;
 
@@ -926,6 +1522,138 @@ mod tests {
         0x0a, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09,
     ];
 
+    fn ntcp2_address(v: &str, s: &str, i: &str) -> RouterAddress {
+        let mut ra = RouterAddress::new(&NTCP2_STYLE, "203.0.113.7:28459".parse().unwrap());
+        ra.set_option(NTCP2_OPT_V.clone(), I2PString::new(v));
+        ra.set_option(NTCP2_OPT_S.clone(), I2PString::new(s));
+        ra.set_option(NTCP2_OPT_I.clone(), I2PString::new(i));
+        ra
+    }
+
+    #[test]
+    fn address_options() {
+        let ra = ntcp2_address(
+            "2",
+            "TJ8LLoHTphV-K8lAP20Yp-XCuQR9Pm-BoMXXKz5PmmE=",
+            "my5NccA~qFZuHfK3BJw6hQ==",
+        );
+        let opts = Ntcp2AddressOptions::from_address(&ra).unwrap();
+        assert_eq!(
+            opts.static_key,
+            [
+                0x4c, 0x9f, 0x0b, 0x2e, 0x81, 0xd3, 0xa6, 0x15, 0x7e, 0x2b, 0xc9, 0x40, 0x3f, 0x6d,
+                0x18, 0xa7, 0xe5, 0xc2, 0xb9, 0x04, 0x7d, 0x3e, 0x6f, 0x81, 0xa0, 0xc5, 0xd7, 0x2b,
+                0x3e, 0x4f, 0x9a, 0x61,
+            ]
+        );
+        assert_eq!(
+            opts.iv,
+            [
+                0x9b, 0x2e, 0x4d, 0x71, 0xc0, 0x3f, 0xa8, 0x56, 0x6e, 0x1d, 0xf2, 0xb7, 0x04, 0x9c,
+                0x3a, 0x85,
+            ]
+        );
+        assert_eq!(opts.versions, vec![I2PString::new("2")]);
+        assert!(opts.is_supported());
+        assert!(is_usable_address(&ra));
+
+        // Versions we don't speak are parsed, but the address is not usable
+        let ra = ntcp2_address(
+            "3",
+            "TJ8LLoHTphV-K8lAP20Yp-XCuQR9Pm-BoMXXKz5PmmE=",
+            "my5NccA~qFZuHfK3BJw6hQ==",
+        );
+        assert!(!Ntcp2AddressOptions::from_address(&ra)
+            .unwrap()
+            .is_supported());
+        assert!(!is_usable_address(&ra));
+    }
+
+    #[test]
+    fn advertised_versions() {
+        let alice = Ntcp2Config {
+            versions: vec![3, 2],
+            padding: vec![PaddingPolicy::None, PaddingPolicy::Random],
+        };
+        let carol = Ntcp2Config {
+            versions: vec![2, 3],
+            padding: vec![PaddingPolicy::Random, PaddingPolicy::None],
+        };
+        let dave = Ntcp2Config {
+            versions: vec![3],
+            padding: vec![PaddingPolicy::None],
+        };
+
+        // Padding isn't advertised, so each side pads with its own first choice
+        assert_eq!(alice.padding_policy(), PaddingPolicy::None);
+        assert_eq!(carol.padding_policy(), PaddingPolicy::Random);
+        assert_eq!(dave.padding_policy(), PaddingPolicy::None);
+
+        // The versions we advertise are used by peers connecting to us
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        manager.set_config(alice);
+        let opts = Ntcp2AddressOptions::from_address(&manager.address()).unwrap();
+        assert_eq!(
+            opts.versions,
+            vec![I2PString::new("3"), I2PString::new("2")]
+        );
+        assert_eq!(carol.version_for(&opts), Some(2));
+        assert_eq!(dave.version_for(&opts), Some(3));
+        assert_eq!(Ntcp2Config::default().version_for(&opts), Some(2));
+    }
+
+    #[test]
+    fn address_options_invalid() {
+        // A 31-byte static key
+        let ra = ntcp2_address(
+            "2",
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==",
+            "my5NccA~qFZuHfK3BJw6hQ==",
+        );
+        assert_eq!(
+            Ntcp2AddressOptions::from_address(&ra),
+            Err(AddressError::InvalidLength {
+                option: "s",
+                expected: 32,
+                actual: 31,
+            })
+        );
+        assert!(!is_usable_address(&ra));
+
+        // A 32-byte IV
+        let ra = ntcp2_address(
+            "2",
+            "TJ8LLoHTphV-K8lAP20Yp-XCuQR9Pm-BoMXXKz5PmmE=",
+            "TJ8LLoHTphV-K8lAP20Yp-XCuQR9Pm-BoMXXKz5PmmE=",
+        );
+        assert_eq!(
+            Ntcp2AddressOptions::from_address(&ra),
+            Err(AddressError::InvalidLength {
+                option: "i",
+                expected: 16,
+                actual: 32,
+            })
+        );
+
+        // Standard base64 is not I2P base64
+        let ra = ntcp2_address(
+            "2",
+            "TJ8LLoHTphV+K8lAP20Yp+XCuQR9Pm+BoMXXKz5PmmE=",
+            "my5NccA~qFZuHfK3BJw6hQ==",
+        );
+        assert_eq!(
+            Ntcp2AddressOptions::from_address(&ra),
+            Err(AddressError::InvalidBase64("s"))
+        );
+
+        // Missing options
+        let ra = RouterAddress::new(&NTCP2_STYLE, "203.0.113.7:28459".parse().unwrap());
+        assert_eq!(
+            Ntcp2AddressOptions::from_address(&ra),
+            Err(AddressError::Missing("v"))
+        );
+    }
+
     #[test]
     fn session_send() {
         let ctx = mock_context();
@@ -955,7 +1683,13 @@ mod tests {
             assert!(received.is_empty());
 
             // Create a session
-            let mut session = Session::new(&rid, alice_framed, manager.session_manager.refs());
+            let mut session = Session::new(
+                &rid,
+                alice_framed,
+                manager.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Outbound, None),
+                PaddingStrategy::default(),
+            );
 
             // Pass it through the session, now it's on the wire
             session.poll().unwrap();
@@ -969,6 +1703,91 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn padding_strategy() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(PaddingStrategy::None.sample(&mut rng, 100, 0x08), 0);
+        assert_eq!(PaddingStrategy::Min.sample(&mut rng, 100, 0), 0);
+
+        // 0x08 is a ratio of 0.5, rounded up
+        assert_eq!(PaddingStrategy::Min.sample(&mut rng, 100, 0x08), 50);
+        assert_eq!(PaddingStrategy::Min.sample(&mut rng, 101, 0x08), 51);
+
+        let random = PaddingStrategy::Random { min: 16, max: 64 };
+        for _ in 0..100 {
+            assert!((16..=64).contains(&random.sample(&mut rng, 100, 0)));
+        }
+        // The peer's minimum takes precedence
+        assert_eq!(random.sample(&mut rng, 1000, 0x08), 500);
+
+        assert_eq!(requested_min_padding(&[0, 0, 0, 0x08, 0]), Some(0x08));
+        assert_eq!(requested_min_padding(&[0, 0, 0]), None);
+
+        // An inverted range is refused
+        let mut manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        assert!(manager.set_padding_strategy(random).is_ok());
+        match manager.set_padding_strategy(PaddingStrategy::Random { min: 64, max: 16 }) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(()) => panic!("Accepted an inverted padding range"),
+        }
+        assert_eq!(manager.padding, random);
+    }
+
+    #[test]
+    fn session_padding() {
+        let ctx = mock_context();
+        let ri = ctx.ri.read().unwrap().clone();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let alice_framed = TestCodec {}.framed(alice_net);
+
+        let distributor = MockDistributor::new();
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
+        manager.set_context(ctx);
+
+        // Run on a task context
+        lazy(move || {
+            let mut session = Session::new(
+                &rid,
+                alice_framed,
+                manager.session_manager.refs(),
+                SessionInfo::new(ConnectionDirection::Outbound, None),
+                PaddingStrategy::Random { min: 16, max: 64 },
+            );
+            let mut bob_net = BobNet::new(cable);
+
+            for _ in 0..20 {
+                manager
+                    .sink()
+                    .send((ri.clone(), Message::dummy_data()))
+                    .poll()
+                    .unwrap();
+                session.poll().unwrap();
+
+                // Each frame ends with padding in range
+                let mut received = Vec::new();
+                assert!(bob_net.read_to_end(&mut received).is_err());
+                match frame::frame(&received) {
+                    Ok((rest, f)) => {
+                        assert!(rest.is_empty());
+                        assert_eq!(f.len(), 2);
+                        match f[1] {
+                            Block::Padding(size) => assert!((16..=64).contains(&size)),
+                            ref block => panic!("Unexpected block: {:?}", block),
+                        }
+                    }
+                    Err(e) => panic!("Unexpected error: {:?}", e),
+                }
+            }
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
     #[test]
     fn session_receive() {
         let ctx = mock_context();
@@ -982,7 +1801,13 @@ mod tests {
         let distributor = MockDistributor::new();
         let received = distributor.received.clone();
         let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
-        let mut session = Session::new(&rid, bob_framed, manager.session_manager.refs());
+        let mut session = Session::new(
+            &rid,
+            bob_framed,
+            manager.session_manager.refs(),
+            SessionInfo::new(ConnectionDirection::Outbound, None),
+            PaddingStrategy::default(),
+        );
 
         // Run on a task context
         lazy(move || {
@@ -1006,4 +1831,49 @@ mod tests {
         .wait()
         .unwrap();
     }
+
+    #[test]
+    fn session_termination() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let bob_net = BobNet::new(cable.clone());
+        let bob_framed = TestCodec {}.framed(bob_net);
+
+        let distributor = MockDistributor::new();
+        let received = distributor.received.clone();
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
+        let mut session = Session::new(
+            &rid,
+            bob_framed,
+            manager.session_manager.refs(),
+            SessionInfo::new(ConnectionDirection::Outbound, None),
+            PaddingStrategy::default(),
+        );
+
+        // Run on a task context
+        lazy(move || {
+            let mut alice_net = AliceNet::new(cable);
+
+            // The session stays open while the peer is connected
+            assert!(alice_net.write_all(DUMMY_MSG_NTCP2_DATA).is_ok());
+            assert!(session.poll().unwrap().is_not_ready());
+            assert_eq!(received.lock().unwrap().len(), 1);
+
+            // A message followed by a Termination block
+            assert!(alice_net.write_all(DUMMY_MSG_NTCP2_DATA).is_ok());
+            assert!(alice_net
+                .write_all(&[0x04, 0x00, 0x09, 0, 0, 0, 0, 0, 0, 0, 2, 0])
+                .is_ok());
+
+            // The message is delivered, and then the session closes
+            assert!(session.poll().unwrap().is_ready());
+            assert_eq!(received.lock().unwrap().len(), 2);
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
 }
@@ -1,13 +1,43 @@
 //! Common structures for managing active sessions over individual transports.
 
-use futures::{sync::mpsc, Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::{
+    future::{self, Either},
+    sync::{mpsc, oneshot},
+    Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
+};
 use std::collections::HashMap;
 use std::fmt;
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::timer::Interval;
 
-use crate::data::Hash;
-use crate::router::types::Distributor;
+use super::{ConnectionDirection, PeerDiagnostics, PoolStats, TransportStats, TransportStyle};
+use crate::data::{BandwidthTier, Hash};
+use crate::i2np::Message;
+use crate::router::{
+    profile::{ProfileEvent, ProfileManager},
+    types::Distributor,
+};
+
+/// How long a session must have carried nothing in either direction before it
+/// can be evicted to make room for a faster peer.
+const IDLE_SESSION_TIME: Duration = Duration::from_secs(60);
+
+/// How long a session may send nothing before it sends a keepalive.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a session may receive nothing before it is closed.
+const KEEPALIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The most frames a session will coalesce into one write.
+const MAX_WRITE_BATCH: usize = 64;
+
+/// How often we carry out scheduled closes and close excess idle sessions.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 //
 // Session state
@@ -19,9 +49,154 @@ type SessionTx<Frame> = mpsc::UnboundedSender<Frame>;
 /// Shorthand for the receive half of a Session-bound message channel.
 pub(super) type SessionRx<Frame> = mpsc::UnboundedReceiver<Frame>;
 
+/// What we learned about a session while establishing it.
+pub(super) struct SessionInfo {
+    pub direction: ConnectionDirection,
+    /// The round-trip time measured during the handshake, if known.
+    pub rtt: Option<Duration>,
+}
+
+impl SessionInfo {
+    pub(super) fn new(direction: ConnectionDirection, rtt: Option<Duration>) -> Self {
+        SessionInfo { direction, rtt }
+    }
+}
+
+/// Counters that a session updates as it runs.
+#[derive(Default)]
+pub(super) struct SessionStats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    queued: AtomicUsize,
+    /// When we last received an I2NP message on the session, if ever.
+    last_received: Mutex<Option<Instant>>,
+    clock_skew: Mutex<Option<i64>>,
+    /// RTT probes awaiting their echo, by nonce.
+    probes: Mutex<HashMap<u32, oneshot::Sender<Instant>>>,
+    /// Where received messages go instead of the distributor, if anywhere.
+    subscriber: Mutex<Option<mpsc::UnboundedSender<Message>>>,
+    /// The counters for the whole transport.
+    transport: Arc<TransportStats>,
+}
+
+impl SessionStats {
+    /// Records an I2NP message of the given size received on the session.
+    pub(super) fn received(&self, bytes: usize) {
+        *self.last_received.lock().unwrap() = Some(Instant::now());
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.transport.received(bytes);
+    }
+
+    /// Records that the session took a frame carrying the given number of
+    /// I2NP message bytes off its queue, for writing to the connection.
+    pub(super) fn dequeued(&self, bytes: usize) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.transport.sent(bytes);
+    }
+
+    fn queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the peer's clock minus ours, in seconds, as measured from a
+    /// time-sync frame it sent.
+    pub(super) fn observed_skew(&self, skew: i64) {
+        *self.clock_skew.lock().unwrap() = Some(skew);
+    }
+
+    fn clock_skew(&self) -> Option<i64> {
+        *self.clock_skew.lock().unwrap()
+    }
+
+    /// Records that the echo of the RTT probe with the given nonce arrived.
+    /// Returns false if we are not waiting for such a probe.
+    pub(super) fn echoed(&self, nonce: u32) -> bool {
+        match self.probes.lock().unwrap().remove(&nonce) {
+            Some(tx) => {
+                // The prober may have given up waiting
+                let _ = tx.send(Instant::now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hands a received message to the session's subscriber. Returns the
+    /// message if there is no subscriber, so it can go to the distributor.
+    pub(super) fn offer(&self, msg: Message) -> Option<Message> {
+        let mut subscriber = self.subscriber.lock().unwrap();
+        let tx = match *subscriber {
+            Some(ref tx) => tx,
+            None => return Some(msg),
+        };
+        match tx.unbounded_send(msg) {
+            Ok(()) => None,
+            Err(e) => {
+                // The subscriber has gone away
+                *subscriber = None;
+                Some(e.into_inner())
+            }
+        }
+    }
+}
+
+struct SessionEntry<F> {
+    tx: SessionTx<F>,
+    /// Time-sync frames, which the session writes ahead of anything in `tx`.
+    timestamps: SessionTx<F>,
+    /// The peer's bandwidth tier, if we know it.
+    tier: Option<BandwidthTier>,
+    /// When we last queued a frame for the session.
+    last_sent: Instant,
+    info: SessionInfo,
+    established: SystemTime,
+    stats: Arc<SessionStats>,
+}
+
+impl<F> SessionEntry<F> {
+    /// Returns when the session last carried a message in either direction.
+    fn last_active(&self) -> Instant {
+        match *self.stats.last_received.lock().unwrap() {
+            Some(received) => received.max(self.last_sent),
+            None => self.last_sent,
+        }
+    }
+
+    fn is_outbound(&self) -> bool {
+        self.info.direction == ConnectionDirection::Outbound
+    }
+}
+
+/// How established sessions check that their peer is still there.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Keepalive {
+    /// How long a session may send nothing before it sends a keepalive.
+    pub interval: Duration,
+    /// How long a session may receive nothing before it is closed.
+    pub idle_timeout: Duration,
+}
+
+struct PendingSession<F> {
+    frames: Vec<F>,
+    tier: Option<BandwidthTier>,
+    /// Told when the session is established. Dropped if the connection fails.
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
 struct Shared<F> {
-    sessions: HashMap<Hash, SessionTx<F>>,
-    pending_sessions: HashMap<Hash, Vec<F>>,
+    sessions: HashMap<Hash, SessionEntry<F>>,
+    pending_sessions: HashMap<Hash, PendingSession<F>>,
+    /// Sessions that will be closed once the given time has passed.
+    closing: HashMap<Hash, Instant>,
+    max_sessions: Option<usize>,
+    max_idle_sessions: Option<usize>,
+    idle_time: Duration,
+    keepalive: Keepalive,
+    max_write_batch: usize,
+    stats: Arc<TransportStats>,
+    /// Where we record how sessions with each peer go, if anywhere.
+    profiles: Option<ProfileManager>,
 }
 
 impl<F> Shared<F> {
@@ -29,6 +204,96 @@ impl<F> Shared<F> {
         Shared {
             sessions: HashMap::new(),
             pending_sessions: HashMap::new(),
+            closing: HashMap::new(),
+            max_sessions: None,
+            max_idle_sessions: None,
+            idle_time: IDLE_SESSION_TIME,
+            keepalive: Keepalive {
+                interval: KEEPALIVE_INTERVAL,
+                idle_timeout: KEEPALIVE_IDLE_TIMEOUT,
+            },
+            max_write_batch: MAX_WRITE_BATCH,
+            stats: Arc::new(TransportStats::default()),
+            profiles: None,
+        }
+    }
+
+    /// Checks whether we can open a new session with a peer in the given tier.
+    /// If we are at the limit, the idle session with the slowest peer is
+    /// evicted to make room, as long as that peer is in a lower tier.
+    ///
+    /// Only sessions that we opened count toward the limit, and only they are
+    /// evicted. Peers in an unknown tier are treated as the slowest.
+    fn make_room(&mut self, tier: Option<BandwidthTier>, now: Instant) -> bool {
+        let outbound = self.sessions.values().filter(|s| s.is_outbound()).count();
+        match self.max_sessions {
+            Some(max) if outbound + self.pending_sessions.len() >= max => (),
+            _ => return true,
+        }
+
+        let evict = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_outbound() && session.tier < tier)
+            .filter(|(_, session)| self.is_idle(session, now))
+            .min_by_key(|(_, session)| (session.tier, session.last_active()))
+            .map(|(hash, _)| hash.clone());
+
+        match evict {
+            Some(hash) => {
+                info!("Evicting idle session with {} to make room", hash);
+                // The session will close once its channel is dropped
+                self.sessions.remove(&hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_idle(&self, session: &SessionEntry<F>, now: Instant) -> bool {
+        now.saturating_duration_since(session.last_active()) >= self.idle_time
+    }
+
+    /// Closes the least-recently-used idle sessions until we are within the
+    /// limit on idle sessions, if there is one.
+    fn evict_idle(&mut self, now: Instant) {
+        let max = match self.max_idle_sessions {
+            Some(max) => max,
+            None => return,
+        };
+
+        let mut idle: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| self.is_idle(session, now))
+            .map(|(hash, session)| (session.last_active(), hash.clone()))
+            .collect();
+        if idle.len() <= max {
+            return;
+        }
+
+        let excess = idle.len() - max;
+        idle.sort_by_key(|(last_active, _)| *last_active);
+        for (_, hash) in idle.into_iter().take(excess) {
+            info!("Closing idle session with {}", hash);
+            // The session will close once its channel is dropped
+            self.sessions.remove(&hash);
+        }
+    }
+
+    /// Closes the sessions whose scheduled close time has passed.
+    fn close_due(&mut self, now: Instant) {
+        let due: Vec<_> = self
+            .closing
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in due {
+            self.closing.remove(&hash);
+            if self.sessions.remove(&hash).is_some() {
+                info!("Closing session with {} as scheduled", hash);
+            }
         }
     }
 }
@@ -46,34 +311,312 @@ impl<F> SessionState<F> {
         self.0.lock().unwrap().sessions.contains_key(hash)
     }
 
+    fn is_connecting(&self, hash: &Hash) -> bool {
+        self.0.lock().unwrap().pending_sessions.contains_key(hash)
+    }
+
+    fn direction(&self, hash: &Hash) -> Option<ConnectionDirection> {
+        self.0
+            .lock()
+            .unwrap()
+            .sessions
+            .get(hash)
+            .map(|session| session.info.direction)
+    }
+
+    /// Records that we are connecting to the peer with the given hash, so that
+    /// frames sent meanwhile wait for that connection. Returns false if we
+    /// already have a session with the peer, or are already connecting to it.
+    pub(super) fn start_connect(&self, hash: &Hash) -> bool {
+        let mut s = self.0.lock().unwrap();
+        if s.sessions.contains_key(hash) || s.pending_sessions.contains_key(hash) {
+            return false;
+        }
+        s.pending_sessions.insert(
+            hash.clone(),
+            PendingSession {
+                frames: vec![],
+                tier: None,
+                waiters: vec![],
+            },
+        );
+        true
+    }
+
+    /// Returns a Future that resolves once we have a session with the peer with
+    /// the given hash. It fails if we are not connecting to the peer, or if the
+    /// connection we are opening fails.
+    pub(super) fn connected(&self, hash: &Hash) -> impl Future<Item = (), Error = io::Error> {
+        let mut s = self.0.lock().unwrap();
+        if s.sessions.contains_key(hash) {
+            return Either::A(future::ok(()));
+        }
+        match s.pending_sessions.get_mut(hash) {
+            Some(pending) => {
+                let (tx, rx) = oneshot::channel();
+                pending.waiters.push(tx);
+                let hash = hash.clone();
+                Either::B(rx.map_err(move |_| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Connection to {} failed", hash),
+                    )
+                }))
+            }
+            None => Either::A(future::err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("Not connecting to {}", hash),
+            ))),
+        }
+    }
+
+    /// Forgets a connection attempt that failed, so that the next frame sent to
+    /// the peer starts a new one. Frames that were waiting for it are dropped.
+    pub(super) fn abandon_connect(&self, hash: &Hash) {
+        let mut s = self.0.lock().unwrap();
+        if let Some(profiles) = &s.profiles {
+            profiles.record(hash, ProfileEvent::Failed);
+        }
+        if let Some(pending) = s.pending_sessions.remove(hash) {
+            if !pending.frames.is_empty() {
+                debug!(
+                    "Dropping {} frames for {}: connection failed",
+                    pending.frames.len(),
+                    hash
+                );
+            }
+        }
+    }
+
+    /// Sends a frame to the peer with the given hash, calling `connect_to_peer`
+    /// if we need to open a session with them first.
+    ///
+    /// The peer's bandwidth tier is used to decide whether they are worth
+    /// evicting another session for, if we are at the session limit. If there
+    /// is no room, the frame is dropped.
     pub(super) fn send<P>(
         &self,
         hash: &Hash,
         frame: F,
+        tier: Option<BandwidthTier>,
         connect_to_peer: P,
     ) -> StartSend<F, mpsc::SendError<F>>
     where
         P: FnOnce(),
     {
         let mut s = self.0.lock().unwrap();
+        let now = Instant::now();
 
-        // If we have an established session, use it.
-        if let Some(mut session) = s.sessions.get(hash) {
-            session.start_send(frame)
-        } else {
+        if let Some(session) = s.sessions.get_mut(hash) {
+            session.last_sent = now;
+        }
+
+        if let Some(session) = s.sessions.get_mut(hash) {
+            // If we have an established session, use it.
+            if tier.is_some() {
+                session.tier = tier;
+            }
+            let res = session.tx.start_send(frame);
+            if let Ok(AsyncSink::Ready) = res {
+                session.stats.queued();
+            }
+            res
+        } else if let Some(pending) = s.pending_sessions.get_mut(hash) {
             // Cache the frame for sending once we have a session.
-            s.pending_sessions
-                .entry(hash.clone())
-                .or_insert_with(|| {
-                    // No pending session, let's create one
-                    connect_to_peer();
-                    vec![]
-                })
-                .push(frame);
+            pending.frames.push(frame);
+            if tier.is_some() {
+                pending.tier = tier;
+            }
+            Ok(AsyncSink::Ready)
+        } else if s.make_room(tier, now) {
+            // No pending session, let's create one
+            connect_to_peer();
+            s.pending_sessions.insert(
+                hash.clone(),
+                PendingSession {
+                    frames: vec![frame],
+                    tier,
+                    waiters: vec![],
+                },
+            );
+            Ok(AsyncSink::Ready)
+        } else {
+            warn!("Dropping frame for {}: too many sessions", hash);
             Ok(AsyncSink::Ready)
         }
     }
 
+    /// Sends a time-sync frame to the peer with the given hash, ahead of any
+    /// frames already queued for them. Returns false if we have no established
+    /// session with the peer.
+    pub(super) fn send_timestamp(&self, hash: &Hash, frame: F) -> bool {
+        let s = self.0.lock().unwrap();
+        match s.sessions.get(hash) {
+            Some(session) if session.timestamps.unbounded_send(frame).is_ok() => {
+                session.stats.queued();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Sends an RTT probe frame to the peer with the given hash, returning a
+    /// receiver for the time its echo arrives. Returns `None` if we have no
+    /// established session with the peer.
+    pub(super) fn probe(
+        &self,
+        hash: &Hash,
+        nonce: u32,
+        frame: F,
+    ) -> Option<oneshot::Receiver<Instant>> {
+        let s = self.0.lock().unwrap();
+        let session = s.sessions.get(hash)?;
+        let (tx, rx) = oneshot::channel();
+        session.stats.probes.lock().unwrap().insert(nonce, tx);
+        if session.tx.unbounded_send(frame).is_err() {
+            session.stats.probes.lock().unwrap().remove(&nonce);
+            return None;
+        }
+        session.stats.queued();
+        Some(rx)
+    }
+
+    /// Returns how sessions should keep themselves alive.
+    pub(super) fn keepalive(&self) -> Keepalive {
+        self.0.lock().unwrap().keepalive
+    }
+
+    /// Returns the most frames a session should coalesce into one write.
+    pub(super) fn max_write_batch(&self) -> usize {
+        self.0.lock().unwrap().max_write_batch
+    }
+
+    /// Sends a frame on our established session with the peer with the given
+    /// hash. Returns false if we have no such session.
+    pub(super) fn send_established(&self, hash: &Hash, frame: F) -> bool {
+        let mut s = self.0.lock().unwrap();
+        match s.sessions.get_mut(hash) {
+            Some(session) if session.tx.unbounded_send(frame).is_ok() => {
+                session.last_sent = Instant::now();
+                session.stats.queued();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the messages received from now on over our established session
+    /// with the peer with the given hash, which then no longer go to the
+    /// distributor. The stream ends when the session does, and is empty if we
+    /// have no session.
+    pub(super) fn subscribe(&self, hash: &Hash) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded();
+        if let Some(session) = self.0.lock().unwrap().sessions.get(hash) {
+            *session.stats.subscriber.lock().unwrap() = Some(tx);
+        }
+        rx
+    }
+
+    /// Stops waiting for the echo of the RTT probe with the given nonce.
+    pub(super) fn cancel_probe(&self, hash: &Hash, nonce: u32) {
+        if let Some(session) = self.0.lock().unwrap().sessions.get(hash) {
+            session.stats.probes.lock().unwrap().remove(&nonce);
+        }
+    }
+
+    /// Returns the peer's clock minus ours, in seconds, as last measured on our
+    /// session with them.
+    pub(super) fn clock_skew(&self, hash: &Hash) -> Option<i64> {
+        let s = self.0.lock().unwrap();
+        s.sessions
+            .get(hash)
+            .and_then(|session| session.stats.clock_skew())
+    }
+
+    /// Schedules the session with the given peer to close once `after` has
+    /// passed. If a close is already scheduled, the earlier time is kept.
+    ///
+    /// Scheduled closes are carried out by [`SessionManager::sweeper`]. Like
+    /// [`SessionState::retire`], the session finishes sending its queued frames
+    /// before it closes.
+    pub(super) fn schedule_close(&self, hash: &Hash, after: Duration) {
+        let mut s = self.0.lock().unwrap();
+        if s.sessions.contains_key(hash) {
+            s.closing
+                .entry(hash.clone())
+                .or_insert(Instant::now() + after);
+        }
+    }
+
+    /// Cancels a close scheduled with [`SessionState::schedule_close`].
+    pub(super) fn cancel_close(&self, hash: &Hash) {
+        self.0.lock().unwrap().closing.remove(hash);
+    }
+
+    /// Closes the sessions whose scheduled close is due by `now`, and then
+    /// the least-recently-used idle sessions beyond the limit.
+    fn sweep(&self, now: Instant) {
+        let mut s = self.0.lock().unwrap();
+        s.close_due(now);
+        s.evict_idle(now);
+    }
+
+    /// Removes the session with the given peer, returning any frames that were
+    /// waiting for it to be established. Dropping the session's channel means
+    /// an established session will finish sending its queued frames and close.
+    pub(super) fn retire(&self, hash: &Hash) -> Vec<F> {
+        let mut s = self.0.lock().unwrap();
+        s.sessions.remove(hash);
+        s.pending_sessions
+            .remove(hash)
+            .map(|pending| pending.frames)
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of established sessions with peers in each
+    /// bandwidth tier.
+    pub(super) fn sessions_by_tier(&self) -> HashMap<Option<BandwidthTier>, usize> {
+        let mut tiers = HashMap::new();
+        for session in self.0.lock().unwrap().sessions.values() {
+            *tiers.entry(session.tier).or_insert(0) += 1;
+        }
+        tiers
+    }
+
+    fn pool_stats(&self, now: Instant) -> PoolStats {
+        let s = self.0.lock().unwrap();
+        PoolStats {
+            established: s.sessions.len(),
+            idle: s
+                .sessions
+                .values()
+                .filter(|session| s.is_idle(session, now))
+                .count(),
+            pending: s.pending_sessions.len(),
+        }
+    }
+
+    /// Returns a snapshot of each established session.
+    pub(super) fn diagnostics(&self, style: TransportStyle) -> Vec<PeerDiagnostics> {
+        self.0
+            .lock()
+            .unwrap()
+            .sessions
+            .iter()
+            .map(|(hash, session)| PeerDiagnostics {
+                peer: hash.to_base32(),
+                style,
+                direction: session.info.direction,
+                established: session.established,
+                rtt: session.info.rtt,
+                bytes_in: session.stats.bytes_in.load(Ordering::Relaxed),
+                bytes_out: session.stats.bytes_out.load(Ordering::Relaxed),
+                queue_depth: session.stats.queued.load(Ordering::Relaxed),
+                clock_skew: session.stats.clock_skew(),
+            })
+            .collect()
+    }
+
     fn new() -> Self {
         SessionState(Arc::new(Mutex::new(Shared::new())))
     }
@@ -81,37 +624,106 @@ impl<F> SessionState<F> {
 
 pub(super) struct SessionContext<F> {
     pub hash: Hash,
+    pub stats: Arc<SessionStats>,
     state: SessionState<F>,
 }
 
 impl<F: fmt::Debug> SessionContext<F> {
-    pub(super) fn new(hash: Hash, state: SessionState<F>, tx: SessionTx<F>) -> Self {
+    pub(super) fn new(
+        hash: Hash,
+        state: SessionState<F>,
+        tx: SessionTx<F>,
+        timestamps: SessionTx<F>,
+        info: SessionInfo,
+    ) -> Self {
+        SessionContext::new_at(hash, state, tx, timestamps, info, Instant::now())
+    }
+
+    /// Opening a session closes the least-recently-used idle sessions beyond
+    /// the limit, as of `now`.
+    fn new_at(
+        hash: Hash,
+        state: SessionState<F>,
+        tx: SessionTx<F>,
+        timestamps: SessionTx<F>,
+        info: SessionInfo,
+        now: Instant,
+    ) -> Self {
         info!("Session established with {}", hash);
 
-        {
+        let stats = {
             let mut s = state.0.lock().unwrap();
+            s.stats.handshake_completed();
+            if let (Some(profiles), Some(rtt)) = (&s.profiles, info.rtt) {
+                profiles.record(&hash, ProfileEvent::Connected(rtt));
+            }
+            let stats = Arc::new(SessionStats {
+                transport: s.stats.clone(),
+                ..Default::default()
+            });
 
             // If there were any pending messages waiting for the session to
             // open, queue them now for sending.
-            if let Some(msgs) = s.pending_sessions.remove(&hash) {
-                for msg in msgs {
+            let mut tier = None;
+            if let Some(pending) = s.pending_sessions.remove(&hash) {
+                tier = pending.tier;
+                for msg in pending.frames {
                     debug!("Sending pending message: {:?}", msg);
                     tx.unbounded_send(msg).unwrap();
+                    stats.queued();
+                }
+                for waiter in pending.waiters {
+                    let _ = waiter.send(());
                 }
             }
 
             // Store the session for future messages
-            s.sessions.insert(hash.clone(), tx);
-        }
+            s.sessions.insert(
+                hash.clone(),
+                SessionEntry {
+                    tx,
+                    timestamps,
+                    tier,
+                    last_sent: now,
+                    info,
+                    established: SystemTime::now(),
+                    stats: stats.clone(),
+                },
+            );
+            s.evict_idle(now);
+            stats
+        };
 
-        SessionContext { hash, state }
+        SessionContext { hash, stats, state }
+    }
+
+    /// Sends a time-sync frame to the peer, ahead of any frames already
+    /// queued for them.
+    pub(super) fn send_timestamp(&self, frame: F) -> bool {
+        self.state.send_timestamp(&self.hash, frame)
     }
 }
 
 impl<F> Drop for SessionContext<F> {
     fn drop(&mut self) {
         info!("Session ended with {}", self.hash);
-        self.state.0.lock().unwrap().sessions.remove(&self.hash);
+        let mut s = self.state.0.lock().unwrap();
+        // A newer session with the same peer may have replaced ours
+        let ours = s
+            .sessions
+            .get(&self.hash)
+            .map_or(false, |session| Arc::ptr_eq(&session.stats, &self.stats));
+        if ours {
+            s.sessions.remove(&self.hash);
+            s.closing.remove(&self.hash);
+        }
+        if let Some(profiles) = &s.profiles {
+            let bytes = self.stats.bytes_in.load(Ordering::Relaxed)
+                + self.stats.bytes_out.load(Ordering::Relaxed);
+            if bytes > 0 {
+                profiles.record(&self.hash, ProfileEvent::Transferred(bytes as usize));
+            }
+        }
     }
 }
 
@@ -168,4 +780,402 @@ impl<F, D: Distributor> SessionManager<F, D> {
     pub fn have_session(&self, hash: &Hash) -> bool {
         self.state.contains(hash)
     }
+
+    /// Returns true if we are connecting to the peer with the given hash.
+    pub fn is_connecting(&self, hash: &Hash) -> bool {
+        self.state.is_connecting(hash)
+    }
+
+    /// Returns which side opened our session with the given peer, if we have
+    /// one.
+    pub(super) fn direction(&self, hash: &Hash) -> Option<ConnectionDirection> {
+        self.state.direction(hash)
+    }
+
+    /// Limits the number of sessions we will open. At the limit, an idle
+    /// session with a slower peer is evicted to make room for a faster one.
+    ///
+    /// Sessions that peers open with us are not limited here.
+    pub(super) fn set_max_sessions(&self, max_sessions: usize) {
+        self.state.0.lock().unwrap().max_sessions = Some(max_sessions);
+    }
+
+    /// Limits the number of idle sessions we will keep open. Beyond the limit,
+    /// the least-recently-used idle sessions are closed when a session opens.
+    pub(super) fn set_max_idle_sessions(&self, max_idle_sessions: usize) {
+        let mut s = self.state.0.lock().unwrap();
+        s.max_idle_sessions = Some(max_idle_sessions);
+        s.evict_idle(Instant::now());
+    }
+
+    /// Records how sessions go in the given peer profiles: an established
+    /// session with a measured RTT counts as a success, a failed connection
+    /// attempt as a failure, and a closed session adds the bytes it carried.
+    pub(super) fn set_profiles(&self, profiles: ProfileManager) {
+        self.state.0.lock().unwrap().profiles = Some(profiles);
+    }
+
+    /// Sets how sessions established afterwards keep themselves alive.
+    pub(super) fn set_keepalive(&self, keepalive: Keepalive) {
+        self.state.0.lock().unwrap().keepalive = keepalive;
+    }
+
+    /// Sets the most frames that sessions established afterwards coalesce
+    /// into one write.
+    pub(super) fn set_max_write_batch(&self, max_write_batch: usize) {
+        self.state.0.lock().unwrap().max_write_batch = max_write_batch.max(1);
+    }
+
+    /// Returns the number of established sessions with peers in each
+    /// bandwidth tier.
+    pub(super) fn sessions_by_tier(&self) -> HashMap<Option<BandwidthTier>, usize> {
+        self.state.sessions_by_tier()
+    }
+
+    pub(super) fn pool_stats(&self) -> PoolStats {
+        self.state.pool_stats(Instant::now())
+    }
+
+    pub(super) fn stats(&self) -> Arc<TransportStats> {
+        self.state.0.lock().unwrap().stats.clone()
+    }
+
+    /// Returns a snapshot of each established session.
+    pub(super) fn diagnostics(&self, style: TransportStyle) -> Vec<PeerDiagnostics> {
+        self.state.diagnostics(style)
+    }
+
+    /// Sends a time-sync frame to the peer, ahead of any frames already queued
+    /// for them. Returns false if we have no established session with the peer.
+    pub(super) fn send_timestamp(&self, hash: &Hash, frame: F) -> bool {
+        self.state.send_timestamp(hash, frame)
+    }
+
+    /// Returns the peer's clock minus ours, in seconds, as last measured on our
+    /// session with them.
+    pub(super) fn clock_skew(&self, hash: &Hash) -> Option<i64> {
+        self.state.clock_skew(hash)
+    }
+
+    /// Closes the session with the given peer once `after` has passed, unless
+    /// the close is cancelled first.
+    pub(super) fn schedule_close(&self, hash: &Hash, after: Duration) {
+        self.state.schedule_close(hash, after)
+    }
+
+    /// Cancels a scheduled close of the session with the given peer.
+    pub(super) fn cancel_close(&self, hash: &Hash) {
+        self.state.cancel_close(hash)
+    }
+
+    /// Returns a future that carries out scheduled closes and closes excess
+    /// idle sessions, every [`SWEEP_INTERVAL`].
+    pub(super) fn sweeper(&self) -> impl Future<Item = (), Error = ()> {
+        let state = self.state.clone();
+        Interval::new(Instant::now() + SWEEP_INTERVAL, SWEEP_INTERVAL)
+            .map_err(|e| error!("Session sweep timer error: {}", e))
+            .for_each(move |_| {
+                state.sweep(Instant::now());
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sync::mpsc;
+    use futures::{lazy, Async, Future, Stream};
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    use super::{SessionContext, SessionInfo, SessionState};
+    use crate::data::{BandwidthTier, Hash};
+    use crate::router::profile::{PeerProfile, ProfileEvent, ProfileManager};
+    use crate::transport::{ConnectionDirection, PoolStats};
+
+    fn open_session(
+        state: &SessionState<u8>,
+        hash: &Hash,
+    ) -> (SessionContext<u8>, mpsc::UnboundedReceiver<u8>) {
+        open_session_with(state, hash, ConnectionDirection::Outbound)
+    }
+
+    fn open_session_with(
+        state: &SessionState<u8>,
+        hash: &Hash,
+        direction: ConnectionDirection,
+    ) -> (SessionContext<u8>, mpsc::UnboundedReceiver<u8>) {
+        open_session_at(state, hash, direction, Instant::now())
+    }
+
+    fn open_session_at(
+        state: &SessionState<u8>,
+        hash: &Hash,
+        direction: ConnectionDirection,
+        now: Instant,
+    ) -> (SessionContext<u8>, mpsc::UnboundedReceiver<u8>) {
+        let (tx, rx) = mpsc::unbounded();
+        let (timestamps, _) = mpsc::unbounded();
+        let info = SessionInfo {
+            direction,
+            rtt: None,
+        };
+        let ctx = SessionContext::new_at(hash.clone(), state.clone(), tx, timestamps, info, now);
+        (ctx, rx)
+    }
+
+    #[test]
+    fn evict_for_faster_peer() {
+        let state = SessionState::new();
+        {
+            let mut s = state.0.lock().unwrap();
+            s.max_sessions = Some(2);
+            s.idle_time = Duration::from_secs(60);
+        }
+
+        // Reach the limit with slow peers
+        let slow = Hash([1; 32]);
+        let slower = Hash([2; 32]);
+        let mut sessions = vec![];
+        for (hash, tier) in &[
+            (slow.clone(), BandwidthTier::L),
+            (slower.clone(), BandwidthTier::K),
+        ] {
+            let connected = Cell::new(false);
+            state
+                .send(hash, 0u8, Some(*tier), || connected.set(true))
+                .unwrap();
+            assert!(connected.get());
+            sessions.push(open_session(&state, hash));
+        }
+
+        // While the sessions are active, a faster peer is refused
+        let fast = Hash([3; 32]);
+        let connected = Cell::new(false);
+        state
+            .send(&fast, 0u8, Some(BandwidthTier::X), || connected.set(true))
+            .unwrap();
+        assert!(!connected.get());
+        assert!(state.0.lock().unwrap().pending_sessions.is_empty());
+
+        // Once they are idle, the slowest is evicted to make room
+        state.0.lock().unwrap().idle_time = Duration::from_secs(0);
+        state
+            .send(&fast, 0u8, Some(BandwidthTier::X), || connected.set(true))
+            .unwrap();
+        assert!(connected.get());
+        assert!(state.contains(&slow));
+        assert!(!state.contains(&slower));
+
+        // A peer in the same tier as the slowest remaining one is refused
+        let also_slow = Hash([4; 32]);
+        let connected = Cell::new(false);
+        state
+            .send(&also_slow, 0u8, Some(BandwidthTier::L), || {
+                connected.set(true)
+            })
+            .unwrap();
+        assert!(!connected.get());
+
+        let tiers = state.sessions_by_tier();
+        assert_eq!(tiers.len(), 1);
+        assert_eq!(tiers[&Some(BandwidthTier::L)], 1);
+    }
+
+    #[test]
+    fn evict_outbound_by_activity() {
+        let state = SessionState::new();
+        {
+            let mut s = state.0.lock().unwrap();
+            s.max_sessions = Some(1);
+            s.idle_time = Duration::from_secs(60);
+        }
+        let now = Instant::now();
+
+        // Sessions that peers open with us don't count toward the limit
+        let inbound = Hash([1; 32]);
+        let (_inbound_ctx, _) = open_session_with(&state, &inbound, ConnectionDirection::Inbound);
+        let outbound = Hash([2; 32]);
+        let connected = Cell::new(false);
+        state
+            .send(&outbound, 0u8, Some(BandwidthTier::L), || {
+                connected.set(true)
+            })
+            .unwrap();
+        assert!(connected.get());
+        let (outbound_ctx, _outbound_rx) = open_session(&state, &outbound);
+
+        // Receiving on a session keeps it active, even if we send nothing
+        *outbound_ctx.stats.last_received.lock().unwrap() = Some(now + Duration::from_secs(90));
+        let mut s = state.0.lock().unwrap();
+        assert!(!s.make_room(Some(BandwidthTier::X), now + Duration::from_secs(120)));
+
+        // Once it is quiet in both directions, it is evicted, rather than the
+        // inbound session with a slower peer
+        assert!(s.make_room(Some(BandwidthTier::X), now + Duration::from_secs(200)));
+        assert!(s.sessions.contains_key(&inbound));
+        assert!(!s.sessions.contains_key(&outbound));
+    }
+
+    #[test]
+    fn replaced_session_ends() {
+        let state = SessionState::new();
+        let hash = Hash([1; 32]);
+        let (old_ctx, _old_rx) = open_session(&state, &hash);
+        let (new_ctx, _new_rx) = open_session_with(&state, &hash, ConnectionDirection::Inbound);
+
+        // The old session ending leaves the new one in place
+        state.schedule_close(&hash, Duration::from_secs(60));
+        drop(old_ctx);
+        assert!(state.contains(&hash));
+        assert!(state.0.lock().unwrap().closing.contains_key(&hash));
+
+        // Until the new one ends too
+        drop(new_ctx);
+        assert!(!state.contains(&hash));
+        assert!(state.0.lock().unwrap().closing.is_empty());
+    }
+
+    #[test]
+    fn scheduled_close() {
+        let state = SessionState::new();
+        let expired = Hash([1; 32]);
+        let refreshed = Hash([2; 32]);
+        let (_expired_ctx, expired_rx) = open_session(&state, &expired);
+        let (_refreshed_ctx, _refreshed_rx) = open_session(&state, &refreshed);
+
+        // Closes are not carried out before they are due
+        state.schedule_close(&expired, Duration::from_secs(60));
+        state.schedule_close(&refreshed, Duration::from_secs(60));
+        state.cancel_close(&refreshed);
+        assert!(state.contains(&expired));
+        assert!(!state.0.lock().unwrap().closing.contains_key(&refreshed));
+
+        // Rescheduling keeps the earlier time
+        let now = Instant::now();
+        state.schedule_close(&expired, Duration::from_secs(0));
+        state.sweep(now + Duration::from_secs(30));
+        assert!(state.contains(&expired));
+
+        // A due close is carried out on the next sweep, after queued frames
+        state
+            .send(&expired, 7u8, None, || panic!("Session should be open"))
+            .unwrap();
+        state.sweep(now + Duration::from_secs(61));
+        assert!(!state.contains(&expired));
+        assert!(state.contains(&refreshed));
+        assert_eq!(expired_rx.collect().wait(), Ok(vec![7]));
+    }
+
+    #[test]
+    fn evict_idle_sessions() {
+        let state = SessionState::new();
+        {
+            let mut s = state.0.lock().unwrap();
+            s.max_idle_sessions = Some(1);
+            s.idle_time = Duration::from_secs(60);
+        }
+        let start = Instant::now();
+
+        // Open more sessions than we allow to be idle
+        let oldest = Hash([1; 32]);
+        let older = Hash([2; 32]);
+        let active = Hash([3; 32]);
+        let mut sessions = vec![];
+        for (hash, opened) in &[(&oldest, 0), (&older, 1), (&active, 30)] {
+            let now = start + Duration::from_secs(*opened);
+            sessions.push(open_session_at(
+                &state,
+                hash,
+                ConnectionDirection::Outbound,
+                now,
+            ));
+        }
+        assert_eq!(
+            state.pool_stats(start + Duration::from_secs(30)),
+            PoolStats {
+                established: 3,
+                idle: 0,
+                pending: 0,
+            }
+        );
+
+        // Once two are idle, opening another session closes the oldest of them
+        let newest = Hash([4; 32]);
+        let now = start + Duration::from_secs(61);
+        let _newest = open_session_at(&state, &newest, ConnectionDirection::Outbound, now);
+        assert!(!state.contains(&oldest));
+        assert!(state.contains(&older));
+        assert!(state.contains(&active));
+        assert!(state.contains(&newest));
+        assert_eq!(
+            state.pool_stats(now),
+            PoolStats {
+                established: 3,
+                idle: 1,
+                pending: 0,
+            }
+        );
+
+        // The closed session's queue has ended
+        let (_, oldest_rx) = sessions.remove(0);
+        assert_eq!(oldest_rx.collect().wait(), Ok(vec![]));
+    }
+
+    #[test]
+    fn wait_for_connection() {
+        let state = SessionState::<u8>::new();
+        let peer = Hash([1; 32]);
+
+        // There is nothing to wait for before we start connecting
+        assert!(state.connected(&peer).wait().is_err());
+
+        // Waiters are told when the session is established
+        assert!(state.start_connect(&peer));
+        let mut waiter = state.connected(&peer);
+        assert_eq!(lazy(|| waiter.poll()).wait().unwrap(), Async::NotReady);
+        let _session = open_session(&state, &peer);
+        assert!(waiter.wait().is_ok());
+        assert!(state.connected(&peer).wait().is_ok());
+
+        // Or that the connection failed
+        let failed = Hash([2; 32]);
+        assert!(state.start_connect(&failed));
+        let waiter = state.connected(&failed);
+        state.abandon_connect(&failed);
+        assert!(waiter.wait().is_err());
+    }
+
+    #[test]
+    fn record_profiles() {
+        let state = SessionState::<u8>::new();
+        let profiles = ProfileManager::new();
+        state.0.lock().unwrap().profiles = Some(profiles.clone());
+
+        // A failed connection attempt counts against the peer
+        let failed = Hash([1; 32]);
+        assert!(state.start_connect(&failed));
+        state.abandon_connect(&failed);
+        let mut expected = PeerProfile::new();
+        expected.record(ProfileEvent::Failed);
+        assert_eq!(profiles.profile(&failed), Some(expected));
+
+        // An established session records its RTT, and its traffic once closed
+        let peer = Hash([2; 32]);
+        let (tx, _rx) = mpsc::unbounded();
+        let (timestamps, _) = mpsc::unbounded();
+        let info = SessionInfo {
+            direction: ConnectionDirection::Outbound,
+            rtt: Some(Duration::from_millis(100)),
+        };
+        let ctx = SessionContext::new(peer.clone(), state, tx, timestamps, info);
+        let mut expected = PeerProfile::new();
+        expected.record(ProfileEvent::Connected(Duration::from_millis(100)));
+        assert_eq!(profiles.profile(&peer), Some(expected.clone()));
+
+        ctx.stats.received(1024);
+        drop(ctx);
+        expected.record(ProfileEvent::Transferred(1024));
+        assert_eq!(profiles.profile(&peer), Some(expected));
+    }
 }
@@ -8,9 +8,11 @@ mod acceptor;
 mod encryption;
 mod frame;
 mod processor;
+mod reassembly;
 
 pub use self::acceptor::Listener;
 pub use self::processor::Participant;
+pub use self::reassembly::{TunnelDataReassembler, MAX_PARTIAL_MESSAGES};
 
 /// The lifetime of a tunnel. Always 10 minutes for current I2P tunnels.
 const TUNNEL_LIFETIME: u64 = 10 * 60;
@@ -43,8 +45,9 @@ impl PartialEq for HopConfig {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum TunnelMessageDeliveryType {
+/// Where a tunnel endpoint should send a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TunnelMessageDeliveryType {
     Local,
     Tunnel(TunnelId, Hash),
     Router(Hash),
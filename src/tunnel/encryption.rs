@@ -96,4 +96,30 @@ mod tests {
         cipher.encrypt_layer(&mut td);
         assert_eq!(&td.data[..], &[0; 1024][..]);
     }
+
+    #[test]
+    fn round_trip_in_place() {
+        let iv_key = SessionKey([3; 32]);
+        let layer_key = SessionKey([4; 32]);
+        let cipher = LayerCipher::new(&iv_key, layer_key);
+
+        let mut payload = [0; 1024];
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut td = TunnelData {
+            tid: TunnelId(5678),
+            data: Box::new(payload),
+        };
+
+        // Both the IV and the data are transformed, in place
+        cipher.encrypt_layer(&mut td);
+        assert!(td.data[..16] != payload[..16]);
+        assert!(td.data[16..] != payload[16..]);
+        assert_eq!(td.tid, TunnelId(5678));
+
+        cipher.decrypt_layer(&mut td);
+        assert_eq!(&td.data[..], &payload[..]);
+        assert_eq!(td.tid, TunnelId(5678));
+    }
 }
@@ -0,0 +1,323 @@
+//! Reassembly of I2NP messages from the fragments inside tunnel messages.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::{frame::tunnel_message, TunnelMessageDeliveryInstructions, TunnelMessageDeliveryType};
+use crate::data::ReadError;
+use crate::i2np::{HeaderFormat, Message, TunnelData};
+
+/// A suitable limit on the number of messages to reassemble at once.
+pub const MAX_PARTIAL_MESSAGES: usize = 1024;
+
+/// The fragments we have received so far of a single I2NP message.
+struct PartialMessage {
+    first: Option<(TunnelMessageDeliveryType, Vec<u8>)>,
+    follow_on: HashMap<u8, Vec<u8>>,
+    last_fragment: Option<u8>,
+    started: Instant,
+}
+
+impl PartialMessage {
+    fn new(now: Instant) -> Self {
+        PartialMessage {
+            first: None,
+            follow_on: HashMap::new(),
+            last_fragment: None,
+            started: now,
+        }
+    }
+
+    /// Returns true if every fragment has arrived.
+    fn is_complete(&self) -> bool {
+        match (&self.first, self.last_fragment) {
+            (Some(_), Some(last_fragment)) => {
+                (1..=last_fragment).all(|n| self.follow_on.contains_key(&n))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the delivery type and bytes of a complete message.
+    fn assemble(mut self) -> Option<(TunnelMessageDeliveryType, Vec<u8>)> {
+        let (delivery_type, mut buf) = self.first.take()?;
+        for n in 1..=self.last_fragment? {
+            buf.extend_from_slice(&self.follow_on.remove(&n)?);
+        }
+        Some((delivery_type, buf))
+    }
+}
+
+/// Reassembles the I2NP messages carried by a series of decrypted
+/// [`TunnelData`] messages, as a tunnel endpoint receives them.
+///
+/// Fragments are tracked by message ID. If a message is not complete within the
+/// timeout, its fragments are discarded. At most `max_partial` messages are
+/// tracked at once; beyond that, the oldest incomplete message is discarded.
+pub struct TunnelDataReassembler {
+    timeout: Duration,
+    max_partial: usize,
+    partial: HashMap<u32, PartialMessage>,
+}
+
+impl TunnelDataReassembler {
+    pub fn new(timeout: Duration, max_partial: usize) -> Self {
+        TunnelDataReassembler {
+            timeout,
+            max_partial,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Accepts the decrypted payload of a [`TunnelData`] message, and returns
+    /// any messages that it completes, along with where each should go.
+    ///
+    /// Messages that fail to parse once reassembled are dropped.
+    pub fn receive(
+        &mut self,
+        td: &TunnelData,
+    ) -> Result<Vec<(TunnelMessageDeliveryType, Message)>, ReadError> {
+        self.receive_at(td, Instant::now())
+    }
+
+    fn receive_at(
+        &mut self,
+        td: &TunnelData,
+        now: Instant,
+    ) -> Result<Vec<(TunnelMessageDeliveryType, Message)>, ReadError> {
+        self.expire_at(now);
+
+        let (_, tm) = tunnel_message(&td.data[..])?;
+        let mut completed = vec![];
+        for (tmdi, frag) in tm.0 {
+            let msg_id = match tmdi {
+                TunnelMessageDeliveryInstructions::First(di) => match di.msg_id {
+                    Some(msg_id) => {
+                        self.partial_at(msg_id, now).first =
+                            Some((di.delivery_type, frag.to_vec()));
+                        msg_id
+                    }
+                    None => {
+                        // Unfragmented message
+                        completed.extend(parse_message(frag).map(|msg| (di.delivery_type, msg)));
+                        continue;
+                    }
+                },
+                TunnelMessageDeliveryInstructions::FollowOn(di) => {
+                    let partial = self.partial_at(di.msg_id, now);
+                    partial.follow_on.insert(di.fragment_number, frag.to_vec());
+                    if di.last_fragment {
+                        partial.last_fragment = Some(di.fragment_number);
+                    }
+                    di.msg_id
+                }
+            };
+
+            if self.partial.get(&msg_id).map_or(false, |p| p.is_complete()) {
+                if let Some((delivery_type, buf)) =
+                    self.partial.remove(&msg_id).and_then(|p| p.assemble())
+                {
+                    completed.extend(parse_message(&buf).map(|msg| (delivery_type, msg)));
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    /// Returns the fragments received so far for the given message, making
+    /// room for it if necessary.
+    fn partial_at(&mut self, msg_id: u32, now: Instant) -> &mut PartialMessage {
+        if !self.partial.contains_key(&msg_id) && self.partial.len() >= self.max_partial {
+            let oldest = self
+                .partial
+                .iter()
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                debug!("Too many incomplete messages, discarding {}", oldest);
+                self.partial.remove(&oldest);
+            }
+        }
+        self.partial
+            .entry(msg_id)
+            .or_insert_with(|| PartialMessage::new(now))
+    }
+
+    /// Discards the fragments of any messages that did not complete within the
+    /// timeout.
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.partial.retain(|msg_id, partial| {
+            let keep = now.duration_since(partial.started) < timeout;
+            if !keep {
+                debug!("Discarding incomplete message {}", msg_id);
+            }
+            keep
+        });
+    }
+}
+
+fn parse_message(buf: &[u8]) -> Option<Message> {
+    match Message::parse(buf, HeaderFormat::Standard) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            warn!("Dropping reassembled message: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{TunnelDataReassembler, MAX_PARTIAL_MESSAGES};
+    use crate::data::{Hash, TunnelId};
+    use crate::i2np::{Message, MessagePayload, TunnelData};
+    use crate::tunnel::{
+        frame::gen_tunnel_message, FirstFragmentDeliveryInstructions,
+        FollowOnFragmentDeliveryInstructions, TunnelMessage, TunnelMessageDeliveryInstructions,
+        TunnelMessageDeliveryType,
+    };
+
+    const MSG_ID: u32 = 123_456_789;
+
+    fn tunnel_data(tm: &TunnelMessage) -> TunnelData {
+        let mut data = [0; 1024];
+        gen_tunnel_message((&mut data[..], 0), &[0; 16], tm).unwrap();
+        TunnelData {
+            tid: TunnelId(42),
+            data: Box::new(data),
+        }
+    }
+
+    fn first(msg_id: u32, delivery_type: TunnelMessageDeliveryType, frag: &[u8]) -> TunnelData {
+        tunnel_data(&TunnelMessage(vec![(
+            TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                delivery_type,
+                msg_id: Some(msg_id),
+            }),
+            frag,
+        )]))
+    }
+
+    fn last(msg_id: u32, frag: &[u8]) -> TunnelData {
+        tunnel_data(&TunnelMessage(vec![(
+            TunnelMessageDeliveryInstructions::FollowOn(FollowOnFragmentDeliveryInstructions {
+                fragment_number: 1,
+                last_fragment: true,
+                msg_id,
+            }),
+            frag,
+        )]))
+    }
+
+    #[test]
+    fn reassemble_two_fragments() {
+        let msg = Message::from_payload(MessagePayload::Data(vec![0xab; 1500]));
+        let buf = msg.to_bytes();
+        let (frag_1, frag_2) = buf.split_at(900);
+        let gateway = TunnelMessageDeliveryType::Tunnel(TunnelId(7), Hash([3; 32]));
+
+        let mut reassembler =
+            TunnelDataReassembler::new(Duration::from_secs(45), MAX_PARTIAL_MESSAGES);
+        assert!(reassembler
+            .receive(&first(MSG_ID, gateway.clone(), frag_1))
+            .unwrap()
+            .is_empty());
+
+        // The message goes where its first fragment said
+        let completed = reassembler.receive(&last(MSG_ID, frag_2)).unwrap();
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].0, gateway);
+        assert_eq!(completed[0].1.to_bytes(), buf);
+        assert!(reassembler.partial.is_empty());
+
+        // An unfragmented message is returned straight away
+        let small = Message::from_payload(MessagePayload::Data(vec![0xcd; 100]));
+        let completed = reassembler
+            .receive(&tunnel_data(&TunnelMessage(vec![(
+                TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                    delivery_type: TunnelMessageDeliveryType::Router(Hash([5; 32])),
+                    msg_id: None,
+                }),
+                &small.to_bytes(),
+            )])))
+            .unwrap();
+        assert_eq!(completed.len(), 1);
+        assert_eq!(
+            completed[0].0,
+            TunnelMessageDeliveryType::Router(Hash([5; 32]))
+        );
+        assert_eq!(completed[0].1.to_bytes(), small.to_bytes());
+    }
+
+    #[test]
+    fn incomplete_message_times_out() {
+        let msg = Message::from_payload(MessagePayload::Data(vec![0xab; 1500]));
+        let buf = msg.to_bytes();
+        let (frag_1, frag_2) = buf.split_at(900);
+
+        let timeout = Duration::from_secs(45);
+        let mut reassembler = TunnelDataReassembler::new(timeout, MAX_PARTIAL_MESSAGES);
+        let start = Instant::now();
+        assert!(reassembler
+            .receive_at(
+                &first(MSG_ID, TunnelMessageDeliveryType::Local, frag_1),
+                start
+            )
+            .unwrap()
+            .is_empty());
+        assert_eq!(reassembler.partial.len(), 1);
+
+        // The first fragment is kept until the timeout
+        reassembler.expire_at(start + timeout / 2);
+        assert_eq!(reassembler.partial.len(), 1);
+        reassembler.expire_at(start + timeout);
+        assert!(reassembler.partial.is_empty());
+
+        // A late fragment does not complete the message
+        assert!(reassembler
+            .receive_at(&last(MSG_ID, frag_2), start + timeout)
+            .unwrap()
+            .is_empty());
+        assert_eq!(reassembler.partial.len(), 1);
+    }
+
+    #[test]
+    fn too_many_incomplete_messages() {
+        let msg = Message::from_payload(MessagePayload::Data(vec![0xab; 1500]));
+        let buf = msg.to_bytes();
+        let (frag_1, frag_2) = buf.split_at(900);
+
+        let mut reassembler = TunnelDataReassembler::new(Duration::from_secs(45), 2);
+        let start = Instant::now();
+        for (i, msg_id) in (1..=3).enumerate() {
+            reassembler
+                .receive_at(
+                    &first(msg_id, TunnelMessageDeliveryType::Local, frag_1),
+                    start + Duration::from_secs(i as u64),
+                )
+                .unwrap();
+        }
+
+        // The oldest message made room for the newest
+        assert_eq!(reassembler.partial.len(), 2);
+        assert!(!reassembler.partial.contains_key(&1));
+        assert!(reassembler
+            .receive_at(&last(1, frag_2), start + Duration::from_secs(3))
+            .unwrap()
+            .is_empty());
+
+        // Messages still being tracked can complete
+        let completed = reassembler
+            .receive_at(&last(3, frag_2), start + Duration::from_secs(4))
+            .unwrap();
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].1.to_bytes(), buf);
+    }
+}
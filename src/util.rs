@@ -3,30 +3,52 @@
 use bloom_filter_rs::{BloomFilter, Murmur3};
 use cookie_factory::GenError;
 use core::fmt;
+use futures::{Future, Stream};
+use log::Level;
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::iter::repeat;
 use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::timer::Interval;
 
 pub fn serialize<S>(serializer: S) -> Vec<u8>
 where
     S: Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError>,
 {
-    let mut buf = vec![];
+    serialize_with_len(0, serializer)
+}
+
+/// Serializes into a buffer that starts with the given length, so a caller who
+/// knows the exact serialized length avoids growing the buffer and retrying.
+pub fn serialize_with_len<S>(len: usize, serializer: S) -> Vec<u8>
+where
+    S: Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError>,
+{
+    try_serialize_with_len(len, serializer).expect("Couldn't serialize")
+}
+
+/// Like `serialize_with_len`, but returns errors other than the buffer being
+/// too small, instead of panicking.
+pub fn try_serialize_with_len<S>(len: usize, serializer: S) -> Result<Vec<u8>, GenError>
+where
+    S: Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError>,
+{
+    let mut buf = vec![0; len];
     loop {
         match serializer((&mut buf, 0)).map(|tup| tup.1) {
             Ok(sz) => {
                 buf.truncate(sz);
-                break;
+                return Ok(buf);
             }
-            Err(e) => match e {
-                GenError::BufferTooSmall(sz) => {
-                    let cur_len = buf.len();
-                    buf.extend(repeat(0).take(sz - cur_len));
-                }
-                _ => panic!("Couldn't serialize"),
-            },
+            Err(GenError::BufferTooSmall(sz)) => {
+                let cur_len = buf.len();
+                buf.extend(repeat(0).take(sz - cur_len));
+            }
+            Err(e) => return Err(e),
         }
     }
-    buf
 }
 
 /// Format a byte array as a colon-delimited hex string.
@@ -90,9 +112,196 @@ impl DecayingBloomFilter {
     }
 }
 
+/// The messages that a `LogLimiter` is suppressing for one key.
+struct LogWindow {
+    start: SystemTime,
+    level: Level,
+    suppressed: usize,
+}
+
+struct LogWindows<K> {
+    open: HashMap<K, LogWindow>,
+    /// Messages suppressed because too many keys were being tracked.
+    overflow: usize,
+}
+
+/// Rate-limits log messages about a particular key, such as a misbehaving peer.
+///
+/// The first message for a key opens a window, and is logged. Further messages
+/// for that key are suppressed until the window closes, either when the next
+/// message for the key arrives after the interval, or when it is swept. The
+/// number of suppressed messages is reported when the window closes.
+///
+/// At most `max_keys` windows are open at once. Messages about other keys are
+/// suppressed while the limiter is full, and counted separately.
+pub struct LogLimiter<K> {
+    interval: Duration,
+    max_keys: usize,
+    windows: Mutex<LogWindows<K>>,
+}
+
+impl<K: Clone + Eq + Hash> LogLimiter<K> {
+    pub fn new(interval: Duration, max_keys: usize) -> Self {
+        LogLimiter {
+            interval,
+            max_keys,
+            windows: Mutex::new(LogWindows {
+                open: HashMap::new(),
+                overflow: 0,
+            }),
+        }
+    }
+
+    /// Returns the number of messages that were suppressed in the key's
+    /// previous window if a message for it should be logged now, or `None` if
+    /// it should be suppressed.
+    fn check(&self, key: K, level: Level, now: SystemTime) -> Option<usize> {
+        let mut windows = self.windows.lock().unwrap();
+
+        if let Some(window) = windows.open.get_mut(&key) {
+            if now.duration_since(window.start).unwrap_or_default() < self.interval {
+                window.suppressed += 1;
+                return None;
+            }
+            let suppressed = window.suppressed;
+            *window = LogWindow {
+                start: now,
+                level,
+                suppressed: 0,
+            };
+            return Some(suppressed);
+        }
+
+        if windows.open.len() >= self.max_keys {
+            windows.overflow += 1;
+            return None;
+        }
+        windows.open.insert(
+            key,
+            LogWindow {
+                start: now,
+                level,
+                suppressed: 0,
+            },
+        );
+        Some(0)
+    }
+
+    /// Closes the windows that ended by `now`. Returns the keys that had
+    /// messages suppressed, with the level and number of those messages, and
+    /// the number of messages suppressed because the limiter was full.
+    fn close_windows(&self, now: SystemTime) -> (Vec<(K, Level, usize)>, usize) {
+        let mut windows = self.windows.lock().unwrap();
+        let interval = self.interval;
+        let mut closed = vec![];
+        windows.open.retain(|key, window| {
+            let ended = now.duration_since(window.start).unwrap_or_default() >= interval;
+            if ended && window.suppressed > 0 {
+                closed.push((key.clone(), window.level, window.suppressed));
+            }
+            !ended
+        });
+        (closed, mem::replace(&mut windows.overflow, 0))
+    }
+
+    /// Logs the given message, unless too many have been logged for this key.
+    pub fn log(&self, key: K, level: Level, args: fmt::Arguments<'_>) {
+        match self.check(key, level, SystemTime::now()) {
+            Some(0) => log!(level, "{}", args),
+            Some(suppressed) => log!(
+                level,
+                "{} ({} similar messages suppressed)",
+                args,
+                suppressed
+            ),
+            None => (),
+        }
+    }
+}
+
+/// Lets a limiter held in a static be swept along with the other caches.
+impl<K: Clone + Eq + Hash + fmt::Display + Send> Expirable for &'static LogLimiter<K> {
+    fn sweep_expired(&mut self, now: SystemTime) {
+        let (closed, overflow) = self.close_windows(now);
+        for (key, level, suppressed) in closed {
+            log!(level, "{}: {} similar messages suppressed", key, suppressed);
+        }
+        if overflow > 0 {
+            warn!("{} messages about other peers suppressed", overflow);
+        }
+    }
+}
+
+/// A cache whose entries expire, so that they can be swept out periodically
+/// instead of only when the cache is next used.
+pub trait Expirable: Send {
+    /// Removes the entries that have expired by `now`.
+    fn sweep_expired(&mut self, now: SystemTime);
+}
+
+type SharedCache = Arc<Mutex<dyn Expirable>>;
+
+/// Periodically sweeps the expired entries out of a set of caches.
+#[derive(Clone)]
+pub struct ExpirySweeper {
+    caches: Arc<Mutex<Vec<SharedCache>>>,
+    interval: Duration,
+    clock: fn() -> SystemTime,
+}
+
+impl ExpirySweeper {
+    pub fn new(interval: Duration) -> Self {
+        ExpirySweeper {
+            caches: Arc::new(Mutex::new(vec![])),
+            interval,
+            clock: SystemTime::now,
+        }
+    }
+
+    /// Sets how often the caches are swept. Takes effect the next time the
+    /// sweeper is run.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Sets the clock used to decide which entries have expired.
+    pub fn set_clock(&mut self, clock: fn() -> SystemTime) {
+        self.clock = clock;
+    }
+
+    /// Adds a cache to be swept. Caches can be added while the sweeper is
+    /// running.
+    pub fn register(&self, cache: Arc<Mutex<dyn Expirable>>) {
+        self.caches.lock().unwrap().push(cache);
+    }
+
+    /// Sweeps every registered cache once.
+    pub fn sweep(&self) {
+        let now = (self.clock)();
+        for cache in self.caches.lock().unwrap().iter() {
+            cache.lock().unwrap().sweep_expired(now);
+        }
+    }
+
+    /// Returns a future that sweeps the caches every interval, starting one
+    /// interval from now.
+    pub fn run(&self) -> impl Future<Item = (), Error = ()> {
+        let sweeper = self.clone();
+        Interval::new(Instant::now() + self.interval, self.interval)
+            .map_err(|e| error!("Expiry sweep timer error: {}", e))
+            .for_each(move |_| {
+                sweeper.sweep();
+                Ok(())
+            })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::DecayingBloomFilter;
+    use log::Level;
+    use std::time::{Duration, SystemTime};
+
+    use super::{DecayingBloomFilter, LogLimiter};
 
     #[test]
     fn decaying_bloom_filter() {
@@ -140,4 +349,55 @@ mod tests {
         assert!(filter.feed(&second[..]));
         assert!(!filter.feed(&third[..]));
     }
+
+    #[test]
+    fn log_limiter() {
+        let start = SystemTime::now();
+        let interval = Duration::from_secs(60);
+        let limiter = LogLimiter::new(interval, 2);
+
+        // Only the first message in a window is logged
+        assert_eq!(limiter.check("peer-a", Level::Error, start), Some(0));
+        for _ in 0..99 {
+            assert_eq!(limiter.check("peer-a", Level::Error, start), None);
+        }
+        assert_eq!(limiter.check("peer-b", Level::Warn, start), Some(0));
+        assert_eq!(
+            limiter.check("peer-b", Level::Warn, start + interval / 2),
+            None
+        );
+
+        // Keys beyond the limit are suppressed, and counted together
+        assert_eq!(limiter.check("peer-c", Level::Error, start), None);
+
+        // The first message after a window reports what was suppressed in it
+        assert_eq!(
+            limiter.check("peer-a", Level::Error, start + interval),
+            Some(99)
+        );
+        assert_eq!(
+            limiter.check("peer-a", Level::Error, start + interval),
+            None
+        );
+
+        // Sweeping closes the windows that have ended
+        assert_eq!(
+            limiter.close_windows(start + interval),
+            (vec![("peer-b", Level::Warn, 1)], 1)
+        );
+        assert_eq!(limiter.windows.lock().unwrap().open.len(), 1);
+
+        // There is room for another key now
+        assert_eq!(
+            limiter.check("peer-c", Level::Error, start + interval),
+            Some(0)
+        );
+
+        // Windows that suppressed nothing close without being reported
+        assert_eq!(
+            limiter.close_windows(start + interval * 2),
+            (vec![("peer-a", Level::Error, 1)], 0)
+        );
+        assert!(limiter.windows.lock().unwrap().open.is_empty());
+    }
 }